@@ -1,6 +1,8 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use clap::Parser;
+use std::path::PathBuf;
 use std::process::{Command, Stdio}; // For executing external commands
+use std::time::Instant;
 
 // ==================================================================================
 // 模块声明 (Module declarations) - 已被移除
@@ -25,6 +27,10 @@ use std::process::{Command, Stdio}; // For executing external commands
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Append a JSON completion record (command, inputs, outputs, counts, elapsed) to this file
+    #[arg(long, global = true)]
+    log_json: Option<PathBuf>,
 }
 
 #[derive(Parser, Debug)]
@@ -71,13 +77,101 @@ enum Commands{
     /// Find motif occurrences and extract flanks; counts unique per-read windows; supports reverse complement
     #[command(name = "find_seq", about = "Find motif occurrences and extract flanks; counts unique per-read windows; supports reverse complement")]
     FindSeq(find_seq::Args),
+
+    /// Build a MinHash sketch of a FASTA/FASTQ file, or estimate the Jaccard similarity of two sketches
+    Sketch(sketch::Args),
+
+    /// Fixed-length, primer and quality trimming in a single pass
+    Trim(trim::Args),
+
+    /// Outer-join CSVs on a shared key column into a wide matrix
+    #[command(name = "merge_csv")]
+    MergeCsv(merge_csv::Args),
+
+    /// Reverse-complement every record of a FASTA/FASTQ file
+    Revcomp(revcomp::Args),
+
+    /// Extract records or FASTA sub-sequences by ID
+    #[command(alias = "extract")]
+    Subseq(subseq::Args),
+
+    /// Convert FASTQ files (or stdin) to FASTA
+    #[command(name = "fq2fa")]
+    Fq2Fa(fq2fa::Args),
+}
+
+impl Commands {
+    fn global(&self) -> &common::GlobalOpts {
+        match self {
+            Commands::DemuxAll(args) => &args.global,
+            Commands::MergePE(args) => &args.global,
+            Commands::DemuxOnly(args) => &args.global,
+            Commands::Fastp(args) => &args.global,
+            Commands::Flash2(args) => &args.global,
+            Commands::Stats(args) => &args.global,
+            Commands::Filter(args) => &args.global,
+            Commands::MergeFile(args) => &args.global,
+            Commands::NsCount(args) => &args.global,
+            Commands::DNA2AA(args) => &args.global,
+            Commands::CountAA(args) => &args.global,
+            Commands::FindSeq(args) => &args.global,
+            Commands::Sketch(args) => &args.global,
+            Commands::Trim(args) => &args.global,
+            Commands::MergeCsv(args) => &args.global,
+            Commands::Revcomp(args) => &args.global,
+            Commands::Subseq(args) => &args.global,
+            Commands::Fq2Fa(args) => &args.global,
+        }
+    }
+
+    /// Whether the subcommand applies `(--threads, --seed)`; the others reject them instead of ignoring them.
+    fn uses_threads_and_seed(&self) -> (bool, bool) {
+        match self {
+            Commands::MergeFile(_) => (true, true),
+            Commands::DemuxAll(_)
+            | Commands::MergePE(_)
+            | Commands::DemuxOnly(_)
+            | Commands::Fastp(_)
+            | Commands::Flash2(_)
+            | Commands::NsCount(_)
+            | Commands::DNA2AA(_)
+            | Commands::CountAA(_) => (true, false),
+            Commands::Stats(_)
+            | Commands::Filter(_)
+            | Commands::FindSeq(_)
+            | Commands::Sketch(_)
+            | Commands::Trim(_)
+            | Commands::MergeCsv(_)
+            | Commands::Revcomp(_)
+            | Commands::Subseq(_)
+            | Commands::Fq2Fa(_) => (false, false),
+        }
+    }
+
+    fn check_global_opts(&self) -> Result<()> {
+        let global = self.global();
+        let (uses_threads, uses_seed) = self.uses_threads_and_seed();
+        if global.threads.is_some() && !uses_threads {
+            return Err(anyhow!("--threads is not supported by this subcommand: it has no parallel step"));
+        }
+        if global.seed.is_some() && !uses_seed {
+            return Err(anyhow!("--seed is not supported by this subcommand: it has no random step"));
+        }
+        Ok(())
+    }
 }
 
 fn main() -> Result<()> {
     // FIX: Changed Cli.parse() to Cli::parse()
     let cli = Cli::parse();
+    cli.command.check_global_opts()?;
+    let start_time = Instant::now();
+    let verbose = cli.command.global().verbose;
+    if verbose {
+        eprintln!("{:#?}", cli.command);
+    }
 
-    match cli.command {
+    let report = match cli.command {
         Commands::DemuxAll(args) => pipeline::run(args),
         Commands::MergePE(args) => merge_pe::run(args),
         Commands::DemuxOnly(args) => demux::run(args),
@@ -90,6 +184,62 @@ fn main() -> Result<()> {
         Commands::DNA2AA(args) => dna2aa::run(args),
         Commands::CountAA(args) => count_aa::run(args),
         Commands::FindSeq(args) => find_seq::run(args), // <-- 新添加的分支
+        Commands::Sketch(args) => sketch::run(args),
+        Commands::Trim(args) => trim::run(args),
+        Commands::MergeCsv(args) => merge_csv::run(args),
+        Commands::Revcomp(args) => revcomp::run(args),
+        Commands::Subseq(args) => subseq::run(args),
+        Commands::Fq2Fa(args) => fq2fa::run(args),
+    }?;
+
+    if verbose {
+        report.print_summary(start_time.elapsed());
+    }
+    if let Some(path) = &cli.log_json {
+        report.write_json(path, start_time.elapsed())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn cli_definition_is_consistent() {
+        // Catches clashing short flags (e.g. a subcommand's -h vs help) that clap only panics on at parse time
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn fastp_html_takes_long_flag_and_h_prints_help() {
+        let cli = Cli::try_parse_from(["hammer_fastx", "fastp", "--single", "-i", "in.fq", "-o", "out.fq", "--html", "r.html"]).unwrap();
+        match cli.command {
+            Commands::Fastp(args) => assert_eq!(args.html, Some(PathBuf::from("r.html"))),
+            other => panic!("unexpected command {:?}", other),
+        }
+        let err = Cli::try_parse_from(["hammer_fastx", "fastp", "-h"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::DisplayHelp);
+    }
+
+    #[test]
+    fn t_is_short_for_threads() {
+        let fastp = Cli::try_parse_from(["hammer_fastx", "fastp", "--single", "-i", "in.fq", "-o", "out.fq", "-t", "8"]).unwrap();
+        let flash2 = Cli::try_parse_from(["hammer_fastx", "flash2", "r1.fq", "r2.fq", "-t", "4"]).unwrap();
+        assert_eq!(fastp.command.global().threads, Some(8));
+        assert_eq!(flash2.command.global().threads, Some(4));
+    }
+
+    #[test]
+    fn threads_and_seed_are_rejected_where_unused() {
+        let check = |argv: &[&str]| Cli::try_parse_from(argv).unwrap().command.check_global_opts();
+        assert!(check(&["hammer_fastx", "stats", "--inputfile", "a.fq", "--threads", "4"]).is_err());
+        assert!(check(&["hammer_fastx", "revcomp", "--inputfile", "a.fq", "--seed", "1"]).is_err());
+        assert!(check(&["hammer_fastx", "Ns_count", "--reads", "r.fa", "--refSEQ", "ref.fa", "--output", "o", "--seed", "1"]).is_err());
+        assert!(check(&["hammer_fastx", "Ns_count", "--reads", "r.fa", "--refSEQ", "ref.fa", "--output", "o", "--threads", "2"]).is_ok());
+        assert!(check(&["hammer_fastx", "merge_file", "--input-files", "a.fq", "--seed", "1", "--threads", "2"]).is_ok());
+        assert!(check(&["hammer_fastx", "stats", "--inputfile", "a.fq", "--no-progress", "--verbose"]).is_ok());
     }
 }
 
@@ -97,24 +247,35 @@ fn main() -> Result<()> {
 // `pipeline` subcommand module (for `demux_all`)
 // ==================================================================================
 mod pipeline {
+    use super::common::{DescArgs, GlobalOpts, RunReport};
     use super::{demux, fastp, flash2};
-    use anyhow::{Context, Result};
+    use anyhow::{anyhow, Context, Result};
     use clap::Parser;
+    use std::collections::HashSet;
     use std::fs;
-    use std::path::PathBuf;
-    use std::time::Instant;
+    use std::path::{Path, PathBuf};
+    use std::time::{Instant, SystemTime};
+
+    /// Written into each step's directory only once that step finished, for --resume; a run
+    /// killed mid-step leaves partial outputs but no marker.
+    const FASTP_DONE_MARKER: &str = ".fastp_complete";
+    const FLASH_DONE_MARKER: &str = ".flash2_complete";
+    const DEMUX_DONE_MARKER: &str = ".demux_complete";
 
     #[derive(Parser, Debug)]
     #[command(name = "demux_all", about = "[Workflow] Run the complete pipeline: QC (fastp), merge (flash2), and demultiplex (demux)")]
     pub struct Args {
-        #[arg(short = 'i', long, help = "Input file 1 (Raw Read1)")]
-        pub in1: PathBuf,
+        #[arg(short = 'i', long, required_unless_present = "sample_sheet", conflicts_with = "sample_sheet", help = "Input file 1 (Raw Read1)")]
+        pub in1: Option<PathBuf>,
 
-        #[arg(short = 'I', long, help = "Input file 2 (Raw Read2)")]
-        pub in2: PathBuf,
+        #[arg(short = 'I', long, required_unless_present = "sample_sheet", conflicts_with = "sample_sheet", help = "Input file 2 (Raw Read2)")]
+        pub in2: Option<PathBuf>,
 
-        #[arg(long, help = "Sample tags file for demultiplexing (CSV format)")]
-        pub tags: PathBuf,
+        #[arg(long, required_unless_present = "sample_sheet", conflicts_with = "sample_sheet", help = "Sample tags file for demultiplexing (CSV format)")]
+        pub tags: Option<PathBuf>,
+
+        #[arg(long, help = "Batch mode: CSV with a header and rows of sampleName,R1,R2,tags; each row runs the whole pipeline into <output-dir>/<sampleName>, one after another")]
+        pub sample_sheet: Option<PathBuf>,
 
         #[arg(short = 'o', long, help = "Main output directory for all results and intermediate files")]
         pub output_dir: PathBuf,
@@ -122,81 +283,242 @@ mod pipeline {
         #[arg(long, help = "Delete intermediate files from fastp and flash2 upon successful completion")]
         pub cleanup: bool,
 
-        #[arg(long, help = "Number of threads for fastp", default_value_t = 4)]
+        #[arg(long, help = "Skip steps whose outputs already exist and are newer than their inputs; everything after the first re-run step runs again (option changes are not detected)")]
+        pub resume: bool,
+
+        #[arg(long, help = "Number of threads for fastp (overridden by --threads)", default_value_t = 4)]
         pub fastp_threads: usize,
 
-        #[arg(long, help = "Number of threads for flash2", default_value_t = 4)]
+        #[arg(long, help = "Number of threads for flash2 (overridden by --threads)", default_value_t = 4)]
         pub flash_threads: usize,
         #[arg(long, help = "Minimum overlap length for flash2", default_value_t = 10)]
         pub min_overlap: usize,
         #[arg(long, help = "Maximum overlap length for flash2", default_value_t = 300)]
         pub max_overlap: usize,
-
-        #[arg(long, help = "Number of threads for demux_only", default_value_t = num_cpus::get_physical())]
+        #[arg(long, help = "Estimate flash2 overlap bounds from the first read pairs instead of using --max-overlap")]
+        pub auto_overlap: bool,
+        #[arg(long, help = "Number of read pairs sampled for --auto-overlap", default_value_t = 10000)]
+        pub auto_overlap_reads: usize,
+        #[arg(long, help = "Expected amplicon length used by --auto-overlap (max_overlap = 2*read_len - amplicon_len)")]
+        pub amplicon_len: Option<usize>,
+
+        #[arg(long, help = "Number of threads for demux_only (overridden by --threads)", default_value_t = num_cpus::get_physical())]
         pub demux_threads: usize,
-        #[arg(short = 'l', long, help = "Tag length for demux_only", default_value_t = 8)]
-        pub tag_len: usize,
+        #[arg(short = 'l', long, help = "Tag length for demux_only; if set, every tag must have this length (default: taken from the tag file)")]
+        pub tag_len: Option<usize>,
         #[arg(long, help = "Activate tag trimming for demux_only")]
         pub trim: bool,
         #[arg(long, help = "Output in FASTA format after demux_only (default: FASTQ)")]
         pub out_fasta: bool,
+        #[command(flatten)]
+        pub desc: DescArgs,
+        #[command(flatten)]
+        pub global: GlobalOpts,
+    }
+
+    fn modified(path: &Path) -> Option<SystemTime> {
+        fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    /// True when every output exists and none is older than any input. A missing input (e.g. an
+    /// intermediate removed by --cleanup) counts as stale, so the run restarts from that step.
+    fn up_to_date(outputs: &[&Path], inputs: &[&Path]) -> bool {
+        let Some(newest_input) = inputs.iter().map(|p| modified(p)).collect::<Option<Vec<_>>>().and_then(|t| t.into_iter().max()) else {
+            return false;
+        };
+        outputs.iter().all(|p| modified(p).is_some_and(|t| t >= newest_input))
+    }
+
+    /// Removes a step's completion marker before re-running it, so a stale marker cannot outlive a failed re-run.
+    fn clear_marker(marker: &Path) -> Result<()> {
+        if marker.exists() {
+            fs::remove_file(marker).with_context(|| format!("Failed to remove {:?}", marker))?;
+        }
+        Ok(())
+    }
+
+    fn write_marker(marker: &Path) -> Result<()> {
+        fs::write(marker, "").with_context(|| format!("Failed to write {:?}", marker))
+    }
+
+    /// One R1/R2 pair with its tag file (a --sample-sheet row, or the --in1/--in2/--tags run).
+    struct Sample {
+        name: String,
+        in1: PathBuf,
+        in2: PathBuf,
+        tags: PathBuf,
+    }
+
+    fn read_sample_sheet(path: &Path) -> Result<Vec<Sample>> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(path)
+            .with_context(|| format!("Failed to open sample sheet: {:?}", path))?;
+        let mut samples = Vec::new();
+        let mut names = HashSet::new();
+        for (i, result) in rdr.records().enumerate() {
+            let record = result.with_context(|| format!("Failed to read sample sheet {:?}", path))?;
+            if record.len() < 4 {
+                return Err(anyhow!("Sample sheet {:?} row {}: expected sampleName,R1,R2,tags", path, i + 2));
+            }
+            let name = record[0].to_string();
+            if name.is_empty() || name.contains(['/', '\\']) {
+                return Err(anyhow!("Sample sheet {:?} row {}: invalid sample name {:?}", path, i + 2, name));
+            }
+            if !names.insert(name.clone()) {
+                return Err(anyhow!("Sample sheet {:?}: duplicate sample name {}", path, name));
+            }
+            samples.push(Sample { name, in1: record[1].into(), in2: record[2].into(), tags: record[3].into() });
+        }
+        if samples.is_empty() {
+            return Err(anyhow!("Sample sheet {:?} has no samples", path));
+        }
+        Ok(samples)
+    }
+
+    pub fn run(args: Args) -> Result<RunReport> {
+        let Some(sheet) = &args.sample_sheet else {
+            // clap guarantees all three without --sample-sheet
+            let sample = Sample {
+                name: String::new(),
+                in1: args.in1.clone().unwrap(),
+                in2: args.in2.clone().unwrap(),
+                tags: args.tags.clone().unwrap(),
+            };
+            return run_sample(&args, &sample, &args.output_dir);
+        };
+
+        let total_start_time = Instant::now();
+        let samples = read_sample_sheet(sheet)?;
+        println!("📋 [Batch] {} sample(s) from {}", samples.len(), sheet.display());
+        let mut report = RunReport::new("demux_all").input(sheet).count("samples", samples.len() as u64);
+        let mut times = Vec::with_capacity(samples.len());
+        for (i, sample) in samples.iter().enumerate() {
+            println!("\n================ [Batch {}/{}] Sample {} ================", i + 1, samples.len(), sample.name);
+            let start = Instant::now();
+            let sample_report = run_sample(&args, sample, &args.output_dir.join(&sample.name))
+                .with_context(|| format!("Sample {} failed", sample.name))?;
+            times.push(start.elapsed());
+            report = report.merge_prefixed(&sample.name, sample_report);
+        }
+
+        println!("\n📋 [Batch] Summary");
+        for (sample, elapsed) in samples.iter().zip(&times) {
+            println!("   - {:<20} ✔ {:.2?} -> {}", sample.name, elapsed, args.output_dir.join(&sample.name).display());
+        }
+        println!("🎉 [Batch] All {} samples completed! Total time: {:.2?}", samples.len(), total_start_time.elapsed());
+        Ok(report)
     }
 
-    pub fn run(args: Args) -> Result<()> {
+    fn run_sample(args: &Args, sample: &Sample, output_dir: &Path) -> Result<RunReport> {
         let total_start_time = Instant::now();
         println!("🚀 [Workflow] Starting hammer_fastx demux_all pipeline...");
+        // Once a step re-runs, every later step must too
+        let mut rerun = !args.resume;
 
-        let fastp_dir = args.output_dir.join("01_fastp_out");
-        let flash_dir = args.output_dir.join("02_flash2_out");
-        let demux_dir = args.output_dir.join("03_demux_out");
+        let fastp_dir = output_dir.join("01_fastp_out");
+        let flash_dir = output_dir.join("02_flash2_out");
+        let demux_dir = output_dir.join("03_demux_out");
 
-        fs::create_dir_all(&args.output_dir)
-            .with_context(|| format!("Failed to create main output directory: {:?}", args.output_dir))?;
+        fs::create_dir_all(output_dir)
+            .with_context(|| format!("Failed to create main output directory: {:?}", output_dir))?;
         fs::create_dir_all(&fastp_dir)
             .with_context(|| format!("Failed to create fastp output directory: {:?}", fastp_dir))?;
         fs::create_dir_all(&flash_dir)
             .with_context(|| format!("Failed to create flash2 output directory: {:?}", flash_dir))?;
         
-        println!("\n[Step 1/3] ➡️  Running fastp for quality control...");
         let fastp_out1 = fastp_dir.join("filtered_R1.fastq.gz");
         let fastp_out2 = fastp_dir.join("filtered_R2.fastq.gz");
-        let fastp_args = fastp::Args {
-            in1: args.in1.clone(),
-            in2: args.in2.clone(),
-            out1: fastp_out1.clone(),
-            out2: fastp_out2.clone(),
-            html: Some(fastp_dir.join("fastp_report.html")),
-            json: Some(fastp_dir.join("fastp_report.json")),
-            report_title: "Hammer_fastx demux_all pipeline: fastp report".to_string(),
-            threads: Some(args.fastp_threads),
-        };
-        fastp::run(fastp_args)?;
+        let fastp_marker = fastp_dir.join(FASTP_DONE_MARKER);
+        rerun = rerun || !up_to_date(&[&fastp_marker, &fastp_out1, &fastp_out2], &[&sample.in1, &sample.in2]);
+        if rerun {
+            println!("\n[Step 1/3] ➡️  Running fastp for quality control...");
+            clear_marker(&fastp_marker)?;
+            let fastp_args = fastp::Args {
+                in1: sample.in1.clone(),
+                in2: Some(sample.in2.clone()),
+                out1: fastp_out1.clone(),
+                out2: Some(fastp_out2.clone()),
+                single: false,
+                html: Some(fastp_dir.join("fastp_report.html")),
+                json: Some(fastp_dir.join("fastp_report.json")),
+                report_title: "Hammer_fastx demux_all pipeline: fastp report".to_string(),
+                extra: Vec::new(),
+                // --threads, when given, replaces every per-step thread count
+                global: GlobalOpts { threads: Some(args.global.threads.unwrap_or(args.fastp_threads)), ..args.global },
+            };
+            fastp::run(fastp_args)?;
+            write_marker(&fastp_marker)?;
+        } else {
+            println!("\n[Step 1/3] ⏭  Skipping fastp: outputs are up to date (--resume)");
+        }
 
-        println!("\n[Step 2/3] ➡️  Running flash2 to merge reads...");
         let flash_prefix = "merged";
-        let flash_args = flash2::Args {
-            in1: fastp_out1.clone(),
-            in2: fastp_out2.clone(),
-            out_prefix: flash_prefix.to_string(),
-            out_dir: flash_dir.clone(),
-            min_overlap: args.min_overlap,
-            max_overlap: args.max_overlap,
-            threads: args.flash_threads,
-        };
-        flash2::run(flash_args)?;
-
-        println!("\n[Step 3/3] ➡️  Running demux_only to demultiplex...");
         let demux_input = flash_dir.join(format!("{}.extendedFrags.fastq", flash_prefix));
-        let demux_args = demux::Args {
-            inputfile: demux_input,
-            output: demux_dir.clone(),
-            threads: args.demux_threads,
-            tags: args.tags.clone(),
-            tag_len: args.tag_len,
-            trim: args.trim,
-            out_fasta: args.out_fasta,
-        };
-        demux::run(demux_args)?;
+        let flash_marker = flash_dir.join(FLASH_DONE_MARKER);
+        rerun = rerun || !up_to_date(&[&flash_marker, &demux_input], &[&fastp_out1, &fastp_out2]);
+        if rerun {
+            println!("\n[Step 2/3] ➡️  Running flash2 to merge reads...");
+            clear_marker(&flash_marker)?;
+            let (min_overlap, max_overlap) = if args.auto_overlap {
+                flash2::estimate_overlap(&fastp_out1, &fastp_out2, args.auto_overlap_reads, args.amplicon_len, args.min_overlap)?
+            } else {
+                (args.min_overlap, args.max_overlap)
+            };
+            let flash_args = flash2::Args {
+                in1: fastp_out1.clone(),
+                in2: fastp_out2.clone(),
+                out_prefix: flash_prefix.to_string(),
+                out_dir: flash_dir.clone(),
+                min_overlap,
+                max_overlap,
+                extra: Vec::new(),
+                global: GlobalOpts { threads: Some(args.global.threads.unwrap_or(args.flash_threads)), ..args.global },
+            };
+            flash2::run(flash_args)?;
+            write_marker(&flash_marker)?;
+        } else {
+            println!("\n[Step 2/3] ⏭  Skipping flash2: merged reads are up to date (--resume)");
+        }
+
+        let demux_marker = demux_dir.join(DEMUX_DONE_MARKER);
+        rerun = rerun || !up_to_date(&[&demux_marker], &[&demux_input, &sample.tags]);
+        let mut demux_report = None;
+        if rerun {
+            println!("\n[Step 3/3] ➡️  Running demux_only to demultiplex...");
+            clear_marker(&demux_marker)?;
+            let demux_args = demux::Args {
+                inputfile: Some(demux_input),
+                in1: None,
+                in2: None,
+                output: Some(demux_dir.clone()),
+                count_only: false,
+                tags: sample.tags.clone(),
+                tag_len: args.tag_len,
+                trim: args.trim,
+                out_fasta: args.out_fasta,
+                anchor: demux::Anchor::Both,
+                single_tag: false,
+                tag_orientation_stats: None,
+                total_reads: None,
+                also_fastq: false,
+                mismatches: 0,
+                max_open_files: 0,
+                gzip: false,
+                summary_csv: None,
+                detect_chimera: false,
+                scan_tag_len: None,
+                scan_reads: 0,
+                min_sample_reads: None,
+                merge_low_yield: false,
+                desc: args.desc,
+                global: GlobalOpts { threads: Some(args.global.threads.unwrap_or(args.demux_threads)), ..args.global },
+            };
+            demux_report = Some(demux::run(demux_args)?);
+            write_marker(&demux_marker)?;
+        } else {
+            println!("\n[Step 3/3] ⏭  Skipping demux_only: results are up to date (--resume)");
+        }
 
         if args.cleanup {
             println!("\n[Cleanup] Removing intermediate files...");
@@ -210,7 +532,15 @@ mod pipeline {
         println!("\n🎉 [Workflow] All steps completed successfully! Total time: {:.2?}", total_start_time.elapsed());
         println!("Final demultiplexed results are in: {}", demux_dir.display());
 
-        Ok(())
+        let report = RunReport::new("demux_all")
+            .input(&sample.in1)
+            .input(&sample.in2)
+            .input(&sample.tags)
+            .output(&demux_dir);
+        Ok(match demux_report {
+            Some(demux_report) => report.counts_from(demux_report),
+            None => report,
+        })
     }
 }
 
@@ -218,13 +548,14 @@ mod pipeline {
 // `merge_pe` subcommand module
 // ==================================================================================
 mod merge_pe {
+    use super::common::{create_output, strip_compression_ext, DescArgs, GlobalOpts, RunReport};
     use super::{fastp, flash2};
     use anyhow::{anyhow, Context, Result};
     use bio::io::{fasta, fastq};
     use clap::Parser;
     use std::fs;
     use std::io::BufReader;
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
     use std::time::Instant;
 
     #[derive(Parser, Debug)]
@@ -240,23 +571,37 @@ mod merge_pe {
 
         #[arg(long, help = "Convert final output to FASTA format (default: FASTQ)")]
         pub out_fasta: bool,
+        #[arg(long, help = "Also write the pairs flash2 could not merge to {outfile_stem}_unmerged_R1/R2 next to the output")]
+        pub keep_unmerged: bool,
+        #[arg(long, help = "Also write the pairs flash2 could not merge (notCombined_1/2) to this path as interleaved FASTQ")]
+        pub interleaved: Option<PathBuf>,
         #[arg(long, help = "Delete intermediate files upon successful completion")]
         pub cleanup: bool,
         #[arg(long, help = "Directory for intermediate files (default: 'intermediates' in the output file's directory)")]
         pub temp_dir: Option<PathBuf>,
 
-        #[arg(long, help = "Number of threads for fastp", default_value_t = 4)]
+        #[arg(long, help = "Number of threads for fastp (overridden by --threads)", default_value_t = 4)]
         pub fastp_threads: usize,
 
-        #[arg(long, help = "Number of threads for flash2", default_value_t = 4)]
+        #[arg(long, help = "Number of threads for flash2 (overridden by --threads)", default_value_t = 4)]
         pub flash_threads: usize,
         #[arg(long, help = "Minimum overlap length for flash2", default_value_t = 10)]
         pub min_overlap: usize,
         #[arg(long, help = "Maximum overlap length for flash2", default_value_t = 300)]
         pub max_overlap: usize,
+        #[arg(long, help = "Estimate flash2 overlap bounds from the first read pairs instead of using --max-overlap")]
+        pub auto_overlap: bool,
+        #[arg(long, help = "Number of read pairs sampled for --auto-overlap", default_value_t = 10000)]
+        pub auto_overlap_reads: usize,
+        #[arg(long, help = "Expected amplicon length used by --auto-overlap (max_overlap = 2*read_len - amplicon_len)")]
+        pub amplicon_len: Option<usize>,
+        #[command(flatten)]
+        pub desc: DescArgs,
+        #[command(flatten)]
+        pub global: GlobalOpts,
     }
 
-    pub fn run(args: Args) -> Result<()> {
+    pub fn run(args: Args) -> Result<RunReport> {
         let total_start_time = Instant::now();
         println!("🚀 [Workflow] Starting hammer_fastx mergePE workflow...");
 
@@ -273,68 +618,202 @@ mod merge_pe {
         let fastp_out2 = temp_dir.join("filtered_R2.fastq.gz");
         let fastp_args = fastp::Args {
             in1: args.in1.clone(),
-            in2: args.in2.clone(),
+            in2: Some(args.in2.clone()),
             out1: fastp_out1.clone(),
-            out2: fastp_out2.clone(),
+            out2: Some(fastp_out2.clone()),
+            single: false,
             html: Some(temp_dir.join("fastp_report.html")),
             json: Some(temp_dir.join("fastp_report.json")),
             report_title: "Hammer_fastx mergePE: fastp report".to_string(),
-            threads: Some(args.fastp_threads),
+            extra: Vec::new(),
+            // --threads, when given, replaces every per-step thread count
+            global: GlobalOpts { threads: Some(args.global.threads.unwrap_or(args.fastp_threads)), ..args.global },
         };
         fastp::run(fastp_args)?;
 
         println!("\n[Step 2/3] ➡️  Running flash2 to merge reads...");
+        let (min_overlap, max_overlap) = if args.auto_overlap {
+            flash2::estimate_overlap(&fastp_out1, &fastp_out2, args.auto_overlap_reads, args.amplicon_len, args.min_overlap)?
+        } else {
+            (args.min_overlap, args.max_overlap)
+        };
         let flash_prefix = "merged";
         let flash_args = flash2::Args {
             in1: fastp_out1.clone(),
             in2: fastp_out2.clone(),
             out_prefix: flash_prefix.to_string(),
             out_dir: temp_dir.clone(),
-            min_overlap: args.min_overlap,
-            max_overlap: args.max_overlap,
-            threads: args.flash_threads,
+            min_overlap,
+            max_overlap,
+            extra: Vec::new(),
+            global: GlobalOpts { threads: Some(args.global.threads.unwrap_or(args.flash_threads)), ..args.global },
         };
         flash2::run(flash_args)?;
 
         println!("\n[Step 3/3] ➡️  Writing final output file...");
         let merged_fastq_path = temp_dir.join(format!("{}.extendedFrags.fastq", flash_prefix));
-        
-        let in_file = fs::File::open(&merged_fastq_path)
-            .with_context(|| format!("Failed to open merged file: {:?}", merged_fastq_path))?;
-        let in_reader = BufReader::new(in_file);
-        let fastq_reader = fastq::Reader::new(in_reader);
+        let records_written = copy_records(&merged_fastq_path, &args.outfile, &args)?;
+        println!("✔ Successfully wrote {} records to {}", records_written, args.outfile.display());
+
+        let unmerged_r1 = temp_dir.join(format!("{}.notCombined_1.fastq", flash_prefix));
+        let unmerged_r2 = temp_dir.join(format!("{}.notCombined_2.fastq", flash_prefix));
+        let mut unmerged_outputs = Vec::new();
+        let unmerged_pairs = if args.keep_unmerged {
+            let mut counts = [0; 2];
+            for (count, (input, mate)) in counts.iter_mut().zip([(&unmerged_r1, "R1"), (&unmerged_r2, "R2")]) {
+                let output = unmerged_output_path(&args.outfile, mate, args.out_fasta)?;
+                *count = copy_records(input, &output, &args)?;
+                println!("✔ Wrote {} unmerged {} records to {}", count, mate, output.display());
+                unmerged_outputs.push(output);
+            }
+            if counts[0] != counts[1] {
+                return Err(anyhow!(
+                    "Unmerged files have different record counts: {:?} has {}, {:?} has {}",
+                    unmerged_r1, counts[0], unmerged_r2, counts[1]
+                ));
+            }
+            counts[0]
+        } else {
+            let file = fs::File::open(&unmerged_r1)
+                .with_context(|| format!("Failed to open unmerged file: {:?}", unmerged_r1))?;
+            let mut count = 0;
+            for result in fastq::Reader::new(file).records() {
+                result?;
+                count += 1;
+            }
+            count
+        };
+        let total_pairs = records_written + unmerged_pairs;
+        let merged_pct = if total_pairs > 0 { records_written as f64 * 100.0 / total_pairs as f64 } else { 0.0 };
+        println!("📊 Pairs merged: {} / {} ({:.2}%), left unmerged: {}", records_written, total_pairs, merged_pct, unmerged_pairs);
+
+        let mut pairs_interleaved = None;
+        if let Some(interleaved_path) = &args.interleaved {
+            let pairs = write_interleaved(&unmerged_r1, &unmerged_r2, interleaved_path)?;
+            println!("✔ Wrote {} unmerged pairs (interleaved) to {}", pairs, interleaved_path.display());
+            pairs_interleaved = Some(pairs);
+        }
+
+        if args.cleanup {
+            println!("\n[Cleanup] Removing intermediate files...");
+            fs::remove_dir_all(&temp_dir)
+                .with_context(|| format!("Failed to clean up temporary directory: {:?}", temp_dir))?;
+            println!("✔ Cleanup complete.");
+        }
+
+        println!("\n🎉 [Workflow] mergePE workflow completed successfully! Total time: {:.2?}", total_start_time.elapsed());
+        let mut report = RunReport::new("mergePE")
+            .input(&args.in1)
+            .input(&args.in2)
+            .output(&args.outfile)
+            .count("records_written", records_written)
+            .count("unmerged_pairs", unmerged_pairs);
+        for path in &unmerged_outputs {
+            report = report.output(path);
+        }
+        if let (Some(path), Some(pairs)) = (&args.interleaved, pairs_interleaved) {
+            report = report.output(path).count("pairs_interleaved", pairs as u64);
+        }
+        Ok(report)
+    }
 
-        let out_file = fs::File::create(&args.outfile)
-            .with_context(|| format!("Failed to create final output file: {:?}", args.outfile))?;
+    /// Copies a flash2 FASTQ output to `output`, converting to FASTA with `--out-fasta`
+    /// and gzip-compressing when `output` ends in `.gz`. Returns the number of records.
+    fn copy_records(input: &Path, output: &Path, args: &Args) -> Result<u64> {
+        let in_file = fs::File::open(input)
+            .with_context(|| format!("Failed to open flash2 output: {:?}", input))?;
+        let fastq_reader = fastq::Reader::new(in_file);
+        let mut out_file = create_output(output)?;
 
         let mut records_written = 0;
         if args.out_fasta {
-            let mut fasta_writer = fasta::Writer::new(out_file);
+            let mut fasta_writer = fasta::Writer::new(&mut out_file);
             for result in fastq_reader.records() {
                 let record = result?;
-                let fasta_record = fasta::Record::with_attrs(record.id(), record.desc(), record.seq());
+                let fasta_record = fasta::Record::with_attrs(record.id(), args.desc.apply(record.desc()), record.seq());
                 fasta_writer.write_record(&fasta_record)?;
                 records_written += 1;
             }
+            fasta_writer.flush()?;
         } else {
-            let mut fastq_writer = fastq::Writer::new(out_file);
+            let mut fastq_writer = fastq::Writer::new(&mut out_file);
             for result in fastq_reader.records() {
                 let record = result?;
                 fastq_writer.write_record(&record)?;
                 records_written += 1;
             }
+            fastq_writer.flush()?;
         }
-        println!("✔ Successfully wrote {} records to {}", records_written, args.outfile.display());
+        out_file.finish()?;
+        Ok(records_written)
+    }
 
-        if args.cleanup {
-            println!("\n[Cleanup] Removing intermediate files...");
-            fs::remove_dir_all(&temp_dir)
-                .with_context(|| format!("Failed to clean up temporary directory: {:?}", temp_dir))?;
-            println!("✔ Cleanup complete.");
+    /// `{outfile_stem}_unmerged_R1.fastq[.gz]` next to the final output, keeping its extension.
+    fn unmerged_output_path(outfile: &Path, mate: &str, out_fasta: bool) -> Result<PathBuf> {
+        let file_name = outfile.file_name().and_then(|n| n.to_str())
+            .ok_or_else(|| anyhow!("Invalid output file name: {:?}", outfile))?;
+        let gz = if file_name.ends_with(".gz") { ".gz" } else { "" };
+        let base = Path::new(strip_compression_ext(file_name));
+        let stem = base.file_stem().and_then(|s| s.to_str()).unwrap_or(file_name);
+        let ext = base.extension().and_then(|e| e.to_str())
+            .unwrap_or(if out_fasta { "fasta" } else { "fastq" });
+        Ok(outfile.with_file_name(format!("{}_unmerged_{}.{}{}", stem, mate, ext, gz)))
+    }
+
+    /// Writes R1/R2 records alternately into one FASTQ file, failing if the
+    /// two inputs do not hold the same number of records.
+    fn write_interleaved(r1: &Path, r2: &Path, out: &Path) -> Result<usize> {
+        let open = |path: &Path| -> Result<fastq::Reader<BufReader<fs::File>>> {
+            let file = fs::File::open(path).with_context(|| format!("Failed to open unmerged file: {:?}", path))?;
+            Ok(fastq::Reader::new(file))
+        };
+        let mut records1 = open(r1)?.records();
+        let mut records2 = open(r2)?.records();
+        let out_file = fs::File::create(out)
+            .with_context(|| format!("Failed to create interleaved output file: {:?}", out))?;
+        let mut writer = fastq::Writer::new(out_file);
+
+        let mut pairs = 0;
+        loop {
+            match (records1.next(), records2.next()) {
+                (Some(rec1), Some(rec2)) => {
+                    writer.write_record(&rec1?)?;
+                    writer.write_record(&rec2?)?;
+                    pairs += 1;
+                }
+                (None, None) => break,
+                (rest1, rest2) => {
+                    let count1 = pairs + rest1.map_or(0, |_| 1) + records1.count();
+                    let count2 = pairs + rest2.map_or(0, |_| 1) + records2.count();
+                    return Err(anyhow!(
+                        "Unmerged files have different record counts: {:?} has {}, {:?} has {}",
+                        r1, count1, r2, count2
+                    ));
+                }
+            }
         }
+        writer.flush()?;
+        Ok(pairs)
+    }
 
-        println!("\n🎉 [Workflow] mergePE workflow completed successfully! Total time: {:.2?}", total_start_time.elapsed());
-        Ok(())
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::common::test_util::{fasta_headers, test_dir};
+        use clap::Parser;
+
+        #[test]
+        fn fasta_output_keeps_or_strips_descriptions() {
+            let dir = test_dir("merge_pe_desc");
+            let merged = dir.join("merged.extendedFrags.fastq");
+            fs::write(&merged, "@r1 sample=A\nACGT\n+\nIIII\n").unwrap();
+            for (flag, expected) in [("--keep-desc", ">r1 sample=A"), ("--strip-desc", ">r1")] {
+                let out = dir.join("out.fasta");
+                let args = Args::try_parse_from(["mergePE", "-i", "r1", "-I", "r2", "-o", "out.fasta", "--out-fasta", flag]).unwrap();
+                assert_eq!(copy_records(&merged, &out, &args).unwrap(), 1);
+                assert_eq!(fasta_headers(&out), [expected]);
+            }
+        }
     }
 }
 
@@ -342,30 +821,36 @@ mod merge_pe {
 // `fastp` subcommand module
 // ==================================================================================
 mod fastp {
+    use super::common::{run_tool, GlobalOpts, RunReport};
     use super::{Command, Stdio};
-    use anyhow::{anyhow, Context, Result};
+    use anyhow::{anyhow, Result};
     use clap::Parser;
     use std::path::PathBuf;
 
     #[derive(Parser, Debug)]
     #[command(
         name = "fastp",
-        about = "(Wrapper) Quality control paired-end FASTQ files using fastp"
+        about = "(Wrapper) Quality control paired-end (or, with --single, single-end) FASTQ files using fastp",
+        // -t/--threads as in fastp itself (not global: demux uses -t for --tags)
+        mut_arg("threads", |arg| arg.short('t'))
     )]
     pub struct Args {
-        #[arg(short = 'i', long, help = "Input file 1 (Read1)")]
+        #[arg(short = 'i', long, help = "Input file 1 (Read1, or the only input with --single)")]
         pub in1: PathBuf,
 
-        #[arg(short = 'I', long, help = "Input file 2 (Read2)")]
-        pub in2: PathBuf,
+        #[arg(short = 'I', long, required_unless_present = "single", conflicts_with = "single", help = "Input file 2 (Read2)")]
+        pub in2: Option<PathBuf>,
 
-        #[arg(short = 'o', long, help = "Output file 1 (Read1)")]
+        #[arg(short = 'o', long, help = "Output file 1 (Read1, or the only output with --single)")]
         pub out1: PathBuf,
 
-        #[arg(short = 'O', long, help = "Output file 2 (Read2)")]
-        pub out2: PathBuf,
+        #[arg(short = 'O', long, required_unless_present = "single", conflicts_with = "single", help = "Output file 2 (Read2)")]
+        pub out2: Option<PathBuf>,
 
-        #[arg(short = 'h', long, help = "Specify path for HTML report")]
+        #[arg(long, help = "Single-end mode: QC --in1 into --out1 only (for long-read or already-merged data)")]
+        pub single: bool,
+
+        #[arg(long, help = "Specify path for HTML report")]
         pub html: Option<PathBuf>,
 
         #[arg(short = 'j', long, help = "Specify path for JSON report")]
@@ -374,8 +859,12 @@ mod fastp {
         #[arg(short = 'R', long, help = "Report title", default_value = "fastp report")]
         pub report_title: String,
 
-        #[arg(short = 't', long, help = "Number of threads (default: auto-detect)")]
-        pub threads: Option<usize>,
+        #[arg(last = true, help = "Extra fastp arguments, given after `--` and appended verbatim (e.g. -- --cut_tail -q 20)")]
+        pub extra: Vec<String>,
+
+        // --threads is passed to fastp as -t (default: fastp's own choice)
+        #[command(flatten)]
+        pub global: GlobalOpts,
     }
 
     fn command_exists(cmd: &str) -> bool {
@@ -387,7 +876,7 @@ mod fastp {
             .is_ok()
     }
 
-    pub fn run(args: Args) -> Result<()> {
+    pub fn run(args: Args) -> Result<RunReport> {
         println!("---> Starting fastp quality control...");
 
         if !command_exists("fastp") {
@@ -396,11 +885,20 @@ mod fastp {
             ));
         }
 
+        let mates = match (args.single, &args.in2, &args.out2) {
+            (true, None, None) => None,
+            (false, Some(in2), Some(out2)) => Some((in2, out2)),
+            (true, _, _) => return Err(anyhow!("--in2/--out2 cannot be used with --single")),
+            (false, _, _) => return Err(anyhow!("Paired-end mode needs both --in2 and --out2 (use --single for single-end data)")),
+        };
+
         let mut cmd = Command::new("fastp");
         cmd.arg("-i").arg(&args.in1);
-        cmd.arg("-I").arg(&args.in2);
         cmd.arg("-o").arg(&args.out1);
-        cmd.arg("-O").arg(&args.out2);
+        if let Some((in2, out2)) = mates {
+            cmd.arg("-I").arg(in2);
+            cmd.arg("-O").arg(out2);
+        }
         cmd.arg("-R").arg(&args.report_title);
 
         if let Some(html_path) = &args.html {
@@ -409,30 +907,29 @@ mod fastp {
         if let Some(json_path) = &args.json {
             cmd.arg("-j").arg(json_path);
         }
-        if let Some(threads) = args.threads {
-            cmd.arg("-t").arg(threads.to_string());
+        if args.global.threads.is_some() {
+            cmd.arg("-t").arg(args.global.threads_or(0).to_string());
         }
+        cmd.args(&args.extra);
 
         println!("🔧 Executing command: {:?}", cmd);
 
-        let status = cmd
-            .status()
-            .with_context(|| "Failed to execute fastp command. Please check if fastp is installed correctly.")?;
+        run_tool(&mut cmd, "fastp")?;
 
-        if status.success() {
-            println!("\n✔ fastp quality control completed successfully!");
-            println!("   - Cleaned R1: {}", args.out1.display());
-            println!("   - Cleaned R2: {}", args.out2.display());
-            if let Some(html_path) = &args.html {
-                println!("   - HTML Report: {}", html_path.display());
+        println!("\n✔ fastp quality control completed successfully!");
+        let mut report = RunReport::new("fastp").input(&args.in1).output(&args.out1);
+        match mates {
+            Some((in2, out2)) => {
+                println!("   - Cleaned R1: {}", args.out1.display());
+                println!("   - Cleaned R2: {}", out2.display());
+                report = report.input(in2).output(out2);
             }
-            Ok(())
-        } else {
-            Err(anyhow!(
-                "fastp execution failed with exit code: {:?}\nPlease check the fastp logs for detailed error information.",
-                status.code()
-            ))
+            None => println!("   - Cleaned reads: {}", args.out1.display()),
         }
+        if let Some(html_path) = &args.html {
+            println!("   - HTML Report: {}", html_path.display());
+        }
+        Ok(report)
     }
 }
 
@@ -440,15 +937,18 @@ mod fastp {
 // `flash2` subcommand module
 // ==================================================================================
 mod flash2 {
+    use super::common::{open_input, run_tool, GlobalOpts, RunReport};
     use super::{Command, Stdio};
     use anyhow::{anyhow, Context, Result};
+    use bio::io::fastq;
     use clap::Parser;
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
 
     #[derive(Parser, Debug)]
     #[command(
         name = "flash2",
-        about = "(Wrapper) Merge paired-end reads using flash2"
+        about = "(Wrapper) Merge paired-end reads using flash2",
+        mut_arg("threads", |arg| arg.short('t'))
     )]
     pub struct Args {
         #[arg(help = "Input Read1 file")]
@@ -469,8 +969,12 @@ mod flash2 {
         #[arg(short = 'M', long, help = "Maximum overlap length", default_value_t = 300)]
         pub max_overlap: usize,
 
-        #[arg(short = 't', long, help = "Number of threads (default: 1)", default_value_t = 1)]
-        pub threads: usize,
+        #[arg(last = true, help = "Extra flash2 arguments, given after `--` and appended verbatim (e.g. -- --allow-outies)")]
+        pub extra: Vec<String>,
+
+        // --threads is passed to flash2 as -t (default: 1)
+        #[command(flatten)]
+        pub global: GlobalOpts,
     }
     
     fn command_exists(cmd: &str) -> bool {
@@ -483,7 +987,53 @@ mod flash2 {
             .is_ok()
     }
 
-    pub fn run(args: Args) -> Result<()> {
+    fn mean_read_len(path: &Path, sample_pairs: usize) -> Result<usize> {
+        let reader = open_input(path).with_context(|| format!("Failed to open {:?}", path))?;
+        let mut n = 0usize;
+        let mut total = 0usize;
+        for result in fastq::Reader::new(reader).records().take(sample_pairs) {
+            total += result?.seq().len();
+            n += 1;
+        }
+        if n == 0 {
+            return Err(anyhow!("No reads found in {:?} while estimating overlap", path));
+        }
+        Ok((total as f64 / n as f64).round() as usize)
+    }
+
+    /// Estimates (min_overlap, max_overlap) for flash2 from the first `sample_pairs` read pairs.
+    /// With a known amplicon length the expected overlap is `2 * read_len - amplicon_len`;
+    /// otherwise reads may overlap by up to their full length.
+    pub fn estimate_overlap(
+        in1: &Path,
+        in2: &Path,
+        sample_pairs: usize,
+        amplicon_len: Option<usize>,
+        min_overlap: usize,
+    ) -> Result<(usize, usize)> {
+        let read_len = mean_read_len(in1, sample_pairs)?.max(mean_read_len(in2, sample_pairs)?);
+        let max_overlap = match amplicon_len {
+            Some(amplicon) => (2 * read_len).saturating_sub(amplicon).min(read_len),
+            None => read_len,
+        };
+        if max_overlap < min_overlap {
+            return Err(anyhow!(
+                "Estimated max overlap ({}) is below --min-overlap ({}); read length {} is too short for the amplicon",
+                max_overlap, min_overlap, read_len
+            ));
+        }
+        println!(
+            "🔎 Auto overlap: sampled up to {} pairs, mean read length {} bp{} -> --min-overlap {} --max-overlap {}",
+            sample_pairs,
+            read_len,
+            amplicon_len.map(|a| format!(", amplicon {} bp", a)).unwrap_or_default(),
+            min_overlap,
+            max_overlap
+        );
+        Ok((min_overlap, max_overlap))
+    }
+
+    pub fn run(args: Args) -> Result<RunReport> {
         println!("---> Starting flash2 read merging...");
         
         if !command_exists("flash2") {
@@ -499,26 +1049,19 @@ mod flash2 {
         cmd.arg("-d").arg(&args.out_dir);
         cmd.arg("-m").arg(args.min_overlap.to_string());
         cmd.arg("-M").arg(args.max_overlap.to_string());
-        cmd.arg("-t").arg(args.threads.to_string());
+        cmd.arg("-t").arg(args.global.threads_or(1).to_string());
+        cmd.args(&args.extra);
 
         println!("🔧 Executing command: {:?}", cmd);
 
-        let status = cmd
-            .status()
-            .with_context(|| "Failed to execute flash2 command. Please check if flash2 is installed correctly.")?;
+        run_tool(&mut cmd, "flash2")?;
 
-        if status.success() {
-            println!("\n✔ flash2 merging completed successfully!");
-            println!("   - Output directory: {}", args.out_dir.display());
-            println!("   - Output prefix: {}", args.out_prefix);
-            println!("   - Merged file: {}", args.out_dir.join(format!("{}.extendedFrags.fastq", args.out_prefix)).display());
-            Ok(())
-        } else {
-            Err(anyhow!(
-                "flash2 execution failed with exit code: {:?}\nPlease check the flash2 logs for detailed error information.",
-                status.code()
-            ))
-        }
+        println!("\n✔ flash2 merging completed successfully!");
+        println!("   - Output directory: {}", args.out_dir.display());
+        println!("   - Output prefix: {}", args.out_prefix);
+        let merged = args.out_dir.join(format!("{}.extendedFrags.fastq", args.out_prefix));
+        println!("   - Merged file: {}", merged.display());
+        Ok(RunReport::new("flash2").input(&args.in1).input(&args.in2).output(merged))
     }
 }
 
@@ -526,11 +1069,20 @@ mod flash2 {
 // `common` module: Shared utility functions
 // ==================================================================================
 mod common {
-    use anyhow::{anyhow, Result};
+    use anyhow::{anyhow, Context, Result};
+    use bio::io::{fasta, fastq};
+    use bzip2::bufread::MultiBzDecoder;
     use flate2::bufread::MultiGzDecoder;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::collections::VecDeque;
     use std::fs::File;
-    use std::io::{BufRead, BufReader, Read};
-    use std::path::Path;
+    use std::io::{self, BufRead, BufReader, BufWriter, Write};
+    use std::path::{Path, PathBuf};
+    use std::process::{Command, Stdio};
 
     #[derive(Debug, PartialEq, Eq, Clone, Copy)]
     pub enum Format {
@@ -538,1994 +1090,7582 @@ mod common {
         Fastq,
     }
 
-    pub fn detect_format(path: &Path) -> Result<Format> {
-        let file = File::open(path)?;
-        let buf_reader = BufReader::new(file);
-        let mut first_char_reader: Box<dyn BufRead> =
-            if path.extension().map_or(false, |ext| ext == "gz") {
-                Box::new(BufReader::new(MultiGzDecoder::new(buf_reader)))
-            } else {
-                Box::new(buf_reader)
-            };
-        let mut buf = [0; 1];
-        match first_char_reader.read_exact(&mut buf) {
-            Ok(_) => match buf[0] {
-                b'>' => Ok(Format::Fasta),
-                b'@' => Ok(Format::Fastq),
-                _ => Err(anyhow!(
-                    "Cannot identify file format for {:?}. Please ensure it starts with '>' (FASTA) or '@' (FASTQ).",
-                    path
-                )),
-            },
-            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
-                Err(anyhow!("File is empty or unreadable: {:?}", path))
+    /// Skips a UTF-8 BOM and any leading whitespace/blank lines so that the next byte is the
+    /// record marker ('>' or '@').
+    fn skip_leading_noise(reader: &mut dyn BufRead) -> std::io::Result<()> {
+        if reader.fill_buf()?.starts_with(&[0xEF, 0xBB, 0xBF]) {
+            reader.consume(3);
+        }
+        loop {
+            let buf = reader.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(());
+            }
+            let n = buf.iter().take_while(|b| b.is_ascii_whitespace()).count();
+            let done = n < buf.len();
+            reader.consume(n);
+            if done {
+                return Ok(());
             }
-            Err(e) => Err(e.into()),
         }
     }
-}
-
-// ==================================================================================
-// `demux` subcommand module (for `demux_only`)
-// ==================================================================================
-mod demux {
-    use anyhow::{anyhow, Context, Result};
-    use bio::io::{
-        fasta,
-        fastq::{self, Record},
-    };
-    use clap::Parser;
-    use csv::ReaderBuilder;
-    use flate2::bufread::MultiGzDecoder;
-    use indicatif::{ProgressBar, ProgressStyle};
-    use rayon::prelude::*;
-    use std::collections::{HashMap, HashSet};
-    use std::fs::File;
-    use std::io::{BufRead, BufReader};
-    use std::path::{Path, PathBuf};
-    use std::sync::Arc;
-    use std::thread;
-    use std::time::Instant;
 
-    const CHUNK_SIZE: usize = 8192;
+    /// Lines of an external tool's stderr kept for the error message when it fails.
+    const TOOL_STDERR_TAIL: usize = 20;
+
+    /// Runs an external tool, echoing its stderr live while keeping the last lines so that a
+    /// failure reports why the tool died (not just its exit code).
+    pub fn run_tool(cmd: &mut Command, tool: &str) -> Result<()> {
+        let mut child = cmd
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to execute {} command. Please check if {} is installed correctly.", tool, tool))?;
+        let mut tail = VecDeque::with_capacity(TOOL_STDERR_TAIL);
+        for line in BufReader::new(child.stderr.take().unwrap()).lines() {
+            let line = line?;
+            eprintln!("{}", line);
+            if tail.len() == TOOL_STDERR_TAIL {
+                tail.pop_front();
+            }
+            tail.push_back(line);
+        }
+        let status = child.wait()?;
+        if status.success() {
+            return Ok(());
+        }
+        let tail: Vec<String> = tail.into();
+        Err(anyhow!(
+            "{} execution failed with exit code: {:?}\nLast {} line(s) of {} stderr:\n{}",
+            tool,
+            status.code(),
+            tail.len(),
+            tool,
+            tail.join("\n")
+        ))
+    }
 
-    #[derive(Parser, Debug)]
-    pub struct Args {
-        #[arg(long, help = "Input FASTQ file (can be gzipped)")]
-        pub inputfile: PathBuf,
+    /// Wraps `reader` in a decoder chosen by its magic bytes: gzip (`1f 8b`), bzip2 (`BZh`) or
+    /// zstd (`28 b5 2f fd`); anything else is passed through.
+    fn decompress_by_magic(mut reader: Box<dyn BufRead>) -> Result<Box<dyn BufRead>> {
+        let head = reader.fill_buf()?;
+        if head.starts_with(&[0x1f, 0x8b]) {
+            reader = Box::new(BufReader::new(MultiGzDecoder::new(reader)));
+        } else if head.starts_with(b"BZh") {
+            reader = Box::new(BufReader::new(MultiBzDecoder::new(reader)));
+        } else if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            reader = Box::new(BufReader::new(zstd::stream::read::Decoder::with_buffer(reader)?));
+        }
+        Ok(reader)
+    }
 
-        #[arg(long, help = "Output directory")]
-        pub output: PathBuf,
+    /// Opens a file, decompressing gzip, bzip2 or zstd content; the extension is not consulted,
+    /// so misnamed files (gzip as `.fastq`, plain text as `.gz`) still read correctly.
+    pub fn open_reader(path: &Path) -> Result<Box<dyn BufRead>> {
+        let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+        decompress_by_magic(Box::new(BufReader::new(file)))
+    }
 
-        #[arg(long, help = "Number of threads", default_value_t = num_cpus::get_physical())]
-        pub threads: usize,
-        
-        #[arg(short, long, help = "Sample tags file (CSV format: SampleID,F_tag,R_tag)")]
-        pub tags: PathBuf,
-        
-        #[arg(short = 'l', long, default_value_t = 8, help = "Length of the tags")]
-        pub tag_len: usize,
-        
-        #[arg(long, help = "Activate this flag to trim tags from both ends of the sequence")]
-        pub trim: bool,
-        
-        #[arg(long, help = "Convert output to FASTA format (default: FASTQ)")]
-        pub out_fasta: bool,
+    /// `name` without a trailing `.gz`, `.bz2` or `.zst`.
+    pub fn strip_compression_ext(name: &str) -> &str {
+        [".gz", ".bz2", ".zst"].iter().find_map(|ext| name.strip_suffix(ext)).unwrap_or(name)
     }
 
-    #[derive(Debug, Clone)]
-    struct MatchInfo {
-        sample_id: String,
-        orientation: Orientation,
+    /// Record output: a plain or gzip file from `create_output`, or buffered stdout.
+    /// Call `finish` when done; dropping a gzip stream writes its trailer but discards any error.
+    pub enum OutputWriter {
+        Plain(BufWriter<File>),
+        Gzip(GzEncoder<BufWriter<File>>),
+        Stdout(BufWriter<io::StdoutLock<'static>>),
     }
-    #[derive(Debug, Clone, PartialEq)]
-    enum Orientation {
-        Forward,
-        Reverse,
-    }
-    type RawChunk = Vec<Record>;
-    type ProcessedChunk = HashMap<String, Vec<Record>>;
-    enum GenericWriter {
-        Fastq(fastq::Writer<File>),
-        Fasta(fasta::Writer<File>),
-    }
-    impl GenericWriter {
-        fn write_record(&mut self, record: &Record) -> Result<()> {
+
+    impl OutputWriter {
+        pub fn stdout() -> Self {
+            OutputWriter::Stdout(BufWriter::new(io::stdout().lock()))
+        }
+
+        /// Flushes all buffered output, writing the gzip trailer if compressed.
+        pub fn finish(self) -> Result<()> {
             match self {
-                GenericWriter::Fastq(writer) => writer.write_record(record)?,
-                GenericWriter::Fasta(writer) => {
-                    let fasta_record =
-                        fasta::Record::with_attrs(record.id(), record.desc(), record.seq());
-                    writer.write_record(&fasta_record)?;
-                }
+                OutputWriter::Plain(mut file) => file.flush()?,
+                OutputWriter::Gzip(encoder) => encoder.finish()?.flush()?,
+                OutputWriter::Stdout(mut out) => out.flush()?,
             }
             Ok(())
         }
     }
-    fn load_tags(
-        tag_file: &Path,
-        tag_len: usize,
-    ) -> Result<(HashMap<(Vec<u8>, Vec<u8>), MatchInfo>, HashSet<String>)> {
-        let mut lookup_map = HashMap::new();
-        let mut all_samples = HashSet::new();
-        let file = File::open(tag_file)
-            .with_context(|| format!("Failed to open tag file: {:?}", tag_file))?;
-        let mut rdr = ReaderBuilder::new()
-            .has_headers(true)
-            .flexible(true)
-            .delimiter(b',')
-            .from_reader(file);
-        let headers = rdr.headers()?.clone();
-        if !headers.iter().any(|h| h == "SampleID")
-            || !headers.iter().any(|h| h == "F_tag")
-            || !headers.iter().any(|h| h == "R_tag")
-        {
-            return Err(anyhow!(
-                "Tag file must contain the columns 'SampleID', 'F_tag', and 'R_tag'."
-            ));
+
+    impl Write for OutputWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            match self {
+                OutputWriter::Plain(file) => file.write(buf),
+                OutputWriter::Gzip(encoder) => encoder.write(buf),
+                OutputWriter::Stdout(out) => out.write(buf),
+            }
         }
-        for result in rdr.records() {
-            let record = result?;
-            let sample_id = record.get(0).ok_or_else(|| anyhow!("Missing SampleID"))?.to_string();
-            let f_tag = record.get(1).ok_or_else(|| anyhow!("Missing F_tag"))?.as_bytes().to_ascii_uppercase();
-            let r_tag = record.get(2).ok_or_else(|| anyhow!("Missing R_tag"))?.as_bytes().to_ascii_uppercase();
-            if f_tag.len() != tag_len || r_tag.len() != tag_len {
-                return Err(anyhow!("Tag length for sample {} does not match the specified --tag-len {}", sample_id, tag_len));
+        fn flush(&mut self) -> io::Result<()> {
+            match self {
+                OutputWriter::Plain(file) => file.flush(),
+                OutputWriter::Gzip(encoder) => encoder.flush(),
+                OutputWriter::Stdout(out) => out.flush(),
             }
-            all_samples.insert(sample_id.clone());
-            let r_tag_rc = bio::alphabets::dna::revcomp(&r_tag);
+        }
+    }
 
-            // Forward key: 5'-[F_tag]...[R_tag_rc]-3'
-            let fwd_key = (f_tag.clone(), r_tag_rc.clone());
-            lookup_map.insert(fwd_key, MatchInfo { sample_id: sample_id.clone(), orientation: Orientation::Forward });
-            
-            // Reverse key: 5'-[R_tag_rc]...[F_tag]-3'
-            // FIX: The original code used f_tag_rc here, which was incorrect.
-            let rev_key = (r_tag_rc, f_tag);
-            lookup_map.insert(rev_key, MatchInfo { sample_id, orientation: Orientation::Reverse });
+    /// Creates an output file, gzip-compressed when the path ends in `.gz`.
+    pub fn create_output(path: &Path) -> Result<OutputWriter> {
+        let file = File::create(path).with_context(|| format!("Failed to create output file: {:?}", path))?;
+        if path.extension().is_some_and(|ext| ext == "gz") {
+            Ok(OutputWriter::Gzip(GzEncoder::new(BufWriter::new(file), Compression::default())))
+        } else {
+            Ok(OutputWriter::Plain(BufWriter::new(file)))
         }
-        Ok((lookup_map, all_samples))
     }
-    fn reader_thread(
-        input_path: PathBuf,
-        tx: crossbeam_channel::Sender<RawChunk>,
-        pb: ProgressBar,
-    ) -> Result<()> {
-        let file = File::open(&input_path)?;
-        let buf_reader = BufReader::new(file);
-        let boxed_buf_reader: Box<dyn BufRead> =
-            if input_path.extension().map_or(false, |ext| ext == "gz") {
-                Box::new(BufReader::new(MultiGzDecoder::new(buf_reader)))
-            } else {
-                Box::new(buf_reader)
-            };
-        let mut records_iter = fastq::Reader::new(boxed_buf_reader).records();
-        loop {
-            let mut chunk = Vec::with_capacity(CHUNK_SIZE);
-            for _ in 0..CHUNK_SIZE {
-                match records_iter.next() {
-                    Some(Ok(record)) => chunk.push(record),
-                    Some(Err(e)) => return Err(e.into()),
-                    None => break,
-                }
-            }
-            if chunk.is_empty() {
-                break;
+
+    /// Opens a FASTA/FASTQ input (transparently decompressing gzip), positioned at the first record.
+    pub fn open_input(path: &Path) -> Result<Box<dyn BufRead>> {
+        let mut reader = open_reader(path)?;
+        skip_leading_noise(&mut reader)?;
+        Ok(reader)
+    }
+
+    pub fn detect_format(path: &Path) -> Result<Format> {
+        open_input_with_format(path).map(|(format, _)| format)
+    }
+
+    /// Opens an input and detects its format from the first record marker in a single pass, so
+    /// non-seekable streams work. A path of `-` reads stdin.
+    pub fn open_input_with_format(path: &Path) -> Result<(Format, Box<dyn BufRead>)> {
+        let raw: Box<dyn BufRead> = if path.as_os_str() == "-" {
+            Box::new(BufReader::new(std::io::stdin()))
+        } else {
+            Box::new(BufReader::new(File::open(path).with_context(|| format!("Failed to open {:?}", path))?))
+        };
+        stream_with_format(raw, path)
+    }
+
+    /// Decompresses `raw` if needed, skips a BOM and leading blank lines, and detects the format
+    /// from the first record marker; `path` only names the input in errors.
+    fn stream_with_format(raw: Box<dyn BufRead>, path: &Path) -> Result<(Format, Box<dyn BufRead>)> {
+        let mut reader = decompress_by_magic(raw)?;
+        skip_leading_noise(&mut reader)?;
+        let format = match reader.fill_buf()?.first() {
+            Some(b'>') => Format::Fasta,
+            Some(b'@') => Format::Fastq,
+            Some(_) => {
+                return Err(anyhow!(
+                    "Cannot identify file format for {:?}. Please ensure it starts with '>' (FASTA) or '@' (FASTQ).",
+                    path
+                ))
             }
-            pb.inc(chunk.len() as u64);
-            if tx.send(chunk).is_err() {
-                break;
+            None => return Err(anyhow!("File is empty or unreadable: {:?}", path)),
+        };
+        Ok((format, reader))
+    }
+
+    /// Pre-flight check for `--validate-first`: every input must have a detectable format and
+    /// parse cleanly to the end (FASTQ records also need matching sequence/quality lengths).
+    /// Returns on the first invalid file so that no processing starts.
+    pub fn validate_inputs(paths: &[PathBuf]) -> Result<()> {
+        eprintln!("---> Validating {} input file(s) before processing...", paths.len());
+        for path in paths {
+            let format = detect_format(path)?;
+            let input_reader = open_input(path)
+                .with_context(|| format!("Failed to open input file: {:?}", path))?;
+            let mut n = 0u64;
+            match format {
+                Format::Fasta => {
+                    for result in fasta::Reader::new(input_reader).records() {
+                        n += 1;
+                        let record = result.with_context(|| format!("Validation failed for {:?} at record {}", path, n))?;
+                        record.check().map_err(|e| anyhow!("Validation failed for {:?} at record {}: {}", path, n, e))?;
+                    }
+                }
+                Format::Fastq => {
+                    for result in fastq::Reader::new(input_reader).records() {
+                        n += 1;
+                        let record = result.with_context(|| format!("Validation failed for {:?} at record {}", path, n))?;
+                        record.check().map_err(|e| anyhow!("Validation failed for {:?} at record {}: {}", path, n, e))?;
+                    }
+                }
             }
+            eprintln!("✔ {} OK ({} records)", path.display(), n);
         }
-        pb.finish_with_message("✔ File reading complete");
         Ok(())
     }
 
-    // This worker function processes a record
-    // MODIFIED: Takes ownership of Record to avoid clones
-    fn process_record(
-        record: Record, // Takes ownership
-        lookup_map: &HashMap<(Vec<u8>, Vec<u8>), MatchInfo>,
-        args: &Args,
-    ) -> (String, Record) { // Returns tuple, not Option
-        let seq = record.seq();
-        if seq.len() < args.tag_len * 2 {
-            return ("unmatched".to_string(), record); // Move record
+    /// Structured completion record returned by every subcommand's `run`, written by `--log-json`.
+    #[derive(Debug)]
+    pub struct RunReport {
+        command: &'static str,
+        inputs: Vec<PathBuf>,
+        outputs: Vec<PathBuf>,
+        counts: Vec<(String, u64)>,
+    }
+
+    impl RunReport {
+        pub fn new(command: &'static str) -> Self {
+            RunReport { command, inputs: Vec::new(), outputs: Vec::new(), counts: Vec::new() }
         }
-        let read_start = seq[..args.tag_len].to_ascii_uppercase();
-        let read_end = seq[seq.len() - args.tag_len..].to_ascii_uppercase();
-        let lookup_key = (read_start, read_end);
-        match lookup_map.get(&lookup_key) {
-            Some(match_info) => {
-                let final_record = if args.trim {
-                    let trimmed_seq = &seq[args.tag_len..seq.len() - args.tag_len];
-                    let trimmed_qual = &record.qual()[args.tag_len..record.qual().len() - args.tag_len];
-                    if match_info.orientation == Orientation::Reverse {
-                        let rc_seq = bio::alphabets::dna::revcomp(trimmed_seq);
-                        let mut rc_qual = trimmed_qual.to_vec();
-                        rc_qual.reverse();
-                        Record::with_attrs(record.id(), record.desc(), &rc_seq, &rc_qual)
-                    } else {
-                        Record::with_attrs(record.id(), record.desc(), trimmed_seq, trimmed_qual)
-                    }
-                } else {
-                    record // Move record
-                };
-                (match_info.sample_id.clone(), final_record)
-            }
-            None => ("unmatched".to_string(), record), // Move record
+
+        pub fn input(mut self, path: impl AsRef<Path>) -> Self {
+            self.inputs.push(path.as_ref().to_path_buf());
+            self
         }
-    }
 
-    fn writer_thread(
-        rx_processed: crossbeam_channel::Receiver<ProcessedChunk>,
-        output_dir: PathBuf,
-        mut all_samples: HashSet<String>,
-        out_fasta: bool,
-    ) -> Result<HashMap<String, u64>> {
-        let mut writers: HashMap<String, GenericWriter> = HashMap::new();
-        let extension = if out_fasta { "fasta" } else { "fastq" };
-        
-        all_samples.insert("unmatched".to_string());
+        pub fn output(mut self, path: impl AsRef<Path>) -> Self {
+            self.outputs.push(path.as_ref().to_path_buf());
+            self
+        }
 
-        for sample_id in &all_samples {
-            let path = output_dir.join(format!("{}.{}", sample_id, extension));
-            let file = File::create(&path)?;
-            let writer = if out_fasta {
-                GenericWriter::Fasta(fasta::Writer::new(file))
-            } else {
-                GenericWriter::Fastq(fastq::Writer::new(file))
-            };
-            writers.insert(sample_id.clone(), writer);
+        pub fn count(mut self, key: impl Into<String>, value: u64) -> Self {
+            self.counts.push((key.into(), value));
+            self
         }
 
-        let mut counts: HashMap<String, u64> = HashMap::new();
-        for chunk in rx_processed {
-            for (sample_id, records) in chunk {
-                *counts.entry(sample_id.clone()).or_insert(0) += records.len() as u64;
-                let writer = writers.get_mut(&sample_id).expect("Writer for sample not found!");
-                for record in records {
-                    writer.write_record(&record)?;
-                }
+        /// Carries over the counts of a nested step (e.g. demux inside demux_all).
+        pub fn counts_from(mut self, other: RunReport) -> Self {
+            self.counts.extend(other.counts);
+            self
+        }
+
+        /// Folds in a whole nested run (e.g. one sample of a batch): its paths, and its counts
+        /// with keys prefixed by `prefix.`.
+        pub fn merge_prefixed(mut self, prefix: &str, other: RunReport) -> Self {
+            self.inputs.extend(other.inputs);
+            self.outputs.extend(other.outputs);
+            self.counts.extend(other.counts.into_iter().map(|(k, v)| (format!("{}.{}", prefix, k), v)));
+            self
+        }
+
+        /// Prints the command and its counts to stderr (`--verbose`).
+        pub fn print_summary(&self, elapsed: std::time::Duration) {
+            eprintln!("[{}] finished in {:.2?}", self.command, elapsed);
+            for (key, value) in &self.counts {
+                eprintln!("  {}: {}", key, value);
             }
         }
-        Ok(counts)
+
+        /// Appends the report as one JSON line to `path`.
+        pub fn write_json(&self, path: &Path, elapsed: std::time::Duration) -> Result<()> {
+            let paths = |v: &[PathBuf]| {
+                v.iter().map(|p| json_string(&p.to_string_lossy())).collect::<Vec<_>>().join(",")
+            };
+            let counts = self.counts.iter()
+                .map(|(k, v)| format!("{}:{}", json_string(k), v))
+                .collect::<Vec<_>>()
+                .join(",");
+            let line = format!(
+                "{{\"command\":{},\"inputs\":[{}],\"outputs\":[{}],\"counts\":{{{}}},\"elapsed_secs\":{:.3}}}",
+                json_string(self.command), paths(&self.inputs), paths(&self.outputs), counts, elapsed.as_secs_f64()
+            );
+            let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)
+                .with_context(|| format!("Failed to open JSON log: {:?}", path))?;
+            writeln!(file, "{}", line)?;
+            Ok(())
+        }
     }
-    fn print_summary(counts: HashMap<String, u64>, start_time: Instant, output_dir: &PathBuf) {
-        let duration = start_time.elapsed();
-        let total_reads = counts.values().sum::<u64>();
-        let matched_reads = total_reads - *counts.get("unmatched").unwrap_or(&0);
-        println!("\n\n==================== Demultiplexing Summary (Multi-threaded) ====================");
-        println!("Processing Time: {:.2?}", duration);
-        println!("Total Reads Processed: {}", total_reads);
-        if total_reads > 0 {
-            let matched_percent = matched_reads as f64 * 100.0 / total_reads as f64;
-            let unmatched_percent = *counts.get("unmatched").unwrap_or(&0) as f64 * 100.0 / total_reads as f64;
-            println!("  - Matched Reads:       {:>10} ({:.2}%)", matched_reads, matched_percent);
-            println!("  - Unmatched Reads: {:>10} ({:.2}%)", counts.get("unmatched").unwrap_or(&0), unmatched_percent);
-            println!("--------------------------------------------------");
-            let mut sorted_samples: Vec<_> = counts.into_iter().collect();
-            sorted_samples.sort_by(|a, b| b.1.cmp(&a.1));
-            for (sample, count) in sorted_samples {
-                if sample != "unmatched" {
-                    let sample_percent = count as f64 * 100.0 / total_reads as f64;
-                    println!("  - Sample {}: {:>10} reads ({:.2}%)", sample, count, sample_percent);
-                }
+
+    /// Quotes and escapes a string for hand-written JSON output.
+    pub fn json_string(s: &str) -> String {
+        let mut out = String::with_capacity(s.len() + 2);
+        out.push('"');
+        for c in s.chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                '\t' => out.push_str("\\t"),
+                c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+                c => out.push(c),
             }
         }
-        println!("===================================================================================");
-        println!("✔ Done! Results written to: {}", output_dir.display());
+        out.push('"');
+        out
     }
 
-    // Optimization: This function combines the original worker_thread and the rayon::par_bridge logic
-    fn parallel_processing(
-        rx_raw: crossbeam_channel::Receiver<RawChunk>,
-        tx_processed: crossbeam_channel::Sender<ProcessedChunk>,
-        lookup_map: Arc<HashMap<(Vec<u8>, Vec<u8>), MatchInfo>>,
-        args: Arc<Args>,
-    ) {
-        // Use rayon's par_bridge to consume chunks from the channel in parallel
-        rx_raw.into_iter().par_bridge().for_each(|chunk| {
-            let processed_results: Vec<(String, Record)> = chunk
-                .into_par_iter() // Process records within the chunk in parallel (moves records)
-                .map(|record| process_record(record, &lookup_map, &args)) // Use map
-                .collect();
-            
-            let mut processed_chunk: ProcessedChunk = HashMap::new();
-            for (sample_id, record) in processed_results {
-                processed_chunk.entry(sample_id).or_default().push(record);
-            }
+    /// `<path>.<ext>`, e.g. `reads.fa.gz.fai`.
+    fn sidecar_path(path: &Path, ext: &str) -> PathBuf {
+        let mut sidecar = path.as_os_str().to_owned();
+        sidecar.push(".");
+        sidecar.push(ext);
+        PathBuf::from(sidecar)
+    }
 
-            if !processed_chunk.is_empty() {
-                let _ = tx_processed.send(processed_chunk);
-            }
-        });
+    /// Reads the `<path>.count` sidecar (a single integer), if present.
+    pub fn read_count_sidecar(path: &Path) -> Result<Option<u64>> {
+        let sidecar = sidecar_path(path, "count");
+        if !sidecar.is_file() {
+            return Ok(None);
+        }
+        let text = std::fs::read_to_string(&sidecar)
+            .with_context(|| format!("Failed to read read-count file: {:?}", sidecar))?;
+        let total = text
+            .trim()
+            .parse::<u64>()
+            .with_context(|| format!("Read-count file {:?} must contain a single integer", sidecar))?;
+        Ok(Some(total))
     }
 
-    pub fn run(args: Args) -> Result<()> {
-        let start_time = Instant::now();
-        let output_dir = args.output.clone();
-        std::fs::create_dir_all(&output_dir)
-            .with_context(|| format!("Failed to create output directory: {:?}", output_dir))?;
-        
-        println!("---> Loading tags...");
-        let (lookup_map, all_samples) = load_tags(&args.tags, args.tag_len)?;
-        let lookup_map = Arc::new(lookup_map);
-        let args_arc = Arc::new(args);
-        
-        // Configure rayon thread pool
-        rayon::ThreadPoolBuilder::new().num_threads(args_arc.threads).build_global()?;
+    /// Writes the `<path>.count` sidecar so later runs know the record total up front.
+    /// The sidecar only saves later runs a count, so callers warn on failure instead of failing the run.
+    pub fn write_count_sidecar(path: &Path, total: u64) -> Result<()> {
+        let sidecar = sidecar_path(path, "count");
+        std::fs::write(&sidecar, format!("{}\n", total))
+            .with_context(|| format!("Failed to write read-count file: {:?}", sidecar))
+    }
 
-        let channel_capacity = args_arc.threads * 2;
-        let (raw_tx, raw_rx) = crossbeam_channel::bounded::<RawChunk>(channel_capacity);
-        let (processed_tx, processed_rx) = crossbeam_channel::bounded::<ProcessedChunk>(channel_capacity);
-        
-        let pb = ProgressBar::new_spinner();
+    /// Record total of a reads file from a `<path>.fai` index (one line per record) or, failing that,
+    /// a `<path>.count` sidecar. `None` when neither exists.
+    pub fn indexed_record_count(path: &Path) -> Result<Option<u64>> {
+        let fai = sidecar_path(path, "fai");
+        if fai.is_file() {
+            let text = std::fs::read_to_string(&fai)
+                .with_context(|| format!("Failed to read FASTA index: {:?}", fai))?;
+            return Ok(Some(text.lines().filter(|line| !line.trim().is_empty()).count() as u64));
+        }
+        read_count_sidecar(path)
+    }
+
+    /// Progress bar over `total` records, or a spinner counting records when the total is unknown.
+    pub fn records_progress(total: Option<u64>, unit: &str) -> Result<ProgressBar> {
+        let pb = match total {
+            Some(total) => {
+                let pb = ProgressBar::new(total);
+                pb.set_style(
+                    ProgressStyle::default_bar()
+                        .template(&format!("[{{elapsed_precise}}] {{msg}} [{{bar:40.cyan/blue}}] {{pos:>10}}/{{len}} {} ({{percent}}%, ETA {{eta}})", unit))?
+                        .progress_chars("=> "),
+                );
+                pb
+            }
+            None => {
+                let pb = ProgressBar::new_spinner();
+                pb.set_style(
+                    ProgressStyle::default_spinner()
+                        .tick_strings(&["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"])
+                        .template(&format!("{{spinner:.blue}} [{{elapsed_precise}}] {{msg}} {{pos:>10}} {}", unit))?,
+                );
+                pb
+            }
+        };
         pb.enable_steady_tick(std::time::Duration::from_millis(120));
-        pb.set_style(
-            ProgressStyle::default_spinner()
-                .tick_strings(&["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "a"])
-                .template("{spinner:.blue} [{elapsed_precise}] {msg} {pos:>10} reads")?,
-        );
-        pb.set_message("Processing...");
+        Ok(pb)
+    }
 
-        thread::scope(|s| -> Result<()> {
-            let out_fasta_flag = args_arc.out_fasta;
-            let output_dir_for_writer = output_dir.clone();
+    /// `--threads 0` means "use all available cores".
+    pub fn resolve_threads(threads: usize) -> usize {
+        if threads == 0 { num_cpus::get() } else { threads }
+    }
 
-            // 1. Writer Thread
-            let writer_handle = s.spawn(move || {
-                writer_thread(processed_rx, output_dir_for_writer, all_samples, out_fasta_flag)
-            });
+    /// Concrete bases an uppercase IUPAC nucleotide code stands for (U reads as T); None for other characters.
+    pub fn iupac_bases(code: u8) -> Option<&'static [u8]> {
+        let bases: &[u8] = match code {
+            b'A' => b"A",
+            b'C' => b"C",
+            b'G' => b"G",
+            b'T' | b'U' => b"T",
+            b'R' => b"AG",
+            b'Y' => b"CT",
+            b'S' => b"CG",
+            b'W' => b"AT",
+            b'K' => b"GT",
+            b'M' => b"AC",
+            b'B' => b"CGT",
+            b'D' => b"AGT",
+            b'H' => b"ACT",
+            b'V' => b"ACG",
+            b'N' => b"ACGT",
+            _ => return None,
+        };
+        Some(bases)
+    }
 
-            // 2. Parallel Processing (consuming from raw_rx, sending to processed_tx)
-            let (lookup_clone, args_clone) = (lookup_map.clone(), args_arc.clone());
-            let processing_handle = s.spawn(move || {
-                parallel_processing(raw_rx, processed_tx, lookup_clone, args_clone);
-            });
+    /// Reverse complement that maps IUPAC ambiguity codes to their complementary codes (R<->Y,
+    /// K<->M, B<->V, D<->H; S, W and N are their own complement; U pairs with A). Case is kept and
+    /// any other byte is copied unchanged.
+    pub fn revcomp_iupac(seq: &[u8]) -> Vec<u8> {
+        seq.iter()
+            .rev()
+            .map(|&b| {
+                let c = match b.to_ascii_uppercase() {
+                    b'A' => b'T',
+                    b'T' | b'U' => b'A',
+                    b'C' => b'G',
+                    b'G' => b'C',
+                    b'R' => b'Y',
+                    b'Y' => b'R',
+                    b'K' => b'M',
+                    b'M' => b'K',
+                    b'B' => b'V',
+                    b'V' => b'B',
+                    b'D' => b'H',
+                    b'H' => b'D',
+                    other => other,
+                };
+                if b.is_ascii_lowercase() { c.to_ascii_lowercase() } else { c }
+            })
+            .collect()
+    }
 
-            // 3. Reader Thread (Main thread role, feeds raw_tx)
-            // This will block until reading is done, then drop raw_tx
-            let reader_res = reader_thread(args_arc.inputfile.clone(), raw_tx, pb);
-            if let Err(e) = reader_res {
-                eprintln!("Error in reader thread: {:?}", e);
-            }
+    /// Collapses runs of identical bases to a single base (run-length encoding without the lengths).
+    /// e.g. `AAACCGTTT` -> `ACGT`
+    pub fn collapse_homopolymers(seq: &[u8]) -> Vec<u8> {
+        let mut out = seq.to_vec();
+        out.dedup();
+        out
+    }
 
-            // Wait for processing to finish
-            processing_handle.join().unwrap(); 
+    /// Returns true if the sequence contains RNA uracil (U/u).
+    pub fn has_uracil(seq: &[u8]) -> bool {
+        seq.iter().any(|&b| b == b'U' || b == b'u')
+    }
 
-            // Wait for writer to finish
-            match writer_handle.join().unwrap() {
-                Ok(counts) => print_summary(counts, start_time, &output_dir),
-                Err(e) => eprintln!("Writer thread error: {:?}", e),
+    /// Rewrites RNA uracil as DNA thymine in place (U -> T, u -> t).
+    pub fn rna_to_dna(seq: &mut [u8]) {
+        for base in seq.iter_mut() {
+            match *base {
+                b'U' => *base = b'T',
+                b'u' => *base = b't',
+                _ => {}
             }
-            Ok(())
-        })?;
-        Ok(())
+        }
     }
-}
 
-// ==================================================================================
-// `stats` subcommand module
-// ==================================================================================
-mod stats {
-    use super::common::{detect_format, Format};
-    use anyhow::Result;
-    use bio::io::{fasta, fastq};
-    use clap::Parser;
-    use csv::Writer;
-    use flate2::bufread::MultiGzDecoder;
-    use std::collections::HashMap;
-    use std::fs::File;
-    use std::io::{BufRead, BufReader};
-    use std::path::{Path, PathBuf};
+    /// `--keep-desc` / `--strip-desc`, shared by every subcommand that converts records to FASTA.
+    /// Descriptions are kept unless `--strip-desc` is given; the later of the two flags wins.
+    #[derive(clap::Args, Debug, Clone, Copy, Default)]
+    pub struct DescArgs {
+        #[arg(long, overrides_with = "strip_desc", help = "Keep record descriptions in converted FASTA headers (default)")]
+        pub keep_desc: bool,
 
-    #[derive(Parser, Debug)]
-    pub struct Args {
-        #[arg(long, help = "One or more input files (wildcards supported, e.g., '*.fasta')", required = true, num_args = 1..)]
-        inputfile: Vec<PathBuf>,
-        #[arg(long, help = "Output CSV file for per-sequence counts")]
-        outfile: Option<PathBuf>,
+        #[arg(long, overrides_with = "keep_desc", help = "Drop record descriptions from converted FASTA headers, leaving only the ID")]
+        pub strip_desc: bool,
     }
-    
-    struct FileStats {
-        filename: String,
-        count: u64,
-        total_len: u64,
-        min_len: usize,
-        max_len: usize,
+
+    impl DescArgs {
+        /// Description to write for a converted record.
+        pub fn apply<'a>(&self, desc: Option<&'a str>) -> Option<&'a str> {
+            if self.strip_desc { None } else { desc }
+        }
     }
 
-    fn get_sample_name(path: &Path) -> String {
-        let filename = path.file_name()
-            .unwrap_or_default()
-            .to_str()
-            .unwrap_or_default();
+    /// `--threads` / `--seed` / `--no-progress` / `--verbose`, flattened into every subcommand.
+    /// Without `--threads` each subcommand keeps its own default; subcommands with no parallel or
+    /// random step reject `--threads` and `--seed` (see `Commands::check_global_opts`).
+    #[derive(clap::Args, Debug, Clone, Copy, Default)]
+    pub struct GlobalOpts {
+        #[arg(long, help = "Number of worker threads (0 = all cores; default depends on the subcommand)")]
+        pub threads: Option<usize>,
 
-        // Try to handle .fastq.gz, .fa.gz etc.
-        let stem1 = Path::new(filename)
-            .file_stem()
-            .unwrap_or_default()
-            .to_str()
-            .unwrap_or_default();
-        
-        if stem1.ends_with(".fastq") || stem1.ends_with(".fa") || stem1.ends_with(".fasta") || stem1.ends_with(".fq") {
-             Path::new(stem1)
-                .file_stem()
-                .unwrap_or_default()
-                .to_str()
-                .unwrap_or_default()
-                .to_string()
-        } else {
-            stem1.to_string()
-        }
+        #[arg(long, help = "Seed for randomised steps such as shuffling and subsampling, for reproducible output")]
+        pub seed: Option<u64>,
+
+        #[arg(long, help = "Hide progress bars and spinners")]
+        pub no_progress: bool,
+
+        #[arg(long, help = "Print the parsed arguments before the run and its counts after it to stderr")]
+        pub verbose: bool,
     }
-    
-    fn print_stats_table(stats: &[FileStats]) {
-        if stats.is_empty() {
-            println!("No files processed or no sequences found.");
-            return;
+
+    impl GlobalOpts {
+        /// `--threads`, or `default` when not given; 0 resolves to all cores.
+        pub fn threads_or(&self, default: usize) -> usize {
+            resolve_threads(self.threads.unwrap_or(default))
         }
 
-        println!("\n====================================== Sequence Statistics Summary ======================================");
-        println!("{:<30} {:>15} {:>18} {:>10} {:>10} {:>12}",
-                 "Sample Name", "Total Seqs", "Total Bases", "Min Length", "Max Length", "Avg Length");
-        println!("{:-<30} {:-<15} {:-<18} {:-<10} {:-<10} {:-<12}",
-                 "", "", "", "", "", "");
+        /// Random generator seeded by `--seed`, or from entropy without it.
+        pub fn rng(&self) -> StdRng {
+            match self.seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            }
+        }
 
-        for s in stats {
-            let avg_len = if s.count > 0 {
-                s.total_len as f64 / s.count as f64
-            } else {
-                0.0
-            };
-            println!("{:<30} {:>15} {:>18} {:>10} {:>10} {:<12.2}",
-                     s.filename, s.count, s.total_len, s.min_len, s.max_len, avg_len);
+        /// Hides `pb` under `--no-progress`.
+        pub fn progress(&self, pb: ProgressBar) -> ProgressBar {
+            if self.no_progress {
+                pb.set_draw_target(ProgressDrawTarget::hidden());
+            }
+            pb
         }
-        println!("===================================================================================================");
     }
 
-    pub fn run(args: Args) -> Result<()> {
-        let mut all_stats: Vec<FileStats> = Vec::new();
-        let mut wtr_opt: Option<Writer<File>> = if let Some(path) = args.outfile.clone() {
-            let mut w = Writer::from_path(path)?;
-            w.write_record(["filename", "sequence", "count"])?;
-            Some(w)
-        } else { None };
+    /// Position x Phred quality counter for FASTQ reads (Phred+33).
+    #[derive(Default)]
+    pub struct QualMatrix {
+        // counts[position][q]
+        counts: Vec<Vec<u64>>,
+        max_q: usize,
+    }
 
-        for input_path in &args.inputfile {
-            println!("---> Processing: {}", input_path.display());
-            let format = detect_format(input_path)?;
+    impl QualMatrix {
+        pub fn add(&mut self, qual: &[u8]) {
+            if self.counts.len() < qual.len() {
+                self.counts.resize_with(qual.len(), Vec::new);
+            }
+            for (pos, &q) in qual.iter().enumerate() {
+                let q = q.saturating_sub(33) as usize;
+                let row = &mut self.counts[pos];
+                if row.len() <= q {
+                    row.resize(q + 1, 0);
+                }
+                row[q] += 1;
+                self.max_q = self.max_q.max(q);
+            }
+        }
 
-            let file = File::open(input_path)?;
-            let buf_reader = BufReader::new(file);
-            let input_reader: Box<dyn BufRead> =
-                if input_path.extension().map_or(false, |ext| ext == "gz") {
-                    Box::new(BufReader::new(MultiGzDecoder::new(buf_reader)))
-                } else {
-                    Box::new(buf_reader)
-                };
+        /// Writes `position,q0,q1,...,qN` rows (1-based positions), 0 where a quality was not observed.
+        pub fn write_csv(&self, path: &Path) -> Result<()> {
+            let mut wtr = csv::Writer::from_path(path)
+                .with_context(|| format!("Failed to create quality matrix CSV: {:?}", path))?;
+            let mut header = vec!["position".to_string()];
+            header.extend((0..=self.max_q).map(|q| format!("q{}", q)));
+            wtr.write_record(&header)?;
+            for (pos, row) in self.counts.iter().enumerate() {
+                let mut record = vec![(pos + 1).to_string()];
+                record.extend((0..=self.max_q).map(|q| row.get(q).copied().unwrap_or(0).to_string()));
+                wtr.write_record(&record)?;
+            }
+            wtr.flush()?;
+            Ok(())
+        }
+    }
 
-            let mut count = 0;
-            let mut total_len = 0;
-            let mut min_len = usize::MAX;
-            let mut max_len = 0;
-            let mut seq_counts: HashMap<String, u64> = HashMap::new();
+    /// Plausibility check of FASTQ quality bytes, which every quality feature reads as Phred+33.
+    pub struct QualEncodingCheck {
+        bases: u64,
+        // Bytes outside 33..=104 ('!'..='h'), i.e. Q0-Q71 in Phred+33
+        out_of_range: u64,
+        min: u8,
+        max: u8,
+    }
 
-            match format {
-                Format::Fasta => {
-                    let reader = fasta::Reader::new(input_reader);
-                    for result in reader.records() {
-                        let record = result?;
-                        count += 1;
-                        let len = record.seq().len();
-                        total_len += len as u64;
-                        if len < min_len { min_len = len; }
-                        if len > max_len { max_len = len; }
-                        let seq = String::from_utf8(record.seq().to_vec()).unwrap().trim().to_uppercase();
-                        *seq_counts.entry(seq).or_insert(0) += 1;
-                    }
-                }
-                Format::Fastq => {
-                    let reader = fastq::Reader::new(input_reader);
-                    for result in reader.records() {
-                        let record = result?;
-                        count += 1;
-                        let len = record.seq().len();
-                        total_len += len as u64;
-                        if len < min_len { min_len = len; }
-                        if len > max_len { max_len = len; }
-                        let seq = String::from_utf8(record.seq().to_vec()).unwrap().trim().to_uppercase();
-                        *seq_counts.entry(seq).or_insert(0) += 1;
-                    }
+    impl Default for QualEncodingCheck {
+        fn default() -> Self {
+            QualEncodingCheck { bases: 0, out_of_range: 0, min: u8::MAX, max: 0 }
+        }
+    }
+
+    impl QualEncodingCheck {
+        // Share of out-of-range bytes above which the qualities are reported
+        const MAX_OUT_OF_RANGE: f64 = 0.01;
+
+        pub fn add(&mut self, qual: &[u8]) {
+            for &q in qual {
+                if !(33..=104).contains(&q) {
+                    self.out_of_range += 1;
                 }
+                self.min = self.min.min(q);
+                self.max = self.max.max(q);
+            }
+            self.bases += qual.len() as u64;
+        }
+
+        /// Warns on stderr about implausible qualities in `input`, or fails instead when `strict`.
+        pub fn finish(&self, input: &Path, strict: bool) -> Result<()> {
+            if self.bases == 0 {
+                return Ok(());
+            }
+            let problem = if self.out_of_range as f64 > self.bases as f64 * Self::MAX_OUT_OF_RANGE {
+                format!(
+                    "{} of {} quality bytes ({:.2}%) fall outside the Phred+33 range 33-104; the quality strings look corrupt or the file is not FASTQ",
+                    self.out_of_range,
+                    self.bases,
+                    self.out_of_range as f64 * 100.0 / self.bases as f64
+                )
+            } else if self.min >= 64 && self.max > b'K' {
+                // No byte below '@' but scores beyond Illumina 1.8's Q42: the Phred+64 signature
+                format!(
+                    "quality bytes span '{}'-'{}', which looks like Phred+64 encoding; qualities are read as Phred+33, so every score is 31 too high",
+                    self.min as char, self.max as char
+                )
+            } else {
+                return Ok(());
             };
+            if strict {
+                return Err(anyhow!("{:?}: {}", input, problem));
+            }
+            eprintln!("Warning: {:?}: {} (use --strict to make this an error)", input, problem);
+            Ok(())
+        }
+    }
 
-            if let Some(wtr) = wtr_opt.as_mut() {
-                let fname = get_sample_name(input_path);
-                let mut entries: Vec<(String, u64)> = seq_counts.into_iter().collect();
-                entries.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
-                for (seq, c) in entries {
-                    wtr.write_record([fname.clone(), seq, c.to_string()])?;
-                }
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::io::{Cursor, Read};
+
+        fn detect(bytes: &[u8]) -> (Format, Box<dyn BufRead>) {
+            stream_with_format(Box::new(Cursor::new(bytes.to_vec())), Path::new("test")).unwrap()
+        }
+
+        #[test]
+        fn bom_prefixed_input_is_detected_and_parsed() {
+            let (format, reader) = detect(b"\xEF\xBB\xBF>r1 desc\nACGT\n");
+            assert_eq!(format, Format::Fasta);
+            let record = fasta::Reader::new(reader).records().next().unwrap().unwrap();
+            assert_eq!((record.id(), record.seq()), ("r1", &b"ACGT"[..]));
+
+            let (format, reader) = detect(b"\xEF\xBB\xBF@r1\nACGT\n+\nIIII\n");
+            assert_eq!(format, Format::Fastq);
+            let record = fastq::Reader::new(reader).records().next().unwrap().unwrap();
+            assert_eq!((record.id(), record.seq()), ("r1", &b"ACGT"[..]));
+        }
+
+        #[test]
+        fn blank_line_prefixed_input_is_detected_and_parsed() {
+            let (format, reader) = detect(b"\n\n>r1\nACGT\n");
+            assert_eq!(format, Format::Fasta);
+            let record = fasta::Reader::new(reader).records().next().unwrap().unwrap();
+            assert_eq!((record.id(), record.seq()), ("r1", &b"ACGT"[..]));
+
+            let (format, reader) = detect(b"\n\n@r1\nACGT\n+\nIIII\n");
+            assert_eq!(format, Format::Fastq);
+            let record = fastq::Reader::new(reader).records().next().unwrap().unwrap();
+            assert_eq!((record.id(), record.qual()), ("r1", &b"IIII"[..]));
+        }
+
+        #[test]
+        fn gzip_output_finish_writes_trailer_and_reports_errors() {
+            let path = test_util::test_dir("create_output_finish").join("out.fa.gz");
+            let mut out = create_output(&path).unwrap();
+            out.write_all(b">r1\nACGT\n").unwrap();
+            out.finish().unwrap();
+            let mut text = String::new();
+            open_reader(&path).unwrap().read_to_string(&mut text).unwrap();
+            assert_eq!(text, ">r1\nACGT\n");
+
+            // The compressed bytes only reach the file in finish, so a full disk must fail there
+            if let Ok(file) = File::create("/dev/full") {
+                let mut out = OutputWriter::Gzip(GzEncoder::new(BufWriter::new(file), Compression::default()));
+                out.write_all(b">r1\nACGT\n").unwrap();
+                assert!(out.finish().is_err());
             }
-            
-            all_stats.push(FileStats {
-                filename: get_sample_name(input_path),
-                count,
-                total_len,
-                min_len: if count > 0 { min_len } else { 0 },
-                max_len,
-            });
         }
+    }
 
-        if let Some(wtr) = wtr_opt.as_mut() { wtr.flush()?; }
-        print_stats_table(&all_stats);
-        Ok(())
+    /// Scratch files shared by the unit tests of every subcommand module.
+    #[cfg(test)]
+    pub mod test_util {
+        use std::fs;
+        use std::path::{Path, PathBuf};
+
+        /// A fresh, empty directory unique to `name` and this test process.
+        pub fn test_dir(name: &str) -> PathBuf {
+            let dir = std::env::temp_dir().join(format!("hammer_fastx_{}_{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            dir
+        }
+
+        /// Header lines (with the leading '>') of a plain or gzip-compressed FASTA file.
+        pub fn fasta_headers(path: &Path) -> Vec<String> {
+            let mut text = String::new();
+            std::io::Read::read_to_string(&mut super::open_reader(path).unwrap(), &mut text).unwrap();
+            text.lines().filter(|l| l.starts_with('>')).map(str::to_string).collect()
+        }
     }
 }
 
 // ==================================================================================
-// `filter` subcommand module (MODIFIED FOR BATCH PROCESSING)
+// `demux` subcommand module (for `demux_only`)
 // ==================================================================================
-mod filter {
-    use super::common::{detect_format, Format};
+mod demux {
+    use super::common::{iupac_bases, open_input, read_count_sidecar, DescArgs, GlobalOpts, RunReport};
     use anyhow::{anyhow, Context, Result};
-    use bio::io::{fasta, fastq};
+    use bio::io::{
+        fasta,
+        fastq::{self, Record},
+    };
     use clap::Parser;
-    use flate2::bufread::MultiGzDecoder;
-    use std::fs::{self, File};
-    use std::io::{self, BufRead, BufReader, BufWriter, Write};
+    use csv::ReaderBuilder;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use indicatif::{ProgressBar, ProgressStyle};
+    use rayon::prelude::*;
+    use std::collections::{HashMap, HashSet};
+    use std::fs::{File, OpenOptions};
+    use std::io::Write;
     use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Instant;
+
+    const CHUNK_SIZE: usize = 8192;
+    // Upper bound on concrete sequences per degenerate tag (and per sample tag pair)
+    const MAX_TAG_VARIANTS: usize = 1024;
+    // Output name for the samples merged by --merge-low-yield
+    const LOW_YIELD: &str = "low_yield";
 
     #[derive(Parser, Debug)]
-    #[command(name = "filter", about = "Filter FASTA/FASTQ files by length, either individually or in batches.")]
-    #[clap(group(
-        clap::ArgGroup::new("input_mode")
-            .required(true)
-            .args(["input_files", "input_dir"]),
-    ))]
     pub struct Args {
-        #[arg(long, help = "One or more input files to concatenate and filter", num_args = 1..)]
-        input_files: Vec<PathBuf>,
-
-        #[arg(long, help = "Input directory to batch process files")]
-        input_dir: Option<PathBuf>,
-
-        #[arg(long, help = "Output file (default: stdout, used with --input-files)")]
-        outfile: Option<PathBuf>,
+        #[arg(long, required_unless_present = "in1", help = "Input FASTQ file (can be gzipped)")]
+        pub inputfile: Option<PathBuf>,
 
-        #[arg(long, help = "Output directory (required with --input-dir)")]
-        output_dir: Option<PathBuf>,
+        #[arg(long, requires = "in2", conflicts_with = "inputfile", help = "Paired-end mode: R1 FASTQ file (F_tag expected at its 5' end)")]
+        pub in1: Option<PathBuf>,
 
-        #[arg(short = 'm', long, help = "Filter out sequences shorter than this length")]
-        min_len: Option<usize>,
-        
-        #[arg(short = 'M', long, help = "Filter out sequences longer than this length")]
-        max_len: Option<usize>,
-    }
+        #[arg(long, requires = "in1", help = "Paired-end mode: R2 FASTQ file (R_tag expected at its 5' end)")]
+        pub in2: Option<PathBuf>,
 
-    /// Helper function to process a single stream (file)
-    fn process_file_stream(
-        input_reader: Box<dyn BufRead>,
-        writer: &mut Box<dyn Write>,
-        format: &Format,
-        min_len: usize,
-        max_len: usize,
-    ) -> Result<u64> { // Returns count of records written
-        let mut records_written = 0;
-        match format {
-            Format::Fasta => {
-                let reader = fasta::Reader::new(input_reader);
-                let mut fasta_writer = fasta::Writer::new(writer);
-                for result in reader.records() {
-                    let record = result?;
-                    let len = record.seq().len();
-                    if len >= min_len && len <= max_len {
-                        fasta_writer.write_record(&record)?;
-                        records_written += 1;
-                    }
-                }
-            }
-            Format::Fastq => {
-                let reader = fastq::Reader::new(input_reader);
-                let mut fastq_writer = fastq::Writer::new(writer);
-                for result in reader.records() {
-                    let record = result?;
-                    let len = record.seq().len();
-                    if len >= min_len && len <= max_len {
-                        fastq_writer.write_record(&record)?;
-                        records_written += 1;
-                    }
-                }
-            }
-        }
-        Ok(records_written)
-    }
+        #[arg(long, required_unless_present_any = ["count_only", "scan_tag_len"], help = "Output directory")]
+        pub output: Option<PathBuf>,
 
-    /// Generates the output filename with `_filtered` suffix
-    fn get_output_filename(input_path: &Path) -> Result<(String, bool)> {
-        let file_name = input_path.file_name()
-            .ok_or_else(|| anyhow!("Failed to get file name from path: {:?}", input_path))?
-            .to_str()
-            .ok_or_else(|| anyhow!("File name contains invalid UTF-8: {:?}", input_path))?;
+        #[arg(long, conflicts_with_all = ["output", "out_fasta", "gzip"], help = "Only tally per-sample read counts; no output directory or sample files are created")]
+        pub count_only: bool,
 
-        let (stem, extension) = match file_name.rfind('.') {
-            Some(dot_pos) => (&file_name[..dot_pos], &file_name[dot_pos..]),
-            None => (file_name, "")
-        };
+        #[arg(short, long, help = "Sample tags file (CSV format: SampleID,F_tag,R_tag)")]
+        pub tags: PathBuf,
         
-        let (real_stem, real_ext, is_gz) = if extension == ".gz" {
-             if let Some(dot_pos) = stem.rfind('.') {
-                 // e.g., ("input", ".fastq", true) from "input.fastq.gz"
-                 (&stem[..dot_pos], &stem[dot_pos..], true)
-             } else {
-                 // e.g., ("archive", "", true) from "archive.gz"
-                 (stem, "", true)
-             }
-        } else {
-            // e.g., ("input", ".fasta", false) from "input.fasta"
-            (stem, extension, false)
-        };
+        #[arg(short = 'l', long, help = "Length of the tags; if set, every F_tag/R_tag must have this length (default: per-tag lengths from the tag file)")]
+        pub tag_len: Option<usize>,
         
-        // Check if this is a file type we want to process
-        let extensions_to_process = [".fasta", ".fa", ".fastq", ".fq", ".fna"];
-        let should_process = extensions_to_process.contains(&real_ext);
+        #[arg(long, help = "Activate this flag to trim tags from both ends of the sequence")]
+        pub trim: bool,
         
-        let new_file_name = if is_gz {
-            format!("{}_filtered{}{}", real_stem, real_ext, extension) // input_filtered.fastq.gz
-        } else {
-            format!("{}_filtered{}", real_stem, real_ext) // input_filtered.fasta
-        };
+        #[arg(long, help = "Convert output to FASTA format (default: FASTQ)")]
+        pub out_fasta: bool,
 
-        Ok((new_file_name, should_process))
-    }
+        #[arg(long, requires = "out_fasta", help = "With --out-fasta, also write a FASTQ file per sample in the same pass")]
+        pub also_fastq: bool,
 
+        #[arg(long, help = "Gzip-compress per-sample output files (adds .gz to each filename)")]
+        pub gzip: bool,
 
-    pub fn run(args: Args) -> Result<()> {
-        let min_len = args.min_len.unwrap_or(0);
-        let max_len = args.max_len.unwrap_or(usize::MAX);
+        #[arg(long, value_enum, default_value_t = Anchor::Both, help = "Which tag(s) determine the sample assignment")]
+        pub anchor: Anchor,
 
-        // --- BRANCH 1: Batch processing from a directory ---
-        if let Some(input_dir) = args.input_dir {
-            let output_dir = args.output_dir.ok_or_else(|| {
-                anyhow!("--output-dir is required when using --input-dir")
-            })?;
-            
-            if args.outfile.is_some() {
-                return Err(anyhow!("--outfile cannot be used with --input-dir. Use --output-dir instead."));
-            }
+        #[arg(long, conflicts_with = "anchor", help = "Single-indexed library: the tag file has SampleID,Tag columns and reads are assigned by the forward tag at the 5' end only (implies --anchor forward)")]
+        pub single_tag: bool,
 
-            fs::create_dir_all(&output_dir)
-                .with_context(|| format!("Failed to create output directory: {:?}", output_dir))?;
+        #[arg(long, default_value_t = 0, help = "Mismatches allowed per tag when no exact match is found; ambiguous best hits stay unmatched")]
+        pub mismatches: usize,
 
-            println!("---> Starting batch filter in directory: {}", input_dir.display());
+        #[arg(long, default_value_t = 0, help = "Maximum number of sample output files kept open at once; least recently used files are closed and reopened in append mode (0 = unlimited)")]
+        pub max_open_files: usize,
 
-            for entry in fs::read_dir(input_dir)? {
-                let entry = entry?;
-                let input_path = entry.path();
-                
-                if input_path.is_file() {
-                    let (new_file_name, should_process) = match get_output_filename(&input_path) {
-                        Ok((name, process)) => (name, process),
-                        Err(e) => {
-                             println!("---> Skipping file {}: {}", input_path.display(), e); // <-- 修复：将 input_PANTS 改为 input_path
-                             continue;
-                        }
-                    };
+        #[arg(long, help = "Write per-sample forward/reverse orientation counts to this CSV (SampleID,FwdReads,RevReads)")]
+        pub tag_orientation_stats: Option<PathBuf>,
 
-                    if !should_process {
-                         println!("---> Skipping unsupported file type: {}", input_path.display());
-                         continue;
-                    }
-                    
-                    let output_path = output_dir.join(new_file_name);
+        #[arg(long, help = "Write per-sample read counts to this CSV (SampleID,Reads,Percent), including unmatched")]
+        pub summary_csv: Option<PathBuf>,
 
-                    // 2. Open reader
-                    let format = match detect_format(&input_path) {
-                         Ok(f) => f,
-                         Err(e) => {
-                             println!("---> Skipping file {}: {}", input_path.display(), e);
-                             continue;
-                         }
-                    };
-                    
-                    let file = File::open(&input_path)?;
-                    let buf_reader = BufReader::new(file);
-                    let input_reader: Box<dyn BufRead> =
-                        if input_path.extension().map_or(false, |ext| ext == "gz") {
-                            Box::new(BufReader::new(MultiGzDecoder::new(buf_reader)))
-                        } else {
-                            Box::new(buf_reader)
-                        };
-                    
-                    // 3. Open writer
-                    let mut writer: Box<dyn Write> = Box::new(BufWriter::new(File::create(&output_path)?));
+        #[arg(long, num_args = 2, value_names = ["MIN", "MAX"], conflicts_with = "in1", help = "Diagnostic: report how many sampled reads match the tag set exactly with tags cut to each length in MIN..=MAX, then exit")]
+        pub scan_tag_len: Option<Vec<usize>>,
 
-                    // 4. Process
-                    println!("---> Filtering {} -> {}", input_path.display(), output_path.display());
-                    let count = process_file_stream(input_reader, &mut writer, &format, min_len, max_len)
-                        .with_context(|| format!("Failed to process file: {:?}", input_path))?;
-                    println!("✔ Wrote {} records to {}", count, output_path.display());
-                }
-            }
-            println!("🎉 Batch filtering complete.");
+        #[arg(long, default_value_t = 100_000, help = "Number of reads sampled from the start of the input for --scan-tag-len")]
+        pub scan_reads: usize,
 
-        // --- BRANCH 2: Original logic (concatenate and filter) ---
-        } else if !args.input_files.is_empty() {
-            if args.output_dir.is_some() {
-                 return Err(anyhow!("--output-dir can only be used with --input-dir."));
-            }
-            
-            let mut writer: Box<dyn Write> = if let Some(path) = args.outfile {
-                Box::new(BufWriter::new(File::create(path)?))
-            } else {
-                Box::new(BufWriter::new(io::stdout().lock()))
-            };
+        #[arg(long, help = "Scan unmatched reads for interior tags of two or more samples and write them to chimeric.<ext> with the sample IDs in the header")]
+        pub detect_chimera: bool,
 
-            let mut first_format: Option<Format> = None;
-            let mut total_records = 0;
+        #[arg(long, help = "Expected number of input reads (pairs in paired-end mode), used to show a percentage/ETA progress bar (default: read <inputfile>.count or <in1>.count if present)")]
+        pub total_reads: Option<u64>,
 
-            for input_path in &args.input_files {
-                eprintln!("---> Processing (and appending): {}", input_path.display());
-                let format = detect_format(input_path)?;
-                
-                // Ensure all files are the same format when concatenating
-                if let Some(ref first) = first_format {
-                    if *first != format {
-                         return Err(anyhow!(
-                            "Mismatched formats: Cannot concatenate FASTA and FASTQ files into one output."
-                         ));
-                    }
-                } else {
-                    first_format = Some(format);
-                }
-                
-                let file = File::open(input_path)?;
-                let buf_reader = BufReader::new(file);
-                let input_reader: Box<dyn BufRead> =
-                    if input_path.extension().map_or(false, |ext| ext == "gz") {
-                        Box::new(BufReader::new(MultiGzDecoder::new(buf_reader)))
-                    } else {
-                        Box::new(buf_reader)
-                    };
+        #[arg(long, help = "After demultiplexing, report samples from the tag file with fewer than N reads as low-yield")]
+        pub min_sample_reads: Option<u64>,
 
-                total_records += process_file_stream(input_reader, &mut writer, first_format.as_ref().unwrap(), min_len, max_len)
-                    .with_context(|| format!("Failed to process file: {:?}", input_path))?;
-            }
-            eprintln!("✔ Total records written: {}", total_records);
-        }
-        // No 'else' needed, as clap's 'input_mode' group ensures one branch is taken
-        
-        Ok(())
-    }
-}
+        #[arg(long, requires = "min_sample_reads", conflicts_with = "count_only", help = "With --min-sample-reads, concatenate low-yield sample files into low_yield.<ext> and remove the per-sample files")]
+        pub merge_low_yield: bool,
 
-// ==================================================================================
-// `merge_file` subcommand module
-// ==================================================================================
-mod merge_file {
-    use super::common::{detect_format, Format};
-    use anyhow::{anyhow, Context, Result};
-    use bio::io::{fasta, fastq};
-    use clap::Parser;
-    use flate2::bufread::MultiGzDecoder;
-    use flate2::write::GzEncoder;
-    use flate2::Compression;
-    use std::fs::File;
-    use std::io::{BufRead, BufReader, BufWriter, Write};
-    use std::path::PathBuf;
-    use indicatif::{ProgressBar, ProgressStyle};
-    use rand::seq::SliceRandom;
-    use rand::thread_rng;
+        #[command(flatten)]
+        pub desc: DescArgs,
 
-    #[derive(Parser, Debug)]
-    #[command(name = "merge_file", about = "Merge multiple FASTA/FASTQ files with optional shuffle, concurrency, progress, and fastq→fasta conversion.")]
-    pub struct Args {
-        #[arg(long, num_args = 1.., help = "Input FASTA/FASTQ files (gz supported)")]
-        pub input_files: Vec<PathBuf>,
+        #[command(flatten)]
+        pub global: GlobalOpts,
+    }
 
-        #[arg(long, help = "Output file (.fasta/.fastq or .gz)")]
-        pub outfile: Option<PathBuf>,
+    #[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Anchor {
+        /// Match both the forward tag (5' end) and the reverse tag (3' end)
+        Both,
+        /// Match only the reverse tag (R_tag_rc at the 3' end); reverse tags must be unique across samples
+        Reverse,
+        /// Match only the forward tag (F_tag at the 5' end); forward tags must be unique across samples
+        Forward,
+    }
 
-        #[arg(long, help = "Keep input file order (default)")]
-        pub keep_order: bool,
+    #[derive(Debug, Clone)]
+    struct MatchInfo {
+        sample_id: String,
+        orientation: Orientation,
+        // Tag bases at the 5' and 3' end of a read assigned through this entry
+        trim_lens: (usize, usize),
+    }
+    #[derive(Debug, Clone, PartialEq)]
+    enum Orientation {
+        Forward,
+        Reverse,
+    }
+    type LookupMap = HashMap<(Vec<u8>, Vec<u8>), MatchInfo>;
+    // (SampleID, F_tag, R_tag) as read from the tag file
+    type TagRow = (String, Vec<u8>, Vec<u8>);
+    struct TagLookup {
+        pairs: LookupMap,
+        // R_tag_rc -> sample, only populated for `--anchor reverse`
+        reverse_only: HashMap<Vec<u8>, MatchInfo>,
+        // Distinct (5', 3') key lengths present in `pairs`, longest first
+        pair_lens: Vec<(usize, usize)>,
+        // Distinct 3' key lengths present in `reverse_only`, longest first
+        reverse_lens: Vec<usize>,
+        // F_tag -> sample, only populated for `--anchor forward`
+        forward_only: HashMap<Vec<u8>, MatchInfo>,
+        // Distinct 5' key lengths present in `forward_only`, longest first
+        forward_lens: Vec<usize>,
+        // F_tag / R_tag_rc variant -> sample, for tags that belong to a single sample (chimera scan)
+        interior: HashMap<Vec<u8>, String>,
+        // Distinct key lengths present in `interior`
+        interior_lens: Vec<usize>,
+    }
+    // A read and, in paired-end mode, its R2 mate
+    type ReadUnit = (Record, Option<Record>);
+    type RawChunk = Vec<ReadUnit>;
+    // (read unit, orientation, rescued by fuzzy matching)
+    type ProcessedChunk = HashMap<String, Vec<(ReadUnit, Option<Orientation>, bool)>>;
+
+    #[derive(Debug, Default)]
+    struct SampleCounts {
+        total: u64,
+        fwd: u64,
+        rev: u64,
+        rescued: u64,
+    }
+    /// Underlying file for one sample output.
+    /// Flushing a gzip sink finishes its gzip member, so it is only flushed when the file is closed.
+    enum Sink {
+        Plain(File),
+        Gzip(GzEncoder<File>),
+    }
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            match self {
+                Sink::Plain(file) => file.write(buf),
+                Sink::Gzip(encoder) => encoder.write(buf),
+            }
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            match self {
+                Sink::Plain(file) => file.flush(),
+                Sink::Gzip(encoder) => encoder.try_finish(),
+            }
+        }
+    }
+    enum GenericWriter {
+        Fastq(fastq::Writer<Sink>),
+        Fasta(fasta::Writer<Sink>, DescArgs),
+    }
+    impl GenericWriter {
+        fn write_record(&mut self, record: &Record) -> Result<()> {
+            match self {
+                GenericWriter::Fastq(writer) => writer.write_record(record)?,
+                GenericWriter::Fasta(writer, desc) => {
+                    let fasta_record =
+                        fasta::Record::with_attrs(record.id(), desc.apply(record.desc()), record.seq());
+                    writer.write_record(&fasta_record)?;
+                }
+            }
+            Ok(())
+        }
+        /// Flushes buffered records and, for gzip output, writes the stream trailer.
+        fn close(&mut self) -> Result<()> {
+            match self {
+                GenericWriter::Fastq(writer) => writer.flush()?,
+                GenericWriter::Fasta(writer, _) => writer.flush()?,
+            }
+            Ok(())
+        }
+    }
 
-        #[arg(long, help = "Shuffle record order before writing")]
-        pub shuffle: bool,
+    /// Per-sample writers with an optional cap on open files.
+    /// Output files are truncated once up front; evicted samples are flushed, closed and later reopened in append mode.
+    struct WriterCache {
+        output_dir: PathBuf,
+        // One entry per output format; true = FASTA
+        fasta_flags: Vec<bool>,
+        // Paired-end output: every format gets an _R1 and an _R2 file
+        paired: bool,
+        gzip: bool,
+        desc: DescArgs,
+        // Maximum number of samples with open writers (None = unlimited)
+        max_samples: Option<usize>,
+        open: HashMap<String, (u64, Vec<GenericWriter>)>,
+        clock: u64,
+    }
+    impl WriterCache {
+        fn new(output_dir: PathBuf, fasta_flags: Vec<bool>, paired: bool, gzip: bool, desc: DescArgs, max_open_files: usize, samples: &HashSet<String>) -> Result<Self> {
+            let files_per_sample = fasta_flags.len() * if paired { 2 } else { 1 };
+            let max_samples = (max_open_files > 0).then(|| (max_open_files / files_per_sample).max(1));
+            let cache = WriterCache { output_dir, fasta_flags, paired, gzip, desc, max_samples, open: HashMap::new(), clock: 0 };
+            for sample_id in samples {
+                for path in cache.paths(sample_id) {
+                    let file = File::create(&path).with_context(|| format!("Failed to create output file: {:?}", path))?;
+                    if gzip {
+                        // Empty gzip member so samples without reads are still valid .gz files
+                        GzEncoder::new(file, Compression::default()).finish()?;
+                    }
+                }
+            }
+            Ok(cache)
+        }
 
-        #[arg(long, default_value_t = num_cpus::get_physical(), help = "Parallel read workers")]
-        pub threads: usize,
+        /// Output paths of a sample, in the same order as `formats`.
+        fn paths(&self, sample_id: &str) -> Vec<PathBuf> {
+            sample_paths(&self.output_dir, &self.fasta_flags, self.paired, self.gzip, sample_id)
+        }
 
-        #[arg(long, default_value_t = 10000, help = "Chunk size per read batch")]
-        pub chunk_size: usize,
+        /// FASTA flag of each output file of a sample; in paired-end mode R1 and R2 files alternate.
+        fn formats(&self) -> Vec<bool> {
+            let per_format = if self.paired { 2 } else { 1 };
+            self.fasta_flags.iter().flat_map(|&as_fasta| std::iter::repeat_n(as_fasta, per_format)).collect()
+        }
 
-        #[arg(long, help = "Convert FASTQ to FASTA before merging (if inputs are FASTQ)")]
-        pub fastq_to_fasta: bool,
+        fn get(&mut self, sample_id: &str) -> Result<&mut Vec<GenericWriter>> {
+            self.clock += 1;
+            if !self.open.contains_key(sample_id) {
+                if self.max_samples.is_some_and(|max| self.open.len() >= max) {
+                    self.evict_lru()?;
+                }
+                let formats = self.formats();
+                let mut sample_writers = Vec::with_capacity(formats.len());
+                for (path, &as_fasta) in self.paths(sample_id).iter().zip(&formats) {
+                    let file = OpenOptions::new()
+                        .append(true)
+                        .open(path)
+                        .with_context(|| format!("Failed to reopen output file: {:?}", path))?;
+                    // A reopened gzip file gets a new member; multi-member gzip reads back as one stream
+                    let file = if self.gzip {
+                        Sink::Gzip(GzEncoder::new(file, Compression::default()))
+                    } else {
+                        Sink::Plain(file)
+                    };
+                    sample_writers.push(if as_fasta {
+                        GenericWriter::Fasta(fasta::Writer::new(file), self.desc)
+                    } else {
+                        GenericWriter::Fastq(fastq::Writer::new(file))
+                    });
+                }
+                self.open.insert(sample_id.to_string(), (self.clock, sample_writers));
+            }
+            let entry = self.open.get_mut(sample_id).expect("Writer for sample not found!");
+            entry.0 = self.clock;
+            Ok(&mut entry.1)
+        }
 
-        #[arg(long, help = "Only perform FASTQ→FASTA conversion and write output (no merge)")]
-        pub convert_only: bool,
+        fn evict_lru(&mut self) -> Result<()> {
+            let lru = self.open.iter().min_by_key(|(_, (used, _))| *used).map(|(id, _)| id.clone());
+            if let Some(sample_id) = lru {
+                if let Some((_, mut sample_writers)) = self.open.remove(&sample_id) {
+                    for writer in sample_writers.iter_mut() {
+                        writer.close()?;
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        fn finish(mut self) -> Result<()> {
+            for (_, sample_writers) in self.open.values_mut() {
+                for writer in sample_writers.iter_mut() {
+                    writer.close()?;
+                }
+            }
+            Ok(())
+        }
     }
 
-    pub fn run(args: Args) -> Result<()> {
-        if args.input_files.is_empty() {
-            return Err(anyhow!("No input files provided"));
+    /// One entry per requested output format; true = FASTA. Every record goes to all of them.
+    fn output_formats(args: &Args) -> Vec<bool> {
+        let mut fasta_flags = vec![args.out_fasta];
+        if args.out_fasta && args.also_fastq {
+            fasta_flags.push(false);
         }
+        fasta_flags
+    }
 
-        // Determine output path (required unless convert_only prints to stdout in future)
-        let outfile = args.outfile
-            .clone()
-            .ok_or_else(|| anyhow!("--outfile is required unless future support for stdout is added"))?;
+    /// Output paths of a sample: every format in order, with _R1/_R2 files adjacent in paired-end mode.
+    fn sample_paths(output_dir: &Path, fasta_flags: &[bool], paired: bool, gzip: bool, sample_id: &str) -> Vec<PathBuf> {
+        let mates: &[&str] = if paired { &["_R1", "_R2"] } else { &[""] };
+        fasta_flags
+            .iter()
+            .flat_map(|&as_fasta| {
+                let extension = if as_fasta { "fasta" } else { "fastq" };
+                let suffix = if gzip { ".gz" } else { "" };
+                mates.iter().map(move |mate| output_dir.join(format!("{}{}.{}{}", sample_id, mate, extension, suffix)))
+            })
+            .collect()
+    }
 
-        let first_format = detect_format(&args.input_files[0])?;
-        for p in &args.input_files[1..] {
-            let f = detect_format(p)?;
-            if f != first_format {
-                return Err(anyhow!("Input files must have the same format"));
+    /// Reads (SampleID, F_tag, R_tag) rows from the tag CSV, uppercased.
+    /// A single-tag file (SampleID,Tag) yields its tag as F_tag and an empty R_tag.
+    fn read_tag_rows(tag_file: &Path, single_tag: bool) -> Result<Vec<TagRow>> {
+        let file = File::open(tag_file)
+            .with_context(|| format!("Failed to open tag file: {:?}", tag_file))?;
+        let mut rdr = ReaderBuilder::new()
+            .has_headers(true)
+            .flexible(true)
+            .delimiter(b',')
+            .from_reader(file);
+        let headers = rdr.headers()?.clone();
+        if single_tag {
+            if !headers.iter().any(|h| h == "SampleID") || !headers.iter().any(|h| h == "Tag") {
+                return Err(anyhow!("Single-tag file must contain the columns 'SampleID' and 'Tag'."));
+            }
+            let mut rows = Vec::new();
+            for result in rdr.records() {
+                let record = result?;
+                let sample_id = record.get(0).ok_or_else(|| anyhow!("Missing SampleID"))?.to_string();
+                let tag = record.get(1).ok_or_else(|| anyhow!("Missing Tag"))?.as_bytes().to_ascii_uppercase();
+                if tag.is_empty() {
+                    return Err(anyhow!("Empty Tag for sample {}", sample_id));
+                }
+                rows.push((sample_id, tag, Vec::new()));
             }
+            return Ok(rows);
         }
-
-        // If fastq_to_fasta is set, we will treat output as FASTA even if inputs are FASTQ
-        let target_format = if args.convert_only || args.fastq_to_fasta { Format::Fasta } else { first_format };
-
-        if args.convert_only && args.input_files.len() != 1 {
-            return Err(anyhow!("--convert-only 仅支持单输入文件。如需合并请不要使用该选项"));
+        if !headers.iter().any(|h| h == "SampleID")
+            || !headers.iter().any(|h| h == "F_tag")
+            || !headers.iter().any(|h| h == "R_tag")
+        {
+            return Err(anyhow!(
+                "Tag file must contain the columns 'SampleID', 'F_tag', and 'R_tag'."
+            ));
         }
-
-        // Combine and optionally shuffle the list of files respecting keep_order/shuffle
-        let mut files = args.input_files.clone();
-        if args.shuffle && !args.keep_order {
-            files.shuffle(&mut thread_rng());
+        let mut rows = Vec::new();
+        for result in rdr.records() {
+            let record = result?;
+            let sample_id = record.get(0).ok_or_else(|| anyhow!("Missing SampleID"))?.to_string();
+            let f_tag = record.get(1).ok_or_else(|| anyhow!("Missing F_tag"))?.as_bytes().to_ascii_uppercase();
+            let r_tag = record.get(2).ok_or_else(|| anyhow!("Missing R_tag"))?.as_bytes().to_ascii_uppercase();
+            if f_tag.is_empty() || r_tag.is_empty() {
+                return Err(anyhow!("Empty F_tag or R_tag for sample {}", sample_id));
+            }
+            rows.push((sample_id, f_tag, r_tag));
         }
-        let out_file = File::create(&outfile)
-            .with_context(|| format!("Failed to create output file: {:?}", outfile))?;
-        let out_writer: Box<dyn Write> = if outfile.extension().map_or(false, |ext| ext == "gz") {
-            Box::new(GzEncoder::new(BufWriter::new(out_file), Compression::default()))
-        } else {
-            Box::new(BufWriter::new(out_file))
-        };
-        let mut out_writer = out_writer;
+        Ok(rows)
+    }
 
-        let pb = ProgressBar::new(0);
-        pb.set_style(
-            ProgressStyle::default_spinner()
-                .tick_strings(&["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"])
-                .template("{spinner:.blue} {msg} {pos} records")?
-        );
-        pb.set_message("Merging records...");
+    fn load_tags(
+        tag_file: &Path,
+        tag_len: Option<usize>,
+        anchor: Anchor,
+        single_tag: bool,
+    ) -> Result<(TagLookup, HashSet<String>)> {
+        let mut lookup_map = HashMap::new();
+        let mut reverse_only: HashMap<Vec<u8>, MatchInfo> = HashMap::new();
+        let mut forward_only: HashMap<Vec<u8>, MatchInfo> = HashMap::new();
+        let mut all_samples = HashSet::new();
+        let mut tag_owners: HashMap<Vec<u8>, HashSet<String>> = HashMap::new();
+        for (sample_id, f_tag, r_tag) in read_tag_rows(tag_file, single_tag)? {
+            if let Some(tag_len) = tag_len {
+                if f_tag.len() != tag_len || (!single_tag && r_tag.len() != tag_len) {
+                    return Err(anyhow!("Tag length for sample {} does not match the specified --tag-len {}", sample_id, tag_len));
+                }
+            }
+            let (f_len, r_len) = (f_tag.len(), r_tag.len());
+            all_samples.insert(sample_id.clone());
+            // Degenerate tags are expanded so every concrete variant hits the exact-lookup path
+            let f_variants = expand_iupac(&f_tag, &sample_id)?;
+            // Single-tag rows have no reverse tag, so no F/R pair keys either
+            let r_rc_variants: Vec<Vec<u8>> = if r_tag.is_empty() {
+                Vec::new()
+            } else {
+                expand_iupac(&r_tag, &sample_id)?.into_iter().map(bio::alphabets::dna::revcomp).collect()
+            };
+            for tag in f_variants.iter().chain(&r_rc_variants) {
+                tag_owners.entry(tag.clone()).or_default().insert(sample_id.clone());
+            }
+            if f_variants.len() * r_rc_variants.len() > MAX_TAG_VARIANTS {
+                return Err(anyhow!(
+                    "Tags of sample {} expand to {} F/R combinations (limit {}); reduce the ambiguity codes",
+                    sample_id, f_variants.len() * r_rc_variants.len(), MAX_TAG_VARIANTS
+                ));
+            }
 
-        let mut total = 0u64;
+            if anchor == Anchor::Reverse {
+                for r_tag_rc in &r_rc_variants {
+                    if let Some(existing) = reverse_only.get(r_tag_rc) {
+                        return Err(anyhow!(
+                            "R_tag of sample {} collides with sample {}; reverse tags must be unique for --anchor reverse",
+                            sample_id, existing.sample_id
+                        ));
+                    }
+                    reverse_only.insert(r_tag_rc.clone(), MatchInfo { sample_id: sample_id.clone(), orientation: Orientation::Forward, trim_lens: (f_len, r_len) });
+                }
+            }
 
-        match (first_format, target_format) {
-            (Format::Fasta, Format::Fasta) => {
-                let mut out = fasta::Writer::new(&mut out_writer);
-                for input_path in files {
-                    let in_file = File::open(&input_path)
-                        .with_context(|| format!("Failed to open input file: {:?}", input_path))?;
-                    let buf_reader = BufReader::new(in_file);
-                    let input_reader: Box<dyn BufRead> = if input_path.extension().map_or(false, |ext| ext == "gz") {
-                        Box::new(BufReader::new(MultiGzDecoder::new(buf_reader)))
-                    } else { Box::new(buf_reader) };
-                    let reader = fasta::Reader::new(input_reader);
-                    // Optionally parallelize by collecting chunks; here sequential writing keeps order
-                    for result in reader.records() { let record = result?; out.write_record(&record)?; total += 1; pb.inc(1); }
-                }
-            }
-            (Format::Fastq, Format::Fastq) => {
-                let mut out = fastq::Writer::new(&mut out_writer);
-                for input_path in files {
-                    let in_file = File::open(&input_path)
-                        .with_context(|| format!("Failed to open input file: {:?}", input_path))?;
-                    let buf_reader = BufReader::new(in_file);
-                    let input_reader: Box<dyn BufRead> = if input_path.extension().map_or(false, |ext| ext == "gz") {
-                        Box::new(BufReader::new(MultiGzDecoder::new(buf_reader)))
-                    } else { Box::new(buf_reader) };
-                    let reader = fastq::Reader::new(input_reader);
-                    let chunk_size = args.chunk_size;
-                    let mut records_iter = reader.records();
-                    loop {
-                        let mut chunk = Vec::with_capacity(chunk_size);
-                        for _ in 0..chunk_size {
-                            match records_iter.next() { Some(Ok(r)) => chunk.push(r), Some(Err(e)) => return Err(e.into()), None => break }
-                        }
-                        if chunk.is_empty() { break; }
-                        if args.shuffle { chunk.shuffle(&mut thread_rng()); }
-                        // Parallel write is unsafe due to single writer; we parallel map then write sequentially
-                        for rec in chunk { out.write_record(&rec)?; total += 1; pb.inc(1); }
+            if anchor == Anchor::Forward {
+                for f_tag in &f_variants {
+                    if let Some(existing) = forward_only.get(f_tag) {
+                        return Err(anyhow!(
+                            "F_tag of sample {} collides with sample {}; forward tags must be unique for --anchor forward",
+                            sample_id, existing.sample_id
+                        ));
                     }
+                    // Only the forward tag is trimmed; the 3' end is left as sequenced
+                    forward_only.insert(f_tag.clone(), MatchInfo { sample_id: sample_id.clone(), orientation: Orientation::Forward, trim_lens: (f_len, 0) });
                 }
             }
-            (Format::Fastq, Format::Fasta) => {
-                let mut out = fasta::Writer::new(&mut out_writer);
-                for input_path in files {
-                    let in_file = File::open(&input_path)
-                        .with_context(|| format!("Failed to open input file: {:?}", input_path))?;
-                    let buf_reader = BufReader::new(in_file);
-                    let input_reader: Box<dyn BufRead> = if input_path.extension().map_or(false, |ext| ext == "gz") {
-                        Box::new(BufReader::new(MultiGzDecoder::new(buf_reader)))
-                    } else { Box::new(buf_reader) };
-                    let reader = fastq::Reader::new(input_reader);
-                    let chunk_size = args.chunk_size;
-                    let mut records_iter = reader.records();
-                    loop {
-                        let mut chunk = Vec::with_capacity(chunk_size);
-                        for _ in 0..chunk_size {
-                            match records_iter.next() { Some(Ok(r)) => chunk.push(r), Some(Err(e)) => return Err(e.into()), None => break }
-                        }
-                        if chunk.is_empty() { break; }
-                        if args.shuffle { chunk.shuffle(&mut thread_rng()); }
-                        for rec in chunk {
-                            let fasta_rec = fasta::Record::with_attrs(rec.id(), rec.desc(), rec.seq());
-                            out.write_record(&fasta_rec)?; total += 1; pb.inc(1);
+
+            for f_tag in &f_variants {
+                for r_tag_rc in &r_rc_variants {
+                    // Forward key: 5'-[F_tag]...[R_tag_rc]-3'
+                    let fwd_key = (f_tag.clone(), r_tag_rc.clone());
+                    lookup_map.insert(fwd_key, MatchInfo { sample_id: sample_id.clone(), orientation: Orientation::Forward, trim_lens: (f_len, r_len) });
+
+                    // Reverse key: 5'-[R_tag_rc]...[F_tag]-3'
+                    // FIX: The original code used f_tag_rc here, which was incorrect.
+                    let rev_key = (r_tag_rc.clone(), f_tag.clone());
+                    lookup_map.insert(rev_key, MatchInfo { sample_id: sample_id.clone(), orientation: Orientation::Reverse, trim_lens: (r_len, f_len) });
+                }
+            }
+        }
+        let mut pair_lens: Vec<(usize, usize)> = lookup_map.values().map(|m: &MatchInfo| m.trim_lens).collect::<HashSet<_>>().into_iter().collect();
+        pair_lens.sort_by_key(|&(start, end)| std::cmp::Reverse((start + end, start)));
+        let mut reverse_lens: Vec<usize> = reverse_only.keys().map(Vec::len).collect::<HashSet<_>>().into_iter().collect();
+        reverse_lens.sort_by_key(|&len| std::cmp::Reverse(len));
+        let mut forward_lens: Vec<usize> = forward_only.keys().map(Vec::len).collect::<HashSet<_>>().into_iter().collect();
+        forward_lens.sort_by_key(|&len| std::cmp::Reverse(len));
+        // Tags shared by several samples (combinatorial designs) cannot attribute an interior hit
+        let interior: HashMap<Vec<u8>, String> = tag_owners
+            .into_iter()
+            .filter(|(_, owners)| owners.len() == 1)
+            .filter_map(|(tag, owners)| owners.into_iter().next().map(|owner| (tag, owner)))
+            .collect();
+        let interior_lens: Vec<usize> = interior.keys().map(Vec::len).collect::<HashSet<_>>().into_iter().collect();
+        Ok((TagLookup { pairs: lookup_map, reverse_only, pair_lens, reverse_lens, forward_only, forward_lens, interior, interior_lens }, all_samples))
+    }
+    /// `--scan-tag-len`: cuts every tag to each candidate length and counts exact matches over a read sample.
+    /// Keys shared by several samples after cutting are counted as ambiguous rather than matched.
+    fn scan_tag_lengths(args: &Args, min_len: usize, max_len: usize) -> Result<RunReport> {
+        let inputfile = args.inputfile.as_ref().ok_or_else(|| anyhow!("--scan-tag-len requires --inputfile"))?;
+        if min_len == 0 || min_len > max_len {
+            return Err(anyhow!("--scan-tag-len needs 1 <= MIN <= MAX (got {} {})", min_len, max_len));
+        }
+        let rows = read_tag_rows(&args.tags, args.single_tag)?;
+        let mut reads: Vec<Vec<u8>> = Vec::new();
+        for result in fastq::Reader::new(open_input(inputfile)?).records().take(args.scan_reads) {
+            reads.push(result?.seq().to_ascii_uppercase());
+        }
+        println!("---> Tag length scan over {} reads (exact matches, --anchor {}):", reads.len(), format!("{:?}", args.anchor).to_lowercase());
+        println!("  {:>6} {:>8} {:>12} {:>8} {:>10}", "TagLen", "Samples", "Matched", "Percent", "Ambiguous");
+
+        let mut report = RunReport::new("demux_only").input(inputfile).input(&args.tags).count("scan_reads", reads.len() as u64);
+        let mut best: Option<(usize, u64)> = None;
+        for len in min_len..=max_len {
+            // (5' key, 3' key) -> sample, None once two samples share the key;
+            // 5' key is empty for --anchor reverse and 3' key for --anchor forward
+            let mut keys: HashMap<(Vec<u8>, Vec<u8>), Option<String>> = HashMap::new();
+            let mut samples = 0;
+            let uses_r_tag = args.anchor != Anchor::Forward;
+            for (sample_id, f_tag, r_tag) in rows.iter().filter(|(_, f, r)| f.len() >= len && (!uses_r_tag || r.len() >= len)) {
+                samples += 1;
+                let f_variants = expand_iupac(&f_tag[..len], sample_id)?;
+                let r_rc_variants: Vec<Vec<u8>> = if uses_r_tag {
+                    expand_iupac(&r_tag[..len], sample_id)?.iter().map(bio::alphabets::dna::revcomp).collect()
+                } else {
+                    Vec::new()
+                };
+                let mut insert = |key: (Vec<u8>, Vec<u8>)| {
+                    let owner = keys.entry(key).or_insert_with(|| Some(sample_id.clone()));
+                    if owner.as_ref() != Some(sample_id) {
+                        *owner = None;
+                    }
+                };
+                for r_rc in &r_rc_variants {
+                    match args.anchor {
+                        Anchor::Both => {
+                            for f in &f_variants {
+                                insert((f.clone(), r_rc.clone()));
+                                insert((r_rc.clone(), f.clone()));
+                            }
                         }
+                        Anchor::Reverse => insert((Vec::new(), r_rc.clone())),
+                        Anchor::Forward => {}
+                    }
+                }
+                if args.anchor == Anchor::Forward {
+                    for f in &f_variants {
+                        insert((f.clone(), Vec::new()));
                     }
                 }
             }
-            (Format::Fasta, Format::Fastq) => {
-                return Err(anyhow!("Cannot convert FASTA to FASTQ because quality scores are unavailable"));
+
+            let (mut matched, mut ambiguous) = (0u64, 0u64);
+            for seq in &reads {
+                let key = match args.anchor {
+                    Anchor::Both if seq.len() >= 2 * len => (seq[..len].to_vec(), seq[seq.len() - len..].to_vec()),
+                    Anchor::Reverse if seq.len() >= len => (Vec::new(), seq[seq.len() - len..].to_vec()),
+                    Anchor::Forward if seq.len() >= len => (seq[..len].to_vec(), Vec::new()),
+                    _ => continue,
+                };
+                match keys.get(&key) {
+                    Some(Some(_)) => matched += 1,
+                    Some(None) => ambiguous += 1,
+                    None => {}
+                }
+            }
+            let percent = if reads.is_empty() { 0.0 } else { matched as f64 * 100.0 / reads.len() as f64 };
+            println!("  {:>6} {:>8} {:>12} {:>7.2}% {:>10}", len, samples, matched, percent, ambiguous);
+            report = report.count(format!("scan_len_{}.matched", len), matched);
+            // Ties go to the longer, more specific length
+            if best.is_none_or(|(_, best_matched)| matched >= best_matched) {
+                best = Some((len, matched));
             }
         }
+        if let Some((len, matched)) = best {
+            println!("✔ Most reads match at tag length {} ({} reads)", len, matched);
+        }
+        Ok(report)
+    }
 
-        pb.finish_with_message("✔ Merging complete");
-        println!("✔ Processed {} records into {}", total, outfile.display());
-        Ok(())
+    /// Expands IUPAC ambiguity codes in a tag into every concrete A/C/G/T sequence.
+    fn expand_iupac(tag: &[u8], sample_id: &str) -> Result<Vec<Vec<u8>>> {
+        let mut variants: Vec<Vec<u8>> = vec![Vec::with_capacity(tag.len())];
+        for &code in tag {
+            let bases = iupac_bases(code).ok_or_else(|| anyhow!(
+                "Invalid character '{}' in tag {} of sample {}",
+                code as char, String::from_utf8_lossy(tag), sample_id
+            ))?;
+            if variants.len() * bases.len() > MAX_TAG_VARIANTS {
+                return Err(anyhow!(
+                    "Tag {} of sample {} expands to more than {} sequences; reduce the ambiguity codes",
+                    String::from_utf8_lossy(tag), sample_id, MAX_TAG_VARIANTS
+                ));
+            }
+            variants = variants
+                .iter()
+                .flat_map(|prefix| bases.iter().map(move |&base| [prefix.as_slice(), &[base]].concat()))
+                .collect();
+        }
+        Ok(variants)
     }
-}
 
-// ==================================================================================
-// `ns_count` subcommand module (Restored v0.5.1 anchor-based logic with syntax fix)
-// ==================================================================================
-mod ns_count {
-    use anyhow::{Context, Result};
-    use bio::io::fasta::{self, Record};
-    use clap::Parser;
-    use flate2::bufread::MultiGzDecoder;
-    use indicatif::{ProgressBar, ProgressStyle};
-    use std::collections::{HashMap, HashSet};
-    use std::fs::File;
-    use std::io::{BufRead, BufReader};
-    use std::path::PathBuf;
-    use std::sync::Arc;
-    use std::thread;
-
-    const CHUNK_SIZE: usize = 4096;
+    /// Returns the expected read count from `--total-reads`, or from a `<inputfile>.count` sidecar holding a single integer.
+    fn expected_total_reads(args: &Args) -> Result<Option<u64>> {
+        if args.total_reads.is_some() {
+            return Ok(args.total_reads);
+        }
+        match args.inputfile.as_ref().or(args.in1.as_ref()) {
+            Some(input) => read_count_sidecar(input),
+            None => Ok(None),
+        }
+    }
 
-    #[derive(Parser, Debug)]
-    pub struct Args {
-        #[arg(long, help = "FASTA file containing reads to be aligned (can be gzipped)")]
-        reads: PathBuf,
-        #[arg(long = "refSEQ", help = "FASTA file containing the reference sequence with N-regions")]
-        ref_seq: PathBuf,
-        #[arg(long, help = "Output directory for CSV files")]
-        output: PathBuf,
-        #[arg(long, help = "Number of threads", default_value_t = num_cpus::get_physical())]
-        threads: usize,
-        #[arg(long, help = "Prefix for column headers in the output CSV", default_value = "T0")]
-        group: String,
-        #[arg(long, help = "Number of decimal places for frequency", default_value_t = 2)]
-        dig: u8,
-        #[arg(long, help = "Maximum mismatches allowed in non-anchor regions", default_value_t = 2)]
-        mismatches: usize,
-        #[arg(long, help = "Length of the anchor region on each side of an N-block", default_value_t = 15)]
-        anchor_len: usize,
-        #[arg(long, help = "Extract all matching reads into a separate FASTA file")]
-        extract_matches: bool,
+    fn reader_thread(
+        input_path: PathBuf,
+        tx: crossbeam_channel::Sender<RawChunk>,
+        pb: ProgressBar,
+    ) -> Result<()> {
+        let boxed_buf_reader = open_input(&input_path)?;
+        let mut records_iter = fastq::Reader::new(boxed_buf_reader).records();
+        loop {
+            let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+            for _ in 0..CHUNK_SIZE {
+                match records_iter.next() {
+                    Some(Ok(record)) => chunk.push((record, None)),
+                    Some(Err(e)) => return Err(e.into()),
+                    None => break,
+                }
+            }
+            if chunk.is_empty() {
+                break;
+            }
+            pb.inc(chunk.len() as u64);
+            if tx.send(chunk).is_err() {
+                break;
+            }
+        }
+        pb.finish_with_message("✔ File reading complete");
+        Ok(())
     }
 
-    struct MatchResult {
-        ref_id: String,
-        combo: Vec<u8>,
-        read_record: Record,
+    /// Paired-end variant of `reader_thread`: reads R1 and R2 in lockstep and sends record pairs.
+    fn paired_reader_thread(
+        in1: PathBuf,
+        in2: PathBuf,
+        tx: crossbeam_channel::Sender<RawChunk>,
+        pb: ProgressBar,
+    ) -> Result<()> {
+        let mut r1_iter = fastq::Reader::new(open_input(&in1)?).records();
+        let mut r2_iter = fastq::Reader::new(open_input(&in2)?).records();
+        loop {
+            let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+            for _ in 0..CHUNK_SIZE {
+                match (r1_iter.next(), r2_iter.next()) {
+                    (Some(r1), Some(r2)) => chunk.push((r1?, Some(r2?))),
+                    (None, None) => break,
+                    _ => return Err(anyhow!("{:?} and {:?} contain different numbers of reads", in1, in2)),
+                }
+            }
+            if chunk.is_empty() {
+                break;
+            }
+            pb.inc(chunk.len() as u64);
+            if tx.send(chunk).is_err() {
+                break;
+            }
+        }
+        pb.finish_with_message("✔ File reading complete");
+        Ok(())
     }
 
-    struct RefData {
-        id: String,
-        seq: Vec<u8>,
-        len: usize,
-        n_blocks: Vec<(usize, usize)>,
-        anchor_indices: HashSet<usize>,
+    fn hamming(a: &[u8], b: &[u8]) -> usize {
+        a.iter().zip(b).filter(|(x, y)| !x.eq_ignore_ascii_case(y)).count()
     }
 
-    fn find_n_blocks(seq: &[u8]) -> Vec<(usize, usize)> {
-        let mut blocks = Vec::new();
-        let mut in_block = false;
-        let mut start = 0;
-        for (i, &base) in seq.iter().enumerate() {
-            if base == b'N' {
-                if !in_block {
-                    start = i;
-                    in_block = true;
+    /// Best tag hit within `max_mismatches` per tag, by total distance.
+    /// Returns None when nothing is close enough or the best distance is shared by different hits.
+    fn fuzzy_match<'a>(
+        lookup: &'a TagLookup,
+        seq: &[u8],
+        anchor: Anchor,
+        max_mismatches: usize,
+    ) -> Option<&'a MatchInfo> {
+        let candidates: Box<dyn Iterator<Item = (Option<usize>, &MatchInfo)>> = match anchor {
+            Anchor::Both => Box::new(lookup.pairs.iter().map(|((start, end), info)| {
+                if seq.len() < start.len() + end.len() {
+                    return (None, info);
+                }
+                let d_start = hamming(start, &seq[..start.len()]);
+                let d_end = hamming(end, &seq[seq.len() - end.len()..]);
+                let within = d_start <= max_mismatches && d_end <= max_mismatches;
+                (within.then_some(d_start + d_end), info)
+            })),
+            Anchor::Reverse => Box::new(lookup.reverse_only.iter().map(|(end, info)| {
+                if seq.len() < end.len() {
+                    return (None, info);
+                }
+                let d_end = hamming(end, &seq[seq.len() - end.len()..]);
+                ((d_end <= max_mismatches).then_some(d_end), info)
+            })),
+            Anchor::Forward => Box::new(lookup.forward_only.iter().map(|(start, info)| {
+                if seq.len() < start.len() {
+                    return (None, info);
+                }
+                let d_start = hamming(start, &seq[..start.len()]);
+                ((d_start <= max_mismatches).then_some(d_start), info)
+            })),
+        };
+        let mut best: Option<(usize, &MatchInfo)> = None;
+        let mut tied = false;
+        for (distance, info) in candidates {
+            let Some(distance) = distance else { continue };
+            match best {
+                Some((best_distance, _)) if distance > best_distance => {}
+                // Expanded variants of the same degenerate tag are not a conflict
+                Some((best_distance, best_info)) if distance == best_distance => {
+                    if best_info.sample_id != info.sample_id || best_info.orientation != info.orientation {
+                        tied = true;
+                    }
+                }
+                _ => {
+                    best = Some((distance, info));
+                    tied = false;
                 }
-            } else if in_block {
-                blocks.push((start, i - start));
-                in_block = false;
             }
         }
-        if in_block {
-            blocks.push((start, seq.len() - start));
-        }
-        blocks
+        if tied { None } else { best.map(|(_, info)| info) }
     }
 
-    fn calculate_anchor_indices(n_blocks: &[(usize, usize)], ref_len: usize, anchor_len: usize) -> HashSet<usize> {
-        let mut indices = HashSet::new();
-        for &(start, len) in n_blocks {
-            let upstream_start = start.saturating_sub(anchor_len);
-            indices.extend(upstream_start..start);
-
-            let downstream_start = start + len;
-            let downstream_end = (downstream_start + anchor_len).min(ref_len);
-            indices.extend(downstream_start..downstream_end);
+    /// Scans a whole sequence for sample-specific tags; returns the sorted sample IDs when two or more samples are found.
+    fn scan_chimera(seq: &[u8], lookup: &TagLookup) -> Option<Vec<String>> {
+        let seq = seq.to_ascii_uppercase();
+        let mut samples: Vec<String> = lookup
+            .interior_lens
+            .iter()
+            .filter(|&&len| seq.len() >= len)
+            .flat_map(|&len| seq.windows(len).filter_map(|window| lookup.interior.get(window)))
+            .cloned()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        if samples.len() < 2 {
+            return None;
         }
-        indices
+        samples.sort();
+        Some(samples)
     }
 
-    fn find_alignment(read_seq: &[u8], ref_data: &RefData, args: &Arc<Args>, is_rc_read: bool) -> Option<Vec<u8>> {
-        let read_len = read_seq.len();
-        let ref_len = ref_data.len;
-
-        for ref_start in 0..=ref_len.saturating_sub(read_len) {
-            let overlap_len = read_len;
-
-            if !ref_data.n_blocks.iter().all(|(n_start, n_len)| 
-                *n_start >= ref_start && (*n_start + *n_len) <= (ref_start + overlap_len)
-            ) {
-                continue;
-            }
-
-            let mut anchor_mismatch = false;
-            for &anchor_idx in &ref_data.anchor_indices {
-                if anchor_idx >= ref_start && anchor_idx < (ref_start + overlap_len) {
-                    let read_idx = anchor_idx - ref_start;
-                    if read_seq[read_idx] != ref_data.seq[anchor_idx] {
-                        anchor_mismatch = true;
-                        break;
-                    }
-                }
-            }
-            if anchor_mismatch { continue; }
+    /// Copy of a record with `chimera=<samples>` appended to its description.
+    fn annotate_chimera(record: &Record, samples: &[String]) -> Record {
+        let tag = format!("chimera={}", samples.join(","));
+        let desc = match record.desc() {
+            Some(desc) => format!("{} {}", desc, tag),
+            None => tag,
+        };
+        Record::with_attrs(record.id(), Some(&desc), record.seq(), record.qual())
+    }
 
-            let mut mismatches = 0;
-            for i in 0..overlap_len {
-                let ref_idx = ref_start + i;
-                if ref_data.anchor_indices.contains(&ref_idx) || ref_data.seq[ref_idx] == b'N' {
-                    continue;
-                }
-                let read_idx = i;
-                if read_seq[read_idx] != ref_data.seq[ref_idx] {
-                    mismatches += 1;
-                }
+    /// Finds the tag entry for a sequence whose 5'/3' ends carry the tags; the bool marks fuzzy rescues.
+    fn assign<'a>(seq: &[u8], lookup: &'a TagLookup, args: &Args) -> (Option<&'a MatchInfo>, bool) {
+        // Exact lookup first, probing every tag length combination in the tag set;
+        // Hamming search only for reads that miss
+        let exact = match args.anchor {
+            Anchor::Both => lookup.pair_lens.iter().filter(|&&(start, end)| seq.len() >= start + end).find_map(|&(start, end)| {
+                lookup.pairs.get(&(seq[..start].to_ascii_uppercase(), seq[seq.len() - end..].to_ascii_uppercase()))
+            }),
+            Anchor::Reverse => lookup.reverse_lens.iter().filter(|&&end| seq.len() >= end).find_map(|&end| {
+                lookup.reverse_only.get(&seq[seq.len() - end..].to_ascii_uppercase())
+            }),
+            Anchor::Forward => lookup.forward_lens.iter().filter(|&&start| seq.len() >= start).find_map(|&start| {
+                lookup.forward_only.get(&seq[..start].to_ascii_uppercase())
+            }),
+        };
+        match exact {
+            Some(info) => (Some(info), false),
+            None if args.mismatches > 0 => {
+                let fuzzy = fuzzy_match(lookup, seq, args.anchor, args.mismatches);
+                (fuzzy, fuzzy.is_some())
             }
+            None => (None, false),
+        }
+    }
 
-            if mismatches <= args.mismatches {
-                let mut combo_parts = Vec::new();
-                for &(n_start, n_len) in &ref_data.n_blocks {
-                    let read_idx_start = n_start - ref_start;
-                    let segment = &read_seq[read_idx_start..read_idx_start + n_len];
-                    if is_rc_read {
-                        combo_parts.push(bio::alphabets::dna::revcomp(segment));
+    // This worker function processes a record
+    // MODIFIED: Takes ownership of Record to avoid clones
+    fn process_record(
+        record: Record, // Takes ownership
+        lookup: &TagLookup,
+        args: &Args,
+    ) -> (String, Option<Orientation>, bool, Record) { // Returns tuple, not Option
+        let seq = record.seq();
+        let (matched, rescued) = assign(seq, lookup, args);
+        // The read must be long enough to hold both tags of its sample
+        let matched = matched.filter(|info| seq.len() >= info.trim_lens.0 + info.trim_lens.1);
+        match matched {
+            Some(match_info) => {
+                let final_record = if args.trim {
+                    let (head, tail) = match_info.trim_lens;
+                    let trimmed_seq = &seq[head..seq.len() - tail];
+                    let trimmed_qual = &record.qual()[head..record.qual().len() - tail];
+                    if match_info.orientation == Orientation::Reverse {
+                        let rc_seq = bio::alphabets::dna::revcomp(trimmed_seq);
+                        let mut rc_qual = trimmed_qual.to_vec();
+                        rc_qual.reverse();
+                        Record::with_attrs(record.id(), record.desc(), &rc_seq, &rc_qual)
                     } else {
-                        combo_parts.push(segment.to_vec());
+                        Record::with_attrs(record.id(), record.desc(), trimmed_seq, trimmed_qual)
                     }
-                }
-                return Some(combo_parts.join(&b'-'));
+                } else {
+                    record // Move record
+                };
+                (match_info.sample_id.clone(), Some(match_info.orientation.clone()), rescued, final_record)
             }
+            None => match args.detect_chimera.then(|| scan_chimera(seq, lookup)).flatten() {
+                Some(samples) => ("chimeric".to_string(), None, false, annotate_chimera(&record, &samples)),
+                None => ("unmatched".to_string(), None, false, record), // Move record
+            },
         }
-        None
     }
 
-    fn collector_thread(
-        rx: crossbeam_channel::Receiver<MatchResult>,
-        output_dir: PathBuf,
-        group: String,
-        dig: u8,
-        extract_matches: bool,
-        ref_data_map: HashMap<String, Vec<(usize, usize)>>,
-    ) -> Result<()> {
-        let mut counters: HashMap<String, HashMap<Vec<u8>, u64>> = HashMap::new();
-        let mut writers: HashMap<String, fasta::Writer<File>> = HashMap::new();
-
-        for result in rx {
-            let counter = counters.entry(result.ref_id.clone()).or_default();
-            *counter.entry(result.combo).or_insert(0) += 1;
-
-            if extract_matches {
-                let writer = writers.entry(result.ref_id.clone()).or_insert_with(|| {
-                    let out_path = output_dir.join(format!("{}_matched_reads.fasta", result.ref_id));
-                    fasta::Writer::to_file(out_path).expect("Failed to create writer")
-                });
-                writer.write_record(&result.read_record)?;
+    /// Paired-end counterpart of `process_record`.
+    /// R1 + revcomp(R2) is matched like a merged read, so the F_tag is read at the 5' end of R1
+    /// and the R_tag at the 5' end of R2; `--trim` removes each tag from the start of its mate.
+    fn process_pair(
+        r1: Record,
+        r2: Record,
+        lookup: &TagLookup,
+        args: &Args,
+    ) -> (String, Option<Orientation>, bool, ReadUnit) {
+        let joined = [r1.seq(), &bio::alphabets::dna::revcomp(r2.seq())].concat();
+        let (matched, rescued) = assign(&joined, lookup, args);
+        // Each tag must lie entirely within its own mate
+        let matched = matched.filter(|info| r1.seq().len() >= info.trim_lens.0 && r2.seq().len() >= info.trim_lens.1);
+        match matched {
+            Some(match_info) => {
+                let (r1, r2) = if args.trim {
+                    let clip = |record: &Record, len: usize| {
+                        Record::with_attrs(record.id(), record.desc(), &record.seq()[len..], &record.qual()[len..])
+                    };
+                    (clip(&r1, match_info.trim_lens.0), clip(&r2, match_info.trim_lens.1))
+                } else {
+                    (r1, r2)
+                };
+                (match_info.sample_id.clone(), Some(match_info.orientation.clone()), rescued, (r1, Some(r2)))
             }
+            None => match args.detect_chimera.then(|| scan_chimera(&joined, lookup)).flatten() {
+                Some(samples) => {
+                    let unit = (annotate_chimera(&r1, &samples), Some(annotate_chimera(&r2, &samples)));
+                    ("chimeric".to_string(), None, false, unit)
+                }
+                None => ("unmatched".to_string(), None, false, (r1, Some(r2))),
+            },
         }
+    }
 
-        for (ref_id, counter) in counters {
-            let total: u64 = counter.values().sum();
-            if total > 0 {
-                let n_blocks = ref_data_map.get(&ref_id).unwrap();
-                let n_label = (1..=n_blocks.len()).map(|i| format!("N{}", i)).collect::<Vec<_>>().join("_");
-                let out_csv_path = output_dir.join(format!("{}_combo_counts.csv", ref_id));
-                let mut csv_writer = csv::Writer::from_path(out_csv_path)?;
-                csv_writer.write_record(&[format!("{}_{}_combo", group, n_label), "Count".to_string(), "Frequency (%)".to_string()])?;
-                
-                let mut sorted_combos: Vec<_> = counter.iter().collect();
-                sorted_combos.sort_by(|a, b| b.1.cmp(&a.1));
+    fn writer_thread(
+        rx_processed: crossbeam_channel::Receiver<ProcessedChunk>,
+        output_dir: Option<PathBuf>,
+        mut all_samples: HashSet<String>,
+        args: Arc<Args>,
+    ) -> Result<HashMap<String, SampleCounts>> {
+        let fasta_flags = output_formats(&args);
+        let paired = args.in1.is_some();
+        
+        all_samples.insert("unmatched".to_string());
+        if args.detect_chimera {
+            all_samples.insert("chimeric".to_string());
+        }
+        // No output directory means --count-only: records are tallied and dropped
+        let mut writers = match output_dir {
+            Some(dir) => Some(WriterCache::new(dir, fasta_flags, paired, args.gzip, args.desc, args.max_open_files, &all_samples)?),
+            None => None,
+        };
+        let mut no_writers: Vec<GenericWriter> = Vec::new();
 
-                for (combo, count) in sorted_combos {
-                    let freq = (*count as f64 / total as f64) * 100.0;
-                    csv_writer.write_record(&[String::from_utf8_lossy(combo).to_string(), count.to_string(), format!("{:.1$}", freq, dig as usize)])?;
+        let mut counts: HashMap<String, SampleCounts> = HashMap::new();
+        for chunk in rx_processed {
+            for (sample_id, records) in chunk {
+                let sample_counts = counts.entry(sample_id.clone()).or_default();
+                let sample_writers = match writers.as_mut() {
+                    Some(writers) => writers.get(&sample_id)?,
+                    None => &mut no_writers,
+                };
+                for ((record, mate), orientation, rescued) in records {
+                    sample_counts.total += 1;
+                    if rescued {
+                        sample_counts.rescued += 1;
+                    }
+                    match orientation {
+                        Some(Orientation::Forward) => sample_counts.fwd += 1,
+                        Some(Orientation::Reverse) => sample_counts.rev += 1,
+                        None => {}
+                    }
+                    match &mate {
+                        // Writers alternate R1, R2 for each format
+                        Some(mate) => {
+                            for pair_writers in sample_writers.chunks_mut(2) {
+                                pair_writers[0].write_record(&record)?;
+                                pair_writers[1].write_record(mate)?;
+                            }
+                        }
+                        None => {
+                            for writer in sample_writers.iter_mut() {
+                                writer.write_record(&record)?;
+                            }
+                        }
+                    }
                 }
-                println!("[Done] {}: Found {} matches with {} unique combinations.", ref_id, total, counter.len());
             }
         }
+        if let Some(writers) = writers {
+            writers.finish()?;
+        }
+        Ok(counts)
+    }
 
-        for (_, mut writer) in writers {
-            writer.flush()?;
+    fn write_orientation_stats(path: &Path, sample_counts: &HashMap<String, SampleCounts>) -> Result<()> {
+        let mut wtr = csv::Writer::from_path(path)
+            .with_context(|| format!("Failed to create orientation stats CSV: {:?}", path))?;
+        wtr.write_record(["SampleID", "FwdReads", "RevReads"])?;
+        let mut samples: Vec<_> = sample_counts.iter().filter(|(s, _)| s.as_str() != "unmatched" && s.as_str() != "chimeric").collect();
+        samples.sort_by(|a, b| a.0.cmp(b.0));
+        for (sample, c) in samples {
+            wtr.write_record([sample.clone(), c.fwd.to_string(), c.rev.to_string()])?;
         }
+        wtr.flush()?;
+        Ok(())
+    }
 
+    fn write_summary_csv(path: &Path, sample_counts: &HashMap<String, SampleCounts>) -> Result<()> {
+        let mut wtr = csv::Writer::from_path(path)
+            .with_context(|| format!("Failed to create summary CSV: {:?}", path))?;
+        wtr.write_record(["SampleID", "Reads", "Percent"])?;
+        let total_reads: u64 = sample_counts.values().map(|c| c.total).sum();
+        let mut samples: Vec<(&str, u64)> = sample_counts.iter().map(|(s, c)| (s.as_str(), c.total)).collect();
+        // Always report the unmatched row, even when every read was assigned
+        if !sample_counts.contains_key("unmatched") {
+            samples.push(("unmatched", 0));
+        }
+        samples.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        for (sample, reads) in samples {
+            let percent = if total_reads > 0 { reads as f64 * 100.0 / total_reads as f64 } else { 0.0 };
+            wtr.write_record([sample.to_string(), reads.to_string(), format!("{:.2}", percent)])?;
+        }
+        wtr.flush()?;
         Ok(())
     }
 
-    pub fn run(args: Args) -> Result<()> {
-        std::fs::create_dir_all(&args.output)
-            .with_context(|| format!("Failed to create output directory: {:?}", args.output))?;
-        
-        let ref_file = File::open(&args.ref_seq)?;
-        let ref_reader = BufReader::new(ref_file);
-        let ref_records: Vec<_> = fasta::Reader::new(ref_reader).records().collect::<Result<_,_>>()?;
-        
-        let args_arc = Arc::new(args);
+    /// Tag-file samples with fewer than `min_reads` reads, including samples that got none, sorted by ID.
+    fn low_yield_samples(samples: &HashSet<String>, sample_counts: &HashMap<String, SampleCounts>, min_reads: u64) -> Vec<(String, u64)> {
+        let mut low: Vec<(String, u64)> = samples
+            .iter()
+            .map(|sample| (sample.clone(), sample_counts.get(sample).map_or(0, |c| c.total)))
+            .filter(|(_, reads)| *reads < min_reads)
+            .collect();
+        low.sort();
+        low
+    }
 
-        let ref_data_vec: Vec<RefData> = ref_records.into_iter().filter_map(|rec| {
-            let seq = rec.seq().to_ascii_uppercase();
-            let n_blocks = find_n_blocks(&seq);
-            if n_blocks.is_empty() {
-                println!("[Skipping] {}: No 'N' blocks found in reference sequence.", rec.id());
-                return None;
+    /// `--merge-low-yield`: appends the files of each low-yield sample to the matching low_yield.<ext> file
+    /// and removes them. Plain text and gzip members both concatenate into a valid file, so bytes are copied as-is.
+    fn merge_low_yield(output_dir: &Path, args: &Args, low: &[(String, u64)]) -> Result<Vec<PathBuf>> {
+        let fasta_flags = output_formats(args);
+        let paired = args.in1.is_some();
+        let merged_paths = sample_paths(output_dir, &fasta_flags, paired, args.gzip, LOW_YIELD);
+        let mut merged_files = Vec::with_capacity(merged_paths.len());
+        for path in &merged_paths {
+            merged_files.push(File::create(path).with_context(|| format!("Failed to create output file: {:?}", path))?);
+        }
+        for (sample_id, _) in low {
+            for (path, merged) in sample_paths(output_dir, &fasta_flags, paired, args.gzip, sample_id).iter().zip(merged_files.iter_mut()) {
+                let mut input = File::open(path).with_context(|| format!("Failed to open sample output: {:?}", path))?;
+                std::io::copy(&mut input, merged).with_context(|| format!("Failed to append {:?} to the low-yield output", path))?;
+                std::fs::remove_file(path).with_context(|| format!("Failed to remove sample output: {:?}", path))?;
             }
-            let anchor_indices = calculate_anchor_indices(&n_blocks, seq.len(), args_arc.anchor_len);
-            Some(RefData {
-                id: rec.id().to_string(),
-                len: seq.len(),
-                seq,
-                n_blocks,
-                anchor_indices,
-            })
-        }).collect();
-        
-        println!("---> Starting parallel alignment against {} valid reference(s)...", ref_data_vec.len());
+        }
+        for merged in merged_files.iter_mut() {
+            merged.flush()?;
+        }
+        Ok(merged_paths)
+    }
+
+    fn print_summary(sample_counts: HashMap<String, SampleCounts>, start_time: Instant, output_dir: Option<&Path>, mismatches: usize, detect_chimera: bool) {
+        let counts: HashMap<String, u64> = sample_counts.iter().map(|(s, c)| (s.clone(), c.total)).collect();
+        let duration = start_time.elapsed();
+        let total_reads = counts.values().sum::<u64>();
+        let chimeric_reads = *counts.get("chimeric").unwrap_or(&0);
+        let matched_reads = total_reads - *counts.get("unmatched").unwrap_or(&0) - chimeric_reads;
+        println!("\n\n==================== Demultiplexing Summary (Multi-threaded) ====================");
+        println!("Processing Time: {:.2?}", duration);
+        println!("Total Reads Processed: {}", total_reads);
+        if total_reads > 0 {
+            let matched_percent = matched_reads as f64 * 100.0 / total_reads as f64;
+            let unmatched_percent = *counts.get("unmatched").unwrap_or(&0) as f64 * 100.0 / total_reads as f64;
+            println!("  - Matched Reads:       {:>10} ({:.2}%)", matched_reads, matched_percent);
+            println!("  - Unmatched Reads: {:>10} ({:.2}%)", counts.get("unmatched").unwrap_or(&0), unmatched_percent);
+            if detect_chimera {
+                println!("  - Chimeric Reads:  {:>10} ({:.2}%)", chimeric_reads, chimeric_reads as f64 * 100.0 / total_reads as f64);
+            }
+            if mismatches > 0 {
+                let rescued: u64 = sample_counts.values().map(|c| c.rescued).sum();
+                println!("  - Rescued by fuzzy matching (<= {} mismatches/tag): {}", mismatches, rescued);
+            }
+            println!("--------------------------------------------------");
+            let mut sorted_samples: Vec<_> = counts.into_iter().collect();
+            sorted_samples.sort_by_key(|b| std::cmp::Reverse(b.1));
+            for (sample, count) in sorted_samples {
+                if sample != "unmatched" && sample != "chimeric" {
+                    let sample_percent = count as f64 * 100.0 / total_reads as f64;
+                    let c = &sample_counts[&sample];
+                    println!("  - Sample {}: {:>10} reads ({:.2}%) [Fwd: {}, Rev: {}]", sample, count, sample_percent, c.fwd, c.rev);
+                }
+            }
+        }
+        println!("===================================================================================");
+        match output_dir {
+            Some(output_dir) => println!("✔ Done! Results written to: {}", output_dir.display()),
+            None => println!("✔ Done! (--count-only: no sample files written)"),
+        }
+    }
+
+    // Optimization: This function combines the original worker_thread and the rayon::par_bridge logic
+    // Fails if the writer thread has gone away, since the remaining reads could not be written
+    fn parallel_processing(
+        rx_raw: crossbeam_channel::Receiver<RawChunk>,
+        tx_processed: crossbeam_channel::Sender<ProcessedChunk>,
+        lookup_map: Arc<TagLookup>,
+        args: Arc<Args>,
+    ) -> Result<()> {
+        // Use rayon's par_bridge to consume chunks from the channel in parallel
+        rx_raw.into_iter().par_bridge().try_for_each(|chunk| {
+            let processed_results: Vec<(String, Option<Orientation>, bool, ReadUnit)> = chunk
+                .into_par_iter() // Process records within the chunk in parallel (moves records)
+                .map(|(record, mate)| match mate {
+                    Some(mate) => process_pair(record, mate, &lookup_map, &args),
+                    None => {
+                        let (sample_id, orientation, rescued, record) = process_record(record, &lookup_map, &args);
+                        (sample_id, orientation, rescued, (record, None))
+                    }
+                })
+                .collect();
+            
+            let mut processed_chunk: ProcessedChunk = HashMap::new();
+            for (sample_id, orientation, rescued, unit) in processed_results {
+                processed_chunk.entry(sample_id).or_default().push((unit, orientation, rescued));
+            }
+
+            if !processed_chunk.is_empty() {
+                tx_processed
+                    .send(processed_chunk)
+                    .map_err(|_| anyhow!("Writer thread stopped before all processed reads were written"))?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn run(mut args: Args) -> Result<RunReport> {
+        let start_time = Instant::now();
+        if args.single_tag {
+            args.anchor = Anchor::Forward;
+        }
+        if let Some(range) = &args.scan_tag_len {
+            return scan_tag_lengths(&args, range[0], range[1]);
+        }
+        // Default: one thread per physical core
+        let threads = args.global.threads_or(num_cpus::get_physical());
+        let output_dir = args.output.clone();
+        if let Some(output_dir) = &output_dir {
+            std::fs::create_dir_all(output_dir)
+                .with_context(|| format!("Failed to create output directory: {:?}", output_dir))?;
+        }
+        
+        println!("---> Loading tags...");
+        let (lookup_map, all_samples) = load_tags(&args.tags, args.tag_len, args.anchor, args.single_tag)?;
+        if args.merge_low_yield && all_samples.contains(LOW_YIELD) {
+            return Err(anyhow!("Sample ID '{}' is reserved for --merge-low-yield output", LOW_YIELD));
+        }
+        let tag_samples = all_samples.clone();
+        let lookup_map = Arc::new(lookup_map);
+        let args_arc = Arc::new(args);
         
-        rayon::ThreadPoolBuilder::new().num_threads(args_arc.threads).build_global()?;
+        // Configure rayon thread pool. It can only be built once per process, so a later demux
+        // in the same run (demux_all --sample-sheet) keeps the first sample's pool.
+        let _ = rayon::ThreadPoolBuilder::new().num_threads(threads).build_global();
+
+        let channel_capacity = threads * 2;
+        let (raw_tx, raw_rx) = crossbeam_channel::bounded::<RawChunk>(channel_capacity);
+        let (processed_tx, processed_rx) = crossbeam_channel::bounded::<ProcessedChunk>(channel_capacity);
         
-        let pb = ProgressBar::new_spinner();
+        let pb = match expected_total_reads(&args_arc)? {
+            Some(total) => {
+                let pb = ProgressBar::new(total);
+                pb.set_style(
+                    ProgressStyle::default_bar()
+                        .template("[{elapsed_precise}] {msg} [{bar:40.cyan/blue}] {pos:>10}/{len} reads ({percent}%, ETA {eta})")?
+                        .progress_chars("=> "),
+                );
+                pb
+            }
+            None => {
+                let pb = ProgressBar::new_spinner();
+                pb.set_style(
+                    ProgressStyle::default_spinner()
+                        .tick_strings(&["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "a"])
+                        .template("{spinner:.blue} [{elapsed_precise}] {msg} {pos:>10} reads")?,
+                );
+                pb
+            }
+        };
+        let pb = args_arc.global.progress(pb);
         pb.enable_steady_tick(std::time::Duration::from_millis(120));
-        pb.set_style(
-            ProgressStyle::default_spinner()
-                .tick_strings(&["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"])
-                .template("{spinner:.blue} [{elapsed_precise}] {msg} {pos:>10} reads")?,
-        );
-        pb.set_message("Reading reads...");
+        pb.set_message("Processing...");
+
+        let report = thread::scope(|s| -> Result<RunReport> {
+            let output_dir_for_writer = output_dir.clone();
+            let args_for_writer = args_arc.clone();
+
+            // 1. Writer Thread
+            let writer_handle = s.spawn(move || {
+                writer_thread(processed_rx, output_dir_for_writer, all_samples, args_for_writer)
+            });
+
+            // 2. Parallel Processing (consuming from raw_rx, sending to processed_tx)
+            let (lookup_clone, args_clone) = (lookup_map.clone(), args_arc.clone());
+            let processing_handle = s.spawn(move || parallel_processing(raw_rx, processed_tx, lookup_clone, args_clone));
+
+            // 3. Reader Thread (Main thread role, feeds raw_tx)
+            // This will block until reading is done, then drop raw_tx
+            let reader_res = match (&args_arc.inputfile, &args_arc.in1, &args_arc.in2) {
+                (_, Some(in1), Some(in2)) => paired_reader_thread(in1.clone(), in2.clone(), raw_tx, pb),
+                (Some(inputfile), _, _) => reader_thread(inputfile.clone(), raw_tx, pb),
+                _ => Err(anyhow!("Either --inputfile or --in1/--in2 is required")),
+            };
+
+            // Wait for processing to finish
+            let processing_res = processing_handle.join().unwrap();
+
+            // Wait for writer to finish before surfacing any thread's error, so output files are closed.
+            // A failed send means the writer stopped, so its own error is the one to report first.
+            let writer_res = writer_handle.join().unwrap();
+            reader_res.context("Error in reader thread")?;
+            let counts = writer_res.context("Writer thread error")?;
+            processing_res.context("Error in reader thread")?;
+
+            let mut report = RunReport::new("demux_only");
+            for input in [&args_arc.inputfile, &args_arc.in1, &args_arc.in2].into_iter().flatten() {
+                report = report.input(input);
+            }
+            report = report.input(&args_arc.tags);
+            if let Some(output_dir) = &output_dir {
+                report = report.output(output_dir);
+            }
+
+            if let Some(path) = &args_arc.tag_orientation_stats {
+                write_orientation_stats(path, &counts)?;
+                report = report.output(path);
+            }
+            if let Some(path) = &args_arc.summary_csv {
+                write_summary_csv(path, &counts)?;
+                report = report.output(path);
+            }
+            let total: u64 = counts.values().map(|c| c.total).sum();
+            let unmatched = counts.get("unmatched").map_or(0, |c| c.total);
+            let chimeric = counts.get("chimeric").map_or(0, |c| c.total);
+            report = report.count("total_reads", total).count("matched_reads", total - unmatched - chimeric).count("unmatched_reads", unmatched);
+            if args_arc.detect_chimera {
+                report = report.count("chimeric_reads", chimeric);
+            }
+            if args_arc.mismatches > 0 {
+                report = report.count("fuzzy_rescued_reads", counts.values().map(|c| c.rescued).sum());
+            }
+            if let Some(min_reads) = args_arc.min_sample_reads {
+                let low = low_yield_samples(&tag_samples, &counts, min_reads);
+                report = report.count("low_yield_samples", low.len() as u64);
+                if low.is_empty() {
+                    println!("---> No samples below {} reads", min_reads);
+                } else {
+                    println!("---> {} sample(s) below {} reads:", low.len(), min_reads);
+                    for (sample_id, reads) in &low {
+                        println!("  - {}: {} reads", sample_id, reads);
+                    }
+                    if let (true, Some(output_dir)) = (args_arc.merge_low_yield, &output_dir) {
+                        for path in merge_low_yield(output_dir, &args_arc, &low)? {
+                            println!("---> Merged low-yield samples into {}", path.display());
+                            report = report.output(path);
+                        }
+                    }
+                }
+            }
+            print_summary(counts, start_time, output_dir.as_deref(), args_arc.mismatches, args_arc.detect_chimera);
+            Ok(report)
+        })?;
+        Ok(report)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::common::test_util::{fasta_headers, test_dir};
+
+        #[test]
+        fn fasta_writer_keeps_or_strips_descriptions() {
+            let dir = test_dir("demux_desc");
+            let tags = dir.join("tags.csv");
+            let reads = dir.join("reads.fastq");
+            std::fs::write(&tags, "SampleID,F_tag,R_tag\nS1,AAAA,AAAA\n").unwrap();
+            std::fs::write(&reads, "@r1 sample=A\nAAAACCCCGGGGTTTT\n+\nIIIIIIIIIIIIIIII\n").unwrap();
+            for (flag, expected) in [("--keep-desc", ">r1 sample=A"), ("--strip-desc", ">r1")] {
+                let out_dir = dir.join(flag.trim_start_matches('-'));
+                let args = Args::try_parse_from([
+                    "demux_only", "--inputfile", reads.to_str().unwrap(), "--tags", tags.to_str().unwrap(),
+                    "--output", out_dir.to_str().unwrap(), "--out-fasta", "--no-progress", flag,
+                ])
+                .unwrap();
+                run(args).unwrap();
+                assert_eq!(fasta_headers(&out_dir.join("S1.fasta")), [expected]);
+            }
+        }
+
+        /// Records per output file of a demux run, keyed by file name.
+        fn record_counts(dir: &std::path::Path) -> std::collections::BTreeMap<String, usize> {
+            std::fs::read_dir(dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| path.is_file())
+                .map(|path| {
+                    let mut text = String::new();
+                    std::io::Read::read_to_string(&mut crate::common::open_reader(&path).unwrap(), &mut text).unwrap();
+                    (path.file_name().unwrap().to_string_lossy().into_owned(), text.lines().count() / 4)
+                })
+                .collect()
+        }
+
+        #[test]
+        fn max_open_files_keeps_counts_across_reopens() {
+            let dir = test_dir("demux_reopen");
+            let tags = dir.join("tags.csv");
+            let reads = dir.join("reads.fastq");
+            std::fs::write(&tags, "SampleID,F_tag,R_tag\nS1,AAAA,AAAA\nS2,CCCC,CCCC\nS3,GTAC,GTAC\n").unwrap();
+            // Reads are grouped by sample within a chunk, so several chunks of interleaved samples
+            // make a one-file cache evict and reopen every sample file (gzip: append a new member)
+            let total = 3 * CHUNK_SIZE;
+            let seqs = ["AAAATTGGCCAATTTT", "CCCCTTGGCCAAGGGG", "GTACTTGGCCAAGTAC", "TTTTTTTTTTTTTTTT"];
+            let mut fastq = String::new();
+            for i in 0..total {
+                let seq = seqs[i % seqs.len()];
+                fastq.push_str(&format!("@r{}\n{}\n+\n{}\n", i, seq, "I".repeat(seq.len())));
+            }
+            std::fs::write(&reads, fastq).unwrap();
+
+            for gzip in [false, true] {
+                let run_with = |max_open_files: &str| {
+                    let out_dir = dir.join(format!("out_{}_{}", gzip, max_open_files));
+                    let mut argv = vec![
+                        "demux_only", "--inputfile", reads.to_str().unwrap(), "--tags", tags.to_str().unwrap(),
+                        "--output", out_dir.to_str().unwrap(), "--max-open-files", max_open_files, "--no-progress",
+                    ];
+                    if gzip {
+                        argv.push("--gzip");
+                    }
+                    run(Args::try_parse_from(argv).unwrap()).unwrap();
+                    record_counts(&out_dir)
+                };
+                let uncapped = run_with("0");
+                assert_eq!(uncapped.values().sum::<usize>(), total);
+                assert!(uncapped.values().all(|&n| n == total / seqs.len()), "{:?}", uncapped);
+                assert_eq!(run_with("1"), uncapped);
+            }
+        }
+
+        #[test]
+        fn processing_fails_when_the_writer_has_stopped() {
+            let dir = test_dir("demux_writer_gone");
+            let tags = dir.join("tags.csv");
+            std::fs::write(&tags, "SampleID,F_tag,R_tag\nS1,AAAA,AAAA\n").unwrap();
+            let args = Args::try_parse_from(["demux_only", "--inputfile", "reads.fastq", "--tags", tags.to_str().unwrap(), "--count-only"]).unwrap();
+            let (lookup, _) = load_tags(&args.tags, args.tag_len, args.anchor, args.single_tag).unwrap();
+            let (raw_tx, raw_rx) = crossbeam_channel::unbounded();
+            let (processed_tx, processed_rx) = crossbeam_channel::unbounded();
+            drop(processed_rx);
+            raw_tx.send(vec![(Record::with_attrs("r1", None, b"AAAACCCCTTTT", b"IIIIIIIIIIII"), None)]).unwrap();
+            drop(raw_tx);
+            let err = parallel_processing(raw_rx, processed_tx, Arc::new(lookup), Arc::new(args)).unwrap_err();
+            assert!(err.to_string().contains("Writer thread stopped"), "{}", err);
+        }
+    }
+}
+
+// ==================================================================================
+// `stats` subcommand module
+// ==================================================================================
+mod stats {
+    use super::common::{
+        detect_format, indexed_record_count, json_string, open_input, records_progress, validate_inputs, write_count_sidecar, Format,
+        GlobalOpts, QualEncodingCheck, QualMatrix, RunReport,
+    };
+    use anyhow::{Context, Result};
+    use bio::io::{fasta, fastq};
+    use clap::Parser;
+    use csv::Writer;
+    use std::collections::{BTreeMap, HashMap};
+    use std::fs::File;
+    use std::path::{Path, PathBuf};
+
+    // Lower bound of a length bin -> number of sequences
+    type LengthHistogram = BTreeMap<usize, u64>;
+    // Normalised (trimmed, uppercased) sequence -> occurrences
+    type SeqCounts = HashMap<Vec<u8>, u64>;
+
+    /// Counts one sequence, normalising it into the reused `buf` so repeated sequences allocate nothing.
+    /// bio's readers already strip trailing whitespace (including `\r`) from every line, so the trim only
+    /// removes leading spaces/tabs on the first sequence line of a record.
+    fn count_seq(seq: &[u8], buf: &mut Vec<u8>, seq_counts: &mut SeqCounts) {
+        buf.clear();
+        buf.extend(seq.trim_ascii().iter().map(u8::to_ascii_uppercase));
+        match seq_counts.get_mut(buf.as_slice()) {
+            Some(c) => *c += 1,
+            None => {
+                seq_counts.insert(buf.clone(), 1);
+            }
+        }
+    }
+
+    #[derive(Parser, Debug)]
+    pub struct Args {
+        #[arg(long, help = "One or more input files (wildcards supported, e.g., '*.fasta')", required = true, num_args = 1..)]
+        inputfile: Vec<PathBuf>,
+        #[arg(long, help = "Output CSV file for per-sequence counts")]
+        outfile: Option<PathBuf>,
+        #[arg(long, help = "Output CSV of the position x quality count matrix, combined over all FASTQ inputs")]
+        qual_matrix: Option<PathBuf>,
+        #[arg(long, help = "Validate the format and structure of all inputs before processing any of them")]
+        validate_first: bool,
+        #[arg(long, help = "Report the exact-duplicate read rate (1 - unique/total) per file")]
+        dup_rate: bool,
+        #[arg(long, help = "Write the per-file statistics as a JSON array to this file")]
+        json: Option<PathBuf>,
+        #[arg(long, help = "Output CSV of the read-length histogram (length,count), summed over all inputs")]
+        hist: Option<PathBuf>,
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..), help = "Bin width of --hist; each bin is labelled by its lower bound")]
+        hist_bin: u64,
+        #[arg(long, requires = "hist", help = "Write one histogram per input file (filename,length,count) instead of a combined one")]
+        per_file_hist: bool,
+        #[arg(long, value_enum, default_value_t = GcDenominator::Acgt, help = "Denominator of GC%: only unambiguous A/C/G/T bases, or all bases (N and other codes count against GC)")]
+        gc_denominator: GcDenominator,
+        #[arg(long, help = "Show a determinate progress bar using the record count from <inputfile>.fai or <inputfile>.count; if neither exists, <inputfile>.count is written after this run")]
+        progress_total_from_index: bool,
+        #[arg(long, help = "Fail instead of warning when FASTQ quality bytes fall outside the Phred+33 range or look Phred+64 encoded")]
+        strict: bool,
+
+        #[command(flatten)]
+        pub global: GlobalOpts,
+    }
+
+    #[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+    enum GcDenominator {
+        /// GC / (A + C + G + T)
+        Acgt,
+        /// GC / all bases
+        All,
+    }
+
+    // Indices into a base-count array; every other symbol counts as N
+    const BASE_LABELS: [&str; 5] = ["A", "C", "G", "T", "N"];
+
+    fn count_bases(seq: &[u8], counts: &mut [u64; 5]) {
+        for &base in seq {
+            let idx = match base.to_ascii_uppercase() {
+                b'A' => 0,
+                b'C' => 1,
+                b'G' => 2,
+                b'T' => 3,
+                _ => 4,
+            };
+            counts[idx] += 1;
+        }
+    }
+
+    fn gc_percent(bases: &[u64; 5], denominator: GcDenominator) -> f64 {
+        let gc = bases[1] + bases[2];
+        let total = match denominator {
+            GcDenominator::Acgt => bases[..4].iter().sum::<u64>(),
+            GcDenominator::All => bases.iter().sum::<u64>(),
+        };
+        if total > 0 { gc as f64 * 100.0 / total as f64 } else { 0.0 }
+    }
+    
+    struct FileStats {
+        filename: String,
+        count: u64,
+        total_len: u64,
+        min_len: usize,
+        max_len: usize,
+        n50: usize,
+        n90: usize,
+        median: f64,
+        bases: [u64; 5],
+        gc_percent: f64,
+        // FASTQ inputs only
+        qual: Option<QualStats>,
+        dup_rate: Option<f64>,
+    }
+
+    impl FileStats {
+        fn avg_len(&self) -> f64 {
+            if self.count > 0 { self.total_len as f64 / self.count as f64 } else { 0.0 }
+        }
+
+        /// One JSON object with every metric; quality and dup-rate fields are null when not computed.
+        fn to_json(&self) -> String {
+            let opt = |v: Option<f64>| v.map_or("null".to_string(), |v| format!("{:.4}", v));
+            let bases = BASE_LABELS
+                .iter()
+                .zip(self.bases)
+                .map(|(label, n)| format!("\"{}\":{}", label, n))
+                .collect::<Vec<_>>()
+                .join(",");
+            format!(
+                "{{\"filename\":{},\"sequences\":{},\"total_bases\":{},\"min_len\":{},\"max_len\":{},\"avg_len\":{:.4},\"median_len\":{:.1},\"n50\":{},\"n90\":{},\"gc_percent\":{:.4},\"bases\":{{{}}},\"mean_q\":{},\"q20_percent\":{},\"q30_percent\":{},\"dup_rate\":{}}}",
+                json_string(&self.filename),
+                self.count,
+                self.total_len,
+                self.min_len,
+                self.max_len,
+                self.avg_len(),
+                self.median,
+                self.n50,
+                self.n90,
+                self.gc_percent,
+                bases,
+                opt(self.qual.as_ref().map(QualStats::mean)),
+                opt(self.qual.as_ref().map(|q| q.percent(q.q20))),
+                opt(self.qual.as_ref().map(|q| q.percent(q.q30))),
+                opt(self.dup_rate),
+            )
+        }
+    }
+
+    fn write_histogram(path: &Path, histograms: &[(String, LengthHistogram)], per_file: bool) -> Result<()> {
+        let mut wtr = Writer::from_path(path).with_context(|| format!("Failed to create histogram CSV: {:?}", path))?;
+        if per_file {
+            wtr.write_record(["filename", "length", "count"])?;
+            for (filename, hist) in histograms {
+                for (length, count) in hist {
+                    wtr.write_record([filename.clone(), length.to_string(), count.to_string()])?;
+                }
+            }
+        } else {
+            let mut combined = LengthHistogram::new();
+            for (length, count) in histograms.iter().flat_map(|(_, hist)| hist) {
+                *combined.entry(*length).or_insert(0) += count;
+            }
+            wtr.write_record(["length", "count"])?;
+            for (length, count) in combined {
+                wtr.write_record([length.to_string(), count.to_string()])?;
+            }
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    fn write_json(path: &Path, stats: &[FileStats]) -> Result<()> {
+        let body = stats.iter().map(|s| format!("  {}", s.to_json())).collect::<Vec<_>>().join(",\n");
+        let text = if stats.is_empty() { "[]\n".to_string() } else { format!("[\n{}\n]\n", body) };
+        std::fs::write(path, text).with_context(|| format!("Failed to write JSON: {:?}", path))
+    }
+
+    /// Phred+33 quality totals over all bases of a FASTQ file.
+    #[derive(Default)]
+    struct QualStats {
+        bases: u64,
+        sum: u64,
+        q20: u64,
+        q30: u64,
+    }
+    impl QualStats {
+        fn add(&mut self, qual: &[u8]) {
+            for &q in qual {
+                let phred = q.saturating_sub(33) as u64;
+                self.bases += 1;
+                self.sum += phred;
+                if phred >= 20 { self.q20 += 1; }
+                if phred >= 30 { self.q30 += 1; }
+            }
+        }
+        fn percent(&self, n: u64) -> f64 {
+            if self.bases > 0 { n as f64 * 100.0 / self.bases as f64 } else { 0.0 }
+        }
+        fn mean(&self) -> f64 {
+            if self.bases > 0 { self.sum as f64 / self.bases as f64 } else { 0.0 }
+        }
+    }
+
+    /// Length at which sequences of this length or longer cover `fraction` of all bases (N50 for 0.5).
+    /// `sorted` must be in ascending order.
+    fn nx(sorted: &[usize], total_len: u64, fraction: f64) -> usize {
+        let target = total_len as f64 * fraction;
+        let mut covered = 0u64;
+        for &len in sorted.iter().rev() {
+            covered += len as u64;
+            if covered as f64 >= target {
+                return len;
+            }
+        }
+        0
+    }
+
+    /// Median length; the mean of the two middle values for an even count. `sorted` must be in ascending order.
+    fn median(sorted: &[usize]) -> f64 {
+        let n = sorted.len();
+        match n {
+            0 => 0.0,
+            _ if n % 2 == 1 => sorted[n / 2] as f64,
+            _ => (sorted[n / 2 - 1] + sorted[n / 2]) as f64 / 2.0,
+        }
+    }
+
+    fn get_sample_name(path: &Path) -> String {
+        let filename = path.file_name()
+            .unwrap_or_default()
+            .to_str()
+            .unwrap_or_default();
+
+        // Try to handle .fastq.gz, .fa.gz etc.
+        let stem1 = Path::new(filename)
+            .file_stem()
+            .unwrap_or_default()
+            .to_str()
+            .unwrap_or_default();
+        
+        if stem1.ends_with(".fastq") || stem1.ends_with(".fa") || stem1.ends_with(".fasta") || stem1.ends_with(".fq") {
+             Path::new(stem1)
+                .file_stem()
+                .unwrap_or_default()
+                .to_str()
+                .unwrap_or_default()
+                .to_string()
+        } else {
+            stem1.to_string()
+        }
+    }
+    
+    fn print_stats_table(stats: &[FileStats]) {
+        if stats.is_empty() {
+            println!("No files processed or no sequences found.");
+            return;
+        }
+
+        let show_dup = stats.iter().any(|s| s.dup_rate.is_some());
+        let show_qual = stats.iter().any(|s| s.qual.is_some());
+
+        println!("\n====================================== Sequence Statistics Summary ======================================");
+        print!("{:<30} {:>15} {:>18} {:>10} {:>10} {:>12} {:>10} {:>10} {:>10} {:>8}",
+                 "Sample Name", "Total Seqs", "Total Bases", "Min Length", "Max Length", "Avg Length", "Median", "N50", "N90", "GC%");
+        if show_qual { print!(" {:>8} {:>8} {:>8}", "Mean Q", "%Q20", "%Q30"); }
+        if show_dup { print!(" {:>10}", "Dup Rate"); }
+        println!();
+        print!("{:-<30} {:-<15} {:-<18} {:-<10} {:-<10} {:-<12} {:-<10} {:-<10} {:-<10} {:-<8}",
+                 "", "", "", "", "", "", "", "", "", "");
+        if show_qual { print!(" {:-<8} {:-<8} {:-<8}", "", "", ""); }
+        if show_dup { print!(" {:-<10}", ""); }
+        println!();
+
+        for s in stats {
+            let avg_len = s.avg_len();
+            print!("{:<30} {:>15} {:>18} {:>10} {:>10} {:<12.2} {:>10.1} {:>10} {:>10} {:>7.2}%",
+                     s.filename, s.count, s.total_len, s.min_len, s.max_len, avg_len, s.median, s.n50, s.n90, s.gc_percent);
+            if show_qual {
+                match &s.qual {
+                    Some(q) => print!(" {:>8.2} {:>7.2}% {:>7.2}%", q.mean(), q.percent(q.q20), q.percent(q.q30)),
+                    None => print!(" {:>8} {:>8} {:>8}", "NA", "NA", "NA"),
+                }
+            }
+            if show_dup { print!(" {:>9.2}%", s.dup_rate.unwrap_or(0.0) * 100.0); }
+            println!();
+        }
+        println!("===================================================================================================");
+    }
+
+    pub fn run(args: Args) -> Result<RunReport> {
+        if args.validate_first {
+            validate_inputs(&args.inputfile)?;
+        }
+        let mut all_stats: Vec<FileStats> = Vec::new();
+        let mut wtr_opt: Option<Writer<File>> = if let Some(path) = args.outfile.clone() {
+            let mut w = Writer::from_path(path)?;
+            w.write_record(["filename", "sequence", "count", "A", "C", "G", "T", "N"])?;
+            Some(w)
+        } else { None };
+        let mut qual_matrix = QualMatrix::default();
+        let mut histograms: Vec<(String, LengthHistogram)> = Vec::new();
+
+        for input_path in &args.inputfile {
+            println!("---> Processing: {}", input_path.display());
+            let format = detect_format(input_path)?;
+
+            let input_reader = open_input(input_path)?;
+
+            let mut count = 0;
+            let mut total_len = 0;
+            let mut min_len = usize::MAX;
+            let mut max_len = 0;
+            let mut lengths: Vec<usize> = Vec::new();
+            let mut bases = [0u64; 5];
+            let mut qual_stats = (format == Format::Fastq).then(QualStats::default);
+            let mut seq_counts = SeqCounts::new();
+            let mut seq_buf: Vec<u8> = Vec::new();
+            let indexed_total = if args.progress_total_from_index { indexed_record_count(input_path)? } else { None };
+            let pb = args.progress_total_from_index.then(|| records_progress(indexed_total, "records")).transpose()?.map(|pb| args.global.progress(pb));
+
+            match format {
+                Format::Fasta => {
+                    let reader = fasta::Reader::new(input_reader);
+                    for result in reader.records() {
+                        let record = result?;
+                        count += 1;
+                        let len = record.seq().len();
+                        total_len += len as u64;
+                        if len < min_len { min_len = len; }
+                        if len > max_len { max_len = len; }
+                        lengths.push(len);
+                        count_bases(record.seq(), &mut bases);
+                        count_seq(record.seq(), &mut seq_buf, &mut seq_counts);
+                        if let Some(pb) = &pb { pb.inc(1); }
+                    }
+                }
+                Format::Fastq => {
+                    let reader = fastq::Reader::new(input_reader);
+                    let mut encoding = QualEncodingCheck::default();
+                    for result in reader.records() {
+                        let record = result?;
+                        count += 1;
+                        let len = record.seq().len();
+                        total_len += len as u64;
+                        if len < min_len { min_len = len; }
+                        if len > max_len { max_len = len; }
+                        lengths.push(len);
+                        count_bases(record.seq(), &mut bases);
+                        count_seq(record.seq(), &mut seq_buf, &mut seq_counts);
+                        if args.qual_matrix.is_some() {
+                            qual_matrix.add(record.qual());
+                        }
+                        if let Some(q) = qual_stats.as_mut() {
+                            q.add(record.qual());
+                        }
+                        encoding.add(record.qual());
+                        if let Some(pb) = &pb { pb.inc(1); }
+                    }
+                    encoding.finish(input_path, args.strict)?;
+                }
+            };
+            if let Some(pb) = &pb {
+                pb.finish_and_clear();
+                if indexed_total.is_none() {
+                    if let Err(e) = write_count_sidecar(input_path, count) {
+                        eprintln!("Warning: {:#}", e);
+                    }
+                }
+            }
+
+            // Sorted once; N50/N90/median all read from the same ordering
+            lengths.sort_unstable();
+            if args.hist.is_some() {
+                let bin = args.hist_bin as usize;
+                let mut hist = LengthHistogram::new();
+                for &len in &lengths {
+                    *hist.entry(len / bin * bin).or_insert(0) += 1;
+                }
+                histograms.push((get_sample_name(input_path), hist));
+            }
+
+            let dup_rate = if args.dup_rate && count > 0 {
+                Some(1.0 - seq_counts.len() as f64 / count as f64)
+            } else {
+                None
+            };
+
+            if let Some(wtr) = wtr_opt.as_mut() {
+                let fname = get_sample_name(input_path);
+                let mut entries: Vec<(Vec<u8>, u64)> = seq_counts.into_iter().collect();
+                entries.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+                for (seq, c) in entries {
+                    let mut seq_bases = [0u64; 5];
+                    count_bases(&seq, &mut seq_bases);
+                    let mut row = vec![fname.clone(), String::from_utf8_lossy(&seq).into_owned(), c.to_string()];
+                    row.extend(seq_bases.iter().map(u64::to_string));
+                    wtr.write_record(&row)?;
+                }
+            }
+            
+            all_stats.push(FileStats {
+                filename: get_sample_name(input_path),
+                count,
+                total_len,
+                min_len: if count > 0 { min_len } else { 0 },
+                max_len,
+                n50: nx(&lengths, total_len, 0.5),
+                n90: nx(&lengths, total_len, 0.9),
+                median: median(&lengths),
+                bases,
+                gc_percent: gc_percent(&bases, args.gc_denominator),
+                qual: qual_stats,
+                dup_rate,
+            });
+        }
+
+        if let Some(wtr) = wtr_opt.as_mut() { wtr.flush()?; }
+        if let Some(path) = &args.qual_matrix {
+            qual_matrix.write_csv(path)?;
+            println!("---> Quality matrix written to: {}", path.display());
+        }
+        if let Some(path) = &args.hist {
+            write_histogram(path, &histograms, args.per_file_hist)?;
+            println!("---> Length histogram written to: {}", path.display());
+        }
+        if let Some(path) = &args.json {
+            write_json(path, &all_stats)?;
+            println!("---> Statistics JSON written to: {}", path.display());
+        }
+        print_stats_table(&all_stats);
+
+        let mut report = RunReport::new("stats");
+        for input_path in &args.inputfile {
+            report = report.input(input_path);
+        }
+        for path in args.outfile.iter().chain(args.qual_matrix.iter()).chain(args.json.iter()).chain(args.hist.iter()) {
+            report = report.output(path);
+        }
+        for s in &all_stats {
+            report = report.count(format!("{}.sequences", s.filename), s.count);
+        }
+        for s in &all_stats {
+            for (label, n) in BASE_LABELS.iter().zip(s.bases) {
+                report = report.count(format!("{}.bases_{}", s.filename, label), n);
+            }
+        }
+        Ok(report)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::common::test_util::test_dir;
+
+        #[test]
+        fn gc_denominators_differ_on_n_rich_sequences() {
+            let mut bases = [0u64; 5];
+            count_bases(b"GGCCnnAT", &mut bases);
+            assert_eq!(bases, [1, 2, 2, 1, 2]);
+            assert!((gc_percent(&bases, GcDenominator::Acgt) - 400.0 / 6.0).abs() < 1e-9);
+            assert!((gc_percent(&bases, GcDenominator::All) - 50.0).abs() < 1e-9);
+            // Without ambiguous bases both denominators agree; no bases at all gives 0
+            assert_eq!(gc_percent(&[1, 1, 1, 1, 0], GcDenominator::Acgt), gc_percent(&[1, 1, 1, 1, 0], GcDenominator::All));
+            assert_eq!(gc_percent(&[0, 0, 0, 0, 5], GcDenominator::Acgt), 0.0);
+            assert_eq!(gc_percent(&[0, 0, 0, 0, 5], GcDenominator::All), 0.0);
+        }
+
+        #[test]
+        fn nx_and_median_match_hand_computed_values() {
+            // Lengths sum to 30: N50 needs 15 bases (10 + 6), N90 needs 27 (10 + 6 + 5 + 4 + 3)
+            let sorted = [2, 3, 4, 5, 6, 10];
+            assert_eq!(nx(&sorted, 30, 0.5), 6);
+            assert_eq!(nx(&sorted, 30, 0.9), 3);
+            assert_eq!(nx(&sorted, 30, 1.0), 2);
+            assert_eq!(nx(&[], 0, 0.5), 0);
+            assert_eq!(median(&sorted), 4.5);
+            assert_eq!(median(&[1, 2, 9]), 2.0);
+            assert_eq!(median(&[]), 0.0);
+        }
+
+        #[test]
+        fn json_summary_reports_n50_n90_and_median() {
+            let dir = test_dir("stats_nx");
+            let input = dir.join("lens.fasta");
+            // Unsorted input: the lengths are sorted once before the metrics
+            let fasta: String = [6, 2, 10, 4, 3, 5].iter().enumerate().map(|(i, &len)| format!(">s{}\n{}\n", i, "A".repeat(len))).collect();
+            std::fs::write(&input, fasta).unwrap();
+            let json = dir.join("stats.json");
+            let argv = ["stats", "--inputfile", input.to_str().unwrap(), "--json", json.to_str().unwrap(), "--no-progress"];
+            run(Args::try_parse_from(argv).unwrap()).unwrap();
+            let text = std::fs::read_to_string(&json).unwrap();
+            for field in ["\"sequences\":6", "\"total_bases\":30", "\"median_len\":4.5", "\"n50\":6", "\"n90\":3"] {
+                assert!(text.contains(field), "{} missing from {}", field, text);
+            }
+        }
+
+        #[test]
+        fn count_seq_normalises_case_and_leading_whitespace() {
+            let mut buf = Vec::new();
+            let mut counts = SeqCounts::new();
+            for seq in [&b"acgt"[..], b"ACGT", b" \tAcGt", b"ACGTN"] {
+                count_seq(seq, &mut buf, &mut counts);
+            }
+            assert_eq!(counts.len(), 2);
+            assert_eq!(counts[&b"ACGT"[..]], 3);
+            assert_eq!(counts[&b"ACGTN"[..]], 1);
+        }
+
+        #[test]
+        fn outfile_matches_the_string_based_counting() {
+            let dir = test_dir("stats_outfile");
+            let input = dir.join("mixed.fasta");
+            let seqs = ["acgtn", "ACGTN", "ggg", "TTAA", "ggg", "GGG", "ttaa", "c"];
+            let fasta: String = seqs.iter().enumerate().map(|(i, s)| format!(">s{}\n{}\n", i, s)).collect();
+            std::fs::write(&input, fasta).unwrap();
+            let out = dir.join("counts.csv");
+            let argv = ["stats", "--inputfile", input.to_str().unwrap(), "--outfile", out.to_str().unwrap(), "--no-progress"];
+            run(Args::try_parse_from(argv).unwrap()).unwrap();
+
+            // The per-record String::from_utf8(..).trim().to_uppercase() counting this replaced
+            let mut old: HashMap<String, u64> = HashMap::new();
+            for s in seqs {
+                *old.entry(s.trim().to_uppercase()).or_insert(0) += 1;
+            }
+            let mut entries: Vec<_> = old.into_iter().collect();
+            entries.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+            let mut expected = "filename,sequence,count,A,C,G,T,N\n".to_string();
+            for (seq, count) in entries {
+                let mut bases = [0u64; 5];
+                count_bases(seq.as_bytes(), &mut bases);
+                let bases: Vec<String> = bases.iter().map(u64::to_string).collect();
+                expected.push_str(&format!("mixed,{},{},{}\n", seq, count, bases.join(",")));
+            }
+            assert_eq!(std::fs::read_to_string(&out).unwrap(), expected);
+        }
+
+        #[test]
+        fn qual_stats_mean_and_q20_q30_percentages() {
+            let empty = QualStats::default();
+            assert_eq!((empty.mean(), empty.percent(empty.q20)), (0.0, 0.0));
+            // Phred+33: '!' = 0, '5' = 20, '?' = 30, 'I' = 40
+            let mut q = QualStats::default();
+            q.add(b"!5");
+            q.add(b"?I");
+            assert_eq!((q.bases, q.sum, q.q20, q.q30), (4, 90, 3, 2));
+            assert_eq!(q.mean(), 22.5);
+            assert_eq!(q.percent(q.q20), 75.0);
+            assert_eq!(q.percent(q.q30), 50.0);
+        }
+
+        #[test]
+        fn to_json_writes_every_metric_and_nulls_for_missing_ones() {
+            let mut stats = FileStats {
+                filename: "s\"1".to_string(),
+                count: 2,
+                total_len: 9,
+                min_len: 4,
+                max_len: 5,
+                n50: 5,
+                n90: 4,
+                median: 4.5,
+                bases: [2, 3, 3, 0, 1],
+                gc_percent: 75.0,
+                qual: None,
+                dup_rate: None,
+            };
+            assert_eq!(
+                stats.to_json(),
+                "{\"filename\":\"s\\\"1\",\"sequences\":2,\"total_bases\":9,\"min_len\":4,\"max_len\":5,\"avg_len\":4.5000,\
+                 \"median_len\":4.5,\"n50\":5,\"n90\":4,\"gc_percent\":75.0000,\"bases\":{\"A\":2,\"C\":3,\"G\":3,\"T\":0,\"N\":1},\
+                 \"mean_q\":null,\"q20_percent\":null,\"q30_percent\":null,\"dup_rate\":null}"
+            );
+            let mut qual = QualStats::default();
+            qual.add(b"5?");
+            stats.qual = Some(qual);
+            stats.dup_rate = Some(0.5);
+            assert!(stats.to_json().ends_with("\"mean_q\":25.0000,\"q20_percent\":100.0000,\"q30_percent\":50.0000,\"dup_rate\":0.5000}"));
+        }
+
+        #[test]
+        fn histogram_csv_is_combined_or_per_file() {
+            let dir = test_dir("stats_hist");
+            let histograms = vec![
+                ("a".to_string(), LengthHistogram::from([(0, 2), (10, 1)])),
+                ("b".to_string(), LengthHistogram::from([(10, 3), (20, 1)])),
+            ];
+            let path = dir.join("hist.csv");
+            write_histogram(&path, &histograms, false).unwrap();
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), "length,count\n0,2\n10,4\n20,1\n");
+            write_histogram(&path, &histograms, true).unwrap();
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), "filename,length,count\na,0,2\na,10,1\nb,10,3\nb,20,1\n");
+        }
+
+        #[test]
+        fn hist_bin_labels_bins_by_lower_bound() {
+            let dir = test_dir("stats_hist_bin");
+            let input = dir.join("lens.fasta");
+            let fasta: String = [9, 10, 19, 20, 35].iter().enumerate().map(|(i, &len)| format!(">s{}\n{}\n", i, "C".repeat(len))).collect();
+            std::fs::write(&input, fasta).unwrap();
+            let hist = dir.join("hist.csv");
+            let argv = ["stats", "--inputfile", input.to_str().unwrap(), "--hist", hist.to_str().unwrap(), "--hist-bin", "10", "--no-progress"];
+            run(Args::try_parse_from(argv).unwrap()).unwrap();
+            assert_eq!(std::fs::read_to_string(&hist).unwrap(), "length,count\n0,1\n10,2\n20,1\n30,1\n");
+        }
+
+        #[test]
+        fn count_sidecar_is_written_once_and_a_write_failure_only_warns() {
+            let dir = test_dir("stats_sidecar");
+            let input = dir.join("reads.fasta");
+            std::fs::write(&input, ">a\nACGT\n>b\nAC\n").unwrap();
+            let argv = ["stats", "--inputfile", input.to_str().unwrap(), "--progress-total-from-index", "--no-progress"];
+            run(Args::try_parse_from(argv).unwrap()).unwrap();
+            assert_eq!(std::fs::read_to_string(dir.join("reads.fasta.count")).unwrap(), "2\n");
+
+            // A directory where the sidecar should go makes the write fail
+            let blocked = dir.join("blocked.fasta");
+            std::fs::write(&blocked, ">a\nACGT\n").unwrap();
+            std::fs::create_dir_all(dir.join("blocked.fasta.count")).unwrap();
+            let argv = ["stats", "--inputfile", blocked.to_str().unwrap(), "--progress-total-from-index", "--no-progress"];
+            run(Args::try_parse_from(argv).unwrap()).unwrap();
+        }
+    }
+}
+
+// ==================================================================================
+// `filter` subcommand module (MODIFIED FOR BATCH PROCESSING)
+// ==================================================================================
+mod filter {
+    use super::common::{
+        create_output, detect_format, open_input, strip_compression_ext, validate_inputs, Format, GlobalOpts, OutputWriter, QualEncodingCheck,
+        RunReport,
+    };
+    use anyhow::{anyhow, Context, Result};
+    use bio::io::{fasta, fastq};
+    use clap::Parser;
+    use regex::Regex;
+    use std::collections::HashSet;
+    use std::fs;
+    use std::io::BufRead;
+    use std::path::{Path, PathBuf};
+
+    #[derive(Parser, Debug)]
+    #[command(name = "filter", about = "Filter FASTA/FASTQ files by length, either individually or in batches.")]
+    #[clap(group(
+        clap::ArgGroup::new("input_mode")
+            .required(true)
+            .args(["input_files", "input_dir"]),
+    ))]
+    pub struct Args {
+        #[arg(long, help = "One or more input files to concatenate and filter", num_args = 1..)]
+        input_files: Vec<PathBuf>,
+
+        #[arg(long, help = "Input directory to batch process files")]
+        input_dir: Option<PathBuf>,
+
+        #[arg(long, help = "Output file (default: stdout, used with --input-files); gzip-compressed if it ends in .gz")]
+        outfile: Option<PathBuf>,
+
+        #[arg(long, help = "Output directory (required with --input-dir)")]
+        output_dir: Option<PathBuf>,
+
+        #[arg(short = 'm', long, help = "Filter out sequences shorter than this length")]
+        min_len: Option<usize>,
+        
+        #[arg(short = 'M', long, help = "Filter out sequences longer than this length")]
+        max_len: Option<usize>,
+
+        #[arg(long, help = "Validate the format and structure of all inputs before processing any of them")]
+        validate_first: bool,
+
+        #[arg(long, help = "Filter out FASTQ reads whose mean Phred+33 quality is below this value (error for FASTA input)")]
+        min_qual: Option<f64>,
+
+        #[arg(long, help = "Filter out sequences whose fraction of N/n bases exceeds this value (0.0 drops any read containing an N)")]
+        max_n_frac: Option<f64>,
+
+        #[arg(long, group = "id_list", help = "Keep only records whose ID is listed in this file (one ID per line)")]
+        keep_ids: Option<PathBuf>,
+
+        #[arg(long, group = "id_list", help = "Remove records whose ID is listed in this file (one ID per line)")]
+        drop_ids: Option<PathBuf>,
+
+        #[arg(long, alias = "record-filter-expr", help = "Keep only records matching this expression, e.g. 'len >= 100 && gc < 0.6 && meanq >= 25' (variables: len, gc, n, meanq)")]
+        expr: Option<String>,
+
+        #[arg(long, help = "Keep only records whose ID matches this regular expression")]
+        id_regex: Option<String>,
+
+        #[arg(long, requires = "id_regex", help = "Match --id-regex against the full header (ID, a space and the description) instead of the ID alone")]
+        match_full_header: bool,
+
+        #[arg(long, requires = "id_regex", help = "Keep records that do NOT match --id-regex")]
+        invert_match: bool,
+
+        #[arg(long, help = "Truncate longer sequences (and FASTQ qualities) to this length from the 5' end instead of dropping them; other filters see the truncated record")]
+        trim_to: Option<usize>,
+
+        #[arg(long, help = "Fail instead of warning when FASTQ quality bytes fall outside the Phred+33 range or look Phred+64 encoded")]
+        strict: bool,
+
+        #[arg(long, conflicts_with = "input_files", help = "Print a per-file and total read/written/dropped table after batch filtering")]
+        batch_summary: bool,
+
+        #[arg(long, conflicts_with = "input_files", help = "Write the per-file read/written/dropped counts of batch filtering to this CSV, with a final TOTAL row")]
+        summary_csv: Option<PathBuf>,
+
+        #[command(flatten)]
+        pub global: GlobalOpts,
+    }
+
+    /// Records read and written for one input of batch filtering.
+    struct FileCounts {
+        file: String,
+        read: u64,
+        written: u64,
+    }
+
+    fn print_batch_summary(counts: &[FileCounts]) {
+        let (read, written) = counts.iter().fold((0, 0), |(r, w), c| (r + c.read, w + c.written));
+        println!("\n==================== Batch Filter Summary ====================");
+        println!("{:<30} {:>10} {:>10} {:>10}", "File", "Read", "Written", "Dropped");
+        println!("{:-<30} {:-<10} {:-<10} {:-<10}", "", "", "", "");
+        for c in counts {
+            println!("{:<30} {:>10} {:>10} {:>10}", c.file, c.read, c.written, c.read - c.written);
+        }
+        println!("{:-<30} {:-<10} {:-<10} {:-<10}", "", "", "", "");
+        println!("{:<30} {:>10} {:>10} {:>10}", format!("Total ({} files)", counts.len()), read, written, read - written);
+        println!("===============================================================");
+    }
+
+    fn write_batch_summary_csv(path: &Path, counts: &[FileCounts]) -> Result<()> {
+        let mut wtr = csv::Writer::from_path(path)
+            .with_context(|| format!("Failed to create summary CSV: {:?}", path))?;
+        wtr.write_record(["file", "records_read", "records_written", "records_dropped"])?;
+        let (read, written) = counts.iter().fold((0, 0), |(r, w), c| (r + c.read, w + c.written));
+        let rows = counts.iter().map(|c| (c.file.as_str(), c.read, c.written)).chain(std::iter::once(("TOTAL", read, written)));
+        for (file, read, written) in rows {
+            wtr.write_record([file.to_string(), read.to_string(), written.to_string(), (read - written).to_string()])?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// Per-record values an `--expr` predicate can refer to.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Var {
+        Len,
+        Gc,
+        N,
+        MeanQ,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum CmpOp {
+        Lt,
+        Le,
+        Gt,
+        Ge,
+        Eq,
+        Ne,
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Operand {
+        Var(Var),
+        Num(f64),
+    }
+
+    /// Parsed `--expr` predicate.
+    #[derive(Debug, Clone, PartialEq)]
+    enum Expr {
+        Cmp(Operand, CmpOp, Operand),
+        And(Box<Expr>, Box<Expr>),
+        Or(Box<Expr>, Box<Expr>),
+        Not(Box<Expr>),
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Token {
+        Ident(String),
+        Num(f64),
+        Op(CmpOp),
+        And,
+        Or,
+        Not,
+        LParen,
+        RParen,
+    }
+
+    /// Splits an expression into tokens, tagging each with its byte offset for error messages.
+    fn tokenize(src: &str) -> Result<Vec<(usize, Token)>> {
+        let bytes = src.as_bytes();
+        let mut tokens = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            let c = bytes[i];
+            let start = i;
+            let two = bytes.get(i..i + 2);
+            let token = match c {
+                b' ' | b'\t' => {
+                    i += 1;
+                    continue;
+                }
+                b'(' => { i += 1; Token::LParen }
+                b')' => { i += 1; Token::RParen }
+                _ if two == Some(b"&&") => { i += 2; Token::And }
+                _ if two == Some(b"||") => { i += 2; Token::Or }
+                _ if two == Some(b"<=") => { i += 2; Token::Op(CmpOp::Le) }
+                _ if two == Some(b">=") => { i += 2; Token::Op(CmpOp::Ge) }
+                _ if two == Some(b"==") => { i += 2; Token::Op(CmpOp::Eq) }
+                _ if two == Some(b"!=") => { i += 2; Token::Op(CmpOp::Ne) }
+                b'<' => { i += 1; Token::Op(CmpOp::Lt) }
+                b'>' => { i += 1; Token::Op(CmpOp::Gt) }
+                b'!' => { i += 1; Token::Not }
+                b'0'..=b'9' | b'.' => {
+                    while i < bytes.len() && (bytes[i].is_ascii_digit() || bytes[i] == b'.') {
+                        i += 1;
+                    }
+                    let text = &src[start..i];
+                    let value = text
+                        .parse::<f64>()
+                        .map_err(|_| anyhow!("--expr: invalid number '{}' at position {}", text, start))?;
+                    Token::Num(value)
+                }
+                _ if c.is_ascii_alphabetic() || c == b'_' => {
+                    while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                        i += 1;
+                    }
+                    Token::Ident(src[start..i].to_string())
+                }
+                _ => {
+                    return Err(anyhow!(
+                        "--expr: unexpected character '{}' at position {}",
+                        src[start..].chars().next().unwrap_or('?'),
+                        start
+                    ))
+                }
+            };
+            tokens.push((start, token));
+        }
+        Ok(tokens)
+    }
+
+    /// Recursive-descent parser: `||` binds loosest, then `&&`, then `!`, then comparisons.
+    struct ExprParser<'a> {
+        tokens: &'a [(usize, Token)],
+        pos: usize,
+        src_len: usize,
+    }
+
+    impl ExprParser<'_> {
+        fn peek(&self) -> Option<&Token> {
+            self.tokens.get(self.pos).map(|(_, t)| t)
+        }
+
+        fn offset(&self) -> usize {
+            self.tokens.get(self.pos).map_or(self.src_len, |(o, _)| *o)
+        }
+
+        fn parse_or(&mut self) -> Result<Expr> {
+            let mut lhs = self.parse_and()?;
+            while self.peek() == Some(&Token::Or) {
+                self.pos += 1;
+                lhs = Expr::Or(Box::new(lhs), Box::new(self.parse_and()?));
+            }
+            Ok(lhs)
+        }
+
+        fn parse_and(&mut self) -> Result<Expr> {
+            let mut lhs = self.parse_not()?;
+            while self.peek() == Some(&Token::And) {
+                self.pos += 1;
+                lhs = Expr::And(Box::new(lhs), Box::new(self.parse_not()?));
+            }
+            Ok(lhs)
+        }
+
+        fn parse_not(&mut self) -> Result<Expr> {
+            match self.peek() {
+                Some(Token::Not) => {
+                    self.pos += 1;
+                    Ok(Expr::Not(Box::new(self.parse_not()?)))
+                }
+                Some(Token::LParen) => {
+                    self.pos += 1;
+                    let inner = self.parse_or()?;
+                    if self.peek() != Some(&Token::RParen) {
+                        return Err(anyhow!("--expr: expected ')' at position {}", self.offset()));
+                    }
+                    self.pos += 1;
+                    Ok(inner)
+                }
+                _ => self.parse_cmp(),
+            }
+        }
+
+        fn parse_cmp(&mut self) -> Result<Expr> {
+            let lhs = self.parse_operand()?;
+            let op = match self.peek() {
+                Some(Token::Op(op)) => *op,
+                _ => return Err(anyhow!("--expr: expected a comparison operator (<, <=, >, >=, ==, !=) at position {}", self.offset())),
+            };
+            self.pos += 1;
+            let rhs = self.parse_operand()?;
+            Ok(Expr::Cmp(lhs, op, rhs))
+        }
+
+        fn parse_operand(&mut self) -> Result<Operand> {
+            let offset = self.offset();
+            let operand = match self.peek() {
+                Some(Token::Num(v)) => Operand::Num(*v),
+                Some(Token::Ident(name)) => Operand::Var(match name.as_str() {
+                    "len" => Var::Len,
+                    "gc" => Var::Gc,
+                    "n" => Var::N,
+                    "meanq" => Var::MeanQ,
+                    other => return Err(anyhow!(
+                        "--expr: unknown variable '{}' at position {} (expected len, gc, n or meanq)",
+                        other, offset
+                    )),
+                }),
+                Some(_) => return Err(anyhow!("--expr: expected a variable or number at position {}", offset)),
+                None => return Err(anyhow!("--expr: unexpected end of expression")),
+            };
+            self.pos += 1;
+            Ok(operand)
+        }
+    }
+
+    /// Parses an `--expr` string into a predicate.
+    fn parse_expr(src: &str) -> Result<Expr> {
+        let tokens = tokenize(src)?;
+        let mut parser = ExprParser { tokens: &tokens, pos: 0, src_len: src.len() };
+        let expr = parser.parse_or()?;
+        if parser.pos < tokens.len() {
+            return Err(anyhow!("--expr: unexpected trailing input at position {}", parser.offset()));
+        }
+        Ok(expr)
+    }
+
+    impl Expr {
+        fn uses(&self, var: Var) -> bool {
+            match self {
+                Expr::Cmp(a, _, b) => [a, b].iter().any(|o| **o == Operand::Var(var)),
+                Expr::And(a, b) | Expr::Or(a, b) => a.uses(var) || b.uses(var),
+                Expr::Not(a) => a.uses(var),
+            }
+        }
+
+        fn eval(&self, m: &Metrics) -> bool {
+            match self {
+                Expr::Cmp(a, op, b) => {
+                    let (a, b) = (m.value(a), m.value(b));
+                    match op {
+                        CmpOp::Lt => a < b,
+                        CmpOp::Le => a <= b,
+                        CmpOp::Gt => a > b,
+                        CmpOp::Ge => a >= b,
+                        CmpOp::Eq => a == b,
+                        CmpOp::Ne => a != b,
+                    }
+                }
+                Expr::And(a, b) => a.eval(m) && b.eval(m),
+                Expr::Or(a, b) => a.eval(m) || b.eval(m),
+                Expr::Not(a) => !a.eval(m),
+            }
+        }
+    }
+
+    /// Values of the `--expr` variables for one record.
+    struct Metrics {
+        len: f64,
+        gc: f64,
+        n: f64,
+        meanq: f64,
+    }
+
+    impl Metrics {
+        fn new(seq: &[u8], qual: Option<&[u8]>) -> Self {
+            let len = seq.len() as f64;
+            let frac = |pred: fn(&u8) -> bool| {
+                if seq.is_empty() { 0.0 } else { seq.iter().filter(|b| pred(b)).count() as f64 / len }
+            };
+            let gc = frac(|b| matches!(b, b'G' | b'C' | b'g' | b'c'));
+            let n = frac(|b| matches!(b, b'N' | b'n'));
+            let meanq = qual.map_or(0.0, mean_qual);
+            Metrics { len, gc, n, meanq }
+        }
+
+        fn value(&self, operand: &Operand) -> f64 {
+            match operand {
+                Operand::Num(v) => *v,
+                Operand::Var(Var::Len) => self.len,
+                Operand::Var(Var::Gc) => self.gc,
+                Operand::Var(Var::N) => self.n,
+                Operand::Var(Var::MeanQ) => self.meanq,
+            }
+        }
+    }
+
+    /// Mean Phred+33 quality of a read (0 for an empty read).
+    fn mean_qual(qual: &[u8]) -> f64 {
+        if qual.is_empty() {
+            return 0.0;
+        }
+        qual.iter().map(|&v| (v.saturating_sub(33)) as f64).sum::<f64>() / qual.len() as f64
+    }
+
+    /// `--keep-ids` / `--drop-ids` list, loaded once.
+    enum IdFilter {
+        Keep(HashSet<String>),
+        Drop(HashSet<String>),
+    }
+
+    /// Reads one ID per line; only the first whitespace-separated token counts and blank lines are skipped.
+    fn load_ids(path: &Path) -> Result<HashSet<String>> {
+        let text = fs::read_to_string(path).with_context(|| format!("Failed to read ID list: {:?}", path))?;
+        Ok(text.lines().filter_map(|line| line.split_whitespace().next()).map(str::to_string).collect())
+    }
+
+    /// `--id-regex`, compiled once.
+    struct IdRegex {
+        regex: Regex,
+        full_header: bool,
+        invert: bool,
+    }
+
+    impl IdRegex {
+        fn keeps(&self, id: &str, desc: Option<&str>) -> bool {
+            let matched = match desc {
+                Some(desc) if self.full_header => self.regex.is_match(&format!("{} {}", id, desc)),
+                _ => self.regex.is_match(id),
+            };
+            matched != self.invert
+        }
+    }
+
+    /// Fixed per-record criteria; a record is kept only if it passes all of them.
+    struct Thresholds {
+        min_len: usize,
+        max_len: usize,
+        min_qual: Option<f64>,
+        max_n_frac: Option<f64>,
+        ids: Option<IdFilter>,
+        id_regex: Option<IdRegex>,
+    }
+
+    impl Thresholds {
+        fn passes(&self, id: &str, desc: Option<&str>, seq: &[u8], qual: Option<&[u8]>) -> bool {
+            match &self.ids {
+                Some(IdFilter::Keep(ids)) if !ids.contains(id) => return false,
+                Some(IdFilter::Drop(ids)) if ids.contains(id) => return false,
+                _ => {}
+            }
+            if self.id_regex.as_ref().is_some_and(|r| !r.keeps(id, desc)) {
+                return false;
+            }
+            let len = seq.len();
+            if len < self.min_len || len > self.max_len {
+                return false;
+            }
+            if let (Some(min_qual), Some(qual)) = (self.min_qual, qual) {
+                if mean_qual(qual) < min_qual {
+                    return false;
+                }
+            }
+            if let Some(max_n_frac) = self.max_n_frac {
+                let n = seq.iter().filter(|&&b| b == b'N' || b == b'n').count();
+                if len > 0 && n as f64 / len as f64 > max_n_frac {
+                    return false;
+                }
+            }
+            true
+        }
+    }
+
+    /// Helper function to process a single stream (file)
+    fn process_file_stream(
+        input_reader: Box<dyn BufRead>,
+        writer: &mut OutputWriter,
+        format: &Format,
+        thresholds: &Thresholds,
+        expr: Option<&Expr>,
+        trim_to: Option<usize>,
+        encoding: &mut QualEncodingCheck,
+    ) -> Result<(u64, u64)> { // Returns counts of records read and written
+        let mut records_read = 0;
+        let mut records_written = 0;
+        match format {
+            Format::Fasta => {
+                if thresholds.min_qual.is_some() {
+                    return Err(anyhow!("--min-qual requires FASTQ input (FASTA has no quality scores)"));
+                }
+                if expr.is_some_and(|e| e.uses(Var::MeanQ)) {
+                    return Err(anyhow!("--expr uses 'meanq', which requires FASTQ input"));
+                }
+                let reader = fasta::Reader::new(input_reader);
+                let mut fasta_writer = fasta::Writer::new(writer);
+                for result in reader.records() {
+                    let mut record = result?;
+                    records_read += 1;
+                    if let Some(len) = trim_to.filter(|&len| record.seq().len() > len) {
+                        record = fasta::Record::with_attrs(record.id(), record.desc(), &record.seq()[..len]);
+                    }
+                    if thresholds.passes(record.id(), record.desc(), record.seq(), None)
+                        && expr.is_none_or(|e| e.eval(&Metrics::new(record.seq(), None)))
+                    {
+                        fasta_writer.write_record(&record)?;
+                        records_written += 1;
+                    }
+                }
+            }
+            Format::Fastq => {
+                let reader = fastq::Reader::new(input_reader);
+                let mut fastq_writer = fastq::Writer::new(writer);
+                for result in reader.records() {
+                    let mut record = result?;
+                    records_read += 1;
+                    encoding.add(record.qual());
+                    if let Some(len) = trim_to.filter(|&len| record.seq().len() > len) {
+                        record.check().map_err(|e| anyhow!("Invalid FASTQ record '{}': {}", record.id(), e))?;
+                        record = fastq::Record::with_attrs(record.id(), record.desc(), &record.seq()[..len], &record.qual()[..len]);
+                    }
+                    if thresholds.passes(record.id(), record.desc(), record.seq(), Some(record.qual()))
+                        && expr.is_none_or(|e| e.eval(&Metrics::new(record.seq(), Some(record.qual()))))
+                    {
+                        fastq_writer.write_record(&record)?;
+                        records_written += 1;
+                    }
+                }
+            }
+        }
+        Ok((records_read, records_written))
+    }
+
+    /// Generates the output filename with `_filtered` suffix. Compressed inputs (`.gz`, `.bz2`,
+    /// `.zst`) are written gzip-compressed, the only compressed output `create_output` supports.
+    fn get_output_filename(input_path: &Path) -> Result<(String, bool)> {
+        let file_name = input_path.file_name()
+            .ok_or_else(|| anyhow!("Failed to get file name from path: {:?}", input_path))?
+            .to_str()
+            .ok_or_else(|| anyhow!("File name contains invalid UTF-8: {:?}", input_path))?;
+
+        let unzipped = strip_compression_ext(file_name);
+        let is_compressed = unzipped.len() != file_name.len();
+        // e.g., ("input", ".fastq") from "input.fastq.bz2"; ("archive", "") from "archive.gz"
+        let (real_stem, real_ext) = match unzipped.rfind('.') {
+            Some(dot_pos) => (&unzipped[..dot_pos], &unzipped[dot_pos..]),
+            None => (unzipped, ""),
+        };
+        
+        // Check if this is a file type we want to process
+        let extensions_to_process = [".fasta", ".fa", ".fastq", ".fq", ".fna"];
+        let should_process = extensions_to_process.contains(&real_ext);
+        
+        let new_file_name = if is_compressed {
+            format!("{}_filtered{}.gz", real_stem, real_ext) // input_filtered.fastq.gz
+        } else {
+            format!("{}_filtered{}", real_stem, real_ext) // input_filtered.fasta
+        };
+
+        Ok((new_file_name, should_process))
+    }
+
+
+    pub fn run(args: Args) -> Result<RunReport> {
+        let thresholds = Thresholds {
+            min_len: args.min_len.unwrap_or(0),
+            max_len: args.max_len.unwrap_or(usize::MAX),
+            min_qual: args.min_qual,
+            max_n_frac: args.max_n_frac,
+            ids: match (&args.keep_ids, &args.drop_ids) {
+                (Some(path), _) => Some(IdFilter::Keep(load_ids(path)?)),
+                (_, Some(path)) => Some(IdFilter::Drop(load_ids(path)?)),
+                _ => None,
+            },
+            id_regex: match &args.id_regex {
+                Some(pattern) => Some(IdRegex {
+                    regex: Regex::new(pattern).with_context(|| format!("Invalid --id-regex: {}", pattern))?,
+                    full_header: args.match_full_header,
+                    invert: args.invert_match,
+                }),
+                None => None,
+            },
+        };
+        let expr = args.expr.as_deref().map(parse_expr).transpose()?;
+        let mut report = RunReport::new("filter");
+
+        // --- BRANCH 1: Batch processing from a directory ---
+        if let Some(input_dir) = args.input_dir {
+            let output_dir = args.output_dir.ok_or_else(|| {
+                anyhow!("--output-dir is required when using --input-dir")
+            })?;
+            
+            if args.outfile.is_some() {
+                return Err(anyhow!("--outfile cannot be used with --input-dir. Use --output-dir instead."));
+            }
+
+            fs::create_dir_all(&output_dir)
+                .with_context(|| format!("Failed to create output directory: {:?}", output_dir))?;
+
+            if args.validate_first {
+                let mut candidates = Vec::new();
+                for entry in fs::read_dir(&input_dir)? {
+                    let input_path = entry?.path();
+                    if input_path.is_file() && matches!(get_output_filename(&input_path), Ok((_, true))) {
+                        candidates.push(input_path);
+                    }
+                }
+                validate_inputs(&candidates)?;
+            }
+
+            println!("---> Starting batch filter in directory: {}", input_dir.display());
+            report = report.input(&input_dir).output(&output_dir);
+            let mut file_counts: Vec<FileCounts> = Vec::new();
+
+            for entry in fs::read_dir(input_dir)? {
+                let entry = entry?;
+                let input_path = entry.path();
+                
+                if input_path.is_file() {
+                    let (new_file_name, should_process) = match get_output_filename(&input_path) {
+                        Ok((name, process)) => (name, process),
+                        Err(e) => {
+                             println!("---> Skipping file {}: {}", input_path.display(), e); // <-- 修复：将 input_PANTS 改为 input_path
+                             continue;
+                        }
+                    };
+
+                    if !should_process {
+                         println!("---> Skipping unsupported file type: {}", input_path.display());
+                         continue;
+                    }
+                    
+                    let output_path = output_dir.join(new_file_name);
+
+                    // 2. Open reader
+                    let format = match detect_format(&input_path) {
+                         Ok(f) => f,
+                         Err(e) => {
+                             println!("---> Skipping file {}: {}", input_path.display(), e);
+                             continue;
+                         }
+                    };
+                    
+                    let input_reader = open_input(&input_path)?;
+                    
+                    // 3. Open writer
+                    let mut writer = create_output(&output_path)?;
+
+                    // 4. Process
+                    println!("---> Filtering {} -> {}", input_path.display(), output_path.display());
+                    let mut encoding = QualEncodingCheck::default();
+                    let (read, count) = process_file_stream(input_reader, &mut writer, &format, &thresholds, expr.as_ref(), args.trim_to, &mut encoding)
+                        .with_context(|| format!("Failed to process file: {:?}", input_path))?;
+                    encoding.finish(&input_path, args.strict)?;
+                    writer.finish()?;
+                    println!("✔ Wrote {} records to {}", count, output_path.display());
+                    file_counts.push(FileCounts { file: entry.file_name().to_string_lossy().into_owned(), read, written: count });
+                }
+            }
+            println!("🎉 Batch filtering complete.");
+            if args.batch_summary {
+                print_batch_summary(&file_counts);
+            }
+            if let Some(path) = &args.summary_csv {
+                write_batch_summary_csv(path, &file_counts)?;
+                println!("---> Batch summary written to: {}", path.display());
+                report = report.output(path);
+            }
+            let total_read: u64 = file_counts.iter().map(|c| c.read).sum();
+            let total_records: u64 = file_counts.iter().map(|c| c.written).sum();
+            report = report
+                .count("records_read", total_read)
+                .count("records_written", total_records)
+                .count("records_dropped", total_read - total_records);
+
+        // --- BRANCH 2: Original logic (concatenate and filter) ---
+        } else if !args.input_files.is_empty() {
+            if args.output_dir.is_some() {
+                 return Err(anyhow!("--output-dir can only be used with --input-dir."));
+            }
+            if args.validate_first {
+                validate_inputs(&args.input_files)?;
+            }
+            
+            let mut writer = if let Some(path) = args.outfile {
+                report = report.output(&path);
+                create_output(&path)?
+            } else {
+                OutputWriter::stdout()
+            };
+
+            let mut first_format: Option<Format> = None;
+            let mut total_records = 0;
+
+            for input_path in &args.input_files {
+                eprintln!("---> Processing (and appending): {}", input_path.display());
+                let format = detect_format(input_path)?;
+                
+                // Ensure all files are the same format when concatenating
+                if let Some(ref first) = first_format {
+                    if *first != format {
+                         return Err(anyhow!(
+                            "Mismatched formats: Cannot concatenate FASTA and FASTQ files into one output."
+                         ));
+                    }
+                } else {
+                    first_format = Some(format);
+                }
+                
+                let input_reader = open_input(input_path)?;
+
+                let mut encoding = QualEncodingCheck::default();
+                let (_, written) = process_file_stream(input_reader, &mut writer, first_format.as_ref().unwrap(), &thresholds, expr.as_ref(), args.trim_to, &mut encoding)
+                    .with_context(|| format!("Failed to process file: {:?}", input_path))?;
+                total_records += written;
+                encoding.finish(input_path, args.strict)?;
+            }
+            writer.finish()?;
+            eprintln!("✔ Total records written: {}", total_records);
+            for input_path in &args.input_files {
+                report = report.input(input_path);
+            }
+            report = report.count("records_written", total_records);
+        }
+        // No 'else' needed, as clap's 'input_mode' group ensures one branch is taken
+        
+        Ok(report)
+    }
+
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::common::test_util::test_dir;
+        use std::fs::File;
+        use std::io::{Read, Write};
+
+        #[test]
+        fn output_filename_handles_every_compression_suffix() {
+            let name = |f: &str| get_output_filename(Path::new(f)).unwrap();
+            assert_eq!(name("a.fasta"), ("a_filtered.fasta".to_string(), true));
+            assert_eq!(name("a.fastq.gz"), ("a_filtered.fastq.gz".to_string(), true));
+            assert_eq!(name("a.fastq.bz2"), ("a_filtered.fastq.gz".to_string(), true));
+            assert_eq!(name("a.fq.zst"), ("a_filtered.fq.gz".to_string(), true));
+            assert_eq!(name("notes.txt.zst"), ("notes_filtered.txt.gz".to_string(), false));
+            assert_eq!(name("archive.gz"), ("archive_filtered.gz".to_string(), false));
+        }
+
+        #[test]
+        fn batch_mode_filters_bzip2_and_zstd_inputs() {
+            let dir = test_dir("filter_batch_compressed");
+            let (input_dir, output_dir) = (dir.join("in"), dir.join("out"));
+            fs::create_dir_all(&input_dir).unwrap();
+            let fastq = b"@r1\nACGTACGT\n+\nIIIIIIII\n@r2\nACG\n+\nIII\n";
+            let mut bz = bzip2::write::BzEncoder::new(File::create(input_dir.join("x.fastq.bz2")).unwrap(), bzip2::Compression::default());
+            bz.write_all(fastq).unwrap();
+            bz.finish().unwrap();
+            fs::write(input_dir.join("y.fq.zst"), zstd::encode_all(&fastq[..], 0).unwrap()).unwrap();
+
+            let argv = ["filter", "--input-dir", input_dir.to_str().unwrap(), "--output-dir", output_dir.to_str().unwrap(), "--min-len", "5"];
+            run(Args::try_parse_from(argv).unwrap()).unwrap();
+            for name in ["x_filtered.fastq.gz", "y_filtered.fq.gz"] {
+                let mut text = String::new();
+                crate::common::open_reader(&output_dir.join(name)).unwrap().read_to_string(&mut text).unwrap();
+                assert_eq!(text, "@r1\nACGTACGT\n+\nIIIIIIII\n", "{}", name);
+            }
+        }
+
+        fn cmp(var: Var, op: CmpOp, value: f64) -> Expr {
+            Expr::Cmp(Operand::Var(var), op, Operand::Num(value))
+        }
+
+        fn and(a: Expr, b: Expr) -> Expr {
+            Expr::And(Box::new(a), Box::new(b))
+        }
+
+        fn or(a: Expr, b: Expr) -> Expr {
+            Expr::Or(Box::new(a), Box::new(b))
+        }
+
+        fn expr_error(src: &str) -> String {
+            parse_expr(src).unwrap_err().to_string()
+        }
+
+        #[test]
+        fn expr_and_binds_tighter_than_or_and_not_tighter_than_and() {
+            let (a, b, c) = (cmp(Var::Len, CmpOp::Gt, 1.0), cmp(Var::Gc, CmpOp::Lt, 0.5), cmp(Var::N, CmpOp::Eq, 0.0));
+            assert_eq!(parse_expr("len > 1 || gc < 0.5 && n == 0").unwrap(), or(a.clone(), and(b.clone(), c.clone())));
+            assert_eq!(parse_expr("len > 1 && gc < 0.5 || n == 0").unwrap(), or(and(a.clone(), b.clone()), c.clone()));
+            assert_eq!(parse_expr("!len > 1 && gc < 0.5").unwrap(), and(Expr::Not(Box::new(a.clone())), b.clone()));
+            assert_eq!(parse_expr("!!len > 1").unwrap(), Expr::Not(Box::new(Expr::Not(Box::new(a.clone())))));
+        }
+
+        #[test]
+        fn expr_binary_operators_are_left_associative() {
+            let (a, b, c) = (cmp(Var::Len, CmpOp::Ge, 1.0), cmp(Var::Len, CmpOp::Ge, 2.0), cmp(Var::Len, CmpOp::Ge, 3.0));
+            assert_eq!(parse_expr("len>=1||len>=2||len>=3").unwrap(), or(or(a.clone(), b.clone()), c.clone()));
+            assert_eq!(parse_expr("len>=1&&len>=2&&len>=3").unwrap(), and(and(a, b), c));
+        }
+
+        #[test]
+        fn expr_parentheses_override_precedence() {
+            let (a, b, c) = (cmp(Var::Len, CmpOp::Gt, 1.0), cmp(Var::Gc, CmpOp::Lt, 0.5), cmp(Var::N, CmpOp::Eq, 0.0));
+            assert_eq!(parse_expr("(len > 1 || gc < 0.5) && n == 0").unwrap(), and(or(a.clone(), b.clone()), c.clone()));
+            assert_eq!(parse_expr("((len > 1))").unwrap(), a.clone());
+            assert_eq!(parse_expr("!(len > 1 || gc < 0.5)").unwrap(), Expr::Not(Box::new(or(a, b))));
+            // A number may sit on either side of the operator
+            assert_eq!(parse_expr("0.5 > gc").unwrap(), Expr::Cmp(Operand::Num(0.5), CmpOp::Gt, Operand::Var(Var::Gc)));
+        }
+
+        #[test]
+        fn expr_comparisons_on_every_variable() {
+            // len 8, gc 3/8, n 1/8, meanq (40 * 4 + 20 * 4) / 8 = 30
+            let m = Metrics::new(b"GCgATTNa", Some(b"IIII5555"));
+            let holds = |src: &str| parse_expr(src).unwrap().eval(&m);
+            for (var, value) in [("len", "8"), ("gc", "0.375"), ("n", "0.125"), ("meanq", "30")] {
+                assert!(holds(&format!("{} == {}", var, value)), "{} == {}", var, value);
+                assert!(holds(&format!("{} <= {}", var, value)));
+                assert!(holds(&format!("{} >= {}", var, value)));
+                assert!(!holds(&format!("{} != {}", var, value)));
+                assert!(!holds(&format!("{} < {}", var, value)));
+                assert!(!holds(&format!("{} > {}", var, value)));
+            }
+            assert!(holds("len > 7.5 && gc < 0.4 && n <= 0.125 && meanq >= 29"));
+            // FASTA records have no qualities: meanq is 0
+            assert!(parse_expr("meanq == 0").unwrap().eval(&Metrics::new(b"ACGT", None)));
+            assert!(parse_expr("gc == 0 && n == 0 && len == 0").unwrap().eval(&Metrics::new(b"", None)));
+        }
+
+        #[test]
+        fn expr_errors_point_at_the_offending_token() {
+            assert!(expr_error("(len > 1").contains("expected ')' at position 8"));
+            assert!(expr_error("len > 1)").contains("unexpected trailing input at position 7"));
+            assert!(expr_error("len > 1 2").contains("unexpected trailing input at position 8"));
+            assert!(expr_error("len > 1 gc < 2").contains("unexpected trailing input at position 8"));
+            assert!(expr_error("len ~ 1").contains("unexpected character '~' at position 4"));
+            assert!(expr_error("len > 1 & gc < 1").contains("unexpected character '&' at position 8"));
+            assert!(expr_error("qual > 20").contains("unknown variable 'qual' at position 0"));
+            assert!(expr_error("len > 1..2").contains("invalid number '1..2' at position 6"));
+            assert!(expr_error("len 5").contains("expected a comparison operator"));
+            assert!(expr_error("&& len > 1").contains("expected a variable or number at position 0"));
+            assert!(expr_error("len >").contains("unexpected end of expression"));
+            assert!(expr_error("").contains("unexpected end of expression"));
+            assert!(expr_error("()").contains("expected a variable or number at position 1"));
+        }
+    }
+}
+
+// ==================================================================================
+// `merge_file` subcommand module
+// ==================================================================================
+mod merge_file {
+    use super::common::{
+        create_output, detect_format, open_input, revcomp_iupac, validate_inputs, DescArgs, Format, GlobalOpts, OutputWriter, RunReport,
+    };
+    use anyhow::{anyhow, Context, Result};
+    use bio::io::{fasta, fastq};
+    use clap::Parser;
+    use crossbeam_channel::{bounded, unbounded, Sender};
+    use std::collections::HashSet;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use std::thread;
+    use indicatif::{ProgressBar, ProgressStyle};
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+    use rand::Rng;
+
+    #[derive(Parser, Debug)]
+    #[command(name = "merge_file", about = "Merge multiple FASTA/FASTQ files with optional shuffle, concurrency, progress, and fastq→fasta conversion.")]
+    pub struct Args {
+        #[arg(long, num_args = 1.., help = "Input FASTA/FASTQ files (gz supported)")]
+        pub input_files: Vec<PathBuf>,
+
+        #[arg(long, help = "Output file (.fasta/.fastq or .gz; default: stdout, uncompressed)")]
+        pub outfile: Option<PathBuf>,
+
+        #[arg(long, help = "Keep input file order (default)")]
+        pub keep_order: bool,
+
+        #[arg(long, help = "Shuffle record order before writing (reproducible with --seed)")]
+        pub shuffle: bool,
+
+        #[arg(long, default_value_t = 10000, help = "Records per chunk passed from a reader thread to the writer")]
+        pub chunk_size: usize,
+
+        #[arg(long, help = "Convert FASTQ to FASTA before merging (if inputs are FASTQ)")]
+        pub fastq_to_fasta: bool,
+
+        #[arg(long, help = "Only perform FASTQ→FASTA conversion and write output (no merge)")]
+        pub convert_only: bool,
+
+        #[arg(long, help = "Validate the format and structure of all inputs before processing any of them")]
+        pub validate_first: bool,
+
+        #[arg(long, help = "Append ' src=<filename>' to each record's description")]
+        pub annotate_source: bool,
+
+        #[arg(long, help = "Skip records that duplicate an already written record (see --dedup-by)")]
+        pub dedup: bool,
+
+        #[arg(long, value_enum, default_value_t = DedupKey::Seq, requires = "dedup", help = "What makes two records duplicates for --dedup")]
+        pub dedup_by: DedupKey,
+
+        #[arg(long, requires = "dedup", help = "With --dedup-by seq, treat a sequence and its reverse complement as duplicates (FASTQ keeps the first-seen record's qualities)")]
+        pub dedup_canonical: bool,
+
+        #[arg(long, requires = "dedup", value_parser = parse_mem_size, help = "Stop with an error once the --dedup key set is estimated to exceed this many bytes (K/M/G suffixes allowed, e.g. 4G)")]
+        pub max_mem: Option<u64>,
+
+        #[arg(long, help = "Write at most N records chosen uniformly at random across all inputs (reservoir sampling; input order is kept unless --shuffle)")]
+        pub subsample: Option<usize>,
+
+        #[command(flatten)]
+        pub desc: DescArgs,
+
+        #[command(flatten)]
+        pub global: GlobalOpts,
+    }
+
+    #[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum DedupKey {
+        /// Same sequence, ignoring case
+        Seq,
+        /// Same record ID
+        Id,
+    }
+
+    /// Estimated bytes a `Dedup::seen` entry costs besides its key: the `Vec` header, the hash
+    /// table slot and control byte at the table's load factor, and allocator rounding.
+    const DEDUP_ENTRY_OVERHEAD: u64 = 48;
+
+    /// Parses `--max-mem`: a byte count with an optional K, M or G (binary) suffix.
+    fn parse_mem_size(value: &str) -> std::result::Result<u64, String> {
+        let value = value.trim();
+        let (digits, unit) = match value.char_indices().last() {
+            Some((i, c)) if c.is_ascii_alphabetic() => (&value[..i], c.to_ascii_uppercase()),
+            _ => (value, 'B'),
+        };
+        let shift = match unit {
+            'B' => 0,
+            'K' => 10,
+            'M' => 20,
+            'G' => 30,
+            _ => return Err(format!("unknown size suffix '{}' (use K, M or G)", unit)),
+        };
+        let n: u64 = digits.trim().parse().map_err(|_| format!("invalid size '{}'", value))?;
+        n.checked_mul(1 << shift).ok_or_else(|| format!("size '{}' is too large", value))
+    }
+
+    /// `--dedup` state: keys of every record written so far.
+    struct Dedup {
+        key: DedupKey,
+        // --dedup-canonical: key sequences on the smaller of the sequence and its reverse complement
+        canonical: bool,
+        seen: HashSet<Vec<u8>>,
+        removed: u64,
+        // Approximate memory held by `seen`, checked against --max-mem
+        bytes: u64,
+        max_bytes: Option<u64>,
+    }
+
+    impl Dedup {
+        /// True the first time a record's key is seen; later copies are counted as removed.
+        /// Fails once the key set outgrows --max-mem, before the process runs out of memory.
+        fn is_new(&mut self, id: &str, seq: &[u8]) -> Result<bool> {
+            let key = match self.key {
+                DedupKey::Seq if self.canonical => {
+                    let seq = seq.to_ascii_uppercase();
+                    let rc = revcomp_iupac(&seq);
+                    seq.min(rc)
+                }
+                DedupKey::Seq => seq.to_ascii_uppercase(),
+                DedupKey::Id => id.as_bytes().to_vec(),
+            };
+            let key_len = key.len() as u64;
+            let new = self.seen.insert(key);
+            if !new {
+                self.removed += 1;
+                return Ok(false);
+            }
+            self.bytes += key_len + DEDUP_ENTRY_OVERHEAD;
+            if let Some(max_bytes) = self.max_bytes.filter(|&max| self.bytes > max) {
+                return Err(anyhow!(
+                    "--dedup key set grew to ~{} bytes ({} unique records), above --max-mem {}; raise --max-mem, use --dedup-by id, or deduplicate fewer inputs at once",
+                    self.bytes,
+                    self.seen.len(),
+                    max_bytes
+                ));
+            }
+            Ok(true)
+        }
+    }
+
+    /// Uniform random sample of at most `capacity` items from a stream (reservoir sampling, algorithm R).
+    struct Reservoir<T> {
+        capacity: usize,
+        seen: u64,
+        // (stream index, item)
+        items: Vec<(u64, T)>,
+    }
+
+    impl<T> Reservoir<T> {
+        fn new(capacity: usize) -> Self {
+            Reservoir { capacity, seen: 0, items: Vec::new() }
+        }
+
+        fn offer(&mut self, item: T, rng: &mut StdRng) {
+            if self.items.len() < self.capacity {
+                self.items.push((self.seen, item));
+            } else {
+                let j = rng.gen_range(0..=self.seen) as usize;
+                if j < self.capacity {
+                    self.items[j] = (self.seen, item);
+                }
+            }
+            self.seen += 1;
+        }
+
+        /// The sampled items, shuffled when `rng` is given and in stream order otherwise.
+        fn into_items(mut self, rng: Option<&mut StdRng>) -> Vec<T> {
+            match rng {
+                Some(rng) => self.items.shuffle(rng),
+                None => self.items.sort_by_key(|(index, _)| *index),
+            }
+            self.items.into_iter().map(|(_, item)| item).collect()
+        }
+    }
+
+    /// A decoded input record.
+    enum AnyRecord {
+        Fasta(fasta::Record),
+        Fastq(fastq::Record),
+    }
+
+    impl AnyRecord {
+        fn id(&self) -> &str {
+            match self {
+                AnyRecord::Fasta(r) => r.id(),
+                AnyRecord::Fastq(r) => r.id(),
+            }
+        }
+
+        fn seq(&self) -> &[u8] {
+            match self {
+                AnyRecord::Fasta(r) => r.seq(),
+                AnyRecord::Fastq(r) => r.seq(),
+            }
+        }
+    }
+
+    type Chunk = Vec<AnyRecord>;
+
+    /// Writer for the output format; records reach it already converted by `prepare`.
+    enum RecordWriter<W: Write> {
+        Fasta(fasta::Writer<W>),
+        Fastq(fastq::Writer<W>),
+    }
+
+    impl<W: Write> RecordWriter<W> {
+        fn write(&mut self, record: &AnyRecord) -> Result<()> {
+            match (self, record) {
+                (RecordWriter::Fasta(w), AnyRecord::Fasta(r)) => w.write_record(r)?,
+                (RecordWriter::Fastq(w), AnyRecord::Fastq(r)) => w.write_record(r)?,
+                _ => return Err(anyhow!("Record format does not match the output format")),
+            }
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            match self {
+                RecordWriter::Fasta(w) => w.flush()?,
+                RecordWriter::Fastq(w) => w.flush()?,
+            }
+            Ok(())
+        }
+    }
+
+    /// Reader thread body: decodes one input file and sends it on as chunks of `chunk_size` records.
+    /// Stops quietly once the writer has hung up.
+    fn read_chunks(path: &Path, format: Format, chunk_size: usize, tx: &Sender<Result<Chunk>>) -> Result<()> {
+        let input_reader = open_input(path)
+            .with_context(|| format!("Failed to open input file: {:?}", path))?;
+        let records: Box<dyn Iterator<Item = Result<AnyRecord>>> = match format {
+            Format::Fasta => Box::new(fasta::Reader::new(input_reader).records().map(|r| Ok(AnyRecord::Fasta(r?)))),
+            Format::Fastq => Box::new(fastq::Reader::new(input_reader).records().map(|r| Ok(AnyRecord::Fastq(r?)))),
+        };
+        let mut chunk = Vec::with_capacity(chunk_size);
+        for record in records {
+            chunk.push(record.with_context(|| format!("Failed to read input file: {:?}", path))?);
+            if chunk.len() == chunk_size {
+                let full = std::mem::replace(&mut chunk, Vec::with_capacity(chunk_size));
+                if tx.send(Ok(full)).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+        if !chunk.is_empty() {
+            let _ = tx.send(Ok(chunk));
+        }
+        Ok(())
+    }
+
+    /// Converts a record to the output format, applying --strip-desc (conversions only) and --annotate-source.
+    fn prepare(record: AnyRecord, target: Format, args: &Args, source: &str) -> AnyRecord {
+        match record {
+            AnyRecord::Fastq(rec) if target == Format::Fasta => {
+                let kept = args.desc.apply(rec.desc());
+                let desc = if args.annotate_source { Some(annotated_desc(kept, source)) } else { kept.map(str::to_string) };
+                AnyRecord::Fasta(fasta::Record::with_attrs(rec.id(), desc.as_deref(), rec.seq()))
+            }
+            AnyRecord::Fastq(rec) if args.annotate_source => {
+                AnyRecord::Fastq(fastq::Record::with_attrs(rec.id(), Some(&annotated_desc(rec.desc(), source)), rec.seq(), rec.qual()))
+            }
+            AnyRecord::Fasta(rec) if args.annotate_source => {
+                AnyRecord::Fasta(fasta::Record::with_attrs(rec.id(), Some(&annotated_desc(rec.desc(), source)), rec.seq()))
+            }
+            record => record,
+        }
+    }
+
+    fn annotated_desc(desc: Option<&str>, source: &str) -> String {
+        match desc {
+            Some(d) if !d.is_empty() => format!("{} src={}", d, source),
+            _ => format!("src={}", source),
+        }
+    }
+
+    fn source_name(path: &Path) -> String {
+        path.file_name().unwrap_or_default().to_string_lossy().to_string()
+    }
+
+    pub fn run(args: Args) -> Result<RunReport> {
+        if args.input_files.is_empty() {
+            return Err(anyhow!("No input files provided"));
+        }
+        // Input files decoded in parallel; default one thread per physical core
+        let threads = args.global.threads_or(num_cpus::get_physical());
+        if args.validate_first {
+            validate_inputs(&args.input_files)?;
+        }
+
+        let first_format = detect_format(&args.input_files[0])?;
+        for p in &args.input_files[1..] {
+            let f = detect_format(p)?;
+            if f != first_format {
+                return Err(anyhow!("Input files must have the same format"));
+            }
+        }
+
+        // If fastq_to_fasta is set, we will treat output as FASTA even if inputs are FASTQ
+        let target_format = if args.convert_only || args.fastq_to_fasta { Format::Fasta } else { first_format };
+
+        if args.convert_only && args.input_files.len() != 1 {
+            return Err(anyhow!("--convert-only 仅支持单输入文件。如需合并请不要使用该选项"));
+        }
+        if first_format == Format::Fasta && target_format == Format::Fastq {
+            return Err(anyhow!("Cannot convert FASTA to FASTQ because quality scores are unavailable"));
+        }
+        if args.dedup_canonical && args.dedup_by != DedupKey::Seq {
+            return Err(anyhow!("--dedup-canonical only applies to --dedup-by seq"));
+        }
+        if args.convert_only && first_format != Format::Fastq {
+            return Err(anyhow!("--convert-only 仅支持 FASTQ 输入 (--convert-only requires a FASTQ input)"));
+        }
+
+        // Combine and optionally shuffle the list of files respecting keep_order/shuffle
+        let mut rng = args.global.rng();
+        let mut files = args.input_files.clone();
+        if args.shuffle && !args.keep_order {
+            files.shuffle(&mut rng);
+        }
+        let mut out_writer = match &args.outfile {
+            Some(outfile) => create_output(outfile)?,
+            None => OutputWriter::stdout(),
+        };
+
+        let pb = args.global.progress(ProgressBar::new(0));
+        pb.set_style(
+            ProgressStyle::default_spinner()
+                .tick_strings(&["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"])
+                .template("{spinner:.blue} {msg} {pos} records")?
+        );
+        pb.set_message("Merging records...");
+
+        let mut total = 0u64;
+        let mut dedup = args.dedup.then(|| Dedup {
+            key: args.dedup_by,
+            canonical: args.dedup_canonical,
+            seen: HashSet::new(),
+            removed: 0,
+            bytes: 0,
+            max_bytes: args.max_mem,
+        });
+        let mut reservoir = args.subsample.map(Reservoir::new);
+        let mut out = match target_format {
+            Format::Fasta => RecordWriter::Fasta(fasta::Writer::new(&mut out_writer)),
+            Format::Fastq => RecordWriter::Fastq(fastq::Writer::new(&mut out_writer)),
+        };
+        let chunk_size = args.chunk_size.max(1);
+
+        // Up to --threads files are decoded at once, each into its own bounded channel.
+        // Files are handed out in input order and the writer drains the channels in that
+        // order, so output order is preserved and at most two chunks per open file are buffered.
+        thread::scope(|s| -> Result<()> {
+            let (work_tx, work_rx) = unbounded::<(PathBuf, Sender<Result<Chunk>>)>();
+            let mut receivers = Vec::with_capacity(files.len());
+            for path in &files {
+                let (tx, rx) = bounded::<Result<Chunk>>(2);
+                work_tx.send((path.clone(), tx))?;
+                receivers.push(rx);
+            }
+            drop(work_tx);
+            for _ in 0..threads.clamp(1, files.len()) {
+                let work_rx = work_rx.clone();
+                s.spawn(move || {
+                    for (path, tx) in work_rx {
+                        if let Err(e) = read_chunks(&path, first_format, chunk_size, &tx) {
+                            let _ = tx.send(Err(e));
+                        }
+                    }
+                });
+            }
+
+            for (rx, path) in receivers.into_iter().zip(&files) {
+                let source = source_name(path);
+                for chunk in rx {
+                    let mut chunk = chunk?;
+                    if args.shuffle { chunk.shuffle(&mut rng); }
+                    for record in chunk {
+                        if let Some(dedup) = dedup.as_mut() {
+                            if !dedup.is_new(record.id(), record.seq())? {
+                                continue;
+                            }
+                        }
+                        let record = prepare(record, target_format, &args, &source);
+                        match reservoir.as_mut() {
+                            Some(reservoir) => reservoir.offer(record, &mut rng),
+                            None => { out.write(&record)?; total += 1; }
+                        }
+                        pb.inc(1);
+                    }
+                }
+            }
+            Ok(())
+        })?;
+        if let Some(reservoir) = reservoir {
+            for record in reservoir.into_items(args.shuffle.then_some(&mut rng)) {
+                out.write(&record)?; total += 1;
+            }
+        }
+        out.flush()?;
+        drop(out);
+
+        out_writer.finish()?;
+        pb.finish_with_message("✔ Merging complete");
+        let mut report = RunReport::new("merge_file");
+        for input_path in &args.input_files {
+            report = report.input(input_path);
+        }
+        match &args.outfile {
+            Some(outfile) => {
+                println!("✔ Processed {} records into {}", total, outfile.display());
+                report = report.output(outfile);
+            }
+            // Keep stdout clean for the converted records
+            None => eprintln!("✔ Processed {} records to stdout", total),
+        }
+        report = report.count("records_written", total);
+        if let Some(dedup) = &dedup {
+            let message = format!("✔ Removed {} duplicate records", dedup.removed);
+            if args.outfile.is_some() { println!("{}", message) } else { eprintln!("{}", message) }
+            report = report.count("duplicates_removed", dedup.removed);
+        }
+        Ok(report)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::common::test_util::{fasta_headers, test_dir};
+
+        #[test]
+        fn fastq_to_fasta_keeps_or_strips_descriptions() {
+            let dir = test_dir("merge_file_desc");
+            let input = dir.join("in.fastq");
+            std::fs::write(&input, "@r1 sample=A\nACGT\n+\nIIII\n@r2\nGGCC\n+\nIIII\n").unwrap();
+            for (flag, expected) in [("--keep-desc", [">r1 sample=A", ">r2"]), ("--strip-desc", [">r1", ">r2"])] {
+                let out = dir.join("out.fasta");
+                let args = Args::try_parse_from([
+                    "merge_file", "--input-files", input.to_str().unwrap(), "--outfile", out.to_str().unwrap(),
+                    "--fastq-to-fasta", "--no-progress", flag,
+                ])
+                .unwrap();
+                run(args).unwrap();
+                assert_eq!(fasta_headers(&out), expected);
+            }
+        }
+
+        #[test]
+        fn dedup_canonical_collapses_reverse_complements() {
+            let dir = test_dir("merge_file_dedup_canonical");
+            let input = dir.join("in.fastq");
+            // r2 is the reverse complement of r1 (with an IUPAC R/Y pair); r3 is unrelated
+            std::fs::write(&input, "@r1\nACRGT\n+\nABCDE\n@r2\nACYGT\n+\nIIIII\n@r3\nTTTTT\n+\nIIIII\n").unwrap();
+            let merge = |canonical: bool| {
+                let out = dir.join("out.fastq");
+                let mut argv = vec!["merge_file", "--input-files", input.to_str().unwrap(), "--outfile", out.to_str().unwrap(), "--dedup", "--no-progress"];
+                if canonical {
+                    argv.push("--dedup-canonical");
+                }
+                run(Args::try_parse_from(argv).unwrap()).unwrap();
+                std::fs::read_to_string(&out).unwrap()
+            };
+            assert_eq!(merge(false).lines().count(), 12);
+            // The first-seen representative is kept, qualities included
+            assert_eq!(merge(true), "@r1\nACRGT\n+\nABCDE\n@r3\nTTTTT\n+\nIIIII\n");
+        }
+
+        #[test]
+        fn keep_order_output_is_identical_across_thread_counts() {
+            let dir = test_dir("merge_file_keep_order");
+            let mut inputs = Vec::new();
+            let mut expected = String::new();
+            for (file, records) in [2500, 7, 1333, 1].into_iter().enumerate() {
+                let mut text = String::new();
+                for i in 0..records {
+                    let seq: String = (0..20 + (i * 7 + file) % 30).map(|j| b"ACGT"[(i + j * file) % 4] as char).collect();
+                    text.push_str(&format!("@f{}_r{}\n{}\n+\n{}\n", file, i, seq, "I".repeat(seq.len())));
+                }
+                let path = dir.join(format!("in{}.fastq", file));
+                std::fs::write(&path, &text).unwrap();
+                expected.push_str(&text);
+                inputs.push(path);
+            }
+            let merge = |threads: &str| {
+                let out = dir.join(format!("out_{}.fastq", threads));
+                let mut argv = vec!["merge_file", "--input-files"];
+                argv.extend(inputs.iter().map(|p| p.to_str().unwrap()));
+                argv.extend(["--outfile", out.to_str().unwrap(), "--keep-order", "--chunk-size", "64", "--threads", threads, "--no-progress"]);
+                run(Args::try_parse_from(argv).unwrap()).unwrap();
+                std::fs::read(&out).unwrap()
+            };
+            let serial = merge("1");
+            assert_eq!(serial, expected.as_bytes());
+            for threads in ["2", "4", "8"] {
+                assert_eq!(merge(threads), serial, "--threads {}", threads);
+            }
+        }
+
+        #[test]
+        fn max_mem_sizes_parse_with_suffixes() {
+            assert_eq!(parse_mem_size("512"), Ok(512));
+            assert_eq!(parse_mem_size("4k"), Ok(4 << 10));
+            assert_eq!(parse_mem_size("3M"), Ok(3 << 20));
+            assert_eq!(parse_mem_size("2G"), Ok(2 << 30));
+            assert!(parse_mem_size("2T").is_err());
+            assert!(parse_mem_size("G").is_err());
+        }
+
+        #[test]
+        fn dedup_fails_cleanly_past_max_mem() {
+            let dir = test_dir("merge_file_max_mem");
+            let input = dir.join("in.fasta");
+            let fasta: String = (0..2000).map(|i| format!(">r{}\n{}\n", i, format!("{:020b}", i).replace('0', "A").replace('1', "C"))).collect();
+            std::fs::write(&input, fasta).unwrap();
+            let merge = |max_mem: &str| {
+                let out = dir.join("out.fasta");
+                let argv = ["merge_file", "--input-files", input.to_str().unwrap(), "--outfile", out.to_str().unwrap(), "--dedup", "--max-mem", max_mem, "--no-progress"];
+                run(Args::try_parse_from(argv).unwrap())
+            };
+            assert!(merge("1M").is_ok());
+            let err = merge("10K").unwrap_err().to_string();
+            assert!(err.contains("--max-mem"), "{}", err);
+        }
+    }
+}
+
+// ==================================================================================
+// `ns_count` subcommand module (Restored v0.5.1 anchor-based logic with syntax fix)
+// ==================================================================================
+mod ns_count {
+    use super::common::{
+        collapse_homopolymers, has_uracil, indexed_record_count, open_input_with_format, open_reader, records_progress, rna_to_dna,
+        write_count_sidecar, Format, GlobalOpts, RunReport,
+    };
+    use anyhow::{anyhow, Context, Result};
+    use bio::io::fasta::{self, Record};
+    use bio::io::fastq;
+    use clap::Parser;
+    use std::collections::hash_map::Entry;
+    use std::collections::{HashMap, HashSet};
+    use std::fs::File;
+    use std::io::{BufWriter, Write};
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::thread;
+
+    const CHUNK_SIZE: usize = 4096;
+
+    #[derive(Parser, Debug)]
+    pub struct Args {
+        #[arg(long, help = "FASTA/FASTQ file containing reads to be aligned (can be gzipped); '-' reads from stdin")]
+        reads: PathBuf,
+        #[arg(long = "refSEQ", help = "FASTA file containing the reference sequence with N-regions")]
+        ref_seq: PathBuf,
+        #[arg(long, help = "Output directory for CSV files")]
+        output: PathBuf,
+        #[arg(long, help = "Group label used as prefix for output filenames and CSV column headers", default_value = "T0")]
+        group: String,
+        #[arg(long, help = "Number of decimal places for frequency", default_value_t = 2)]
+        dig: u8,
+        #[arg(long, help = "Maximum mismatches allowed in non-anchor regions", default_value_t = 2)]
+        mismatches: usize,
+        #[arg(long, help = "Length of the anchor region on each side of an N-block", default_value_t = 15)]
+        anchor_len: usize,
+        #[arg(long, help = "Maximum mismatches allowed in anchor regions (total over all anchors)", default_value_t = 0)]
+        anchor_mismatch: usize,
+        #[arg(long, help = "Minimum number of anchor bases that must be covered by the read", default_value_t = 0)]
+        min_anchor_coverage: usize,
+        #[arg(long, help = "Maximum insertions/deletions allowed when a read has no ungapped alignment (0 = ungapped only); gaps are never placed inside N-blocks, and gaps in anchors also count toward --anchor-mismatch", default_value_t = 0)]
+        indels: usize,
+        #[arg(long, help = "Extract all matching reads into a separate FASTA file")]
+        extract_matches: bool,
+        #[arg(long, help = "Write <group>_<ref>_combo_reads.tsv per reference, mapping each combination to the IDs of the reads that produced it")]
+        combo_reads: bool,
+        #[arg(long, help = "Report the most abundant combination(s) per reference and write <group>_top_combos.csv")]
+        top_combo: bool,
+        #[arg(long, help = "Number of top combinations per reference for --top-combo", default_value_t = 1)]
+        top_n: usize,
+        #[arg(long, help = "Collapse homopolymer runs in each extracted combo (e.g. AAACG -> ACG); reads are still matched as sequenced, so the reference N-block lengths apply")]
+        collapse_homopolymers: bool,
+        #[arg(long, help = "Add MinLen, MaxLen and MeanLen columns (read length per combination) to the combo CSV")]
+        combo_length_stats: bool,
+        #[arg(long, help = "Treat U as T in the reference and reads (RNA input); without this flag U only triggers a warning")]
+        rna: bool,
+        #[arg(long, help = "Keep reads containing N: an N in a covered anchor still rejects the alignment, but Ns elsewhere are not mismatches and Ns in N-blocks appear in the combo (default: skip every read with an N)")]
+        allow_n: bool,
+        #[arg(long, help = "CSV mapping N-blocks to biological coordinate labels (columns: block,label and optionally reference); labels replace N1, N2, ... in the combo CSV header")]
+        segment_map: Option<PathBuf>,
+        #[arg(long, help = "Show a determinate progress bar using the record count from <reads>.fai or <reads>.count; if neither exists, <reads>.count is written after this run")]
+        progress_total_from_index: bool,
+
+        #[command(flatten)]
+        pub global: GlobalOpts,
+    }
+
+    // (min, max, sum) of read lengths for one combination
+    type LengthStats = (usize, usize, u64);
+    // (reference ID or None for all references, 1-based block index) -> label
+    type SegmentMap = HashMap<(Option<String>, usize), String>;
+
+    fn load_segment_map(path: &Path) -> Result<SegmentMap> {
+        let mut rdr = csv::Reader::from_path(path).with_context(|| format!("Failed to open segment map: {:?}", path))?;
+        let headers = rdr.headers()?.clone();
+        let column = |name: &str| headers.iter().position(|h| h.trim().eq_ignore_ascii_case(name));
+        let (block_col, label_col) = match (column("block"), column("label")) {
+            (Some(b), Some(l)) => (b, l),
+            _ => return Err(anyhow!("Segment map {:?} must contain 'block' and 'label' columns", path)),
+        };
+        let reference_col = column("reference");
+        let mut map = SegmentMap::new();
+        for (line, result) in rdr.records().enumerate() {
+            let record = result?;
+            let block: usize = record
+                .get(block_col)
+                .unwrap_or_default()
+                .trim()
+                .parse()
+                .ok()
+                .filter(|&b| b > 0)
+                .ok_or_else(|| anyhow!("Segment map {:?} row {}: 'block' must be a 1-based block index", path, line + 2))?;
+            let reference = reference_col
+                .and_then(|c| record.get(c))
+                .map(str::trim)
+                .filter(|r| !r.is_empty())
+                .map(str::to_string);
+            map.insert((reference, block), record.get(label_col).unwrap_or_default().trim().to_string());
+        }
+        Ok(map)
+    }
+
+    /// Label of block `block` (1-based) of `ref_id`: a reference-specific entry, then a global one, then `N<block>`.
+    fn block_label(segment_map: &SegmentMap, ref_id: &str, block: usize) -> String {
+        segment_map
+            .get(&(Some(ref_id.to_string()), block))
+            .or_else(|| segment_map.get(&(None, block)))
+            .cloned()
+            .unwrap_or_else(|| format!("N{}", block))
+    }
+
+    struct MatchResult {
+        ref_id: String,
+        combo: Vec<u8>,
+        read_record: Record,
+    }
+
+    struct RefData {
+        id: String,
+        seq: Vec<u8>,
+        len: usize,
+        n_blocks: Vec<(usize, usize)>,
+        anchor_indices: HashSet<usize>,
+    }
+
+    fn find_n_blocks(seq: &[u8]) -> Vec<(usize, usize)> {
+        let mut blocks = Vec::new();
+        let mut in_block = false;
+        let mut start = 0;
+        for (i, &base) in seq.iter().enumerate() {
+            if base == b'N' {
+                if !in_block {
+                    start = i;
+                    in_block = true;
+                }
+            } else if in_block {
+                blocks.push((start, i - start));
+                in_block = false;
+            }
+        }
+        if in_block {
+            blocks.push((start, seq.len() - start));
+        }
+        blocks
+    }
+
+    fn calculate_anchor_indices(n_blocks: &[(usize, usize)], ref_len: usize, anchor_len: usize) -> HashSet<usize> {
+        let mut indices = HashSet::new();
+        for &(start, len) in n_blocks {
+            let upstream_start = start.saturating_sub(anchor_len);
+            indices.extend(upstream_start..start);
+
+            let downstream_start = start + len;
+            let downstream_end = (downstream_start + anchor_len).min(ref_len);
+            indices.extend(downstream_start..downstream_end);
+        }
+        indices
+    }
+
+    /// Tries each reference in turn, the read first as given and then reverse-complemented, and
+    /// returns the first reference it aligns to with its combo. The read is aligned as sequenced;
+    /// `--collapse-homopolymers` only collapses the combo, where the '-' between blocks keeps the
+    /// runs of adjacent blocks apart.
+    fn match_read<'a>(read_seq: &[u8], refs: &'a [RefData], args: &Arc<Args>, dp: &mut Vec<u32>) -> Option<(&'a RefData, Vec<u8>)> {
+        refs.iter().find_map(|ref_data| {
+            let combo = find_alignment(read_seq, ref_data, args, false, dp).or_else(|| {
+                let rc_read = bio::alphabets::dna::revcomp(read_seq);
+                find_alignment(&rc_read, ref_data, args, true, dp)
+            })?;
+            let combo = if args.collapse_homopolymers { collapse_homopolymers(&combo) } else { combo };
+            Some((ref_data, combo))
+        })
+    }
+
+    fn find_alignment(read_seq: &[u8], ref_data: &RefData, args: &Arc<Args>, is_rc_read: bool, dp: &mut Vec<u32>) -> Option<Vec<u8>> {
+        let read_len = read_seq.len();
+        let ref_len = ref_data.len;
+
+        // Empty when the read is longer than the reference
+        for ref_start in 0..(ref_len + 1).saturating_sub(read_len) {
+            let overlap_len = read_len;
+
+            if !ref_data.n_blocks.iter().all(|(n_start, n_len)| 
+                *n_start >= ref_start && (*n_start + *n_len) <= (ref_start + overlap_len)
+            ) {
+                continue;
+            }
+
+            let mut anchor_mismatches = 0;
+            let mut anchor_covered = 0;
+            let mut n_in_anchor = false;
+            for &anchor_idx in &ref_data.anchor_indices {
+                if anchor_idx >= ref_start && anchor_idx < (ref_start + overlap_len) {
+                    anchor_covered += 1;
+                    let read_idx = anchor_idx - ref_start;
+                    // Only reachable with --allow-n
+                    if read_seq[read_idx] == b'N' {
+                        n_in_anchor = true;
+                        break;
+                    }
+                    if read_seq[read_idx] != ref_data.seq[anchor_idx] {
+                        anchor_mismatches += 1;
+                        if anchor_mismatches > args.anchor_mismatch {
+                            break;
+                        }
+                    }
+                }
+            }
+            if n_in_anchor || anchor_mismatches > args.anchor_mismatch || anchor_covered < args.min_anchor_coverage { continue; }
+
+            let mut mismatches = 0;
+            for i in 0..overlap_len {
+                let ref_idx = ref_start + i;
+                if ref_data.anchor_indices.contains(&ref_idx) || ref_data.seq[ref_idx] == b'N' {
+                    continue;
+                }
+                let read_idx = i;
+                if read_seq[read_idx] != ref_data.seq[ref_idx] && read_seq[read_idx] != b'N' {
+                    mismatches += 1;
+                }
+            }
+
+            if mismatches <= args.mismatches {
+                return Some(build_combo(read_seq, ref_data, |n_start| n_start - ref_start, is_rc_read));
+            }
+        }
+        if args.indels > 0 {
+            return find_gapped_alignment(read_seq, ref_data, args, is_rc_read, dp);
+        }
+        None
+    }
+
+    /// Joins the N-block contents of an aligned read with '-'; `read_pos` maps a block's
+    /// reference start to its read position.
+    fn build_combo(read_seq: &[u8], ref_data: &RefData, read_pos: impl Fn(usize) -> usize, is_rc_read: bool) -> Vec<u8> {
+        let mut combo_parts = Vec::new();
+        for &(n_start, n_len) in &ref_data.n_blocks {
+            let read_idx_start = read_pos(n_start);
+            let segment = &read_seq[read_idx_start..read_idx_start + n_len];
+            if is_rc_read {
+                combo_parts.push(bio::alphabets::dna::revcomp(segment));
+            } else {
+                combo_parts.push(segment.to_vec());
+            }
+        }
+        combo_parts.join(&b'-')
+    }
+
+    /// `--indels`: edit-distance alignment of the whole read against any part of the reference
+    /// (free reference ends). N columns match any base and never take a gap, so every N-block is
+    /// read as a contiguous run of its own length. The best path is then checked against the
+    /// same limits as the ungapped search, with gaps counted as indels (and, in anchors, as
+    /// anchor mismatches).
+    ///
+    /// The DP is banded: a path starting at reference offset `s` stays within `--indels` of
+    /// diagonal `s`, and only offsets from which the read can still cover every N-block are
+    /// considered, so each row holds `2 * indels + 1` cells plus the slack of those offsets.
+    /// `dp` is a scratch buffer reused across calls by each worker, and the fill stops as soon
+    /// as a whole row exceeds the combined mismatch and indel budget.
+    fn find_gapped_alignment(read_seq: &[u8], ref_data: &RefData, args: &Arc<Args>, is_rc_read: bool, dp: &mut Vec<u32>) -> Option<Vec<u8>> {
+        const BLOCKED: u32 = u32::MAX / 2;
+        let (n, m) = (read_seq.len(), ref_data.len);
+        let k = args.indels as isize;
+        let rs = &ref_data.seq;
+        let is_n = |j: usize| rs[j] == b'N';
+        // A read N (--allow-n) is free outside anchors; in an anchor it rejects the path below
+        let sub_cost = |i: usize, j: usize| {
+            let base = read_seq[i - 1];
+            u32::from(!is_n(j - 1) && base != rs[j - 1] && (base != b'N' || ref_data.anchor_indices.contains(&(j - 1))))
+        };
+        // An insertion before reference column j (between j-1 and j) must not touch an N-block
+        let ins_ok = |j: usize| !((j > 0 && is_n(j - 1)) || (j < m && is_n(j)));
+
+        // Start offsets that fit the read and can reach past the last N-block and start before the first
+        let first_block = ref_data.n_blocks.iter().map(|&(start, _)| start as isize).min().unwrap_or(m as isize);
+        let last_block_end = ref_data.n_blocks.iter().map(|&(start, len)| (start + len) as isize).max().unwrap_or(0);
+        let s_lo = (last_block_end - n as isize - k).max(0);
+        let s_hi = first_block.min(m as isize - n as isize + k);
+        if s_lo > s_hi {
+            return None;
+        }
+        // Diagonals d = j - i reachable from those offsets with at most `k` net gaps
+        let (lo, hi) = ((s_lo - k).max(-(n as isize)), (s_hi + k).min(m as isize));
+        let width = (hi - lo + 1) as usize;
+        let at = |i: usize, d: isize| i * width + (d - lo) as usize;
+        let budget = (args.mismatches + args.anchor_mismatch + args.indels) as u32;
+
+        dp.clear();
+        dp.resize((n + 1) * width, BLOCKED);
+        for d in lo.max(0)..=hi {
+            dp[at(0, d)] = 0;
+        }
+        for i in 1..=n {
+            let mut row_min = BLOCKED;
+            for d in lo..=hi {
+                let j = i as isize + d;
+                if j < 0 || j > m as isize {
+                    continue;
+                }
+                let j = j as usize;
+                let mut best = BLOCKED;
+                if j > 0 {
+                    best = dp[at(i - 1, d)] + sub_cost(i, j);
+                    if !is_n(j - 1) && d > lo {
+                        best = best.min(dp[at(i, d - 1)] + 1);
+                    }
+                }
+                if ins_ok(j) && d < hi {
+                    best = best.min(dp[at(i - 1, d + 1)] + 1);
+                }
+                let best = best.min(BLOCKED);
+                dp[at(i, d)] = best;
+                row_min = row_min.min(best);
+            }
+            // Costs never decrease along a path, so no cell of a later row can get back under budget
+            if row_min > budget {
+                return None;
+            }
+        }
+        let end_d = (lo..=hi).filter(|&d| (0..=m as isize).contains(&(n as isize + d))).min_by_key(|&d| dp[at(n, d)])?;
+        if dp[at(n, end_d)] > budget {
+            return None;
+        }
+        let end = (n as isize + end_d) as usize;
+
+        // Trace back, recording the read position of each aligned reference column
+        let mut read_at: HashMap<usize, usize> = HashMap::new();
+        let (mut i, mut j) = (n, end);
+        let (mut indels, mut anchor_mismatches, mut mismatches) = (0usize, 0usize, 0usize);
+        while i > 0 {
+            let d = j as isize - i as isize;
+            let here = dp[at(i, d)];
+            if j > 0 {
+                let sub = sub_cost(i, j);
+                if here == dp[at(i - 1, d)] + sub {
+                    if sub == 1 && read_seq[i - 1] == b'N' {
+                        return None;
+                    }
+                    if sub == 1 {
+                        if ref_data.anchor_indices.contains(&(j - 1)) { anchor_mismatches += 1 } else { mismatches += 1 }
+                    }
+                    read_at.insert(j - 1, i - 1);
+                    i -= 1;
+                    j -= 1;
+                    continue;
+                }
+                if !is_n(j - 1) && d > lo && here == dp[at(i, d - 1)] + 1 {
+                    indels += 1;
+                    anchor_mismatches += usize::from(ref_data.anchor_indices.contains(&(j - 1)));
+                    j -= 1;
+                    continue;
+                }
+            }
+            // Insertion; it sits in an anchor when a neighbouring column does
+            indels += 1;
+            let anchors = ref_data.anchor_indices.contains(&j) || (j > 0 && ref_data.anchor_indices.contains(&(j - 1)));
+            anchor_mismatches += usize::from(anchors);
+            i -= 1;
+        }
+        let ref_start = j;
+
+        if indels > args.indels || anchor_mismatches > args.anchor_mismatch || mismatches > args.mismatches {
+            return None;
+        }
+        if !ref_data.n_blocks.iter().all(|&(n_start, n_len)| n_start >= ref_start && n_start + n_len <= end) {
+            return None;
+        }
+        let anchor_covered = ref_data.anchor_indices.iter().filter(|&&a| a >= ref_start && a < end).count();
+        if anchor_covered < args.min_anchor_coverage {
+            return None;
+        }
+        Some(build_combo(read_seq, ref_data, |n_start| read_at[&n_start], is_rc_read))
+    }
+
+    fn collector_thread(
+        rx: crossbeam_channel::Receiver<MatchResult>,
+        args: Arc<Args>,
+        ref_data_map: HashMap<String, Vec<(usize, usize)>>,
+        segment_map: SegmentMap,
+    ) -> Result<Vec<(String, u64)>> {
+        let (output_dir, group, dig) = (&args.output, &args.group, args.dig);
+        let top_n = args.top_combo.then_some(args.top_n);
+        let mut counters: HashMap<String, HashMap<Vec<u8>, u64>> = HashMap::new();
+        // ref_id -> combo -> read lengths, only for --combo-length-stats
+        let mut lengths: HashMap<String, HashMap<Vec<u8>, LengthStats>> = HashMap::new();
+        let mut writers: HashMap<String, fasta::Writer<File>> = HashMap::new();
+        let mut combo_read_writers: HashMap<String, BufWriter<File>> = HashMap::new();
+        // (ref_id, rank, combo, count, frequency) rows for --top-combo
+        let mut top_rows: Vec<(String, usize, String, u64, String)> = Vec::new();
+        let mut matches_per_ref: Vec<(String, u64)> = Vec::new();
+
+        for result in rx {
+            if args.combo_length_stats {
+                let len = result.read_record.seq().len();
+                let stats = lengths
+                    .entry(result.ref_id.clone())
+                    .or_default()
+                    .entry(result.combo.clone())
+                    .or_insert((usize::MAX, 0, 0));
+                stats.0 = stats.0.min(len);
+                stats.1 = stats.1.max(len);
+                stats.2 += len as u64;
+            }
+
+            if args.combo_reads {
+                let writer = match combo_read_writers.entry(result.ref_id.clone()) {
+                    Entry::Occupied(e) => e.into_mut(),
+                    Entry::Vacant(e) => {
+                        let out_path = output_dir.join(format!("{}_{}_combo_reads.tsv", group, result.ref_id));
+                        let file = File::create(&out_path).with_context(|| format!("Failed to create {:?}", out_path))?;
+                        let mut writer = BufWriter::new(file);
+                        writeln!(writer, "Combo\tReadID")?;
+                        e.insert(writer)
+                    }
+                };
+                writeln!(writer, "{}\t{}", String::from_utf8_lossy(&result.combo), result.read_record.id())?;
+            }
+
+            let counter = counters.entry(result.ref_id.clone()).or_default();
+            *counter.entry(result.combo).or_insert(0) += 1;
+
+            if args.extract_matches {
+                let writer = writers.entry(result.ref_id.clone()).or_insert_with(|| {
+                    let out_path = output_dir.join(format!("{}_{}_matched_reads.fasta", group, result.ref_id));
+                    fasta::Writer::to_file(out_path).expect("Failed to create writer")
+                });
+                writer.write_record(&result.read_record)?;
+            }
+        }
+
+        for (ref_id, counter) in counters {
+            let total: u64 = counter.values().sum();
+            if total > 0 {
+                let n_blocks = ref_data_map.get(&ref_id).unwrap();
+                let n_label = (1..=n_blocks.len()).map(|i| block_label(&segment_map, &ref_id, i)).collect::<Vec<_>>().join("_");
+                let out_csv_path = output_dir.join(format!("{}_{}_combo_counts.csv", group, ref_id));
+                let mut csv_writer = csv::Writer::from_path(out_csv_path)?;
+                let mut header = vec![format!("{}_{}_combo", group, n_label), "Count".to_string(), "Frequency (%)".to_string()];
+                if args.combo_length_stats {
+                    header.extend(["MinLen", "MaxLen", "MeanLen"].map(String::from));
+                }
+                csv_writer.write_record(&header)?;
+                let ref_lengths = lengths.get(&ref_id);
+                
+                let mut sorted_combos: Vec<_> = counter.iter().collect();
+                sorted_combos.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+
+                for (rank, (combo, count)) in sorted_combos.into_iter().enumerate() {
+                    let freq = (*count as f64 / total as f64) * 100.0;
+                    let combo_str = String::from_utf8_lossy(combo).to_string();
+                    let freq_str = format!("{:.1$}", freq, dig as usize);
+                    if top_n.is_some_and(|n| rank < n) {
+                        top_rows.push((ref_id.clone(), rank + 1, combo_str.clone(), *count, freq_str.clone()));
+                    }
+                    let mut row = vec![combo_str, count.to_string(), freq_str];
+                    if let Some(&(min, max, sum)) = ref_lengths.and_then(|l| l.get(combo)) {
+                        row.extend([min.to_string(), max.to_string(), format!("{:.2}", sum as f64 / *count as f64)]);
+                    }
+                    csv_writer.write_record(&row)?;
+                }
+                println!("[Done] {}: Found {} matches with {} unique combinations.", ref_id, total, counter.len());
+                matches_per_ref.push((ref_id.clone(), total));
+            }
+        }
+
+        if top_n.is_some() {
+            top_rows.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.cmp(&b.1)));
+            let top_path = output_dir.join(format!("{}_top_combos.csv", group));
+            let mut top_writer = csv::Writer::from_path(&top_path)?;
+            top_writer.write_record(["Reference", "Rank", "Combo", "Count", "Frequency (%)"])?;
+            println!("\n---> Top combinations per reference:");
+            for (ref_id, rank, combo, count, freq) in top_rows {
+                println!("  {} #{}: {} ({} reads, {}%)", ref_id, rank, combo, count, freq);
+                top_writer.write_record([ref_id, rank.to_string(), combo, count.to_string(), freq])?;
+            }
+            top_writer.flush()?;
+            println!("Top combinations written to: {}", top_path.display());
+        }
+
+        for (_, mut writer) in writers {
+            writer.flush()?;
+        }
+        for (_, mut writer) in combo_read_writers {
+            writer.flush()?;
+        }
+
+        Ok(matches_per_ref)
+    }
+
+    pub fn run(args: Args) -> Result<RunReport> {
+        // Default: one thread per physical core
+        let threads = args.global.threads_or(num_cpus::get_physical());
+        std::fs::create_dir_all(&args.output)
+            .with_context(|| format!("Failed to create output directory: {:?}", args.output))?;
+        
+        let segment_map = match &args.segment_map {
+            Some(path) => load_segment_map(path)?,
+            None => SegmentMap::new(),
+        };
+        let ref_reader = open_reader(&args.ref_seq)?;
+        let ref_records: Vec<_> = fasta::Reader::new(ref_reader).records().collect::<Result<_,_>>()?;
+        
+        let args_arc = Arc::new(args);
+
+        let ref_data_vec: Vec<RefData> = ref_records.into_iter().filter_map(|rec| {
+            let mut seq = rec.seq().to_ascii_uppercase();
+            if args_arc.rna {
+                rna_to_dna(&mut seq);
+            } else if has_uracil(&seq) {
+                eprintln!("Warning: reference {} contains U (RNA); it will not match T in reads unless --rna is given", rec.id());
+            }
+            let n_blocks = find_n_blocks(&seq);
+            if n_blocks.is_empty() {
+                println!("[Skipping] {}: No 'N' blocks found in reference sequence.", rec.id());
+                return None;
+            }
+            let anchor_indices = calculate_anchor_indices(&n_blocks, seq.len(), args_arc.anchor_len);
+            Some(RefData {
+                id: rec.id().to_string(),
+                len: seq.len(),
+                seq,
+                n_blocks,
+                anchor_indices,
+            })
+        }).collect();
+        
+        println!("---> Starting parallel alignment against {} valid reference(s)...", ref_data_vec.len());
+        
+        rayon::ThreadPoolBuilder::new().num_threads(threads).build_global()?;
+        
+        // stdin has no index and cannot get a sidecar
+        let track_total = args_arc.progress_total_from_index && args_arc.reads.as_os_str() != "-";
+        let indexed_total = if track_total { indexed_record_count(&args_arc.reads)? } else { None };
+        let pb = args_arc.global.progress(records_progress(indexed_total, "reads")?);
+        pb.set_message("Reading reads...");
+
+        let ref_data_arc = Arc::new(ref_data_vec);
+
+        let matches_per_ref = thread::scope(|s| -> Result<Vec<(String, u64)>> {
+            let (reads_tx, reads_rx) = crossbeam_channel::bounded::<Vec<Record>>(threads * 2);
+            let (results_tx, results_rx) = crossbeam_channel::bounded::<MatchResult>(1024);
+
+            let ref_data_for_collector: HashMap<_, _> = ref_data_arc.iter().map(|d| (d.id.clone(), d.n_blocks.clone())).collect();
+            
+            let collector_args = Arc::clone(&args_arc);
+            let collector_handle = s.spawn(move || {
+                collector_thread(results_rx, collector_args, ref_data_for_collector, segment_map)
+            });
+
+            for _ in 0..threads {
+                let rx = reads_rx.clone();
+                let tx = results_tx.clone();
+                let refs = Arc::clone(&ref_data_arc);
+                let args_clone = Arc::clone(&args_arc);
+
+                s.spawn(move || {
+                    // --indels DP scratch, reused for every read of this worker
+                    let mut dp = Vec::new();
+                    for read_chunk in rx {
+                        for read_record in read_chunk {
+                            let mut read_seq = read_record.seq().to_ascii_uppercase();
+                            if args_clone.rna {
+                                rna_to_dna(&mut read_seq);
+                            }
+                            if !args_clone.allow_n && read_seq.contains(&b'N') { continue; }
+
+                            if let Some((ref_data, combo)) = match_read(&read_seq, &refs, &args_clone, &mut dp) {
+                                if tx.send(MatchResult { ref_id: ref_data.id.clone(), combo, read_record }).is_err() {
+                                    return;
+                                }
+                            }
+                        }
+                    }
+                });
+            }
+            drop(results_tx);
+
+            // Single open so `--reads -` (stdin) is consumed only once
+            let (reads_format, boxed_reads_reader) = open_input_with_format(&args_arc.reads)?;
+            // FASTQ reads are carried as FASTA records (sequence only) so --extract-matches can write them as FASTA
+            let mut records_iter: Box<dyn Iterator<Item = Result<Record>>> = match reads_format {
+                Format::Fasta => Box::new(fasta::Reader::new(boxed_reads_reader).records().map(|r| r.map_err(Into::into))),
+                Format::Fastq => Box::new(fastq::Reader::new(boxed_reads_reader).records().map(|r| {
+                    r.map(|rec| Record::with_attrs(rec.id(), rec.desc(), rec.seq())).map_err(Into::into)
+                })),
+            };
+            
+            let mut warned_rna = args_arc.rna;
+            loop {
+                let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+                for _ in 0..CHUNK_SIZE {
+                    match records_iter.next() {
+                        Some(Ok(record)) => chunk.push(record),
+                        Some(Err(e)) => return Err(e),
+                        None => break,
+                    }
+                }
+                if chunk.is_empty() { break; }
+                if !warned_rna && chunk.iter().any(|r| has_uracil(r.seq())) {
+                    pb.println("Warning: reads contain U (RNA); pass --rna to match them as T");
+                    warned_rna = true;
+                }
+                pb.inc(chunk.len() as u64);
+                if reads_tx.send(chunk).is_err() { break; }
+            }
+            drop(reads_tx);
+            pb.finish_with_message("✔ Reads loaded, waiting for alignment to finish...");
+            if track_total && indexed_total.is_none() {
+                if let Err(e) = write_count_sidecar(&args_arc.reads, pb.position()) {
+                    eprintln!("Warning: {:#}", e);
+                }
+            }
+
+            collector_handle.join().unwrap()
+        })?;
+
+        println!("\n✔ All alignment tasks are complete.");
+        let mut report = RunReport::new("Ns_count").input(&args_arc.reads).input(&args_arc.ref_seq).output(&args_arc.output);
+        for (ref_id, total) in matches_per_ref {
+            report = report.count(format!("{}.matches", ref_id), total);
+        }
+        Ok(report)
+    }
+
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn ref_data(seq: &[u8], anchor_len: usize) -> RefData {
+            let n_blocks = find_n_blocks(seq);
+            let anchor_indices = calculate_anchor_indices(&n_blocks, seq.len(), anchor_len);
+            RefData { id: "ref".to_string(), seq: seq.to_vec(), len: seq.len(), n_blocks, anchor_indices }
+        }
+
+        fn args(indels: usize) -> Arc<Args> {
+            let indels = indels.to_string();
+            let argv = ["Ns_count", "--reads", "r.fa", "--refSEQ", "ref.fa", "--output", "out", "--anchor-len", "4", "--mismatches", "1", "--indels", &indels];
+            Arc::new(Args::try_parse_from(argv).unwrap())
+        }
+
+        #[test]
+        fn banded_indel_alignment_finds_gapped_reads_within_budget() {
+            let reference = ref_data(b"GATGCATACGTTNNNCCTTTACTTGCTGTGTCCACCCCATCGG", 4);
+            let mut dp = Vec::new();
+            // One base inserted and one deleted outside the anchors
+            let one_ins = b"ATGCATTACGTTAGCCCTTTACTTGCTGTGTCCACCCCATC";
+            let one_del = b"ATGCATACGTTAGCCCTTTACTTGCTGTGTCACCCCATC";
+            for read in [&one_ins[..], &one_del[..]] {
+                assert_eq!(find_alignment(read, &reference, &args(0), false, &mut dp), None);
+                assert_eq!(find_alignment(read, &reference, &args(1), false, &mut dp), Some(b"AGC".to_vec()));
+            }
+            // Two indels exceed --indels 1 but fit --indels 2, reusing the same buffer
+            let two_indels = b"ATGCATTACGTTAGCCCTTTACTTGCTGTGTCACCCCATC";
+            assert_eq!(find_alignment(two_indels, &reference, &args(1), false, &mut dp), None);
+            assert_eq!(find_alignment(two_indels, &reference, &args(2), false, &mut dp), Some(b"AGC".to_vec()));
+        }
+
+        #[test]
+        fn collapse_homopolymers_applies_to_the_combo_only() {
+            let refs = [ref_data(b"ACGTACNNNNGTCANNNTGCATG", 3)];
+            let argv = ["Ns_count", "--reads", "r.fa", "--refSEQ", "ref.fa", "--output", "out", "--anchor-len", "3", "--collapse-homopolymers"];
+            let collapse = Arc::new(Args::try_parse_from(argv).unwrap());
+            let mut dp = Vec::new();
+            // Full-length reads align as sequenced; the runs inside each block collapse but not across blocks
+            for (read, combo) in [
+                (&b"ACGTACAAACGTCACCGTGCATG"[..], &b"AC-CG"[..]),
+                (b"ACGTACAACCGTCACGGTGCATG", b"AC-CG"),
+                (b"ACGTACACCCGTCACCATGCATG", b"AC-CA"),
+            ] {
+                let (_, found) = match_read(read, &refs, &collapse, &mut dp).unwrap();
+                assert_eq!(found, combo);
+            }
+            // A read already shortened at a homopolymer no longer covers the reference
+            assert!(match_read(b"ACGTACACGTCACCGTGCATG", &refs, &collapse, &mut dp).is_none());
+            let (_, found) = match_read(b"ACGTACAAACGTCACCGTGCATG", &refs, &args(0), &mut dp).unwrap();
+            assert_eq!(found, b"AAAC-CCG");
+        }
+    }
+}
+
+// ==================================================================================
+// `dna2aa` subcommand module (NEW)
+// ==================================================================================
+mod dna2aa {
+    use anyhow::{anyhow, Context, Result};
+    use bio::alphabets::dna::revcomp;
+    use bio::io::fasta; // 只导入 FASTA 读写器
+    use clap::builder::{PossibleValuesParser, TypedValueParser};
+    use clap::Parser;
+    use rayon::prelude::*;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    use super::common::{has_uracil, open_input, strip_compression_ext, DescArgs, GlobalOpts, RunReport};
+
+    #[derive(Parser, Debug)]
+    #[command(name = "DNA2AA", about = "Translate DNA FASTA files (a single file or a directory of them) to Amino Acid FASTA files")]
+    pub struct Args {
+        #[arg(long, short = 'i', help = "Input DNA FASTA file, or a directory of them (.fasta/.fa/.fna, optionally .gz)")]
+        pub input: PathBuf,
+
+        #[arg(long, short = 'o', help = "Output directory for translated protein FASTA files")]
+        pub output: PathBuf,
+
+        #[arg(long, default_value_t = 50, help = "Minimum amino acid length to keep")]
+        pub aa_length: usize,
+
+        #[arg(long, help = "Input is RNA: translate U as T (without this flag, files containing U only trigger a warning)")]
+        pub rna: bool,
+
+        #[arg(long, default_value_t = 1, value_parser = PossibleValuesParser::new(["1", "3", "6"]).map(|s| s.parse::<usize>().unwrap()), help = "Reading frames to translate: 1 (frame +1 only), 3 (all forward frames) or 6 (forward and reverse-complement frames); with 3 or 6, each record ID gets a _frame+N/_frame-N suffix")]
+        pub frames: usize,
+
+        #[arg(long, default_value_t = 1, value_parser = PossibleValuesParser::new(["1", "2", "3", "4", "5", "11"]).map(|s| s.parse::<u32>().unwrap()), help = "NCBI translation table: 1 standard, 2 vertebrate mitochondrial, 3 yeast mitochondrial, 4 mold/protozoan mitochondrial, 5 invertebrate mitochondrial, 11 bacterial/plastid")]
+        pub table: u32,
+
+        #[arg(long, conflicts_with = "frames", help = "ORF mode: scan all six frames for ORFs from a start codon to the next in-frame stop and write the longest protein per record; the ID gets the frame and 1-based nucleotide span, e.g. <id>_frame-2_450-121")]
+        pub orf: bool,
+
+        #[arg(long, requires = "orf", help = "With --orf, write every ORF of at least --aa-length residues instead of only the longest")]
+        pub all_orfs: bool,
+
+        #[arg(long, value_delimiter = ',', default_value = "ATG", requires = "orf", help = "Comma-separated start codons that open an ORF in --orf mode, e.g. ATG,GTG,TTG (the start codon is translated as M)")]
+        pub start_codons: Vec<String>,
+
+        #[command(flatten)]
+        pub desc: DescArgs,
+
+        #[command(flatten)]
+        pub global: GlobalOpts,
+    }
+
+    // --------------------------------------------------------------------------------
+    // 修复：手动实现标准密码子表，移除对 `bio` 库翻译功能的依赖
+    // --------------------------------------------------------------------------------
+    type CodonTable = HashMap<[u8; 3], u8>;
+
+    /// Codon reassignments of an NCBI translation table relative to the standard code (table 1).
+    /// Alternative start codons need no entry: translation always starts at the first base and
+    /// translates an internal ATG/ATA/... as its amino acid.
+    fn table_overrides(table_id: u32) -> &'static [(&'static [u8; 3], u8)] {
+        match table_id {
+            // Vertebrate mitochondrial
+            2 => &[(b"AGA", b'*'), (b"AGG", b'*'), (b"ATA", b'M'), (b"TGA", b'W')],
+            // Yeast mitochondrial
+            3 => &[(b"ATA", b'M'), (b"CTT", b'T'), (b"CTC", b'T'), (b"CTA", b'T'), (b"CTG", b'T'), (b"TGA", b'W')],
+            // Mold, protozoan and coelenterate mitochondrial; Mycoplasma/Spiroplasma
+            4 => &[(b"TGA", b'W')],
+            // Invertebrate mitochondrial
+            5 => &[(b"AGA", b'S'), (b"AGG", b'S'), (b"ATA", b'M'), (b"TGA", b'W')],
+            // 1 standard, 11 bacterial/archaeal/plastid: same codon assignments, only start codons differ
+            _ => &[],
+        }
+    }
+
+    /// 构建一个DNA密码子表：标准密码子表，再叠加 `table_id` 对应的 NCBI 翻译表差异
+    fn build_codon_table(table_id: u32) -> CodonTable {
+        let mut table = HashMap::new();
+        // 终止密码子 (Stop Codons)
+        table.insert(*b"TAA", b'*');
+        table.insert(*b"TAG", b'*');
+        table.insert(*b"TGA", b'*');
+        // 苯丙氨酸 (F)
+        table.insert(*b"TTT", b'F');
+        table.insert(*b"TTC", b'F');
+        // 亮氨酸 (L)
+        table.insert(*b"TTA", b'L');
+        table.insert(*b"TTG", b'L');
+        table.insert(*b"CTT", b'L');
+        table.insert(*b"CTC", b'L');
+        table.insert(*b"CTA", b'L');
+        table.insert(*b"CTG", b'L');
+        // 异亮氨酸 (I)
+        table.insert(*b"ATT", b'I');
+        table.insert(*b"ATC", b'I');
+        table.insert(*b"ATA", b'I');
+        // 甲硫氨酸 (M) - 起始
+        table.insert(*b"ATG", b'M');
+        // 缬氨酸 (V)
+        table.insert(*b"GTT", b'V');
+        table.insert(*b"GTC", b'V');
+        table.insert(*b"GTA", b'V');
+        table.insert(*b"GTG", b'V');
+        // 丝氨酸 (S)
+        table.insert(*b"TCT", b'S');
+        table.insert(*b"TCC", b'S');
+        table.insert(*b"TCA", b'S');
+        table.insert(*b"TCG", b'S');
+        table.insert(*b"AGT", b'S');
+        table.insert(*b"AGC", b'S');
+        // 脯氨酸 (P)
+        table.insert(*b"CCT", b'P');
+        table.insert(*b"CCC", b'P');
+        table.insert(*b"CCA", b'P');
+        table.insert(*b"CCG", b'P');
+        // 苏氨酸 (T)
+        table.insert(*b"ACT", b'T');
+        table.insert(*b"ACC", b'T');
+        table.insert(*b"ACA", b'T');
+        table.insert(*b"ACG", b'T');
+        // 丙氨酸 (A)
+        table.insert(*b"GCT", b'A');
+        table.insert(*b"GCC", b'A');
+        table.insert(*b"GCA", b'A');
+        table.insert(*b"GCG", b'A');
+        // 酪氨酸 (Y)
+        table.insert(*b"TAT", b'Y');
+        table.insert(*b"TAC", b'Y');
+        // 组氨酸 (H)
+        table.insert(*b"CAT", b'H');
+        table.insert(*b"CAC", b'H');
+        // 谷氨酰胺 (Q)
+        table.insert(*b"CAA", b'Q');
+        table.insert(*b"CAG", b'Q');
+        // 天冬酰胺 (N)
+        table.insert(*b"AAT", b'N');
+        table.insert(*b"AAC", b'N');
+        // 赖氨酸 (K)
+        table.insert(*b"AAA", b'K');
+        table.insert(*b"AAG", b'K');
+        // 天冬氨酸 (D)
+        table.insert(*b"GAT", b'D');
+        table.insert(*b"GAC", b'D');
+        // 谷氨酸 (E)
+        table.insert(*b"GAA", b'E');
+        table.insert(*b"GAG", b'E');
+        // 半胱氨酸 (C)
+        table.insert(*b"TGT", b'C');
+        table.insert(*b"TGC", b'C');
+        // 色氨酸 (W)
+        table.insert(*b"TGG", b'W');
+        // 精氨酸 (R)
+        table.insert(*b"CGT", b'R');
+        table.insert(*b"CGC", b'R');
+        table.insert(*b"CGA", b'R');
+        table.insert(*b"CGG", b'R');
+        table.insert(*b"AGA", b'R');
+        table.insert(*b"AGG", b'R');
+        // 甘氨酸 (G)
+        table.insert(*b"GGT", b'G');
+        table.insert(*b"GGC", b'G');
+        table.insert(*b"GGA", b'G');
+        table.insert(*b"GGG", b'G');
+        table.extend(table_overrides(table_id).iter().map(|&(codon, aa)| (*codon, aa)));
+        table
+    }
+
+    /// Translates a DNA sequence until the first stop codon (which is not included).
+    /// Mimics Biopython's `seq.translate(to_stop=True)`
+    /// With `rna`, U is read as T so RNA codons use the same table.
+    fn translate_to_stop(dna_seq: &[u8], table: &CodonTable, rna: bool) -> Vec<u8> {
+        let mut protein = Vec::new();
+
+        // 遍历3碱基的密码子
+        for codon_bytes in dna_seq.chunks_exact(3) {
+            // 将 &[u8] 转换为 [u8; 3]
+            let codon: [u8; 3] = [
+                normalize_base(codon_bytes[0], rna),
+                normalize_base(codon_bytes[1], rna),
+                normalize_base(codon_bytes[2], rna)
+            ];
+
+            match table.get(&codon) {
+                Some(aa) => {
+                    if *aa == b'*' {
+                        // 找到终止密码子，停止翻译
+                        break;
+                    }
+                    // 添加氨基酸
+                    protein.push(*aa);
+                }
+                None => {
+                    // 无法识别的密码子 (例如 'N')，翻译为 'X'
+                    protein.push(b'X');
+                }
+            }
+        }
+        protein
+    }
+    // --------------------------------------------------------------------------------
+    // 修复结束
+    // --------------------------------------------------------------------------------
+
+    /// Uppercases a base; with `rna`, U is read as T so RNA codons use the same table.
+    fn normalize_base(base: u8, rna: bool) -> u8 {
+        match base.to_ascii_uppercase() {
+            b'U' if rna => b'T',
+            upper => upper,
+        }
+    }
+
+    /// An open reading frame found by `--orf`.
+    struct Orf {
+        // "+1".."+3" or "-1".."-3"
+        frame: String,
+        // 1-based forward-strand coordinates of the first base and the last stop-codon base;
+        // start > end on the minus strand
+        start: usize,
+        end: usize,
+        protein: Vec<u8>,
+    }
+
+    /// Finds the ORFs of all six frames: a start codon up to the next in-frame stop (not included in the protein).
+    /// Only the first start before a stop opens an ORF, so nested starts yield no shorter copies, and an
+    /// ORF still open at the end of the sequence is not reported.
+    fn find_orfs(seq: &[u8], table: &CodonTable, rna: bool, start_codons: &[[u8; 3]]) -> Vec<Orf> {
+        let len = seq.len();
+        let forward: Vec<u8> = seq.iter().map(|&b| normalize_base(b, rna)).collect();
+        let reverse = revcomp(&forward);
+        let mut orfs = Vec::new();
+        for (strand, sign) in [(&forward, '+'), (&reverse, '-')] {
+            for offset in 0..3.min(len) {
+                // (0-based strand position of the start codon, protein so far)
+                let mut open: Option<(usize, Vec<u8>)> = None;
+                for (i, bases) in strand[offset..].chunks_exact(3).enumerate() {
+                    let pos = offset + 3 * i;
+                    let codon = [bases[0], bases[1], bases[2]];
+                    let aa = table.get(&codon).copied().unwrap_or(b'X');
+                    match open.as_mut() {
+                        Some((orf_start, protein)) => {
+                            if aa == b'*' {
+                                let (start, end) = match sign {
+                                    '+' => (*orf_start + 1, pos + 3),
+                                    _ => (len - *orf_start, len - pos - 2),
+                                };
+                                orfs.push(Orf { frame: format!("{}{}", sign, offset + 1), start, end, protein: std::mem::take(protein) });
+                                open = None;
+                            } else {
+                                protein.push(aa);
+                            }
+                        }
+                        None if start_codons.contains(&codon) => open = Some((pos, vec![b'M'])),
+                        None => {}
+                    }
+                }
+            }
+        }
+        orfs
+    }
+
+    /// Parses `--start-codons` into uppercase DNA codons (U is accepted as T).
+    fn parse_start_codons(codons: &[String]) -> Result<Vec<[u8; 3]>> {
+        codons
+            .iter()
+            .map(|c| {
+                let bases: Vec<u8> = c.trim().bytes().map(|b| normalize_base(b, true)).collect();
+                match bases.as_slice() {
+                    [a, b, c] if bases.iter().all(|b| b"ACGT".contains(b)) => Ok([*a, *b, *c]),
+                    _ => Err(anyhow!("Invalid start codon '{}': expected three of A/C/G/T", c)),
+                }
+            })
+            .collect()
+    }
+
+    /// Processes a single FASTA file: translates it and saves the result.
+    fn process_single_file(
+        input_path: &Path,
+        args: &Args,
+        table: &CodonTable, // <-- 接收密码子表
+        start_codons: &[[u8; 3]],
+    ) -> Result<u64> {
+        let (output_dir, min_aa_length, rna, frames, desc) = (&args.output, args.aa_length, args.rna, args.frames, args.desc);
+        // 1. Determine output path (x.fa.gz -> x_protein.fasta)
+        let file_name = input_path.file_name().unwrap_or_default().to_string_lossy();
+        let unzipped = Path::new(strip_compression_ext(&file_name));
+        let file_stem = unzipped
+            .file_stem()
+            .ok_or_else(|| anyhow!("Could not get file stem for {:?}", input_path))?;
+        let output_filename = format!("{}_protein.fasta", file_stem.to_string_lossy());
+        let output_path = output_dir.join(output_filename);
+
+        // 2. Setup reader and writer
+        let reader = fasta::Reader::new(
+            open_input(input_path).with_context(|| format!("Failed to open input file: {:?}", input_path))?,
+        );
+        let mut writer = fasta::Writer::to_file(&output_path)
+            .with_context(|| format!("Failed to create output file: {:?}", output_path))?;
+
+        let mut records_written: u64 = 0;
+        let mut warned_rna = rna;
+
+        // 3. Translation logic
+        for result in reader.records() {
+            let record = result?;
+            if !warned_rna && has_uracil(record.seq()) {
+                eprintln!("Warning: {:?} contains U (RNA); U codons translate as X unless --rna is given", input_path);
+                warned_rna = true;
+            }
+
+            if args.orf {
+                let orfs = find_orfs(record.seq(), table, rna, start_codons);
+                let kept: Vec<&Orf> = if args.all_orfs {
+                    orfs.iter().filter(|orf| orf.protein.len() >= min_aa_length).collect()
+                } else {
+                    // Longest ORF; ties go to the first found
+                    let longest = orfs.iter().fold(None::<&Orf>, |best, orf| match best {
+                        Some(b) if b.protein.len() >= orf.protein.len() => Some(b),
+                        _ => Some(orf),
+                    });
+                    longest.filter(|orf| orf.protein.len() >= min_aa_length).into_iter().collect()
+                };
+                for orf in kept {
+                    let id = format!("{}_frame{}_{}-{}", record.id(), orf.frame, orf.start, orf.end);
+                    writer.write_record(&fasta::Record::with_attrs(&id, desc.apply(record.desc()), &orf.protein))?;
+                    records_written += 1;
+                }
+                continue;
+            }
+            
+            // Reverse frames read the reverse complement; U is mapped to T first since revcomp leaves it as is
+            let reverse = (frames == 6).then(|| {
+                if rna {
+                    revcomp(record.seq().iter().map(|&b| match b { b'U' => b'T', b'u' => b't', _ => b }))
+                } else {
+                    revcomp(record.seq())
+                }
+            });
+            for (strand, sign) in std::iter::once((record.seq(), '+')).chain(reverse.as_deref().map(|s| (s, '-'))) {
+                for offset in 0..frames.min(3) {
+                    // Translate the DNA sequence, stopping at the first STOP codon
+                    let protein = translate_to_stop(strand.get(offset..).unwrap_or_default(), table, rna); // <-- 传入密码子表
+
+                    if protein.len() >= min_aa_length {
+                        // Create a new FASTA record for the protein
+                        let id = if frames == 1 {
+                            record.id().to_string()
+                        } else {
+                            format!("{}_frame{}{}", record.id(), sign, offset + 1)
+                        };
+                        let aa_record = fasta::Record::with_attrs(&id, desc.apply(record.desc()), &protein);
+                        writer.write_record(&aa_record)?;
+                        records_written += 1;
+                    }
+                }
+            }
+        }
+
+        if records_written > 0 {
+             println!(
+                "Processed {:?} -> {:?} (Wrote {} records)",
+                input_path.file_name().unwrap_or_default(),
+                output_path.file_name().unwrap_or_default(),
+                records_written
+            );
+        }
+
+        Ok(records_written)
+    }
+
+    /// Main run function for the DNA2AA subcommand
+    pub fn run(args: Args) -> Result<RunReport> {
+        let start_time = std::time::Instant::now();
+        
+        if args.global.threads.is_some() {
+            rayon::ThreadPoolBuilder::new().num_threads(args.global.threads_or(0)).build_global()?;
+        }
+
+        // 1. 创建密码子表并用 Arc 包装，以便安全地跨线程共享
+        let codon_table = Arc::new(build_codon_table(args.table));
+        let start_codons = parse_start_codons(&args.start_codons)?;
+
+        // 2. Create output directory
+        fs::create_dir_all(&args.output)
+            .with_context(|| format!("Failed to create output directory: {:?}", args.output))?;
+
+        // 3. Find all input files; a single file is taken as is, whatever its extension
+        let input_files: Vec<PathBuf> = if args.input.is_file() {
+            vec![args.input.clone()]
+        } else {
+            fs::read_dir(&args.input)
+                .with_context(|| format!("Failed to read input directory: {:?}", args.input))?
+                .filter_map(|entry_result| {
+                    let entry = entry_result.ok()?;
+                    let path = entry.path();
+                    if path.is_file() {
+                        let name = path.file_name()?.to_str()?;
+                        let name = strip_compression_ext(name);
+                        // Match common FASTA extensions
+                        if name.ends_with(".fasta") || name.ends_with(".fa") || name.ends_with(".fna") {
+                            return Some(path);
+                        }
+                    }
+                    None
+                })
+                .collect()
+        };
+        
+        let mut report = RunReport::new("DNA2AA")
+            .input(&args.input)
+            .output(&args.output);
+
+        if input_files.is_empty() {
+             println!("Warning: No FASTA files (.fasta, .fa, .fna, optionally .gz) found in {:?}.", args.input);
+             return Ok(report.count("files_processed", 0));
+        }
+
+        println!(
+            "---> Found {} FASTA files to process in parallel...",
+            input_files.len()
+        );
+
+        // 4. Process files in parallel (similar to Python's ProcessPoolExecutor)
+        let results: Vec<Option<u64>> = input_files.par_iter().map(|input_path| {
+            // 为每个线程克隆 Arc 引用（开销很小）
+            let table_clone = Arc::clone(&codon_table);
+            match process_single_file(input_path, &args, &table_clone, &start_codons) {
+                Ok(written) => Some(written),
+                Err(e) => {
+                    // Print errors from within the parallel loop
+                    eprintln!("\n[Error] Failed to process file {:?}: {}\n", input_path.display(), e);
+                    None
+                }
+            }
+        }).collect();
+
+        let processed = results.iter().filter(|r| r.is_some()).count() as u64;
+        let written: u64 = results.iter().flatten().sum();
+        report = report
+            .count("files_processed", processed)
+            .count("files_failed", input_files.len() as u64 - processed)
+            .count("proteins_written", written);
+
+        println!("\n🎉 All files processed successfully! Total time: {:.2?}", start_time.elapsed());
+        println!("Results are in: {}", args.output.display());
+        Ok(report)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::common::test_util::{fasta_headers, test_dir};
+
+        #[test]
+        fn protein_headers_keep_or_strip_descriptions() {
+            let dir = test_dir("dna2aa_desc");
+            let input = dir.join("genes.fasta");
+            fs::write(&input, ">g1 sample=A\nATGGCCTAA\n").unwrap();
+            for (flag, expected) in [("--keep-desc", ">g1 sample=A"), ("--strip-desc", ">g1")] {
+                let out_dir = dir.join("out");
+                let args = Args::try_parse_from([
+                    "DNA2AA", "-i", input.to_str().unwrap(), "-o", out_dir.to_str().unwrap(), "--aa-length", "1", flag,
+                ])
+                .unwrap();
+                run(args).unwrap();
+                assert_eq!(fasta_headers(&out_dir.join("genes_protein.fasta")), [expected]);
+            }
+        }
+    }
+}
+
+// ==================================================================================
+// `count_AA` subcommand module (NEWLY ADDED)
+// ==================================================================================
+mod count_aa {
+    use super::common::{
+        indexed_record_count, open_input_with_format, open_reader, records_progress, write_count_sidecar, Format, GlobalOpts, RunReport,
+    };
+    use anyhow::{anyhow, Context, Result};
+    use bio::io::fasta::{self, Record};
+    use bio::io::fastq;
+    use clap::Parser;
+    use crossbeam_channel::bounded;
+    use dashmap::DashMap; // For concurrent counting
+    use glob::glob; // For file matching
+    use rayon::prelude::*; // For parallel iteration
+    use std::collections::{BTreeSet, HashMap, HashSet};
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Instant;
+
+    #[derive(Parser, Debug)]
+    #[command(name = "count_AA", about = "[NEW] Count AA mutations against a reference protein sequence, replicating the logic from Count_AAmutants.py")]
+    pub struct Args {
+        #[arg(short = 'r', long, help = "参考蛋白FASTA序列 (Reference protein FASTA sequence)")]
+        pub reference: PathBuf,
+
+        #[arg(short = 'i', long, help = "包含多个FASTA/FASTQ文件的目录 (Directory containing multiple FASTA/FASTQ files: *.fasta, *.fa, *.fastq, *.fq)")]
+        pub input_dir: PathBuf,
+
+        #[arg(short = 'o', long, help = "输出CSV文件的目录 (Output directory for CSV files)")]
+        pub output_dir: PathBuf,
+
+        #[arg(short = 'A', long, help = "位置偏移量 (Position offset)", default_value_t = 0)]
+        pub aa_offset: i32,
+
+        #[arg(short = 'c', long, help = "CSV配置文件，包含protected_sites列 (CSV config file with 'protected_sites' column)")]
+        pub config: Option<PathBuf>,
+
+        #[arg(long, help = "匹配参考起始位置的氨基酸数量 (Number of AAs to match ref start)", default_value_t = 6)]
+        pub match_len: usize,
+
+        #[arg(long, help = "每块reads数量 (Number of reads per chunk)", default_value_t = 100000)]
+        pub chunk_size: usize,
+
+        #[arg(long, alias = "matrix", help = "合并宽表CSV：行为突变、列为文件 (Combined wide CSV: mutations as rows, files as columns, 0 where absent; alias --matrix)")]
+        pub combined_out: Option<PathBuf>,
+
+        #[arg(long, help = "去除参考序列两端的非标准残基如X/*，并自动调整偏移量 (Strip leading/trailing non-standard residues such as X/* from the reference and adjust --aa-offset)")]
+        pub trim_ref_ends: bool,
+
+        #[arg(long, default_value = "", help = "输出文件名前缀 (Prefix prepended to every per-file output CSV name)")]
+        pub output_prefix: String,
+
+        #[arg(long, help = "运行标识，写入每个CSV首行注释 (Run ID stamped as a '# run_id=<id>' first line in each output CSV)")]
+        pub run_id: Option<String>,
+
+        #[arg(long, help = "允许reads在参考起点之前多出的最大氨基酸数，多出部分将被跳过 (Max leading AAs a read may extend 5' of the reference start; the overhang is skipped, 0 = disabled)", default_value_t = 0)]
+        pub max_5p_overhang: usize,
+
+        #[arg(long, help = "多参考模式：使用参考FASTA中的所有序列(或目录中的所有FASTA)，每条read计入最匹配的参考 (Use every record of --reference, or every FASTA in a --reference directory; each read is counted against its best-matching reference)")]
+        pub reference_multiple: bool,
+
+        #[arg(long, help = "进度条：从 <file>.fai 或 <file>.count 读取reads总数 (Show a determinate progress bar using the record count from <file>.fai or <file>.count; if neither exists, <file>.count is written after this run)")]
+        pub progress_total_from_index: bool,
+
+        #[arg(long, help = "位置×氨基酸频率矩阵CSV，所有文件合并为长表 (Position x AA frequency matrix CSV over all files: one row per file and reference position, one column per residue of the 21-letter alphabet ACDEFGHIKLMNPQRSTVWY*)")]
+        pub freq_matrix: Option<PathBuf>,
+
+        #[arg(long, help = "每个文件的reads去向统计CSV (Also write <stem>_qc.csv per file with the read breakdown: total, no start match, protected-site violations, >1 mutation, valid)")]
+        pub qc_csv: bool,
+
+        #[arg(long, requires = "freq_matrix", default_value_t = 0.0, help = "频率伪计数 (Pseudocount C added to every cell: (count + C) / (coverage + 21*C), where coverage counts only residues of the 21-letter alphabet)")]
+        pub pseudocount: f64,
+
+        #[command(flatten)]
+        pub global: GlobalOpts,
+    }
+
+    /// Columns of `--freq-matrix`: the 20 standard amino acids plus the stop '*'. Other residues (X, gaps)
+    /// are left out of both the counts and the coverage, so every row sums to 1.
+    const FREQ_ALPHABET: &[u8; 21] = b"ACDEFGHIKLMNPQRSTVWY*";
+
+    /// Per-file read counters shared by all worker threads.
+    #[derive(Default)]
+    struct ChunkTotals {
+        reads: AtomicU64,
+        valid: AtomicU64,
+        overhang: AtomicU64,
+        // Reads whose start matched no reference (empty reads included)
+        unmatched: AtomicU64,
+        // Reads dropped for changing a protected site
+        violations: AtomicU64,
+        // Counted reads that are not valid because they carry more than one mutation
+        multi_mutation: AtomicU64,
+        // Reads assigned to each reference, in reference order
+        assigned: Vec<AtomicU64>,
+    }
+
+    /// A reference protein prepared for counting.
+    struct Reference {
+        name: String,
+        seq: Vec<u8>,
+        // 0-based indices into `seq`
+        protected: HashSet<usize>,
+        // Added to the 1-based position in `seq` when naming mutations
+        aa_offset: i32,
+    }
+
+    /// Per-position concurrent counters of one reference: AA (u8) -> AtomicU64 (count)
+    type RefCounts = Vec<DashMap<u8, AtomicU64>>;
+
+    /// (Helper) Loads (ID, sequence) records from a FASTA file.
+    fn load_fasta_records(path: &Path) -> Result<Vec<(String, Vec<u8>)>> {
+        let file = open_reader(path)
+            .with_context(|| format!("Failed to open reference file: {:?}", path))?;
+        let reader = fasta::Reader::new(file);
+        let mut records = Vec::new();
+        for result in reader.records() {
+            let record = result.with_context(|| format!("Failed to read reference file: {:?}", path))?;
+            records.push((record.id().to_string(), record.seq().to_ascii_uppercase()));
+        }
+        Ok(records)
+    }
+
+    /// (Helper) Loads the reference sequence(s).
+    /// Without `multiple` only the first record is used; with it, every record of the file
+    /// (or of every .fasta/.fa file when `path` is a directory) becomes a reference.
+    fn load_references(path: &Path, multiple: bool) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut records = if path.is_dir() {
+            if !multiple {
+                return Err(anyhow!("--reference is a directory; pass --reference-multiple to use every FASTA in it"));
+            }
+            let mut files: Vec<PathBuf> = glob(&path.join("*.fasta").to_string_lossy())?
+                .filter_map(Result::ok)
+                .chain(glob(&path.join("*.fa").to_string_lossy())?.filter_map(Result::ok))
+                .collect();
+            files.sort();
+            let mut records = Vec::new();
+            for file in files {
+                records.extend(load_fasta_records(&file)?);
+            }
+            records
+        } else {
+            load_fasta_records(path)?
+        };
+        if records.is_empty() {
+            return Err(anyhow!("Reference FASTA file is empty: {:?}", path));
+        }
+        if !multiple {
+            records.truncate(1);
+        }
+        let mut seen = HashSet::new();
+        for (name, _) in &records {
+            if !seen.insert(name.as_str()) {
+                return Err(anyhow!("Duplicate reference ID '{}' in {:?}", name, path));
+            }
+        }
+        Ok(records)
+    }
+
+    /// (Helper) Strips leading/trailing residues outside the 20 standard amino acids.
+    /// Returns the trimmed sequence and the number of residues removed from the start.
+    fn trim_reference_ends(seq: &[u8]) -> (Vec<u8>, usize) {
+        const STANDARD_AA: &[u8] = b"ACDEFGHIKLMNPQRSTVWY";
+        let start = seq.iter().position(|aa| STANDARD_AA.contains(aa)).unwrap_or(seq.len());
+        let end = seq.iter().rposition(|aa| STANDARD_AA.contains(aa)).map_or(start, |i| i + 1);
+        (seq[start..end].to_vec(), start)
+    }
+
+    /// (Helper) Loads protected sites from the config CSV.
+    fn load_config(path: &Option<PathBuf>) -> Result<HashSet<usize>> {
+        let mut protected = HashSet::new();
+        let config_path = match path {
+            Some(p) => p,
+            None => return Ok(protected), // No config, return empty set
+        };
+
+        println!("---> Loading config: {}", config_path.display());
+        let file = File::open(config_path)
+            .with_context(|| format!("Failed to open config file: {:?}", config_path))?;
+        let mut rdr = csv::Reader::from_reader(file);
+
+        // Find the 'protected_sites' column index
+        let headers = rdr.headers()?.clone();
+        let site_col_idx = headers.iter().position(|h| h == "protected_sites");
+
+        let site_col_idx = match site_col_idx {
+            Some(idx) => idx,
+            None => {
+                println!("Warning: Config file provided, but 'protected_sites' column not found.");
+                return Ok(protected);
+            }
+        };
+
+        // Iterate through records and parse sites
+        for result in rdr.records() {
+            let record = result?;
+            if let Some(val_str) = record.get(site_col_idx) {
+                if val_str.is_empty() { continue; }
+                
+                match val_str.trim().parse::<f64>() { // Parse as f64 to match Python's behavior
+                    Ok(val) => {
+                        let site_1_based = val as usize;
+                        if site_1_based > 0 {
+                            protected.insert(site_1_based - 1); // Convert to 0-based index
+                        }
+                    }
+                    Err(_) => {
+                         println!("Warning: Could not parse protected site value '{}'", val_str);
+                    }
+                }
+            }
+        }
+        
+        println!("Loaded {} protected sites from config.", protected.len());
+        Ok(protected)
+    }
+
+    /// (Helper) Locates a read on a reference by matching its first `match_len` AAs.
+    /// Returns (reference start, leading read residues to skip).
+    fn find_anchor(read: &[u8], reference_seq: &[u8], match_len: usize, max_overhang: usize) -> Option<(usize, usize)> {
+        // Prevent panic on reads shorter than match_len
+        let read_start_segment = &read[..match_len.min(read.len())];
+        if read_start_segment.is_empty() {
+            return None;
+        }
+
+        // Find start position (Rust equivalent of Python's `str.find()`)
+        if let Some(pos) = reference_seq
+            .windows(read_start_segment.len())
+            .position(|window| window == read_start_segment)
+        {
+            return Some((pos, 0));
+        }
+
+        // Read starts 5' of the reference: look for the reference start
+        // a few residues into the read and skip the leading overhang.
+        let ref_head = &reference_seq[..match_len.min(reference_seq.len())];
+        (1..=max_overhang)
+            .take_while(|k| k + ref_head.len() <= read.len())
+            .find(|&k| &read[k..k + ref_head.len()] == ref_head)
+            .map(|k| (0, k))
+    }
+
+    /// (Helper) This is the core logic from the Python `analyze_chunk` function.
+    /// It processes a chunk of reads and updates the global concurrent counters.
+    /// With several references, each read is counted against the anchored reference it differs from least
+    /// (ties go to the earlier reference).
+    fn analyze_chunk(
+        references: &[Reference],
+        reads: Vec<fasta::Record>,
+        match_len: usize,
+        max_overhang: usize,
+        aa_counts: &[RefCounts], // One slice of concurrent maps per reference
+        totals: &ChunkTotals,
+    ) {
+        totals.reads.fetch_add(reads.len() as u64, Ordering::Relaxed);
+        let mut local_valid_reads = 0;
+        let mut local_overhang_reads = 0;
+        let mut local_unmatched_reads = 0;
+        let mut local_violations = 0;
+        let mut local_multi_mutation = 0;
+
+        for record in reads {
+            let read = record.seq().to_ascii_uppercase();
+            if read.is_empty() {
+                local_unmatched_reads += 1;
+                continue;
+            }
+
+            // (reference index, reference start, skipped residues, mismatches)
+            let mut best: Option<(usize, usize, usize, usize)> = None;
+            for (idx, reference) in references.iter().enumerate() {
+                let Some((ref_start, skip)) = find_anchor(&read, &reference.seq, match_len, max_overhang) else {
+                    continue;
+                };
+                let mismatches = read[skip..]
+                    .iter()
+                    .zip(&reference.seq[ref_start..])
+                    .filter(|(aa, ref_aa)| aa != ref_aa)
+                    .count();
+                if best.is_none_or(|(_, _, _, fewest)| mismatches < fewest) {
+                    best = Some((idx, ref_start, skip, mismatches));
+                }
+            }
+            let Some((ref_idx, ref_start, skip, mutation_count)) = best else {
+                local_unmatched_reads += 1;
+                continue; // Not found
+            };
+            if skip > 0 {
+                local_overhang_reads += 1;
+            }
+            let reference = &references[ref_idx];
+            totals.assigned[ref_idx].fetch_add(1, Ordering::Relaxed);
+
+            // Reads that change a protected site are not counted at all
+            let violate = read[skip..]
+                .iter()
+                .zip(&reference.seq[ref_start..])
+                .enumerate()
+                .any(|(i, (aa, ref_aa))| aa != ref_aa && reference.protected.contains(&(ref_start + i)));
+
+            // Only if the read is valid do we add its counts to the global map
+            if !violate {
+                for (i, &aa) in read[skip..].iter().take(reference.seq.len() - ref_start).enumerate() {
+                    // Get or create an AtomicU64 counter for this AA and increment it atomically
+                    aa_counts[ref_idx][ref_start + i]
+                        .entry(aa)
+                        .or_insert_with(|| AtomicU64::new(0))
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+
+                // Check the *other* condition for a "valid read"
+                if mutation_count <= 1 {
+                    local_valid_reads += 1;
+                } else {
+                    local_multi_mutation += 1;
+                }
+            } else {
+                local_violations += 1;
+            }
+        }
+
+        // Atomically update the global "valid", "overhang" and "unmatched" counters
+        totals.valid.fetch_add(local_valid_reads, Ordering::Relaxed);
+        totals.overhang.fetch_add(local_overhang_reads, Ordering::Relaxed);
+        totals.unmatched.fetch_add(local_unmatched_reads, Ordering::Relaxed);
+        totals.violations.fetch_add(local_violations, Ordering::Relaxed);
+        totals.multi_mutation.fetch_add(local_multi_mutation, Ordering::Relaxed);
+    }
+
+
+    /// (Helper) Creates a CSV writer, first writing a `# run_id=<id>` comment line when a run ID is given.
+    fn create_csv(path: &Path, run_id: Option<&str>) -> Result<csv::Writer<File>> {
+        let mut file = File::create(path)
+            .with_context(|| format!("Failed to create output CSV: {:?}", path))?;
+        if let Some(id) = run_id {
+            writeln!(file, "# run_id={}", id)?;
+        }
+        Ok(csv::Writer::from_writer(file))
+    }
+
+    /// (Helper) Appends one reference's `--freq-matrix` rows for a file:
+    /// each cell is (count + C) / (coverage + C * alphabet size), 0 where that is 0/0.
+    fn write_freq_rows(wtr: &mut csv::Writer<File>, table_name: &str, reference: &Reference, counts: &RefCounts, pseudocount: f64) -> Result<()> {
+        for (i, counter_map) in counts.iter().enumerate() {
+            let cells: Vec<u64> = FREQ_ALPHABET
+                .iter()
+                .map(|aa| counter_map.get(aa).map_or(0, |c| c.load(Ordering::Relaxed)))
+                .collect();
+            let coverage: u64 = cells.iter().sum();
+            let denominator = coverage as f64 + pseudocount * FREQ_ALPHABET.len() as f64;
+            let mut row = vec![
+                table_name.to_string(),
+                ((i as i32) + 1 + reference.aa_offset).to_string(),
+                (reference.seq[i] as char).to_string(),
+                coverage.to_string(),
+            ];
+            row.extend(cells.iter().map(|&n| {
+                let freq = if denominator > 0.0 { (n as f64 + pseudocount) / denominator } else { 0.0 };
+                format!("{:.6}", freq)
+            }));
+            wtr.write_record(&row)?;
+        }
+        Ok(())
+    }
+
+    /// (Helper) Writes one wide CSV: the union of all mutations as rows, one count column per file (0 where absent).
+    fn write_combined_matrix(path: &Path, per_file: &[(String, HashMap<String, u64>)], run_id: Option<&str>) -> Result<()> {
+        let all_mutations: BTreeSet<&String> = per_file.iter().flat_map(|(_, m)| m.keys()).collect();
+
+        let mut wtr = create_csv(path, run_id)?;
+        let mut header = vec!["Mutation".to_string()];
+        header.extend(per_file.iter().map(|(name, _)| name.clone()));
+        wtr.write_record(&header)?;
+
+        for mutation in all_mutations {
+            let mut row = vec![mutation.clone()];
+            row.extend(per_file.iter().map(|(_, m)| m.get(mutation).copied().unwrap_or(0).to_string()));
+            wtr.write_record(&row)?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// Main run function for the count_AA subcommand
+    pub fn run(args: Args) -> Result<RunReport> {
+        let main_start_time = Instant::now();
+        // Parallel threads per file, default 8
+        let threads = args.global.threads_or(8);
+        if !(args.pseudocount >= 0.0 && args.pseudocount.is_finite()) {
+            return Err(anyhow!("--pseudocount must be a finite value >= 0"));
+        }
+        if args.match_len == 0 {
+            return Err(anyhow!("--match-len must be at least 1"));
+        }
+        
+        fs::create_dir_all(&args.output_dir)
+            .with_context(|| format!("Failed to create output directory: {:?}", args.output_dir))?;
+
+        // 1. Load Reference(s) and Config
+        let config_sites = load_config(&args.config)?;
+        let mut references = Vec::new();
+        for (name, mut seq) in load_references(&args.reference, args.reference_multiple)? {
+            let mut leading = 0;
+            let mut aa_offset = args.aa_offset;
+            if args.trim_ref_ends {
+                let original_len = seq.len();
+                let (trimmed, removed) = trim_reference_ends(&seq);
+                seq = trimmed;
+                leading = removed;
+                aa_offset += leading as i32;
+                println!(
+                    "Trimmed reference {} ends: {} -> {} AAs ({} leading, {} trailing removed); aa_offset adjusted by +{} to {}.",
+                    name, original_len, seq.len(), leading, original_len - seq.len() - leading, leading, aa_offset
+                );
+            }
+            if seq.is_empty() {
+                return Err(anyhow!("Reference {} has no residues left to count against", name));
+            }
+            // Protected sites are given against the untrimmed reference
+            let protected = config_sites.iter().filter_map(|site| site.checked_sub(leading)).collect();
+            println!("Reference sequence {} loaded ({} AAs).", name, seq.len());
+            references.push(Reference { name, seq, protected, aa_offset });
+        }
+        let references = Arc::new(references);
+
+        // 2. Find input FASTA/FASTQ files (using `glob` crate)
+        let mut fasta_files: Vec<PathBuf> = Vec::new();
+        for ext in ["fasta", "fa", "fastq", "fq"] {
+            let pattern = args.input_dir.join(format!("*.{}", ext)).to_string_lossy().to_string();
+            fasta_files.extend(glob(&pattern)?.filter_map(Result::ok));
+        }
+
+        let mut report = RunReport::new("count_AA")
+            .input(&args.reference)
+            .input(&args.input_dir)
+            .output(&args.output_dir);
+        if let Some(combined_path) = &args.combined_out {
+            report = report.output(combined_path);
+        }
+        if let Some(freq_path) = &args.freq_matrix {
+            report = report.output(freq_path);
+        }
+
+        if fasta_files.is_empty() {
+            println!("No FASTA/FASTQ files (.fasta, .fa, .fastq, .fq) found in {:?}.", args.input_dir);
+            return Ok(report);
+        }
+
+        println!("Processing {} FASTA/FASTQ files in parallel ({} threads per file)...", fasta_files.len(), threads);
+
+        // 3. Configure Rayon global thread pool
+        // This sets the *total* number of threads Rayon will use.
+        rayon::ThreadPoolBuilder::new().num_threads(threads).build_global()?;
+
+        // Per-file mutation counts, only retained when --combined-out is set
+        let mut combined: Vec<(String, HashMap<String, u64>)> = Vec::new();
+        let mut freq_wtr = match &args.freq_matrix {
+            Some(path) => {
+                let mut wtr = create_csv(path, args.run_id.as_deref())?;
+                let mut header: Vec<String> = ["File", "Position", "RefAA", "Coverage"].iter().map(|h| h.to_string()).collect();
+                header.extend(FREQ_ALPHABET.iter().map(|&aa| (aa as char).to_string()));
+                wtr.write_record(&header)?;
+                Some(wtr)
+            }
+            None => None,
+        };
+
+        // 4. Process each file (sequentially, as in Python)
+        // The parallelism is *within* each file's chunk processing.
+        for fasta_file in fasta_files {
+            let file_start_time = Instant::now();
+            let file_stem = fasta_file.file_stem().unwrap_or_default().to_string_lossy();
+            println!("\n---> Processing file: {}", fasta_file.display());
+
+            // --- Setup concurrent data structures for this file ---
+            // For each reference, a Vec of DashMaps with one map per reference position
+            let global_counts: Arc<Vec<RefCounts>> = Arc::new(
+                references.iter().map(|r| (0..r.seq.len()).map(|_| DashMap::new()).collect()).collect(),
+            );
+
+            let totals = Arc::new(ChunkTotals {
+                assigned: references.iter().map(|_| AtomicU64::new(0)).collect(),
+                ..Default::default()
+            });
+
+            // Create Arcs for data to be shared across threads
+            let references_clone = Arc::clone(&references);
+            let global_counts_clone = Arc::clone(&global_counts);
+            let totals_clone = Arc::clone(&totals);
+            
+            let (tx, rx) = bounded::<Vec<fasta::Record>>(threads * 2); // Channel for chunks of records
+
+            let indexed_total = if args.progress_total_from_index { indexed_record_count(&fasta_file)? } else { None };
+            let pb = args.progress_total_from_index.then(|| records_progress(indexed_total, "reads")).transpose()?.map(|pb| args.global.progress(pb));
+
+            // --- Use thread::scope for structured concurrency ---
+            let res: Result<()> = thread::scope(|s| {
+                // --- 1. Reader Thread ---
+                // This thread reads the FASTA file and sends chunks of records to the channel
+                let fasta_file_clone = fasta_file.clone();
+                let chunk_size = args.chunk_size;
+                let pb = pb.clone();
+                s.spawn(move || {
+                    let (format, reader) = match open_input_with_format(&fasta_file_clone) {
+                        Ok(opened) => opened,
+                        Err(e) => {
+                            eprintln!("Error opening {}: {:?}", fasta_file_clone.display(), e);
+                            return; // Exit thread
+                        }
+                    };
+                    // FASTQ reads are carried as FASTA records; quality is never used
+                    let mut records_iter: Box<dyn Iterator<Item = Result<Record>>> = match format {
+                        Format::Fasta => Box::new(fasta::Reader::new(reader).records().map(|r| r.map_err(Into::into))),
+                        Format::Fastq => Box::new(fastq::Reader::new(reader).records().map(|r| {
+                            r.map(|rec| Record::with_attrs(rec.id(), rec.desc(), rec.seq())).map_err(Into::into)
+                        })),
+                    };
+
+                    loop {
+                        let mut chunk = Vec::with_capacity(chunk_size);
+                        for _ in 0..chunk_size {
+                            match records_iter.next() {
+                                Some(Ok(record)) => chunk.push(record),
+                                Some(Err(e)) => {
+                                    eprintln!("Error reading record from {}: {}", fasta_file_clone.display(), e);
+                                    // Continue to next record
+                                },
+                                None => break, // End of file
+                            }
+                        }
+                        
+                        let is_empty = chunk.is_empty();
+                        if let Some(pb) = &pb {
+                            pb.inc(chunk.len() as u64);
+                        }
+                        if tx.send(chunk).is_err() {
+                            break; // Receiver hung up, stop reading
+                        }
+                        if is_empty {
+                            break; // End of file
+                        }
+                    }
+                });
+
+                // --- 2. Worker Pool (using Rayon) ---
+                // This consumes chunks from the channel (rx)
+                // `par_bridge` turns the channel iterator into a parallel iterator
+                // `for_each` processes each chunk in parallel using the Rayon thread pool
+                rx.into_iter().par_bridge().for_each(|chunk: Vec<Record>| {
+                    analyze_chunk(
+                        &references_clone,
+                        chunk,
+                        args.match_len,
+                        args.max_5p_overhang,
+                        &global_counts_clone,
+                        &totals_clone,
+                    );
+                });
+
+                Ok(())
+            }); // --- End of thread::scope ---
+
+            if let Err(e) = res {
+                eprintln!("Error during processing {}: {:?}", fasta_file.display(), e);
+                continue; // Skip to next file
+            }
+
+            // --- 3. Collate and Write Results for this file ---
+            let total_r = totals.reads.load(Ordering::Relaxed);
+            if let Some(pb) = &pb {
+                pb.finish_and_clear();
+                if indexed_total.is_none() {
+                    if let Err(e) = write_count_sidecar(&fasta_file, total_r) {
+                        eprintln!("Warning: {:#}", e);
+                    }
+                }
+            }
+            let total_v = totals.valid.load(Ordering::Relaxed);
+            println!("{} - Valid reads: {} / {}", file_stem, total_v, total_r);
+            report = report
+                .count(format!("{}.total_reads", file_stem), total_r)
+                .count(format!("{}.valid_reads", file_stem), total_v);
+            if args.max_5p_overhang > 0 {
+                let total_o = totals.overhang.load(Ordering::Relaxed);
+                println!("{} - Reads with 5' overhang: {}", file_stem, total_o);
+                report = report.count(format!("{}.overhang_reads", file_stem), total_o);
+            }
+
+            // Every read lands in exactly one of these
+            let breakdown = [
+                ("total_reads", total_r),
+                ("unmatched_reads", totals.unmatched.load(Ordering::Relaxed)),
+                ("protected_site_violation", totals.violations.load(Ordering::Relaxed)),
+                ("multi_mutation", totals.multi_mutation.load(Ordering::Relaxed)),
+                ("valid_reads", total_v),
+            ];
+            println!(
+                "{} - Read breakdown: no start match {}, protected-site violations {} (dropped), >1 mutation {} (counted, not valid), valid {}",
+                file_stem, breakdown[1].1, breakdown[2].1, breakdown[3].1, breakdown[4].1
+            );
+            for &(key, value) in &breakdown[1..4] {
+                report = report.count(format!("{}.{}", file_stem, key), value);
+            }
+            if args.qc_csv {
+                let qc_path = args.output_dir.join(format!("{}{}_qc.csv", args.output_prefix, file_stem));
+                let mut qc_wtr = create_csv(&qc_path, args.run_id.as_deref())?;
+                qc_wtr.write_record(["Metric", "Reads"])?;
+                for (key, value) in breakdown {
+                    qc_wtr.write_record([key.to_string(), value.to_string()])?;
+                }
+                qc_wtr.flush()?;
+            }
+
+            for (ref_idx, reference) in references.iter().enumerate() {
+                let mut mutation_stats = Vec::new();
+                for (i, counter_map) in global_counts[ref_idx].iter().enumerate() {
+                    let ref_aa = reference.seq[i]; // Get the reference AA at this position
+                    let adj_pos = (i as i32) + 1 + reference.aa_offset; // Calculate the adjusted position
+
+                    for item in counter_map.iter() {
+                        let aa = *item.key();
+                        let count = item.value().load(Ordering::Relaxed);
+                        if count > 0 {
+                            // Format: e.g., "A123C"
+                            let mutation_str = format!("{}{}{}", ref_aa as char, adj_pos, aa as char);
+                            mutation_stats.push((mutation_str, count));
+                        }
+                    }
+                }
+
+                // Sort by mutation string (e.g., "A10C" before "A11G")
+                mutation_stats.sort_by(|a, b| a.0.cmp(&b.0));
+
+                // Multi-reference runs get one table per file and reference
+                let table_name = if args.reference_multiple {
+                    let assigned = totals.assigned[ref_idx].load(Ordering::Relaxed);
+                    println!("{} - Reads assigned to {}: {}", file_stem, reference.name, assigned);
+                    report = report.count(format!("{}.{}.assigned_reads", file_stem, reference.name), assigned);
+                    format!("{}_{}", file_stem, reference.name)
+                } else {
+                    file_stem.to_string()
+                };
+
+                // Write to CSV
+                let output_file_name = format!("{}{}_mutation.csv", args.output_prefix, table_name);
+                let output_path = args.output_dir.join(output_file_name);
+
+                let mut wtr = create_csv(&output_path, args.run_id.as_deref())?;
+
+                wtr.write_record(["Mutation", "Count"])?;
+                for (mutation, count) in &mutation_stats {
+                    wtr.write_record(&[mutation.clone(), count.to_string()])?;
+                }
+
+                wtr.flush()?;
+                println!("Results saved to: {}", output_path.display());
+
+                // Reads spanning each position, whatever residue they carry there
+                let coverage_path = args.output_dir.join(format!("{}{}_coverage.csv", args.output_prefix, table_name));
+                let mut cov_wtr = create_csv(&coverage_path, args.run_id.as_deref())?;
+                cov_wtr.write_record(["Position", "RefAA", "Coverage"])?;
+                for (i, counter_map) in global_counts[ref_idx].iter().enumerate() {
+                    let coverage: u64 = counter_map.iter().map(|item| item.value().load(Ordering::Relaxed)).sum();
+                    cov_wtr.write_record([
+                        ((i as i32) + 1 + reference.aa_offset).to_string(),
+                        (reference.seq[i] as char).to_string(),
+                        coverage.to_string(),
+                    ])?;
+                }
+                cov_wtr.flush()?;
+
+                if let Some(freq_wtr) = freq_wtr.as_mut() {
+                    write_freq_rows(freq_wtr, &table_name, reference, &global_counts[ref_idx], args.pseudocount)?;
+                }
+
+                if args.combined_out.is_some() {
+                    combined.push((table_name, mutation_stats.into_iter().collect()));
+                }
+            }
+            println!("Time taken for {}: {:.2?}", file_stem, file_start_time.elapsed());
+        }
+
+        if let Some(combined_path) = &args.combined_out {
+            write_combined_matrix(combined_path, &combined, args.run_id.as_deref())?;
+            println!("Combined matrix saved to: {}", combined_path.display());
+        }
+        if let (Some(mut wtr), Some(freq_path)) = (freq_wtr, &args.freq_matrix) {
+            wtr.flush()?;
+            println!("Frequency matrix saved to: {}", freq_path.display());
+        }
+
+        println!("\n🎉 All files have been processed. Total time: {:.2?}", main_start_time.elapsed());
+        Ok(report)
+    }
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn find_anchor_locates_reads_and_skips_overhangs() {
+            let reference = b"MKTAYIAKQR";
+            assert_eq!(find_anchor(b"AYIAKQ", reference, 3, 2), Some((3, 0)));
+            // Two leading residues 5' of the reference start
+            assert_eq!(find_anchor(b"GGMKTAY", reference, 3, 2), Some((0, 2)));
+            assert_eq!(find_anchor(b"GGGMKTAY", reference, 3, 2), None);
+            // Reads shorter than --match-len match on their whole length
+            assert_eq!(find_anchor(b"QR", reference, 6, 0), Some((8, 0)));
+        }
+
+        #[test]
+        fn find_anchor_rejects_empty_segments() {
+            assert_eq!(find_anchor(b"MKTAY", b"MKTAYIAKQR", 0, 2), None);
+            assert_eq!(find_anchor(b"", b"MKTAYIAKQR", 6, 2), None);
+            let argv = ["count_AA", "-r", "ref.fa", "-i", "in", "-o", "out", "--match-len", "0"];
+            let err = run(Args::try_parse_from(argv).unwrap()).unwrap_err();
+            assert!(err.to_string().contains("--match-len"), "{}", err);
+        }
+    }
+}mod find_seq {
+    use super::common::{collapse_homopolymers, detect_format, iupac_bases, json_string, open_input, Format, GlobalOpts, RunReport};
+    use anyhow::{anyhow, Context, Result};
+    use bio::io::{fasta, fastq};
+    use clap::Parser;
+    use csv::Writer;
+    use std::collections::{HashMap, HashSet};
+    use std::fs::{self, File};
+    use std::io::{self, BufWriter, Write};
+    use std::path::PathBuf;
+
+    #[derive(Parser, Debug)]
+    #[command(
+        name = "find_seq",
+        about = "Find a motif in FASTA/FASTQ (gz supported), consider reverse complement, extract upstream/downstream flanks, and count unique windows per read. Outputs CSV with Sequence, UpFlank, DownFlank, ReadsCount."
+    )]
+    pub struct Args {
+        #[arg(long, help = "Input FASTA/FASTQ file (optionally .gz)")]
+        pub inputfile: PathBuf,
+        #[arg(long, help = "Output CSV file path")]
+        pub output: PathBuf,
+        #[arg(long, required_unless_present = "motif_file", help = "Target motif sequence; IUPAC ambiguity codes (R, Y, N, ...) match any of their bases. Repeat to search several motifs in one pass")]
+        pub motif: Vec<String>,
+        #[arg(long, help = "File with one motif per line ('NAME SEQ' or just 'SEQ'; blank and '#' lines skipped), searched together with any --motif")]
+        pub motif_file: Option<PathBuf>,
+        #[arg(long, help = "Upstream flank length", default_value_t = 0)]
+        pub up_flank: usize,
+        #[arg(long, help = "Downstream flank length", default_value_t = 0)]
+        pub down_flank: usize,
+        #[arg(long, help = "Collapse homopolymer runs in each read before matching (windows are then taken from the collapsed read)")]
+        pub collapse_homopolymers: bool,
+        #[arg(long, help = "Also stream every hit as newline-delimited JSON to this path ('-' for stdout)")]
+        pub ndjson: Option<PathBuf>,
+        #[arg(long, help = "Also write every hit as a CSV row (ReadID,Start,End,Strand,Window) to this path; Start/End are the 0-based, end-exclusive motif coordinates in the read")]
+        pub positions: Option<PathBuf>,
+        #[arg(long, requires = "ndjson", help = "In --ndjson output, report window and flanks as they appear in the read instead of reverse-complementing minus-strand hits to motif orientation (the CSV stays canonical)")]
+        pub raw_strand_flanks: bool,
+        #[arg(long, default_value_t = 0, help = "Maximum substitutions allowed when matching the motif (and its reverse complement); the window is taken from the read as matched")]
+        pub mismatches: usize,
+        #[arg(long, conflicts_with = "mismatches", help = "Treat --motif as a regular expression matched against the uppercased read; the reverse strand is only searched with --regex-rc")]
+        pub regex: bool,
+        #[arg(long, requires = "regex", help = "Regular expression searched on the read for reverse-strand hits of a --regex motif (i.e. the motif's reverse complement); repeat once per motif, in the same order")]
+        pub regex_rc: Vec<String>,
+
+        #[command(flatten)]
+        pub global: GlobalOpts,
+    }
+
+    /// A compiled motif for one strand.
+    enum Pattern {
+        /// Motif bytes scanned by `find_all`; with `iupac`, a read base matching the code is no mismatch
+        Motif { motif: String, iupac: bool },
+        /// IUPAC motif without mismatches (as character classes), or a --regex pattern
+        Regex(regex::bytes::Regex),
+    }
+
+    impl Pattern {
+        /// Builds the forward and (when searchable) reverse-strand patterns of one motif.
+        fn build(motif: &str, regex_rc: Option<&str>, args: &Args) -> Result<(Pattern, Option<Pattern>)> {
+            let compile = |p: &str| regex::bytes::Regex::new(p).with_context(|| format!("Invalid motif regex: {}", p));
+            if args.regex {
+                return Ok((Pattern::Regex(compile(motif)?), regex_rc.map(compile).transpose()?.map(Pattern::Regex)));
+            }
+            let motif = motif.to_uppercase();
+            if let Some(c) = motif.bytes().find(|&c| iupac_bases(c).is_none()) {
+                return Err(anyhow!("Invalid character '{}' in motif {} (use --regex for a regular expression)", c as char, motif));
+            }
+            // bio's revcomp complements IUPAC codes too (R <-> Y, B <-> V, ...)
+            let motif_rc = String::from_utf8(bio::alphabets::dna::revcomp(motif.replace('U', "T").as_bytes())).unwrap();
+            let iupac = motif.bytes().any(|c| !b"ACGT".contains(&c));
+            let pattern = |m: String| -> Result<Pattern> {
+                if iupac && args.mismatches == 0 {
+                    let class: String = m.bytes().map(|c| format!("[{}]", String::from_utf8_lossy(iupac_bases(c).unwrap()))).collect();
+                    Ok(Pattern::Regex(compile(&class)?))
+                } else {
+                    Ok(Pattern::Motif { motif: m, iupac })
+                }
+            };
+            Ok((pattern(motif)?, Some(pattern(motif_rc)?)))
+        }
+
+        /// (start, end) of every match in `hay`, overlapping ones included.
+        fn find(&self, hay: &str, max_mismatches: usize) -> Vec<(usize, usize)> {
+            match self {
+                Pattern::Motif { motif, iupac } => {
+                    find_all(hay, motif, max_mismatches, *iupac).into_iter().map(|i| (i, i + motif.len())).collect()
+                }
+                Pattern::Regex(re) => {
+                    let mut res = Vec::new();
+                    let mut start = 0;
+                    while let Some(m) = re.find_at(hay.as_bytes(), start) {
+                        if !m.is_empty() {
+                            res.push((m.start(), m.end()));
+                        }
+                        start = m.start() + 1;
+                        if start > hay.len() {
+                            break;
+                        }
+                    }
+                    res
+                }
+            }
+        }
+    }
+
+    /// One searched motif.
+    struct Motif {
+        name: String,
+        forward: Pattern,
+        // None when the reverse strand is not searched (--regex without --regex-rc)
+        reverse: Option<Pattern>,
+    }
+
+    /// Motif search settings shared by every read.
+    struct Search {
+        motifs: Vec<Motif>,
+        up: usize,
+        down: usize,
+        collapse: bool,
+        raw_strand_flanks: bool,
+        mismatches: usize,
+    }
+
+    /// A single motif occurrence whose full window fits inside the read.
+    struct Hit {
+        // Index into `Search::motifs`
+        motif: usize,
+        strand: char,
+        // 0-based start of the matched motif (or its reverse complement) in the read
+        position: usize,
+        // Exclusive end of the match
+        end: usize,
+        // up flank + motif + down flank, in motif orientation
+        window: String,
+        // The same bases as they appear in the read; equals `window` on the + strand
+        read_window: String,
+    }
+
+    fn revcomp(s: &str) -> String {
+        let mut out = String::with_capacity(s.len());
+        for &b in s.as_bytes().iter().rev() {
+            let c = match b {
+                b'A' => 'T',
+                b'T' => 'A',
+                b'C' => 'G',
+                b'G' => 'C',
+                b'N' => 'N',
+                _ => 'N',
+            };
+            out.push(c);
+        }
+        out
+    }
+
+    /// Start positions of `needle` in `hay` with at most `max_mismatches` substitutions.
+    /// Exact search uses `str::find`; otherwise every window is Hamming-compared, and with `iupac`
+    /// a read base counts as a match when the needle's ambiguity code covers it.
+    fn find_all(hay: &str, needle: &str, max_mismatches: usize, iupac: bool) -> Vec<usize> {
+        if max_mismatches > 0 || iupac {
+            let needle = needle.as_bytes();
+            if needle.is_empty() {
+                return Vec::new();
+            }
+            let differs = |base: &u8, code: &u8| if iupac { !iupac_bases(*code).is_some_and(|b| b.contains(base)) } else { base != code };
+            return hay
+                .as_bytes()
+                .windows(needle.len())
+                .enumerate()
+                .filter(|(_, w)| w.iter().zip(needle).filter(|(a, b)| differs(a, b)).nth(max_mismatches).is_none())
+                .map(|(i, _)| i)
+                .collect();
+        }
+        let mut res = Vec::new();
+        let mut start = 0usize;
+        while let Some(pos) = hay[start..].find(needle) {
+            res.push(start + pos);
+            start = start + pos + 1;
+        }
+        res
+    }
+
+    pub fn run(mut args: Args) -> Result<RunReport> {
+        let default_flank = 40usize;
+        if args.up_flank == 0 && args.down_flank == 0 { args.up_flank = default_flank; args.down_flank = default_flank; }
+        else if args.up_flank == 0 { args.up_flank = args.down_flank; }
+        else if args.down_flank == 0 { args.down_flank = args.up_flank; }
+        let up = args.up_flank; let down = args.down_flank;
+        let named = collect_motifs(&args)?;
+        if args.regex && args.regex_rc.is_empty() {
+            eprintln!("Warning: --regex without --regex-rc; only the forward strand is searched");
+        } else if args.regex && args.regex_rc.len() != named.len() {
+            return Err(anyhow!("Got {} --regex-rc patterns for {} motifs; give one per motif", args.regex_rc.len(), named.len()));
+        }
+        let mut motifs = Vec::with_capacity(named.len());
+        for (i, (name, motif)) in named.into_iter().enumerate() {
+            let (forward, reverse) = Pattern::build(&motif, args.regex_rc.get(i).map(String::as_str), &args)?;
+            motifs.push(Motif { name, forward, reverse });
+        }
+        // Single-motif runs keep the original column layout
+        let multi = motifs.len() > 1;
+        let search = Search { motifs, up, down, collapse: args.collapse_homopolymers, raw_strand_flanks: args.raw_strand_flanks, mismatches: args.mismatches };
+
+        let mut ndjson: Option<Box<dyn Write>> = match &args.ndjson {
+            Some(path) if path.as_os_str() == "-" => Some(Box::new(BufWriter::new(io::stdout().lock()))),
+            Some(path) => Some(Box::new(BufWriter::new(
+                File::create(path).with_context(|| format!("Failed to create NDJSON output: {:?}", path))?,
+            ))),
+            None => None,
+        };
+
+        let mut positions = match &args.positions {
+            Some(path) => {
+                let mut w = Writer::from_path(path).with_context(|| format!("Failed to create positions output: {:?}", path))?;
+                let header = ["Motif", "ReadID", "Start", "End", "Strand", "Window"];
+                w.write_record(&header[usize::from(!multi)..])?;
+                Some(w)
+            }
+            None => None,
+        };
+
+        let format = detect_format(&args.inputfile)?;
+        let input_reader = open_input(&args.inputfile)?;
+
+        // Per-motif window counts, indexed like `search.motifs`
+        let mut counts: Vec<HashMap<String, usize>> = vec![HashMap::new(); search.motifs.len()];
+        match format {
+            Format::Fasta => {
+                let reader = fasta::Reader::new(input_reader);
+                for result in reader.records() {
+                    let record = result?;
+                    let seq = String::from_utf8(record.seq().to_vec()).unwrap().to_uppercase();
+                    process_seq(record.id(), &seq, &search, &mut counts, ndjson.as_mut(), positions.as_mut())?;
+                }
+            }
+            Format::Fastq => {
+                let reader = fastq::Reader::new(input_reader);
+                for result in reader.records() {
+                    let record = result?;
+                    let seq = String::from_utf8(record.seq().to_vec()).unwrap().to_uppercase();
+                    process_seq(record.id(), &seq, &search, &mut counts, ndjson.as_mut(), positions.as_mut())?;
+                }
+            }
+        }
+        if let Some(w) = ndjson.as_mut() {
+            w.flush()?;
+        }
+        if let Some(w) = positions.as_mut() {
+            w.flush()?;
+        }
+
+        let mut report = RunReport::new("find_seq")
+            .input(&args.inputfile)
+            .output(&args.output)
+            .count("motifs", search.motifs.len() as u64)
+            .count("unique_windows", counts.iter().map(HashMap::len).sum::<usize>() as u64)
+            .count("window_read_counts", counts.iter().flat_map(HashMap::values).sum::<usize>() as u64);
+        if let Some(path) = args.ndjson.as_ref().filter(|p| p.as_os_str() != "-") {
+            report = report.output(path);
+        }
+        if let Some(path) = &args.positions {
+            report = report.output(path);
+        }
+
+        let mut wtr = Writer::from_path(&args.output)?;
+        let header = ["Motif", "Sequence", "UpFlank", "DownFlank", "ReadsCount"];
+        wtr.write_record(&header[usize::from(!multi)..])?;
+        for (motif, motif_counts) in search.motifs.iter().zip(counts) {
+            for (seq, c) in motif_counts.into_iter() {
+                let up_seq = if up > 0 { seq[..up].to_string() } else { String::new() };
+                let down_seq = if down > 0 { seq[seq.len() - down..].to_string() } else { String::new() };
+                let row = [motif.name.clone(), seq, up_seq, down_seq, c.to_string()];
+                wtr.write_record(&row[usize::from(!multi)..])?;
+            }
+        }
+        wtr.flush()?;
+        Ok(report)
+    }
+
+    fn find_hits(seq: &str, search: &Search) -> Vec<Hit> {
+        let (up, down) = (search.up, search.down);
+        let mut hits = Vec::new();
+        for (motif, m) in search.motifs.iter().enumerate() {
+            for (idx, end) in m.forward.find(seq, search.mismatches) {
+                let left = idx as isize - up as isize;
+                let right = end + down;
+                if left < 0 || right > seq.len() { continue; }
+                let w = &seq[left as usize..right];
+                hits.push(Hit { motif, strand: '+', position: idx, end, window: w.to_string(), read_window: w.to_string() });
+            }
+            for (idx, end) in m.reverse.iter().flat_map(|p| p.find(seq, search.mismatches)) {
+                let left = idx as isize - down as isize;
+                let right = end + up;
+                if left < 0 || right > seq.len() { continue; }
+                let w = &seq[left as usize..right];
+                hits.push(Hit { motif, strand: '-', position: idx, end, window: revcomp(w), read_window: w.to_string() });
+            }
+        }
+        hits
+    }
+
+    /// `--motif` values followed by `--motif-file` entries, as (name, motif); a bare motif is its own name.
+    fn collect_motifs(args: &Args) -> Result<Vec<(String, String)>> {
+        let mut motifs: Vec<(String, String)> = args.motif.iter().map(|m| (m.clone(), m.clone())).collect();
+        if let Some(path) = &args.motif_file {
+            let text = fs::read_to_string(path).with_context(|| format!("Failed to read motif file: {:?}", path))?;
+            for line in text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+                let mut fields = line.split_whitespace();
+                let (name, motif) = match (fields.next(), fields.next()) {
+                    (Some(name), Some(motif)) => (name, motif),
+                    (Some(motif), None) => (motif, motif),
+                    _ => unreachable!(),
+                };
+                motifs.push((name.to_string(), motif.to_string()));
+            }
+        }
+        if motifs.is_empty() {
+            return Err(anyhow!("No motifs given"));
+        }
+        let mut seen = HashSet::new();
+        if let Some((name, _)) = motifs.iter().find(|(name, _)| !seen.insert(name.as_str())) {
+            return Err(anyhow!("Duplicate motif name: {}", name));
+        }
+        Ok(motifs)
+    }
+
+    fn process_seq(
+        id: &str,
+        seq: &str,
+        search: &Search,
+        counts: &mut [HashMap<String, usize>],
+        ndjson: Option<&mut Box<dyn Write>>,
+        positions: Option<&mut Writer<File>>,
+    ) -> Result<()> {
+        let collapsed;
+        let seq = if search.collapse {
+            collapsed = String::from_utf8(collapse_homopolymers(seq.as_bytes())).unwrap();
+            collapsed.as_str()
+        } else {
+            seq
+        };
+        let hits = find_hits(seq, search);
+        if let Some(w) = ndjson {
+            for hit in &hits {
+                // In read orientation a minus-strand hit has its down flank on the left and its up flank on the right
+                let (window, up_flank, down_flank) = if search.raw_strand_flanks && hit.strand == '-' {
+                    let raw = &hit.read_window;
+                    (raw, &raw[raw.len() - search.up..], &raw[..search.down])
+                } else {
+                    (&hit.window, &hit.window[..search.up], &hit.window[hit.window.len() - search.down..])
+                };
+                let motif = if search.motifs.len() > 1 {
+                    format!("\"motif\":{},", json_string(&search.motifs[hit.motif].name))
+                } else {
+                    String::new()
+                };
+                writeln!(
+                    w,
+                    "{{{}\"read_id\":{},\"strand\":\"{}\",\"position\":{},\"window\":{},\"up_flank\":{},\"down_flank\":{}}}",
+                    motif,
+                    json_string(id),
+                    hit.strand,
+                    hit.position,
+                    json_string(window),
+                    json_string(up_flank),
+                    json_string(down_flank),
+                )?;
+            }
+        }
+        if let Some(w) = positions {
+            let multi = search.motifs.len() > 1;
+            for hit in &hits {
+                let row = [&search.motifs[hit.motif].name, id, &hit.position.to_string(), &hit.end.to_string(), &hit.strand.to_string(), &hit.window];
+                w.write_record(&row[usize::from(!multi)..])?;
+            }
+        }
+        let per_read: HashSet<(usize, String)> = hits.into_iter().map(|h| (h.motif, h.window)).collect();
+        for (motif, w) in per_read { *counts[motif].entry(w).or_insert(0) += 1; }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::common::test_util::test_dir;
+
+        /// NDJSON lines for `read` searched with motif GATTC, 2 bp up and 3 bp down.
+        fn ndjson_hits(name: &str, read: &str, raw_strand_flanks: bool) -> Vec<String> {
+            let dir = test_dir(name);
+            let (input, output, ndjson) = (dir.join("reads.fasta"), dir.join("out.csv"), dir.join("hits.ndjson"));
+            fs::write(&input, format!(">r1\n{}\n", read)).unwrap();
+            let mut argv = vec![
+                "find_seq", "--inputfile", input.to_str().unwrap(), "--output", output.to_str().unwrap(), "--motif", "GATTC",
+                "--up-flank", "2", "--down-flank", "3", "--ndjson", ndjson.to_str().unwrap(),
+            ];
+            if raw_strand_flanks {
+                argv.push("--raw-strand-flanks");
+            }
+            run(Args::try_parse_from(argv).unwrap()).unwrap();
+            fs::read_to_string(&ndjson).unwrap().lines().map(str::to_string).collect()
+        }
+
+        #[test]
+        fn plus_strand_hit_is_the_same_with_or_without_raw_strand_flanks() {
+            // AC | GATTC | TTG
+            let expected = r#"{"read_id":"r1","strand":"+","position":2,"window":"ACGATTCTTG","up_flank":"AC","down_flank":"TTG"}"#;
+            assert_eq!(ndjson_hits("find_seq_plus", "ACGATTCTTG", false), [expected]);
+            assert_eq!(ndjson_hits("find_seq_plus_raw", "ACGATTCTTG", true), [expected]);
+        }
+
+        #[test]
+        fn minus_strand_hit_is_reverse_complemented_unless_raw_strand_flanks() {
+            // In the read: down flank ACG | GAATC (motif reverse complement) | up flank TT
+            let read = "ACGGAATCTT";
+            assert_eq!(
+                ndjson_hits("find_seq_minus", read, false),
+                [r#"{"read_id":"r1","strand":"-","position":3,"window":"AAGATTCCGT","up_flank":"AA","down_flank":"CGT"}"#]
+            );
+            assert_eq!(
+                ndjson_hits("find_seq_minus_raw", read, true),
+                [r#"{"read_id":"r1","strand":"-","position":3,"window":"ACGGAATCTT","up_flank":"TT","down_flank":"ACG"}"#]
+            );
+        }
+    }
+}
 
-        let ref_data_arc = Arc::new(ref_data_vec);
+// ==================================================================================
+// `sketch` subcommand module
+// ==================================================================================
+mod sketch {
+    use super::common::{detect_format, open_input, Format, GlobalOpts, RunReport};
+    use anyhow::{anyhow, Context, Result};
+    use bio::io::{fasta, fastq};
+    use clap::Parser;
+    use std::collections::BTreeSet;
+    use std::fs::File;
+    use std::io::{BufRead, BufReader, BufWriter, Write};
+    use std::path::{Path, PathBuf};
 
-        thread::scope(|s| -> Result<()> {
-            let (reads_tx, reads_rx) = crossbeam_channel::bounded::<Vec<Record>>(args_arc.threads * 2);
-            let (results_tx, results_rx) = crossbeam_channel::bounded::<MatchResult>(1024);
+    const HEADER_PREFIX: &str = "#hammer_fastx_sketch";
 
-            let ref_data_for_collector: HashMap<_, _> = ref_data_arc.iter().map(|d| (d.id.clone(), d.n_blocks.clone())).collect();
-            
-            let collector_args = Arc::clone(&args_arc);
-            let collector_handle = s.spawn(move || {
-                collector_thread(results_rx, collector_args.output.clone(), collector_args.group.clone(), collector_args.dig, collector_args.extract_matches, ref_data_for_collector)
-            });
+    #[derive(Parser, Debug)]
+    #[command(name = "sketch", about = "Build a bottom-k MinHash sketch of a FASTA/FASTQ file's canonical k-mers, or compare two sketches")]
+    #[clap(group(
+        clap::ArgGroup::new("mode")
+            .required(true)
+            .args(["inputfile", "compare"]),
+    ))]
+    pub struct Args {
+        #[arg(long, help = "Input FASTA/FASTQ file to sketch (optionally .gz)")]
+        pub inputfile: Option<PathBuf>,
 
-            for _ in 0..args_arc.threads {
-                let rx = reads_rx.clone();
-                let tx = results_tx.clone();
-                let refs = Arc::clone(&ref_data_arc);
-                let args_clone = Arc::clone(&args_arc);
+        #[arg(long, requires = "inputfile", help = "Output sketch file (default: <inputfile>.sketch)")]
+        pub output: Option<PathBuf>,
 
-                s.spawn(move || {
-                    for read_chunk in rx {
-                        for read_record in read_chunk {
-                            let read_seq = read_record.seq().to_ascii_uppercase();
-                            if read_seq.contains(&b'N') { continue; }
-
-                            'ref_loop: for ref_data in refs.iter() {
-                                if let Some(combo) = find_alignment(&read_seq, ref_data, &args_clone, false) {
-                                    if tx.send(MatchResult { ref_id: ref_data.id.clone(), combo, read_record: read_record.clone() }).is_ok() {
-                                        break 'ref_loop;
-                                    }
-                                }
-                                let rc_read = bio::alphabets::dna::revcomp(&read_seq);
-                                if let Some(combo) = find_alignment(&rc_read, ref_data, &args_clone, true) {
-                                    if tx.send(MatchResult { ref_id: ref_data.id.clone(), combo, read_record: read_record.clone() }).is_ok() {
-                                        break 'ref_loop;
-                                    }
-                                }
-                            }
-                        }
-                    }
-                });
+        #[arg(short = 'k', long, default_value_t = 21, help = "k-mer length (1-32)")]
+        pub k: usize,
+
+        #[arg(long, default_value_t = 1000, help = "Number of smallest k-mer hashes kept in the sketch")]
+        pub sketch_size: usize,
+
+        #[arg(long, num_args = 2, value_names = ["A", "B"], help = "Estimate the Jaccard similarity of two sketch files")]
+        pub compare: Vec<PathBuf>,
+
+        #[command(flatten)]
+        pub global: GlobalOpts,
+    }
+
+    /// Bottom-k MinHash sketch: the `size` smallest distinct k-mer hashes seen.
+    struct Sketch {
+        k: usize,
+        size: usize,
+        hashes: BTreeSet<u64>,
+    }
+
+    impl Sketch {
+        fn new(k: usize, size: usize) -> Self {
+            Sketch { k, size, hashes: BTreeSet::new() }
+        }
+
+        fn insert(&mut self, hash: u64) {
+            if self.hashes.len() < self.size {
+                self.hashes.insert(hash);
+            } else if self.hashes.last().is_some_and(|&max| hash < max) && self.hashes.insert(hash) {
+                self.hashes.pop_last();
             }
-            drop(results_tx);
+        }
 
-            let reads_file = File::open(&args_arc.reads)?;
-            let reads_reader = BufReader::new(reads_file);
-            let boxed_reads_reader: Box<dyn BufRead> = if args_arc.reads.extension().map_or(false, |ext| ext == "gz") {
-                Box::new(BufReader::new(MultiGzDecoder::new(reads_reader)))
-            } else {
-                Box::new(reads_reader)
-            };
-            let mut records_iter = fasta::Reader::new(boxed_reads_reader).records();
-            
-            loop {
-                let mut chunk = Vec::with_capacity(CHUNK_SIZE);
-                for _ in 0..CHUNK_SIZE {
-                    match records_iter.next() {
-                        Some(Ok(record)) => chunk.push(record),
-                        Some(Err(e)) => return Err(e.into()),
-                        None => break,
+        /// Adds every canonical k-mer of `seq`, skipping any k-mer that contains a non-ACGT base.
+        fn add_sequence(&mut self, seq: &[u8]) {
+            let k = self.k;
+            let mask = if k == 32 { u64::MAX } else { (1u64 << (2 * k)) - 1 };
+            let shift = 2 * (k as u64 - 1);
+            let (mut fwd, mut rev, mut valid) = (0u64, 0u64, 0usize);
+            for &base in seq {
+                let code = match base {
+                    b'A' | b'a' => 0u64,
+                    b'C' | b'c' => 1,
+                    b'G' | b'g' => 2,
+                    b'T' | b't' => 3,
+                    _ => {
+                        valid = 0;
+                        continue;
                     }
+                };
+                fwd = ((fwd << 2) | code) & mask;
+                rev = (rev >> 2) | ((3 - code) << shift);
+                valid += 1;
+                if valid >= k {
+                    self.insert(mix64(fwd.min(rev)));
                 }
-                if chunk.is_empty() { break; }
-                pb.inc(chunk.len() as u64);
-                if reads_tx.send(chunk).is_err() { break; }
             }
-            drop(reads_tx);
-            pb.finish_with_message("✔ Reads loaded, waiting for alignment to finish...");
+        }
+
+        /// Jaccard estimate from the bottom-k of the union of both sketches.
+        fn jaccard(&self, other: &Sketch) -> f64 {
+            let size = self.size.min(other.size);
+            let union: Vec<&u64> = self.hashes.union(&other.hashes).take(size).collect();
+            if union.is_empty() {
+                return 0.0;
+            }
+            let shared = union
+                .iter()
+                .filter(|h| self.hashes.contains(h) && other.hashes.contains(h))
+                .count();
+            shared as f64 / union.len() as f64
+        }
 
-            collector_handle.join().unwrap()?;
+        fn write(&self, path: &Path, source: &Path) -> Result<()> {
+            let file = File::create(path).with_context(|| format!("Failed to create sketch file: {:?}", path))?;
+            let mut w = BufWriter::new(file);
+            writeln!(w, "{} k={} size={} source={}", HEADER_PREFIX, self.k, self.size, source.display())?;
+            for hash in &self.hashes {
+                writeln!(w, "{}", hash)?;
+            }
+            w.flush()?;
             Ok(())
-        })?;
+        }
 
-        println!("\n✔ All alignment tasks are complete.");
-        Ok(())
+        fn read(path: &Path) -> Result<Self> {
+            let file = File::open(path).with_context(|| format!("Failed to open sketch file: {:?}", path))?;
+            let mut lines = BufReader::new(file).lines();
+            let header = lines.next().transpose()?.unwrap_or_default();
+            if !header.starts_with(HEADER_PREFIX) {
+                return Err(anyhow!("{:?} is not a sketch file (missing '{}' header)", path, HEADER_PREFIX));
+            }
+            let field = |name: &str| -> Result<usize> {
+                header
+                    .split_whitespace()
+                    .find_map(|kv| kv.strip_prefix(name).and_then(|v| v.strip_prefix('=')))
+                    .ok_or_else(|| anyhow!("Sketch header in {:?} has no '{}' field", path, name))?
+                    .parse()
+                    .with_context(|| format!("Invalid '{}' in sketch header of {:?}", name, path))
+            };
+            let mut sketch = Sketch::new(field("k")?, field("size")?);
+            for (i, line) in lines.enumerate() {
+                let line = line?;
+                let hash = line
+                    .trim()
+                    .parse::<u64>()
+                    .with_context(|| format!("Invalid hash on line {} of {:?}", i + 2, path))?;
+                sketch.hashes.insert(hash);
+            }
+            Ok(sketch)
+        }
+    }
+
+    /// 64-bit finalizer (from MurmurHash3) so 2-bit encoded k-mers hash uniformly.
+    fn mix64(mut x: u64) -> u64 {
+        x ^= x >> 33;
+        x = x.wrapping_mul(0xff51afd7ed558ccd);
+        x ^= x >> 33;
+        x = x.wrapping_mul(0xc4ceb9fe1a85ec53);
+        x ^= x >> 33;
+        x
+    }
+
+    fn sketch_file(path: &Path, k: usize, size: usize) -> Result<(Sketch, u64)> {
+        let mut sketch = Sketch::new(k, size);
+        let mut records = 0u64;
+        let reader = open_input(path)?;
+        match detect_format(path)? {
+            Format::Fasta => {
+                for result in fasta::Reader::new(reader).records() {
+                    sketch.add_sequence(result?.seq());
+                    records += 1;
+                }
+            }
+            Format::Fastq => {
+                for result in fastq::Reader::new(reader).records() {
+                    sketch.add_sequence(result?.seq());
+                    records += 1;
+                }
+            }
+        }
+        Ok((sketch, records))
+    }
+
+    pub fn run(args: Args) -> Result<RunReport> {
+        if let [a, b] = args.compare.as_slice() {
+            let (sa, sb) = (Sketch::read(a)?, Sketch::read(b)?);
+            if sa.k != sb.k {
+                return Err(anyhow!("Cannot compare sketches built with different k ({} vs {})", sa.k, sb.k));
+            }
+            let jaccard = sa.jaccard(&sb);
+            println!("Jaccard similarity (k={}): {:.4}", sa.k, jaccard);
+            return Ok(RunReport::new("sketch").input(a).input(b));
+        }
+
+        let input = args.inputfile.ok_or_else(|| anyhow!("--inputfile is required unless --compare is given"))?;
+        if args.k == 0 || args.k > 32 {
+            return Err(anyhow!("--k must be between 1 and 32, got {}", args.k));
+        }
+        if args.sketch_size == 0 {
+            return Err(anyhow!("--sketch-size must be greater than 0"));
+        }
+        let output = args.output.unwrap_or_else(|| {
+            let mut name = input.clone().into_os_string();
+            name.push(".sketch");
+            PathBuf::from(name)
+        });
+
+        let (sketch, records) = sketch_file(&input, args.k, args.sketch_size)?;
+        sketch.write(&output, &input)?;
+        println!(
+            "✔ Sketched {} records from {} ({} hashes, k={}) -> {}",
+            records,
+            input.display(),
+            sketch.hashes.len(),
+            args.k,
+            output.display()
+        );
+        Ok(RunReport::new("sketch")
+            .input(&input)
+            .output(&output)
+            .count("records", records)
+            .count("hashes", sketch.hashes.len() as u64))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::common::test_util::test_dir;
+
+        const SEQ: &[u8] = b"ATGCGTACGTTAGCCGATCGATTACGGCATGCAAGTCCGATAGCTAGGCTTACGATCGGA";
+
+        #[test]
+        fn reverse_complement_gives_the_same_sketch() {
+            let (mut fwd, mut rev) = (Sketch::new(11, 1000), Sketch::new(11, 1000));
+            fwd.add_sequence(SEQ);
+            rev.add_sequence(&bio::alphabets::dna::revcomp(SEQ));
+            assert_eq!(fwd.hashes.len(), SEQ.len() - 11 + 1);
+            assert_eq!(fwd.hashes, rev.hashes);
+        }
+
+        #[test]
+        fn kmers_with_n_are_skipped() {
+            let mut with_n = Sketch::new(5, 1000);
+            with_n.add_sequence(b"ACGTNACGTA");
+            let mut clean = Sketch::new(5, 1000);
+            clean.add_sequence(b"ACGTA");
+            assert_eq!(with_n.hashes, clean.hashes);
+
+            let mut only_n_windows = Sketch::new(5, 1000);
+            only_n_windows.add_sequence(b"ACGTNACGT");
+            assert!(only_n_windows.hashes.is_empty());
+        }
+
+        #[test]
+        fn jaccard_of_identical_and_disjoint_files() {
+            let dir = test_dir("sketch_jaccard");
+            let (a, b, c) = (dir.join("a.fasta"), dir.join("b.fastq"), dir.join("c.fasta"));
+            std::fs::write(&a, format!(">s\n{}\n", std::str::from_utf8(SEQ).unwrap())).unwrap();
+            std::fs::write(&b, format!("@s\n{}\n+\n{}\n", std::str::from_utf8(SEQ).unwrap(), "I".repeat(SEQ.len()))).unwrap();
+            std::fs::write(&c, ">s\nAAAAAAAAAAAAAAAAAAAAAAAA\n").unwrap();
+            let (sa, _) = sketch_file(&a, 11, 1000).unwrap();
+            let (sb, _) = sketch_file(&b, 11, 1000).unwrap();
+            let (sc, _) = sketch_file(&c, 11, 1000).unwrap();
+            assert_eq!(sa.jaccard(&sb), 1.0);
+            assert_eq!(sa.jaccard(&sc), 0.0);
+        }
+
+        #[test]
+        fn sketch_round_trips_through_a_file() {
+            let dir = test_dir("sketch_round_trip");
+            let path = dir.join("a.sketch");
+            let mut sketch = Sketch::new(7, 10);
+            sketch.add_sequence(SEQ);
+            assert_eq!(sketch.hashes.len(), 10);
+            sketch.write(&path, Path::new("a.fasta")).unwrap();
+            let read = Sketch::read(&path).unwrap();
+            assert_eq!((read.k, read.size), (7, 10));
+            assert_eq!(read.hashes, sketch.hashes);
+        }
     }
 }
 
 // ==================================================================================
-// `dna2aa` subcommand module (NEW)
+// `trim` subcommand module
 // ==================================================================================
-mod dna2aa {
-    use anyhow::{anyhow, Context, Result};
-    use bio::io::fasta; // 只导入 FASTA 读写器
+mod trim {
+    use super::common::{create_output, open_input_with_format, Format, GlobalOpts, OutputWriter, RunReport};
+    use anyhow::{anyhow, Result};
+    use bio::io::{fasta, fastq};
     use clap::Parser;
-    use rayon::prelude::*;
-    use std::collections::HashMap;
-    use std::fs::{self, File};
-    use std::io::BufReader;
-    use std::path::{Path, PathBuf};
-    use std::sync::Arc;
+    use std::ops::Range;
+    use std::path::PathBuf;
 
     #[derive(Parser, Debug)]
-    #[command(name = "DNA2AA", about = "Translate DNA FASTA files in a directory to Amino Acid FASTA files")]
+    #[command(name = "trim", about = "Fixed-length, primer and quality trimming of a FASTA/FASTQ file in a single pass")]
     pub struct Args {
-        #[arg(long, short = 'i', help = "Input directory containing DNA FASTA files")]
-        pub input: PathBuf,
+        #[arg(long, help = "Input FASTA/FASTQ file (gz supported, '-' for stdin)")]
+        pub inputfile: PathBuf,
 
-        #[arg(long, short = 'o', help = "Output directory for translated protein FASTA files")]
-        pub output: PathBuf,
+        #[arg(long, help = "Output file (.gz compresses; default: stdout)")]
+        pub outfile: Option<PathBuf>,
 
-        #[arg(long, default_value_t = 50, help = "Minimum amino acid length to keep")]
-        pub aa_length: usize,
-    }
+        #[arg(long, default_value_t = 0, help = "Step 1: remove this many bases from the 5' end")]
+        pub trim_5p: usize,
 
-    // --------------------------------------------------------------------------------
-    // 修复：手动实现标准密码子表，移除对 `bio` 库翻译功能的依赖
-    // --------------------------------------------------------------------------------
-    type CodonTable = HashMap<[u8; 3], u8>;
+        #[arg(long, default_value_t = 0, help = "Step 1: remove this many bases from the 3' end")]
+        pub trim_3p: usize,
 
-    /// 构建一个标准的DNA密码子表
-    fn build_codon_table() -> CodonTable {
-        let mut table = HashMap::new();
-        // 终止密码子 (Stop Codons)
-        table.insert(*b"TAA", b'*');
-        table.insert(*b"TAG", b'*');
-        table.insert(*b"TGA", b'*');
-        // 苯丙氨酸 (F)
-        table.insert(*b"TTT", b'F');
-        table.insert(*b"TTC", b'F');
-        // 亮氨酸 (L)
-        table.insert(*b"TTA", b'L');
-        table.insert(*b"TTG", b'L');
-        table.insert(*b"CTT", b'L');
-        table.insert(*b"CTC", b'L');
-        table.insert(*b"CTA", b'L');
-        table.insert(*b"CTG", b'L');
-        // 异亮氨酸 (I)
-        table.insert(*b"ATT", b'I');
-        table.insert(*b"ATC", b'I');
-        table.insert(*b"ATA", b'I');
-        // 甲硫氨酸 (M) - 起始
-        table.insert(*b"ATG", b'M');
-        // 缬氨酸 (V)
-        table.insert(*b"GTT", b'V');
-        table.insert(*b"GTC", b'V');
-        table.insert(*b"GTA", b'V');
-        table.insert(*b"GTG", b'V');
-        // 丝氨酸 (S)
-        table.insert(*b"TCT", b'S');
-        table.insert(*b"TCC", b'S');
-        table.insert(*b"TCA", b'S');
-        table.insert(*b"TCG", b'S');
-        table.insert(*b"AGT", b'S');
-        table.insert(*b"AGC", b'S');
-        // 脯氨酸 (P)
-        table.insert(*b"CCT", b'P');
-        table.insert(*b"CCC", b'P');
-        table.insert(*b"CCA", b'P');
-        table.insert(*b"CCG", b'P');
-        // 苏氨酸 (T)
-        table.insert(*b"ACT", b'T');
-        table.insert(*b"ACC", b'T');
-        table.insert(*b"ACA", b'T');
-        table.insert(*b"ACG", b'T');
-        // 丙氨酸 (A)
-        table.insert(*b"GCT", b'A');
-        table.insert(*b"GCC", b'A');
-        table.insert(*b"GCA", b'A');
-        table.insert(*b"GCG", b'A');
-        // 酪氨酸 (Y)
-        table.insert(*b"TAT", b'Y');
-        table.insert(*b"TAC", b'Y');
-        // 组氨酸 (H)
-        table.insert(*b"CAT", b'H');
-        table.insert(*b"CAC", b'H');
-        // 谷氨酰胺 (Q)
-        table.insert(*b"CAA", b'Q');
-        table.insert(*b"CAG", b'Q');
-        // 天冬酰胺 (N)
-        table.insert(*b"AAT", b'N');
-        table.insert(*b"AAC", b'N');
-        // 赖氨酸 (K)
-        table.insert(*b"AAA", b'K');
-        table.insert(*b"AAG", b'K');
-        // 天冬氨酸 (D)
-        table.insert(*b"GAT", b'D');
-        table.insert(*b"GAC", b'D');
-        // 谷氨酸 (E)
-        table.insert(*b"GAA", b'E');
-        table.insert(*b"GAG", b'E');
-        // 半胱氨酸 (C)
-        table.insert(*b"TGT", b'C');
-        table.insert(*b"TGC", b'C');
-        // 色氨酸 (W)
-        table.insert(*b"TGG", b'W');
-        // 精氨酸 (R)
-        table.insert(*b"CGT", b'R');
-        table.insert(*b"CGC", b'R');
-        table.insert(*b"CGA", b'R');
-        table.insert(*b"CGG", b'R');
-        table.insert(*b"AGA", b'R');
-        table.insert(*b"AGG", b'R');
-        // 甘氨酸 (G)
-        table.insert(*b"GGT", b'G');
-        table.insert(*b"GGC", b'G');
-        table.insert(*b"GGA", b'G');
-        table.insert(*b"GGG", b'G');
-        table
+        #[arg(long, help = "Step 2: primer to find near the 5' end; the primer and everything before it are removed")]
+        pub primer: Option<String>,
+
+        #[arg(long, default_value_t = 10, help = "Maximum offset from the 5' end at which the primer may start")]
+        pub primer_max_offset: usize,
+
+        #[arg(long, default_value_t = 0, help = "Mismatches allowed when matching the primer")]
+        pub primer_mismatches: usize,
+
+        #[arg(long, help = "Drop reads in which the primer is not found (default: keep them untrimmed by step 2)")]
+        pub discard_no_primer: bool,
+
+        #[arg(long, help = "Step 3 (FASTQ only): trim bases below this Phred quality from both ends")]
+        pub qual_threshold: Option<u8>,
+
+        #[arg(long, default_value_t = 1, help = "Drop reads shorter than this after trimming")]
+        pub min_len: usize,
+
+        #[command(flatten)]
+        pub global: GlobalOpts,
     }
 
-    /// Translates a DNA sequence until the first stop codon (which is not included).
-    /// Mimics Biopython's `seq.translate(to_stop=True)`
-    fn translate_to_stop(dna_seq: &[u8], table: &CodonTable) -> Vec<u8> {
-        let mut protein = Vec::new();
+    #[derive(Default)]
+    struct TrimCounts {
+        reads_in: u64,
+        reads_written: u64,
+        primer_found: u64,
+        no_primer_dropped: u64,
+        too_short: u64,
+    }
 
-        // 遍历3碱基的密码子
-        for codon_bytes in dna_seq.chunks_exact(3) {
-            // 将 &[u8] 转换为 [u8; 3]
-            let codon: [u8; 3] = [
-                codon_bytes[0].to_ascii_uppercase(), 
-                codon_bytes[1].to_ascii_uppercase(), 
-                codon_bytes[2].to_ascii_uppercase()
-            ];
+    /// Trimming steps applied to every record, in order: fixed lengths, primer, quality.
+    struct Trimmer {
+        trim_5p: usize,
+        trim_3p: usize,
+        primer: Option<Vec<u8>>,
+        primer_max_offset: usize,
+        primer_mismatches: usize,
+        discard_no_primer: bool,
+        qual_threshold: Option<u8>,
+        min_len: usize,
+    }
 
-            match table.get(&codon) {
-                Some(aa) => {
-                    if *aa == b'*' {
-                        // 找到终止密码子，停止翻译
-                        break;
+    impl Trimmer {
+        /// Returns the retained range of the read, or None if the read is dropped.
+        fn apply(&self, seq: &[u8], qual: Option<&[u8]>, counts: &mut TrimCounts) -> Option<Range<usize>> {
+            // Step 1: fixed-length trimming
+            let mut start = self.trim_5p.min(seq.len());
+            let mut end = seq.len().saturating_sub(self.trim_3p).max(start);
+
+            // Step 2: primer search within the first `primer_max_offset` positions
+            if let Some(primer) = &self.primer {
+                match self.find_primer(&seq[start..end], primer) {
+                    Some(pos) => {
+                        counts.primer_found += 1;
+                        start += pos + primer.len();
                     }
-                    // 添加氨基酸
-                    protein.push(*aa);
-                }
-                None => {
-                    // 无法识别的密码子 (例如 'N')，翻译为 'X'
-                    protein.push(b'X');
+                    None if self.discard_no_primer => {
+                        counts.no_primer_dropped += 1;
+                        return None;
+                    }
+                    None => {}
                 }
             }
+
+            // Step 3: quality trimming from both ends
+            if let (Some(threshold), Some(qual)) = (self.qual_threshold, qual) {
+                let low = |i: &usize| qual[*i].saturating_sub(33) < threshold;
+                start = (start..end).find(|i| !low(i)).unwrap_or(end);
+                end = (start..end).rev().find(|i| !low(i)).map_or(start, |i| i + 1);
+            }
+
+            if end - start < self.min_len {
+                counts.too_short += 1;
+                return None;
+            }
+            Some(start..end)
+        }
+
+        fn find_primer(&self, seq: &[u8], primer: &[u8]) -> Option<usize> {
+            if seq.len() < primer.len() {
+                return None;
+            }
+            let last_start = self.primer_max_offset.min(seq.len() - primer.len());
+            (0..=last_start).find(|&pos| {
+                let mismatches = seq[pos..pos + primer.len()]
+                    .iter()
+                    .zip(primer)
+                    .filter(|(a, b)| !a.eq_ignore_ascii_case(b))
+                    .count();
+                mismatches <= self.primer_mismatches
+            })
         }
-        protein
     }
-    // --------------------------------------------------------------------------------
-    // 修复结束
-    // --------------------------------------------------------------------------------
 
-    /// Processes a single FASTA file: translates it and saves the result.
-    fn process_single_file(
-        input_path: &Path,
-        output_dir: &Path,
-        min_aa_length: usize,
-        table: &CodonTable, // <-- 接收密码子表
-    ) -> Result<()> {
-        // 1. Determine output path
-        let file_stem = input_path
-            .file_stem()
-            .ok_or_else(|| anyhow!("Could not get file stem for {:?}", input_path))?;
-        let output_filename = format!("{}_protein.fasta", file_stem.to_string_lossy());
-        let output_path = output_dir.join(output_filename);
+    pub fn run(args: Args) -> Result<RunReport> {
+        let trimmer = Trimmer {
+            trim_5p: args.trim_5p,
+            trim_3p: args.trim_3p,
+            primer: args.primer.as_ref().map(|p| p.to_ascii_uppercase().into_bytes()),
+            primer_max_offset: args.primer_max_offset,
+            primer_mismatches: args.primer_mismatches,
+            discard_no_primer: args.discard_no_primer,
+            qual_threshold: args.qual_threshold,
+            min_len: args.min_len,
+        };
+        if trimmer.primer.as_ref().is_some_and(|p| p.is_empty()) {
+            return Err(anyhow!("--primer must not be empty"));
+        }
 
-        // 2. Setup reader and writer
-        let file = File::open(input_path)
-            .with_context(|| format!("Failed to open input file: {:?}", input_path))?;
-        let reader = fasta::Reader::new(BufReader::new(file));
-        let mut writer = fasta::Writer::to_file(&output_path)
-            .with_context(|| format!("Failed to create output file: {:?}", output_path))?;
+        let (format, input_reader) = open_input_with_format(&args.inputfile)?;
+        if format == Format::Fasta && args.qual_threshold.is_some() {
+            return Err(anyhow!("--qual-threshold requires FASTQ input"));
+        }
 
-        let mut records_written = 0;
+        let mut writer = match &args.outfile {
+            Some(path) => create_output(path)?,
+            None => OutputWriter::stdout(),
+        };
 
-        // 3. Translation logic
-        for result in reader.records() {
-            let record = result?;
-            
-            // Translate the DNA sequence, stopping at the first STOP codon
-            let protein = translate_to_stop(record.seq(), table); // <-- 传入密码子表
-
-            if protein.len() >= min_aa_length {
-                // Create a new FASTA record for the protein
-                let aa_record =
-                    fasta::Record::with_attrs(record.id(), None, &protein);
-                writer.write_record(&aa_record)?;
-                records_written += 1;
+        let mut counts = TrimCounts::default();
+        match format {
+            Format::Fasta => {
+                let mut out = fasta::Writer::new(&mut writer);
+                for result in fasta::Reader::new(input_reader).records() {
+                    let record = result?;
+                    counts.reads_in += 1;
+                    if let Some(range) = trimmer.apply(record.seq(), None, &mut counts) {
+                        out.write(record.id(), record.desc(), &record.seq()[range])?;
+                        counts.reads_written += 1;
+                    }
+                }
+                out.flush()?;
+            }
+            Format::Fastq => {
+                let mut out = fastq::Writer::new(&mut writer);
+                for result in fastq::Reader::new(input_reader).records() {
+                    let record = result?;
+                    counts.reads_in += 1;
+                    if let Some(range) = trimmer.apply(record.seq(), Some(record.qual()), &mut counts) {
+                        out.write(record.id(), record.desc(), &record.seq()[range.clone()], &record.qual()[range])?;
+                        counts.reads_written += 1;
+                    }
+                }
+                out.flush()?;
             }
         }
+        writer.finish()?;
+
+        eprintln!(
+            "✔ Trimmed {} reads: {} written, {} too short{}",
+            counts.reads_in,
+            counts.reads_written,
+            counts.too_short,
+            if trimmer.primer.is_some() {
+                format!(", primer found in {} ({} dropped without primer)", counts.primer_found, counts.no_primer_dropped)
+            } else {
+                String::new()
+            }
+        );
 
-        if records_written > 0 {
-             println!(
-                "Processed {:?} -> {:?} (Wrote {} records)",
-                input_path.file_name().unwrap_or_default(),
-                output_path.file_name().unwrap_or_default(),
-                records_written
-            );
+        let mut report = RunReport::new("trim")
+            .input(&args.inputfile)
+            .count("reads_in", counts.reads_in)
+            .count("reads_written", counts.reads_written)
+            .count("too_short", counts.too_short);
+        if trimmer.primer.is_some() {
+            report = report
+                .count("primer_found", counts.primer_found)
+                .count("no_primer_dropped", counts.no_primer_dropped);
         }
-
-        Ok(())
+        if let Some(path) = &args.outfile {
+            report = report.output(path);
+        }
+        Ok(report)
     }
 
-    /// Main run function for the DNA2AA subcommand
-    pub fn run(args: Args) -> Result<()> {
-        let start_time = std::time::Instant::now();
-        
-        // 1. 创建密码子表并用 Arc 包装，以便安全地跨线程共享
-        let codon_table = Arc::new(build_codon_table());
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::common::test_util::test_dir;
+
+        fn trimmer() -> Trimmer {
+            Trimmer {
+                trim_5p: 0,
+                trim_3p: 0,
+                primer: None,
+                primer_max_offset: 10,
+                primer_mismatches: 0,
+                discard_no_primer: false,
+                qual_threshold: None,
+                min_len: 1,
+            }
+        }
 
-        // 2. Create output directory
-        fs::create_dir_all(&args.output)
-            .with_context(|| format!("Failed to create output directory: {:?}", args.output))?;
+        #[test]
+        fn quality_trimming_cuts_low_quality_ends_only() {
+            let t = Trimmer { qual_threshold: Some(20), ..trimmer() };
+            let mut counts = TrimCounts::default();
+            // Q0 Q10 | Q40 Q0 Q40 | Q10: the internal low base is kept
+            assert_eq!(t.apply(b"ACGTAC", Some(b"!+I!I+"), &mut counts), Some(2..5));
+            // Quality is checked after fixed-length trimming
+            let t = Trimmer { trim_5p: 3, ..t };
+            assert_eq!(t.apply(b"ACGTAC", Some(b"!+I!I+"), &mut counts), Some(4..5));
+            // All low: nothing left, dropped by --min-len
+            assert_eq!(t.apply(b"ACGT", Some(b"!!!!"), &mut counts), None);
+            assert_eq!(counts.too_short, 1);
+        }
 
-        // 3. Find all input files
-        let input_files: Vec<PathBuf> = fs::read_dir(&args.input)
-            .with_context(|| format!("Failed to read input directory: {:?}", args.input))?
-            .filter_map(|entry_result| {
-                let entry = entry_result.ok()?;
-                let path = entry.path();
-                if path.is_file() {
-                    if let Some(ext_str) = path.extension().and_then(|s| s.to_str()) {
-                        // Match common FASTA extensions
-                        if ext_str == "fasta" || ext_str == "fa" || ext_str == "fna" {
-                            return Some(path);
-                        }
-                    }
-                }
-                None
-            })
-            .collect();
-        
-        if input_files.is_empty() {
-             println!("Warning: No FASTA files (.fasta, .fa, .fna) found in {:?}.", args.input);
-             return Ok(());
+        #[test]
+        fn primer_is_removed_with_everything_before_it() {
+            let t = Trimmer { primer: Some(b"GGCC".to_vec()), primer_max_offset: 2, ..trimmer() };
+            let mut counts = TrimCounts::default();
+            assert_eq!(t.apply(b"GGCCAAAA", None, &mut counts), Some(4..8));
+            assert_eq!(t.apply(b"TTggccAAAA", None, &mut counts), Some(6..10));
+            // Beyond --primer-max-offset: left untrimmed by the primer step
+            assert_eq!(t.apply(b"TTTGGCCAAAA", None, &mut counts), Some(0..11));
+            assert_eq!(counts.primer_found, 2);
+
+            let t = Trimmer { primer_mismatches: 1, ..t };
+            assert_eq!(t.apply(b"GACCAAAA", None, &mut counts), Some(4..8));
+
+            let t = Trimmer { discard_no_primer: true, ..t };
+            assert_eq!(t.apply(b"TTTTTTTT", None, &mut counts), None);
+            assert_eq!(counts.no_primer_dropped, 1);
+            // Reads shorter than the primer never match
+            assert_eq!(t.apply(b"GGC", None, &mut counts), None);
+            assert_eq!(counts.no_primer_dropped, 2);
         }
 
-        println!(
-            "---> Found {} FASTA files to process in parallel...",
-            input_files.len()
-        );
+        #[test]
+        fn primer_is_searched_after_fixed_trimming() {
+            let t = Trimmer { trim_5p: 2, primer: Some(b"GGCC".to_vec()), primer_max_offset: 0, ..trimmer() };
+            let mut counts = TrimCounts::default();
+            assert_eq!(t.apply(b"TTGGCCAAAA", None, &mut counts), Some(6..10));
+        }
 
-        // 4. Process files in parallel (similar to Python's ProcessPoolExecutor)
-        input_files.par_iter().for_each(|input_path| {
-            // 为每个线程克隆 Arc 引用（开销很小）
-            let table_clone = Arc::clone(&codon_table);
-            if let Err(e) = process_single_file(input_path, &args.output, args.aa_length, &table_clone) {
-                // Print errors from within the parallel loop
-                eprintln!("\n[Error] Failed to process file {:?}: {}\n", input_path.display(), e);
-            }
-        });
+        #[test]
+        fn reads_shorter_than_min_len_are_dropped() {
+            let t = Trimmer { trim_5p: 2, trim_3p: 2, min_len: 3, ..trimmer() };
+            let mut counts = TrimCounts::default();
+            assert_eq!(t.apply(b"AACCCAA", None, &mut counts), Some(2..5));
+            assert_eq!(t.apply(b"AACCAA", None, &mut counts), None);
+            // Trimming more than the read length leaves an empty read, never a panic
+            assert_eq!(t.apply(b"AAA", None, &mut counts), None);
+            assert_eq!(counts.too_short, 2);
+        }
 
-        println!("\n🎉 All files processed successfully! Total time: {:.2?}", start_time.elapsed());
-        println!("Results are in: {}", args.output.display());
-        Ok(())
+        #[test]
+        fn run_trims_fastq_sequence_and_quality_together() {
+            let dir = test_dir("trim_run");
+            let input = dir.join("in.fq");
+            let output = dir.join("out.fq");
+            std::fs::write(&input, "@r1\nTTGGCCACGTAA\n+\nIIIIIIIII+!!\n@r2\nTTTTTTTT\n+\nIIIIIIII\n@r3\nGGCCAC\n+\nIIIII!\n").unwrap();
+            let args = Args::try_parse_from([
+                "trim",
+                "--inputfile",
+                input.to_str().unwrap(),
+                "--outfile",
+                output.to_str().unwrap(),
+                "--primer",
+                "ggcc",
+                "--qual-threshold",
+                "20",
+                "--min-len",
+                "2",
+            ])
+            .unwrap();
+            run(args).unwrap();
+            // r1: primer and 3' low-quality tail removed; r2: no primer, kept; r3: one base left, too short
+            assert_eq!(std::fs::read_to_string(&output).unwrap(), "@r1\nACG\n+\nIII\n@r2\nTTTTTTTT\n+\nIIIIIIII\n");
+        }
+
+        #[test]
+        fn run_rejects_quality_trimming_of_fasta_and_empty_primer() {
+            let dir = test_dir("trim_errors");
+            let input = dir.join("in.fa");
+            std::fs::write(&input, ">r1\nACGT\n").unwrap();
+            let input = input.to_str().unwrap();
+            let err = run(Args::try_parse_from(["trim", "--inputfile", input, "--qual-threshold", "20"]).unwrap()).unwrap_err();
+            assert!(err.to_string().contains("requires FASTQ"), "{}", err);
+            let err = run(Args::try_parse_from(["trim", "--inputfile", input, "--primer", ""]).unwrap()).unwrap_err();
+            assert!(err.to_string().contains("must not be empty"), "{}", err);
+        }
     }
 }
 
 // ==================================================================================
-// `count_AA` subcommand module (NEWLY ADDED)
+// `merge_csv` subcommand module
 // ==================================================================================
-mod count_aa {
+mod merge_csv {
+    use super::common::{GlobalOpts, RunReport};
     use anyhow::{anyhow, Context, Result};
-    use bio::io::fasta::{self, Record};
     use clap::Parser;
-    use crossbeam_channel::bounded;
-    use dashmap::DashMap; // For concurrent counting
-    use glob::glob; // For file matching
-    use rayon::prelude::*; // For parallel iteration
-    use std::collections::HashSet; // <-- 修复：移除未使用的 HashMap
-    use std::fs::{self, File};
-    use std::io::BufReader;
+    use csv::{ReaderBuilder, Writer};
+    use std::collections::{HashMap, HashSet};
+    use std::io::{self, Write};
     use std::path::{Path, PathBuf};
-    use std::sync::atomic::{AtomicU64, Ordering};
-    use std::sync::Arc;
-    use std::thread;
-    use std::time::Instant;
 
     #[derive(Parser, Debug)]
-    #[command(name = "count_AA", about = "[NEW] Count AA mutations against a reference protein sequence, replicating the logic from Count_AAmutants.py")]
+    #[command(name = "merge_csv", about = "Outer-join CSVs on a shared key column into a wide matrix (one value column per input)")]
     pub struct Args {
-        #[arg(short = 'r', long, help = "参考蛋白FASTA序列 (Reference protein FASTA sequence)")]
-        pub reference: PathBuf,
-
-        #[arg(short = 'i', long, help = "包含多个FASTA文件的目录 (Directory containing multiple FASTA files)")]
-        pub input_dir: PathBuf,
+        #[arg(long, help = "Input CSV files (lines starting with '#' are skipped)", required = true, num_args = 1..)]
+        pub inputfile: Vec<PathBuf>,
 
-        #[arg(short = 'o', long, help = "输出CSV文件的目录 (Output directory for CSV files)")]
-        pub output_dir: PathBuf,
+        #[arg(long, help = "Name of the key column shared by all inputs (default: first column of each file)")]
+        pub key: Option<String>,
 
-        #[arg(short = 'A', long, help = "位置偏移量 (Position offset)", default_value_t = 0)]
-        pub aa_offset: i32,
+        #[arg(long, help = "Name of the value column taken from each input (default: first non-key column)")]
+        pub value: Option<String>,
 
-        #[arg(short = 'c', long, help = "CSV配置文件，包含protected_sites列 (CSV config file with 'protected_sites' column)")]
-        pub config: Option<PathBuf>,
+        #[arg(long, default_value = "0", help = "Value written for keys missing from an input (use \"\" for empty)")]
+        pub fill: String,
 
-        #[arg(long, help = "匹配参考起始位置的氨基酸数量 (Number of AAs to match ref start)", default_value_t = 6)]
-        pub match_len: usize,
+        #[arg(long, help = "Output CSV file (default: stdout)")]
+        pub outfile: Option<PathBuf>,
 
-        #[arg(long, help = "每个文件的并行线程数 (Parallel threads per file)", default_value_t = 8)]
-        pub threads: usize,
+        #[command(flatten)]
+        pub global: GlobalOpts,
+    }
 
-        #[arg(long, help = "每块reads数量 (Number of reads per chunk)", default_value_t = 100000)]
-        pub chunk_size: usize,
+    /// Column label for an input: its file stem, or the full path when two inputs share a stem.
+    fn labels(inputs: &[PathBuf]) -> Vec<String> {
+        let stems: Vec<String> = inputs
+            .iter()
+            .map(|p| p.file_stem().unwrap_or_default().to_string_lossy().into_owned())
+            .collect();
+        let mut seen = HashMap::new();
+        for stem in &stems {
+            *seen.entry(stem.as_str()).or_insert(0) += 1;
+        }
+        stems
+            .iter()
+            .zip(inputs)
+            .map(|(stem, path)| if seen[stem.as_str()] > 1 { path.display().to_string() } else { stem.clone() })
+            .collect()
     }
 
-    /// (Helper) Loads the first sequence from a FASTA file.
-    fn load_reference_sequence(path: &Path) -> Result<Vec<u8>> {
-        let file = File::open(path)
-            .with_context(|| format!("Failed to open reference file: {:?}", path))?;
-        let reader = fasta::Reader::new(BufReader::new(file)); // <-- 修复：移除 mut
-        let record = reader
-            .records()
-            .next()
-            .ok_or_else(|| anyhow!("Reference FASTA file is empty: {:?}", path))??;
-        
-        Ok(record.seq().to_ascii_uppercase())
+    fn column_index(headers: &csv::StringRecord, name: &str, path: &Path) -> Result<usize> {
+        headers
+            .iter()
+            .position(|h| h == name)
+            .ok_or_else(|| anyhow!("Column '{}' not found in {:?}", name, path))
     }
 
-    /// (Helper) Loads protected sites from the config CSV.
-    fn load_config(path: &Option<PathBuf>) -> Result<HashSet<usize>> {
-        let mut protected = HashSet::new();
-        let config_path = match path {
-            Some(p) => p,
-            None => return Ok(protected), // No config, return empty set
+    pub fn run(args: Args) -> Result<RunReport> {
+        let labels = labels(&args.inputfile);
+        // Keys in first-seen order, each with one optional value per input
+        let mut keys: Vec<String> = Vec::new();
+        let mut rows: HashMap<String, Vec<Option<String>>> = HashMap::new();
+        let mut key_header: Option<String> = args.key.clone();
+
+        for (file_idx, path) in args.inputfile.iter().enumerate() {
+            let mut rdr = ReaderBuilder::new()
+                .has_headers(true)
+                .comment(Some(b'#'))
+                .flexible(true)
+                .from_path(path)
+                .with_context(|| format!("Failed to open CSV: {:?}", path))?;
+            let headers = rdr.headers()?.clone();
+            let key_idx = match &args.key {
+                Some(name) => column_index(&headers, name, path)?,
+                None => 0,
+            };
+            let value_idx = match &args.value {
+                Some(name) => column_index(&headers, name, path)?,
+                None => (0..headers.len())
+                    .find(|&i| i != key_idx)
+                    .ok_or_else(|| anyhow!("{:?} has no value column besides the key", path))?,
+            };
+            if key_header.is_none() {
+                key_header = headers.get(key_idx).map(str::to_string);
+            }
+
+            let mut seen_in_file = HashSet::new();
+            for result in rdr.records() {
+                let record = result?;
+                let key = record.get(key_idx).unwrap_or_default().to_string();
+                let value = record.get(value_idx).unwrap_or_default().to_string();
+                if !seen_in_file.insert(key.clone()) {
+                    return Err(anyhow!("Duplicate key '{}' in {:?}", key, path));
+                }
+                let values = rows.entry(key.clone()).or_insert_with(|| {
+                    keys.push(key);
+                    vec![None; args.inputfile.len()]
+                });
+                values[file_idx] = Some(value);
+            }
+        }
+
+        let out: Box<dyn Write> = match &args.outfile {
+            Some(path) => Box::new(std::fs::File::create(path).with_context(|| format!("Failed to create output file: {:?}", path))?),
+            None => Box::new(io::stdout().lock()),
         };
+        let mut wtr = Writer::from_writer(out);
+        let mut header = vec![key_header.unwrap_or_else(|| "key".to_string())];
+        header.extend(labels.iter().cloned());
+        wtr.write_record(&header)?;
+        for key in &keys {
+            let mut record = vec![key.clone()];
+            record.extend(rows[key].iter().map(|v| v.clone().unwrap_or_else(|| args.fill.clone())));
+            wtr.write_record(&record)?;
+        }
+        wtr.flush()?;
 
-        println!("---> Loading config: {}", config_path.display());
-        let file = File::open(config_path)
-            .with_context(|| format!("Failed to open config file: {:?}", config_path))?;
-        let mut rdr = csv::Reader::from_reader(file);
+        if let Some(path) = &args.outfile {
+            eprintln!("✔ Merged {} files into {} keys: {}", args.inputfile.len(), keys.len(), path.display());
+        }
+        let mut report = RunReport::new("merge_csv");
+        for path in &args.inputfile {
+            report = report.input(path);
+        }
+        if let Some(path) = &args.outfile {
+            report = report.output(path);
+        }
+        Ok(report.count("keys", keys.len() as u64))
+    }
 
-        // Find the 'protected_sites' column index
-        let headers = rdr.headers()?.clone();
-        let site_col_idx = headers.iter().position(|h| h == "protected_sites");
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::common::test_util::test_dir;
+
+        fn merge(dir: &Path, files: &[(&str, &str)], extra: &[&str]) -> Result<String> {
+            let mut argv = vec!["merge_csv".to_string(), "--inputfile".to_string()];
+            for (name, text) in files {
+                let path = dir.join(name);
+                std::fs::write(&path, text).unwrap();
+                argv.push(path.to_str().unwrap().to_string());
+            }
+            let out = dir.join("merged.csv");
+            argv.extend(["--outfile".to_string(), out.to_str().unwrap().to_string()]);
+            argv.extend(extra.iter().map(|s| s.to_string()));
+            run(Args::try_parse_from(argv).unwrap())?;
+            Ok(std::fs::read_to_string(&out).unwrap())
+        }
 
-        let site_col_idx = match site_col_idx {
-            Some(idx) => idx,
-            None => {
-                println!("Warning: Config file provided, but 'protected_sites' column not found.");
-                return Ok(protected);
+        #[test]
+        fn joins_on_the_named_key_column_in_any_position() {
+            let dir = test_dir("merge_csv_key");
+            let files = [
+                ("s1.csv", "# comment\ncombo,Count,Frequency\nAA,5,50\nCC,5,50\n"),
+                ("s2.csv", "Count,combo\n7,CC\n3,GG\n"),
+            ];
+            let merged = merge(&dir, &files, &["--key", "combo", "--value", "Count"]).unwrap();
+            assert_eq!(merged, "combo,s1,s2\nAA,5,0\nCC,5,7\nGG,0,3\n");
+        }
+
+        #[test]
+        fn missing_keys_take_the_fill_value() {
+            let dir = test_dir("merge_csv_fill");
+            let files = [("a.csv", "id,n\nx,1\n"), ("b.csv", "id,n\ny,2\n")];
+            assert_eq!(merge(&dir, &files, &[]).unwrap(), "id,a,b\nx,1,0\ny,0,2\n");
+            assert_eq!(merge(&dir, &files, &["--fill", ""]).unwrap(), "id,a,b\nx,1,\ny,,2\n");
+            assert_eq!(merge(&dir, &files, &["--fill", "NA"]).unwrap(), "id,a,b\nx,1,NA\ny,NA,2\n");
+        }
+
+        #[test]
+        fn duplicate_keys_within_a_file_are_rejected() {
+            let dir = test_dir("merge_csv_dup");
+            let err = merge(&dir, &[("a.csv", "id,n\nx,1\nx,2\n")], &[]).unwrap_err();
+            assert!(err.to_string().contains("Duplicate key 'x'"), "{}", err);
+            // The same key in different files is the join itself
+            assert_eq!(merge(&dir, &[("a.csv", "id,n\nx,1\n"), ("b.csv", "id,n\nx,2\n")], &[]).unwrap(), "id,a,b\nx,1,2\n");
+        }
+
+        #[test]
+        fn mismatched_headers() {
+            let dir = test_dir("merge_csv_headers");
+            let files = [("a.csv", "combo,Count\nAA,1\n"), ("b.csv", "sequence,Count\nAA,2\n")];
+            // Named columns must exist in every input
+            let err = merge(&dir, &files, &["--key", "combo"]).unwrap_err();
+            assert!(err.to_string().contains("Column 'combo' not found") && err.to_string().contains("b.csv"), "{}", err);
+            let err = merge(&dir, &files, &["--value", "Frequency"]).unwrap_err();
+            assert!(err.to_string().contains("Column 'Frequency' not found"), "{}", err);
+            // By position, differently named key columns still join; the first file names the key column
+            assert_eq!(merge(&dir, &files, &[]).unwrap(), "combo,a,b\nAA,1,2\n");
+            let err = merge(&dir, &[("c.csv", "combo\nAA\n")], &[]).unwrap_err();
+            assert!(err.to_string().contains("no value column"), "{}", err);
+        }
+
+        #[test]
+        fn inputs_sharing_a_stem_are_labelled_by_path() {
+            let a = PathBuf::from("x/counts.csv");
+            let b = PathBuf::from("y/counts.csv");
+            let c = PathBuf::from("y/other.csv");
+            assert_eq!(labels(&[a, b, c]), ["x/counts.csv", "y/counts.csv", "other"]);
+        }
+    }
+}
+
+// ==================================================================================
+// `revcomp` subcommand module
+// ==================================================================================
+mod revcomp {
+    use super::common::{create_output, open_input_with_format, Format, GlobalOpts, OutputWriter, RunReport};
+    use anyhow::Result;
+    use bio::alphabets::dna;
+    use bio::io::{fasta, fastq};
+    use clap::{ArgGroup, Parser};
+    use std::path::PathBuf;
+
+    #[derive(Parser, Debug)]
+    #[command(
+        name = "revcomp",
+        about = "Reverse-complement (or only reverse / only complement) every record of a FASTA/FASTQ file",
+        group(ArgGroup::new("mode").args(["rc_only", "complement_only", "reverse_only"]))
+    )]
+    pub struct Args {
+        #[arg(long, help = "Input FASTA/FASTQ file (gz supported, '-' for stdin)")]
+        pub inputfile: PathBuf,
+
+        #[arg(long, help = "Output file (.gz compresses; default: stdout)")]
+        pub outfile: Option<PathBuf>,
+
+        #[arg(long, help = "Reverse-complement each record (default)")]
+        pub rc_only: bool,
+
+        #[arg(long, help = "Complement each base without reversing (qualities unchanged)")]
+        pub complement_only: bool,
+
+        #[arg(long, help = "Reverse each sequence (and FASTQ quality string) without complementing")]
+        pub reverse_only: bool,
+
+        #[command(flatten)]
+        pub global: GlobalOpts,
+    }
+
+    #[derive(Clone, Copy)]
+    enum Mode {
+        ReverseComplement,
+        Complement,
+        Reverse,
+    }
+
+    impl Mode {
+        fn seq(self, seq: &[u8]) -> Vec<u8> {
+            match self {
+                Mode::ReverseComplement => dna::revcomp(seq),
+                Mode::Complement => seq.iter().map(|&b| dna::complement(b)).collect(),
+                Mode::Reverse => seq.iter().rev().copied().collect(),
+            }
+        }
+
+        fn qual(self, qual: &[u8]) -> Vec<u8> {
+            match self {
+                Mode::Complement => qual.to_vec(),
+                Mode::ReverseComplement | Mode::Reverse => qual.iter().rev().copied().collect(),
             }
+        }
+    }
+
+    pub fn run(args: Args) -> Result<RunReport> {
+        let mode = if args.complement_only {
+            Mode::Complement
+        } else if args.reverse_only {
+            Mode::Reverse
+        } else {
+            Mode::ReverseComplement
         };
 
-        // Iterate through records and parse sites
-        for result in rdr.records() {
-            let record = result?;
-            if let Some(val_str) = record.get(site_col_idx) {
-                if val_str.is_empty() { continue; }
-                
-                match val_str.trim().parse::<f64>() { // Parse as f64 to match Python's behavior
-                    Ok(val) => {
-                        let site_1_based = val as usize;
-                        if site_1_based > 0 {
-                            protected.insert(site_1_based - 1); // Convert to 0-based index
-                        }
-                    }
-                    Err(_) => {
-                         println!("Warning: Could not parse protected site value '{}'", val_str);
-                    }
+        let (format, input_reader) = open_input_with_format(&args.inputfile)?;
+        let mut writer = match &args.outfile {
+            Some(path) => create_output(path)?,
+            None => OutputWriter::stdout(),
+        };
+
+        let mut records = 0u64;
+        match format {
+            Format::Fasta => {
+                let mut out = fasta::Writer::new(&mut writer);
+                for result in fasta::Reader::new(input_reader).records() {
+                    let record = result?;
+                    out.write(record.id(), record.desc(), &mode.seq(record.seq()))?;
+                    records += 1;
                 }
+                out.flush()?;
+            }
+            Format::Fastq => {
+                let mut out = fastq::Writer::new(&mut writer);
+                for result in fastq::Reader::new(input_reader).records() {
+                    let record = result?;
+                    out.write(record.id(), record.desc(), &mode.seq(record.seq()), &mode.qual(record.qual()))?;
+                    records += 1;
+                }
+                out.flush()?;
             }
         }
-        
-        println!("Loaded {} protected sites from config.", protected.len());
-        Ok(protected)
+        writer.finish()?;
+
+        let action = match mode {
+            Mode::ReverseComplement => "Reverse-complemented",
+            Mode::Complement => "Complemented",
+            Mode::Reverse => "Reversed",
+        };
+        eprintln!("✔ {} {} records", action, records);
+
+        let mut report = RunReport::new("revcomp").input(&args.inputfile).count("records", records);
+        if let Some(path) = &args.outfile {
+            report = report.output(path);
+        }
+        Ok(report)
     }
 
-    /// (Helper) This is the core logic from the Python `analyze_chunk` function.
-    /// It processes a chunk of reads and updates the global concurrent counters.
-    fn analyze_chunk(
-        reference_seq: &Vec<u8>,
-        reads: Vec<fasta::Record>,
-        protected_sites: &HashSet<usize>,
-        match_len: usize,
-        aa_counts: &[DashMap<u8, AtomicU64>], // A slice of concurrent maps
-        total_reads: &AtomicU64,
-        total_valid: &AtomicU64,
-    ) {
-        let seq_len = reference_seq.len();
-        total_reads.fetch_add(reads.len() as u64, Ordering::Relaxed);
-        let mut local_valid_reads = 0;
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::common::test_util::test_dir;
+
+        fn revcomp_fastq(name: &str, mode_flag: Option<&str>) -> String {
+            let dir = test_dir(name);
+            let input = dir.join("in.fq");
+            let output = dir.join("out.fq");
+            std::fs::write(&input, "@r1 desc\nAACGTN\n+\n!#%')+\n").unwrap();
+            let mut argv = vec!["revcomp", "--inputfile", input.to_str().unwrap(), "--outfile", output.to_str().unwrap()];
+            argv.extend(mode_flag);
+            run(Args::try_parse_from(argv).unwrap()).unwrap();
+            std::fs::read_to_string(&output).unwrap()
+        }
+
+        #[test]
+        fn fastq_quality_follows_the_bases_in_each_mode() {
+            assert_eq!(revcomp_fastq("revcomp_default", None), "@r1 desc\nNACGTT\n+\n+)'%#!\n");
+            assert_eq!(revcomp_fastq("revcomp_rc", Some("--rc-only")), "@r1 desc\nNACGTT\n+\n+)'%#!\n");
+            assert_eq!(revcomp_fastq("revcomp_complement", Some("--complement-only")), "@r1 desc\nTTGCAN\n+\n!#%')+\n");
+            assert_eq!(revcomp_fastq("revcomp_reverse", Some("--reverse-only")), "@r1 desc\nNTGCAA\n+\n+)'%#!\n");
+        }
+
+        #[test]
+        fn modes_are_mutually_exclusive() {
+            assert!(Args::try_parse_from(["revcomp", "--inputfile", "x.fq", "--reverse-only", "--complement-only"]).is_err());
+        }
+    }
+}
+
+// ==================================================================================
+// `subseq` subcommand module
+// ==================================================================================
+mod subseq {
+    use super::common::{create_output, open_input_with_format, Format, GlobalOpts, OutputWriter, RunReport};
+    use anyhow::{anyhow, Context, Result};
+    use bio::io::{fasta, fastq};
+    use clap::Parser;
+    use std::collections::{HashMap, HashSet};
+    use std::fs;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
 
-        for record in reads {
-            let read = record.seq().to_ascii_uppercase();
-            if read.is_empty() { continue; }
+    #[derive(Parser, Debug)]
+    #[command(name = "subseq", about = "Extract records (or FASTA sub-sequences) by ID from a FASTA/FASTQ file")]
+    pub struct Args {
+        #[arg(long, help = "Input FASTA/FASTQ file (gz supported, '-' for stdin)")]
+        pub inputfile: PathBuf,
 
-            // Prevent panic on reads shorter than match_len
-            let match_segment_len = match_len.min(read.len());
-            let read_start_segment = &read[..match_segment_len];
-            
-            if read_start_segment.is_empty() { continue; }
+        #[arg(long, help = "Output file (.gz compresses; default: stdout)")]
+        pub outfile: Option<PathBuf>,
 
-            // Find start position (Rust equivalent of Python's `str.find()`)
-            let ref_start_pos = reference_seq
-                .windows(read_start_segment.len())
-                .position(|window| window == read_start_segment);
+        #[arg(long = "id", value_name = "ID", help = "Record ID to extract (repeatable)")]
+        pub ids: Vec<String>,
 
-            if ref_start_pos.is_none() {
-                continue; // Not found
-            }
-            
-            let ref_start = ref_start_pos.unwrap();
-            let mut violate = false;
-            let mut mutation_count = 0;
-            
-            // We store (pos, aa) pairs and only update counts *after* checking for violations.
-            // This ensures we don't count AAs from invalid (violated) reads.
-            let mut pos_counts: Vec<(usize, u8)> = Vec::with_capacity(read.len());
+        #[arg(long = "ids", value_name = "FILE", help = "File of record IDs to extract (one per line; first whitespace-separated token)")]
+        pub ids_file: Option<PathBuf>,
 
-            for (i, &aa) in read.iter().enumerate() {
-                let pos = ref_start + i;
-                if pos >= seq_len {
-                    break; // Read is longer than remaining ref
-                }
-                
-                let ref_aa = reference_seq[pos];
+        #[arg(long, value_name = "ID:START-END", help = "FASTA only: extract a 1-based, inclusive sub-sequence, written as '>ID:START-END' (repeatable)")]
+        pub region: Vec<String>,
 
-                if aa != ref_aa {
-                    if protected_sites.contains(&pos) {
-                        violate = true;
-                        break; // Stop checking this read immediately
-                    }
-                    mutation_count += 1;
-                }
-                pos_counts.push((pos, aa));
-            }
+        #[arg(long, help = "Write records in the order of the ID list (--id, then --ids, then --region) instead of input order")]
+        pub id_order: bool,
 
-            // Only if the read is valid do we add its counts to the global map
-            if !violate {
-                for (pos, aa) in pos_counts {
-                    // Find the concurrent map for this position
-                    // Get or create an AtomicU64 counter for this AA
-                    // Increment the counter atomically
-                    aa_counts[pos]
-                        .entry(aa)
-                        .or_insert_with(|| AtomicU64::new(0))
-                        .fetch_add(1, Ordering::Relaxed);
-                }
-                
-                // Check the *other* condition for a "valid read"
-                if mutation_count <= 1 {
-                    local_valid_reads += 1;
-                }
-            }
-        }
-        
-        // Atomically update the global "valid" counter
-        total_valid.fetch_add(local_valid_reads, Ordering::Relaxed);
+        #[command(flatten)]
+        pub global: GlobalOpts,
     }
 
+    /// One requested extraction: a whole record, or a 1-based inclusive region of it.
+    struct Target {
+        id: String,
+        region: Option<(usize, usize)>,
+    }
 
-    /// Main run function for the count_AA subcommand
-    pub fn run(args: Args) -> Result<()> {
-        let main_start_time = Instant::now();
-        
-        fs::create_dir_all(&args.output_dir)
-            .with_context(|| format!("Failed to create output directory: {:?}", args.output_dir))?;
+    struct Rec {
+        id: String,
+        desc: Option<String>,
+        seq: Vec<u8>,
+        qual: Option<Vec<u8>>,
+    }
 
-        // 1. Load Reference and Config
-        let reference_seq = Arc::new(load_reference_sequence(&args.reference)?);
-        let protected_sites = Arc::new(load_config(&args.config)?);
-        println!("Reference sequence loaded ({} AAs).", reference_seq.len());
+    enum RecordWriter<W: Write> {
+        Fasta(fasta::Writer<W>),
+        Fastq(fastq::Writer<W>),
+    }
 
-        // 2. Find input FASTA files (using `glob` crate)
-        let pattern1 = args.input_dir.join("*.fasta").to_string_lossy().to_string();
-        let pattern2 = args.input_dir.join("*.fa").to_string_lossy().to_string();
-        
-        let fasta_files: Vec<PathBuf> = glob(&pattern1)?
-            .filter_map(Result::ok)
-            .chain(glob(&pattern2)?.filter_map(Result::ok))
-            .collect();
+    impl<W: Write> RecordWriter<W> {
+        fn write(&mut self, rec: &Rec) -> Result<()> {
+            match (self, &rec.qual) {
+                (RecordWriter::Fasta(w), _) => w.write(&rec.id, rec.desc.as_deref(), &rec.seq)?,
+                (RecordWriter::Fastq(w), Some(qual)) => w.write(&rec.id, rec.desc.as_deref(), &rec.seq, qual)?,
+                (RecordWriter::Fastq(_), None) => unreachable!("FASTQ records always carry qualities"),
+            }
+            Ok(())
+        }
 
-        if fasta_files.is_empty() {
-            println!("No FASTA files (.fasta, .fa) found in {:?}.", args.input_dir);
-            return Ok(());
+        fn flush(&mut self) -> Result<()> {
+            match self {
+                RecordWriter::Fasta(w) => w.flush()?,
+                RecordWriter::Fastq(w) => w.flush()?,
+            }
+            Ok(())
         }
+    }
 
-        println!("Processing {} FASTA files in parallel ({} threads per file)...", fasta_files.len(), args.threads);
+    /// Parses `ID:START-END`; the ID itself may contain ':'.
+    fn parse_region(spec: &str) -> Result<Target> {
+        let err = || anyhow!("Invalid --region '{}': expected ID:START-END", spec);
+        let (id, range) = spec.rsplit_once(':').ok_or_else(err)?;
+        let (start, end) = range.split_once('-').ok_or_else(err)?;
+        let start: usize = start.trim().parse().map_err(|_| err())?;
+        let end: usize = end.trim().parse().map_err(|_| err())?;
+        if id.is_empty() || start == 0 || start > end {
+            return Err(anyhow!("Invalid --region '{}': need a non-empty ID and 1 <= START <= END", spec));
+        }
+        Ok(Target { id: id.to_string(), region: Some((start, end)) })
+    }
 
-        // 3. Configure Rayon global thread pool
-        // This sets the *total* number of threads Rayon will use.
-        rayon::ThreadPoolBuilder::new().num_threads(args.threads).build_global()?;
+    /// Reads one ID per line in file order; only the first whitespace-separated token counts and blank lines are skipped.
+    fn load_ids(path: &Path) -> Result<Vec<String>> {
+        let text = fs::read_to_string(path).with_context(|| format!("Failed to read ID list: {:?}", path))?;
+        Ok(text.lines().filter_map(|line| line.split_whitespace().next()).map(str::to_string).collect())
+    }
 
-        // 4. Process each file (sequentially, as in Python)
-        // The parallelism is *within* each file's chunk processing.
-        for fasta_file in fasta_files {
-            let file_start_time = Instant::now();
-            let file_stem = fasta_file.file_stem().unwrap_or_default().to_string_lossy();
-            println!("\n---> Processing file: {}", fasta_file.display());
+    /// Builds the output record for `target` from a matching input record.
+    fn extract(target: &Target, rec: &Rec) -> Result<Rec> {
+        let Some((start, end)) = target.region else {
+            return Ok(Rec { id: rec.id.clone(), desc: rec.desc.clone(), seq: rec.seq.clone(), qual: rec.qual.clone() });
+        };
+        if end > rec.seq.len() {
+            return Err(anyhow!("--region {}:{}-{} is past the end of '{}' (length {})", target.id, start, end, rec.id, rec.seq.len()));
+        }
+        Ok(Rec {
+            id: format!("{}:{}-{}", rec.id, start, end),
+            desc: rec.desc.clone(),
+            seq: rec.seq[start - 1..end].to_vec(),
+            qual: None,
+        })
+    }
 
-            // --- Setup concurrent data structures for this file ---
-            let seq_len = reference_seq.len();
-            // Create a Vec of DashMaps, one for each position in the reference
-            // Each DashMap stores: AA (u8) -> AtomicU64 (count)
-            let global_counts: Arc<Vec<DashMap<u8, AtomicU64>>> = 
-                Arc::new((0..seq_len).map(|_| DashMap::new()).collect());
-            
-            let total_reads = Arc::new(AtomicU64::new(0));
-            let total_valid = Arc::new(AtomicU64::new(0));
+    pub fn run(args: Args) -> Result<RunReport> {
+        let mut targets: Vec<Target> = args.ids.iter().map(|id| Target { id: id.clone(), region: None }).collect();
+        if let Some(path) = &args.ids_file {
+            targets.extend(load_ids(path)?.into_iter().map(|id| Target { id, region: None }));
+        }
+        for spec in &args.region {
+            targets.push(parse_region(spec)?);
+        }
+        if targets.is_empty() {
+            return Err(anyhow!("Nothing to extract: give at least one --id, --ids or --region"));
+        }
+        // A repeated request (same ID and region) is written once, at its first position
+        let mut requested = HashSet::new();
+        targets.retain(|target| requested.insert((target.id.clone(), target.region)));
+        let mut by_id: HashMap<&str, Vec<usize>> = HashMap::new();
+        for (idx, target) in targets.iter().enumerate() {
+            by_id.entry(target.id.as_str()).or_default().push(idx);
+        }
 
-            // Create Arcs for data to be shared across threads
-            let reference_seq_clone = Arc::clone(&reference_seq);
-            let protected_sites_clone = Arc::clone(&protected_sites);
-            let global_counts_clone = Arc::clone(&global_counts);
-            let total_reads_clone = Arc::clone(&total_reads);
-            let total_valid_clone = Arc::clone(&total_valid);
-            
-            let (tx, rx) = bounded::<Vec<fasta::Record>>(args.threads * 2); // Channel for chunks of records
+        let (format, input_reader) = open_input_with_format(&args.inputfile)?;
+        if format == Format::Fastq && !args.region.is_empty() {
+            return Err(anyhow!("--region requires FASTA input"));
+        }
+        let records: Box<dyn Iterator<Item = Result<Rec>>> = match format {
+            Format::Fasta => Box::new(fasta::Reader::new(input_reader).records().map(|r| {
+                let r = r?;
+                Ok(Rec { id: r.id().to_string(), desc: r.desc().map(str::to_string), seq: r.seq().to_vec(), qual: None })
+            })),
+            Format::Fastq => Box::new(fastq::Reader::new(input_reader).records().map(|r| {
+                let r = r?;
+                Ok(Rec { id: r.id().to_string(), desc: r.desc().map(str::to_string), seq: r.seq().to_vec(), qual: Some(r.qual().to_vec()) })
+            })),
+        };
 
-            // --- Use thread::scope for structured concurrency ---
-            let res: Result<()> = thread::scope(|s| {
-                // --- 1. Reader Thread ---
-                // This thread reads the FASTA file and sends chunks of records to the channel
-                let fasta_file_clone = fasta_file.clone();
-                let chunk_size = args.chunk_size;
-                s.spawn(move || {
-                    let file = match File::open(&fasta_file_clone) {
-                        Ok(f) => f,
-                        Err(e) => {
-                            eprintln!("Error opening {}: {:?}", fasta_file_clone.display(), e);
-                            return; // Exit thread
-                        }
-                    };
-                    let reader = BufReader::new(file);
-                    let fasta_reader = fasta::Reader::new(reader); // <-- 修复：移除 mut
-                    let mut records_iter = fasta_reader.records();
+        let mut writer = match &args.outfile {
+            Some(path) => create_output(path)?,
+            None => OutputWriter::stdout(),
+        };
+        let mut out = match format {
+            Format::Fasta => RecordWriter::Fasta(fasta::Writer::new(&mut writer)),
+            Format::Fastq => RecordWriter::Fastq(fastq::Writer::new(&mut writer)),
+        };
 
-                    loop {
-                        let mut chunk = Vec::with_capacity(chunk_size);
-                        for _ in 0..chunk_size {
-                            match records_iter.next() {
-                                Some(Ok(record)) => chunk.push(record),
-                                Some(Err(e)) => {
-                                    eprintln!("Error reading record from {}: {}", fasta_file_clone.display(), e);
-                                    // Continue to next record
-                                },
-                                None => break, // End of file
-                            }
-                        }
-                        
-                        let is_empty = chunk.is_empty();
-                        if tx.send(chunk).is_err() {
-                            break; // Receiver hung up, stop reading
-                        }
-                        if is_empty {
-                            break; // End of file
-                        }
-                    }
-                });
+        // Only the first input record with a given ID is used for each target; records are
+        // held back only when --id-order needs them, otherwise they are written as found
+        let mut found = vec![false; targets.len()];
+        let mut held: Vec<Option<Rec>> = if args.id_order { targets.iter().map(|_| None).collect() } else { Vec::new() };
+        let mut records_in = 0u64;
+        let mut records_written = 0u64;
+        for rec in records {
+            let rec = rec?;
+            records_in += 1;
+            let Some(indices) = by_id.get(rec.id.as_str()) else { continue };
+            for &idx in indices {
+                if found[idx] {
+                    continue;
+                }
+                let extracted = extract(&targets[idx], &rec)?;
+                if args.id_order {
+                    held[idx] = Some(extracted);
+                } else {
+                    out.write(&extracted)?;
+                    records_written += 1;
+                }
+                found[idx] = true;
+            }
+        }
+        for rec in held.iter().flatten() {
+            out.write(rec)?;
+            records_written += 1;
+        }
+        out.flush()?;
+        drop(out);
+        writer.finish()?;
+
+        let missing: Vec<&str> = targets.iter().zip(&found).filter(|(_, &f)| !f).map(|(t, _)| t.id.as_str()).collect();
+        if !missing.is_empty() {
+            let shown = missing.iter().take(5).copied().collect::<Vec<_>>().join(", ");
+            eprintln!(
+                "⚠ {} requested ID(s) not found in {}: {}{}",
+                missing.len(),
+                args.inputfile.display(),
+                shown,
+                if missing.len() > 5 { ", ..." } else { "" }
+            );
+        }
+        eprintln!("✔ Extracted {} records from {} input records", records_written, records_in);
+
+        let mut report = RunReport::new("subseq")
+            .input(&args.inputfile)
+            .count("records_in", records_in)
+            .count("records_written", records_written)
+            .count("ids_not_found", missing.len() as u64);
+        if let Some(path) = &args.ids_file {
+            report = report.input(path);
+        }
+        if let Some(path) = &args.outfile {
+            report = report.output(path);
+        }
+        Ok(report)
+    }
 
-                // --- 2. Worker Pool (using Rayon) ---
-                // This consumes chunks from the channel (rx)
-                // `par_bridge` turns the channel iterator into a parallel iterator
-                // `for_each` processes each chunk in parallel using the Rayon thread pool
-                rx.into_iter().par_bridge().for_each(|chunk: Vec<Record>| {
-                    analyze_chunk(
-                        &reference_seq_clone,
-                        chunk,
-                        &protected_sites_clone,
-                        args.match_len,
-                        &global_counts_clone,
-                        &total_reads_clone,
-                        &total_valid_clone,
-                    );
-                });
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::common::test_util::test_dir;
+
+        const FASTA: &str = ">a first\nACGTACGTAC\n>b\nGGGGCCCC\n>c\nTTTT\n";
+
+        fn subseq(name: &str, input: &str, extra: &[&str]) -> Result<String> {
+            let dir = test_dir(name);
+            let inputfile = dir.join("in.fa");
+            let outfile = dir.join("out.fa");
+            std::fs::write(&inputfile, input).unwrap();
+            let mut argv = vec!["subseq", "--inputfile", inputfile.to_str().unwrap(), "--outfile", outfile.to_str().unwrap()];
+            argv.extend(extra);
+            run(Args::try_parse_from(argv).unwrap())?;
+            Ok(std::fs::read_to_string(&outfile).unwrap())
+        }
 
-                Ok(())
-            }); // --- End of thread::scope ---
+        #[test]
+        fn region_bounds_are_one_based_and_inclusive() {
+            let out = subseq("subseq_region", FASTA, &["--region", "a:1-1", "--region", "a:8-10", "--region", "b:1-8"]).unwrap();
+            assert_eq!(out, ">a:1-1 first\nA\n>a:8-10 first\nTAC\n>b:1-8\nGGGGCCCC\n");
+        }
 
-            if let Err(e) = res {
-                eprintln!("Error during processing {}: {:?}", fasta_file.display(), e);
-                continue; // Skip to next file
+        #[test]
+        fn region_errors() {
+            let err = subseq("subseq_region_past_end", FASTA, &["--region", "a:5-11"]).unwrap_err();
+            assert!(err.to_string().contains("past the end of 'a' (length 10)"), "{}", err);
+            for spec in ["a", "a:5", "a:x-3", ":1-2", "a:0-3", "a:4-3"] {
+                assert!(parse_region(spec).is_err(), "{}", spec);
             }
+            // The ID may itself contain ':'
+            let target = parse_region("chr1:part:2-3").unwrap();
+            assert_eq!((target.id.as_str(), target.region), ("chr1:part", Some((2, 3))));
+            let fastq = "@r1\nACGT\n+\nIIII\n";
+            let err = subseq("subseq_region_fastq", fastq, &["--region", "r1:1-2"]).unwrap_err();
+            assert!(err.to_string().contains("requires FASTA"), "{}", err);
+        }
 
-            // --- 3. Collate and Write Results for this file ---
-            let total_r = total_reads.load(Ordering::Relaxed);
-            let total_v = total_valid.load(Ordering::Relaxed);
-            println!("{} - Valid reads: {} / {}", file_stem, total_v, total_r);
-
-            let mut mutation_stats = Vec::new();
-            for (i, counter_map) in global_counts.iter().enumerate() {
-                let ref_aa = reference_seq[i]; // Get the reference AA at this position
-                let adj_pos = (i as i32) + 1 + args.aa_offset; // Calculate the adjusted position
-                
-                for item in counter_map.iter() {
-                    let aa = *item.key();
-                    let count = item.value().load(Ordering::Relaxed);
-                    if count > 0 {
-                        // Format: e.g., "A123C"
-                        let mutation_str = format!("{}{}{}", ref_aa as char, adj_pos, aa as char);
-                        mutation_stats.push((mutation_str, count));
-                    }
-                }
-            }
-            
-            // Sort by mutation string (e.g., "A10C" before "A11G")
-            mutation_stats.sort_by(|a, b| a.0.cmp(&b.0));
+        #[test]
+        fn output_follows_input_order_unless_id_order() {
+            let extra = ["--id", "c", "--region", "b:2-3", "--id", "a", "--id", "missing"];
+            assert_eq!(subseq("subseq_input_order", FASTA, &extra).unwrap(), ">a first\nACGTACGTAC\n>b:2-3\nGG\n>c\nTTTT\n");
+            let extra = [&extra[..], &["--id-order"]].concat();
+            assert_eq!(subseq("subseq_id_order", FASTA, &extra).unwrap(), ">c\nTTTT\n>a first\nACGTACGTAC\n>b:2-3\nGG\n");
+        }
 
-            // Write to CSV
-            let output_file_name = format!("{}_mutation.csv", file_stem);
-            let output_path = args.output_dir.join(output_file_name);
-            
-            let mut wtr = csv::Writer::from_path(&output_path)
-                .with_context(|| format!("Failed to create output CSV: {:?}", output_path))?;
-            
-            wtr.write_record(&["Mutation", "Count"])?;
-            for (mutation, count) in mutation_stats {
-                wtr.write_record(&[mutation, count.to_string()])?;
-            }
-            
-            wtr.flush()?;
-            println!("Results saved to: {}", output_path.display());
-            println!("Time taken for {}: {:.2?}", file_stem, file_start_time.elapsed());
+        #[test]
+        fn repeated_targets_and_duplicate_records_are_written_once() {
+            let input = ">a\nAAAA\n>a\nCCCC\n";
+            let extra = ["--id", "a", "--id", "a", "--region", "a:1-2", "--region", "a:1-2", "--region", "a:2-3"];
+            assert_eq!(subseq("subseq_repeats", input, &extra).unwrap(), ">a\nAAAA\n>a:1-2\nAA\n>a:2-3\nAA\n");
         }
 
-        println!("\n🎉 All files have been processed. Total time: {:.2?}", main_start_time.elapsed());
-        Ok(())
+        #[test]
+        fn fastq_records_keep_their_qualities() {
+            let fastq = "@r1\nACGT\n+\nIIII\n@r2\nGG\n+\n!!\n";
+            assert_eq!(subseq("subseq_fastq", fastq, &["--id", "r2"]).unwrap(), "@r2\nGG\n+\n!!\n");
+        }
     }
-}mod find_seq {
-    use super::common::{detect_format, Format};
-    use anyhow::Result;
-    use bio::io::{fasta, fastq};
+}
+
+// ==================================================================================
+// `fq2fa` subcommand module
+// ==================================================================================
+mod fq2fa {
+    use super::common::{create_output, open_input_with_format, DescArgs, Format, GlobalOpts, OutputWriter, RunReport};
+    use anyhow::{anyhow, Result};
+    use bio::io::fastq;
     use clap::Parser;
-    use csv::Writer;
-    use flate2::bufread::MultiGzDecoder;
-    use std::collections::{HashMap, HashSet};
-    use std::fs::File;
-    use std::io::{BufRead, BufReader};
+    use std::io::{self, Write};
     use std::path::PathBuf;
 
     #[derive(Parser, Debug)]
-    #[command(
-        name = "find_seq",
-        about = "Find a motif in FASTA/FASTQ (gz supported), consider reverse complement, extract upstream/downstream flanks, and count unique windows per read. Outputs CSV with Sequence, UpFlank, DownFlank, ReadsCount."
-    )]
+    #[command(name = "fq2fa", about = "Convert one or more FASTQ files (or stdin) to FASTA")]
     pub struct Args {
-        #[arg(long, help = "Input FASTA/FASTQ file (optionally .gz)")]
-        pub inputfile: PathBuf,
-        #[arg(long, help = "Output CSV file path")]
-        pub output: PathBuf,
-        #[arg(long, help = "Target motif sequence")]
-        pub motif: String,
-        #[arg(long, help = "Upstream flank length", default_value_t = 0)]
-        pub up_flank: usize,
-        #[arg(long, help = "Downstream flank length", default_value_t = 0)]
-        pub down_flank: usize,
-    }
+        #[arg(long, num_args = 1.., default_value = "-", help = "Input FASTQ file(s) (gz/bz2/zst supported, '-' for stdin; default: stdin)")]
+        pub inputfile: Vec<PathBuf>,
 
-    fn revcomp(s: &str) -> String {
-        let mut out = String::with_capacity(s.len());
-        for &b in s.as_bytes().iter().rev() {
-            let c = match b {
-                b'A' => 'T',
-                b'T' => 'A',
-                b'C' => 'G',
-                b'G' => 'C',
-                b'N' => 'N',
-                _ => 'N',
-            };
-            out.push(c);
-        }
-        out
+        #[arg(long, help = "Output FASTA file (.gz compresses; default: stdout)")]
+        pub outfile: Option<PathBuf>,
+
+        #[arg(long, help = "Skip reads shorter than this")]
+        pub min_len: Option<usize>,
+
+        #[arg(long, help = "Skip reads longer than this")]
+        pub max_len: Option<usize>,
+
+        #[arg(long, default_value_t = 0, help = "Wrap FASTA sequence lines at this many bases (0 = one line per sequence)")]
+        pub width: usize,
+
+        #[command(flatten)]
+        pub desc: DescArgs,
+
+        #[command(flatten)]
+        pub global: GlobalOpts,
     }
 
-    fn find_all(hay: &str, needle: &str) -> Vec<usize> {
-        let mut res = Vec::new();
-        let mut start = 0usize;
-        while let Some(pos) = hay[start..].find(needle) {
-            res.push(start + pos);
-            start = start + pos + 1;
+    fn write_fasta(out: &mut dyn Write, id: &str, desc: Option<&str>, seq: &[u8], width: usize) -> io::Result<()> {
+        match desc {
+            Some(desc) => writeln!(out, ">{} {}", id, desc)?,
+            None => writeln!(out, ">{}", id)?,
         }
-        res
+        if width == 0 || seq.is_empty() {
+            out.write_all(seq)?;
+            return out.write_all(b"\n");
+        }
+        for line in seq.chunks(width) {
+            out.write_all(line)?;
+            out.write_all(b"\n")?;
+        }
+        Ok(())
     }
 
-    pub fn run(mut args: Args) -> Result<()> {
-        let default_flank = 40usize;
-        if args.up_flank == 0 && args.down_flank == 0 { args.up_flank = default_flank; args.down_flank = default_flank; }
-        else if args.up_flank == 0 { args.up_flank = args.down_flank; }
-        else if args.down_flank == 0 { args.down_flank = args.up_flank; }
-        let up = args.up_flank; let down = args.down_flank;
-        let motif = args.motif.to_uppercase();
-        let motif_rc = revcomp(&motif);
+    pub fn run(args: Args) -> Result<RunReport> {
+        let min_len = args.min_len.unwrap_or(0);
+        let max_len = args.max_len.unwrap_or(usize::MAX);
+        if min_len > max_len {
+            return Err(anyhow!("--min-len ({}) must not exceed --max-len ({})", min_len, max_len));
+        }
 
-        let format = detect_format(&args.inputfile)?;
-        let file = File::open(&args.inputfile)?;
-        let buf_reader = BufReader::new(file);
-        let input_reader: Box<dyn BufRead> = if args.inputfile.extension().map_or(false, |ext| ext == "gz") { Box::new(BufReader::new(MultiGzDecoder::new(buf_reader))) } else { Box::new(buf_reader) };
+        let mut writer = match &args.outfile {
+            Some(path) => create_output(path)?,
+            None => OutputWriter::stdout(),
+        };
 
-        let mut counts: HashMap<String, usize> = HashMap::new();
-        match format {
-            Format::Fasta => {
-                let reader = fasta::Reader::new(input_reader);
-                for result in reader.records() {
-                    let record = result?;
-                    let seq = String::from_utf8(record.seq().to_vec()).unwrap().to_uppercase();
-                    process_seq(&seq, &motif, &motif_rc, up, down, &mut counts);
-                }
+        let mut reads_in = 0u64;
+        let mut reads_written = 0u64;
+        for path in &args.inputfile {
+            let (format, input_reader) = open_input_with_format(path)?;
+            if format != Format::Fastq {
+                return Err(anyhow!("fq2fa requires FASTQ input, but {:?} looks like FASTA", path));
             }
-            Format::Fastq => {
-                let reader = fastq::Reader::new(input_reader);
-                for result in reader.records() {
-                    let record = result?;
-                    let seq = String::from_utf8(record.seq().to_vec()).unwrap().to_uppercase();
-                    process_seq(&seq, &motif, &motif_rc, up, down, &mut counts);
+            for result in fastq::Reader::new(input_reader).records() {
+                let record = result?;
+                reads_in += 1;
+                let len = record.seq().len();
+                if len < min_len || len > max_len {
+                    continue;
                 }
+                write_fasta(&mut writer, record.id(), args.desc.apply(record.desc()), record.seq(), args.width)?;
+                reads_written += 1;
             }
         }
+        writer.finish()?;
 
-        let mut wtr = Writer::from_path(&args.output)?;
-        wtr.write_record(["Sequence", "UpFlank", "DownFlank", "ReadsCount"])?;
-        for (seq, c) in counts.into_iter() {
-            let up_seq = if up > 0 { seq[..up].to_string() } else { String::new() };
-            let down_seq = if down > 0 { seq[seq.len() - down..].to_string() } else { String::new() };
-            wtr.write_record([seq, up_seq, down_seq, c.to_string()])?;
+        eprintln!(
+            "✔ Converted {} of {} reads from {} file(s) to FASTA",
+            reads_written,
+            reads_in,
+            args.inputfile.len()
+        );
+
+        let mut report = RunReport::new("fq2fa")
+            .count("reads_in", reads_in)
+            .count("reads_written", reads_written);
+        for path in &args.inputfile {
+            report = report.input(path);
         }
-        wtr.flush()?;
-        Ok(())
+        if let Some(path) = &args.outfile {
+            report = report.output(path);
+        }
+        Ok(report)
     }
 
-    fn process_seq(seq: &str, motif: &str, motif_rc: &str, up: usize, down: usize, counts: &mut HashMap<String, usize>) {
-        let mut per_read: HashSet<String> = HashSet::new();
-        for idx in find_all(seq, motif) {
-            let left = idx as isize - up as isize;
-            let right = idx + motif.len() + down;
-            if left < 0 || right > seq.len() { continue; }
-            let w = &seq[left as usize..right];
-            per_read.insert(w.to_string());
-        }
-        for idx in find_all(seq, motif_rc) {
-            let left = idx as isize - down as isize;
-            let right = idx + motif.len() + up;
-            if left < 0 || right > seq.len() { continue; }
-            let w = &seq[left as usize..right];
-            let w_rc = revcomp(w);
-            per_read.insert(w_rc);
-        }
-        for w in per_read { *counts.entry(w).or_insert(0) += 1; }
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::common::test_util::{fasta_headers, test_dir};
+
+        #[test]
+        fn keeps_or_strips_descriptions() {
+            let dir = test_dir("fq2fa_desc");
+            let input = dir.join("in.fastq");
+            std::fs::write(&input, "@r1 sample=A\nACGT\n+\nIIII\n").unwrap();
+            for (flag, expected) in [("--keep-desc", ">r1 sample=A"), ("--strip-desc", ">r1")] {
+                let out = dir.join("out.fasta.gz");
+                let args = Args::try_parse_from(["fq2fa", "--inputfile", input.to_str().unwrap(), "--outfile", out.to_str().unwrap(), flag]).unwrap();
+                run(args).unwrap();
+                assert_eq!(fasta_headers(&out), [expected]);
+            }
+        }
+
+        fn fq2fa(name: &str, input: &str, extra: &[&str]) -> Result<String> {
+            let dir = test_dir(name);
+            let inputfile = dir.join("in.fastq");
+            let outfile = dir.join("out.fasta");
+            std::fs::write(&inputfile, input).unwrap();
+            let mut argv = vec!["fq2fa", "--inputfile", inputfile.to_str().unwrap(), "--outfile", outfile.to_str().unwrap()];
+            argv.extend(extra);
+            run(Args::try_parse_from(argv).unwrap())?;
+            Ok(std::fs::read_to_string(&outfile).unwrap())
+        }
+
+        #[test]
+        fn width_wraps_sequence_lines() {
+            let input = "@r1\nACGTACG\n+\nIIIIIII\n@r2\nACG\n+\nIII\n";
+            assert_eq!(fq2fa("fq2fa_width0", input, &[]).unwrap(), ">r1\nACGTACG\n>r2\nACG\n");
+            // An exact multiple of the width gets no trailing empty line
+            assert_eq!(fq2fa("fq2fa_width3", input, &["--width", "3"]).unwrap(), ">r1\nACG\nTAC\nG\n>r2\nACG\n");
+        }
+
+        #[test]
+        fn min_and_max_len_are_inclusive() {
+            let input = "@r2\nAC\n+\nII\n@r3\nACG\n+\nIII\n@r4\nACGT\n+\nIIII\n@r5\nACGTA\n+\nIIIII\n";
+            let out = fq2fa("fq2fa_len", input, &["--min-len", "3", "--max-len", "4"]).unwrap();
+            assert_eq!(out, ">r3\nACG\n>r4\nACGT\n");
+            let err = fq2fa("fq2fa_len_error", input, &["--min-len", "5", "--max-len", "4"]).unwrap_err();
+            assert!(err.to_string().contains("must not exceed"), "{}", err);
+        }
     }
 }